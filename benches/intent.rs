@@ -0,0 +1,127 @@
+//! 意図判定関数のベンチマーク
+//!
+//! 入力のたびに（将来はキー入力ごとに）呼ばれるため、日本語・英語・空文字を混ぜた
+//! 100 件の入力で計測し、1 回あたりの p99 が 100µs 未満であることを確認する。
+//!
+//! 実行: `cargo bench --bench intent`
+
+use criterion::{black_box, criterion_group, Criterion};
+use rkllm_cli::intent::{has_file_operation_intent, has_file_read_intent};
+use std::time::{Duration, Instant};
+
+/// 1 回の判定に許容する p99 レイテンシ
+const P99_LIMIT: Duration = Duration::from_micros(100);
+
+/// p99 の計測で各入力を繰り返す回数
+const P99_ROUNDS: usize = 100;
+
+const BASE_INPUTS: &[&str] = &[
+    "",
+    " ",
+    "\n",
+    "README.md を要約して",
+    "この文章を英語に翻訳して",
+    "hello.py を作成して",
+    "結果をファイルに保存して",
+    "src/main.rs を読み込んで修正して",
+    "議事録を生成して notes.txt に書き込んで",
+    "今日の天気は？",
+    "コマンドを実行して",
+    "ありがとう",
+    "このコードのバグを教えて",
+    "設定ファイルを出力して",
+    "日本語で詩をかいて",
+    "write a poem about autumn",
+    "Create a file named test.txt with hello world",
+    "save the output to result.json",
+    "Please summarize docs/design.md",
+    "translate this paragraph into French",
+    "What is the capital of France?",
+    "read Cargo.toml and explain the dependencies",
+    "generate file report.csv from the data",
+    "Proofread my essay",
+    "how do I make a file in rust?",
+    "run cargo test",
+    "Explain ownership and borrowing",
+    "load config.toml",
+    "README.md を読んで summary を作って",
+    "Write the result into file output.txt",
+    "src/lib.rs の polish をお願い",
+    "こんにちは、hello",
+    "THIS IS ALL UPPERCASE TEXT WITH NO INTENT",
+];
+
+/// 100 件の入力（基本入力に大文字化・ファイル名付き・長文の変種を加える）
+fn sample_inputs() -> Vec<String> {
+    (0..100)
+        .map(|i| {
+            let base = BASE_INPUTS[i % BASE_INPUTS.len()];
+            match i / BASE_INPUTS.len() {
+                0 => base.to_string(),
+                1 => format!("{} {}", base.to_uppercase(), "notes/today.md"),
+                _ => base.repeat(8),
+            }
+        })
+        .collect()
+}
+
+fn bench_intent(c: &mut Criterion) {
+    let inputs = sample_inputs();
+
+    c.bench_function("has_file_operation_intent/100_inputs", |b| {
+        b.iter(|| {
+            for input in &inputs {
+                black_box(has_file_operation_intent(black_box(input)));
+            }
+        })
+    });
+    c.bench_function("has_file_read_intent/100_inputs", |b| {
+        b.iter(|| {
+            for input in &inputs {
+                black_box(has_file_read_intent(black_box(input)));
+            }
+        })
+    });
+}
+
+/// 計測対象の判定（戻り値は捨てる）
+type Check = fn(&str);
+
+/// 1 回ごとの所要時間を計測し、p99 が `P99_LIMIT` 未満であることを確認する
+fn assert_p99_latency() {
+    let inputs = sample_inputs();
+    // Lazy の初期化は初回だけなので計測から外す
+    has_file_operation_intent("");
+    has_file_read_intent("");
+
+    let checks: [(&str, Check); 2] = [
+        ("has_file_operation_intent", |input| {
+            black_box(has_file_operation_intent(input));
+        }),
+        ("has_file_read_intent", |input| {
+            black_box(has_file_read_intent(input));
+        }),
+    ];
+    for (name, check) in checks {
+        let mut samples: Vec<Duration> = Vec::with_capacity(inputs.len() * P99_ROUNDS);
+        for _ in 0..P99_ROUNDS {
+            for input in &inputs {
+                let start = Instant::now();
+                check(black_box(input));
+                samples.push(start.elapsed());
+            }
+        }
+        samples.sort();
+        let p99 = samples[samples.len() * 99 / 100];
+        println!("{}: p99 = {:?}", name, p99);
+        assert!(p99 < P99_LIMIT, "{} p99 {:?} exceeds {:?}", name, p99, P99_LIMIT);
+    }
+}
+
+criterion_group!(benches, bench_intent);
+
+fn main() {
+    benches();
+    assert_p99_latency();
+    Criterion::default().configure_from_args().final_summary();
+}
@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rkllm_cli::tool_detector::ToolCallDetector;
+
+fuzz_target!(|data: &[u8]| {
+    let output = String::from_utf8_lossy(data);
+    for call in ToolCallDetector::default().detect(&output) {
+        assert!(!call.name.is_empty(), "empty tool name detected in {:?}", output);
+        assert!(!call.name.contains('\0'), "tool name with NUL detected in {:?}", output);
+    }
+});
@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rkllm_cli::file_output_parser::parse_file_operations;
+
+fuzz_target!(|data: &[u8]| {
+    let output = String::from_utf8_lossy(data);
+    for op in parse_file_operations(&output) {
+        assert!(!op.path.is_empty(), "empty path parsed from {:?}", output);
+        assert!(!op.path.contains('\0'), "path with NUL parsed from {:?}", output);
+    }
+});
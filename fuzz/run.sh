@@ -0,0 +1,13 @@
+#!/usr/bin/env bash
+# Run every fuzz target for a fixed time (CI smoke test).
+# Requires a nightly toolchain and `cargo install cargo-fuzz`.
+# Usage: fuzz/run.sh [seconds per target, default 60]
+set -euo pipefail
+
+cd "$(dirname "$0")/.."
+seconds="${1:-60}"
+
+for target in $(cargo +nightly fuzz list); do
+    echo "== fuzzing ${target} for ${seconds}s"
+    cargo +nightly fuzz run "${target}" -- -max_total_time="${seconds}"
+done
@@ -1,14 +1,25 @@
-use crate::config::AppConfig;
+use crate::config::{AppConfig, LlmConfig};
+use crate::events;
 use crate::file_detector;
 use crate::file_ops;
-use crate::file_output_parser;
-use crate::llm::{RKLLMConfig, RKLLM};
+use crate::file_output_parser::{self, FileOperation, FileOperationEncoding, FileOperationType};
+use crate::formatters;
+use crate::llm::{InferenceCancelled, InferenceTimeout, RKLLMConfig, RunOutput, RKLLM};
+use tokio::sync::RwLock;
+use tokio_util::sync::CancellationToken;
+use crate::mcp::client::sort_tools;
 use crate::mcp::{McpClient, McpConfig};
-use crate::mcp::types::{Tool, ToolCall, ToolResult};
-use crate::intent::{has_file_operation_intent, has_file_read_intent, prefers_output_only};
-use crate::prompt_builder::build_chat_prompt;
+use crate::mcp::types::{LoggingLevel, Tool, ToolCall, ToolResult};
+use crate::intent::{
+    self, has_file_operation_intent, has_file_read_intent, has_shell_command_intent,
+    has_undo_intent, prefers_output_only,
+};
+use crate::prompt_builder::{build_chat_prompt, EnvironmentInfo, PromptParts};
 use crate::tool_detector::ToolCallDetector;
+use crate::watch::FileWatch;
 use anyhow::{Context, Result};
+use base64::prelude::{BASE64_STANDARD, Engine as _};
+use once_cell::sync::Lazy;
 use crossterm::{
     cursor,
     event::{self, DisableBracketedPaste, EnableBracketedPaste, Event, KeyCode, KeyEvent, KeyModifiers},
@@ -16,30 +27,603 @@ use crossterm::{
     style::{Color, Print, ResetColor, SetBackgroundColor, SetForegroundColor},
     terminal,
 };
-use once_cell::sync::OnceCell;
 use regex::Regex;
 use serde_json::{self, json};
-use std::collections::HashSet;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::cmp::Reverse;
-use std::io::{self, stdout, Write};
-use std::path::PathBuf;
+use std::io::{self, stdout, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
+use unicode_bidi::BidiInfo;
+use unicode_normalization::UnicodeNormalization;
 use unicode_segmentation::UnicodeSegmentation;
 use unicode_width::UnicodeWidthStr;
 
+/// シェル実行の対象にする、バッククォートで囲まれたコマンド
+static SHELL_COMMAND_PATTERN: Lazy<Regex> = Lazy::new(|| Regex::new(r"`([^`]+)`").unwrap());
+
+/// `<files>` 内の1ファイル分（`<file path="...">` と中身）
+static FILE_SECTION_PATTERN: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r#"(?s)<file path="([^"]*)">\n(.*?)\n</file>"#).unwrap());
+
 pub struct ChatSession {
     rkllm: RKLLM,
-    mcp_client: Option<McpClient>,
+    // tools/list_changed で再取得するため、ターンの合間だけ書き込みロックを取る
+    mcp_client: Option<RwLock<McpClient>>,
     tool_detector: ToolCallDetector,
     last_ctrl_c: Arc<Mutex<Option<Instant>>>,
+    // 実行中の推論の中断トークン（セッション共通の SIGINT ハンドラから中断する）
+    inference_cancel: Arc<Mutex<Option<CancellationToken>>>,
+    // SIGINT ハンドラを起動済み（推論ごとではなくセッションで1つだけ登録する）
+    sigint_watcher: std::sync::Once,
+    // SIGTERM を受け取った（実行中のターンを終えてから終了する）
+    shutdown_requested: Arc<AtomicBool>,
+    // Ctrl+Z で一時停止中（再開時に raw モードと bracketed paste を戻す）
+    suspended: Arc<AtomicBool>,
+    // `run_once` の実行中（確認を求めず、状態表示を標準出力に書かない）
+    headless: AtomicBool,
+    // 同順位の書き込みツールが複数ある場合にユーザーが選んだツール（server::tool）
+    preferred_write_tool: Mutex<Option<String>>,
+    // このセッションで書き込んだファイル（/files と終了時に表示）
+    written_files: Mutex<Vec<WrittenFile>>,
+    // 直前のターンで起きた失敗（次のプロンプトに <last_error> として含める）
+    last_error: Mutex<Option<String>>,
+    // 同じセッションで読み込んだファイル（内容が変わっていなければ再利用する）
+    file_cache: Mutex<file_ops::FileCache>,
+    // 読み込み対象から除外する .gitignore / .rkllmignore のルール（[files] ignore_gitignore 有効時のみ）
+    ignore_rules: Option<file_ops::IgnoreRules>,
+    // 確認で 'a'（always）と答えたパス（セッション中は再確認しない）
+    always_confirmed: Mutex<HashSet<String>>,
+    // セッション中のツールごとの (呼び出し回数, 失敗回数)（ツール一覧に添えてモデルに伝える）
+    tool_usage: Mutex<HashMap<String, (usize, usize)>>,
+    // トークン上限で打ち切られた直前の応答（`continue` で続きを生成する）
+    continuation: Mutex<Option<Continuation>>,
+    // --watch / /watch で監視中のファイル（変更されたら直前の質問を再実行する）
+    watch: Mutex<Option<FileWatch>>,
+    // 直前に送った質問（監視中のファイルが変わったときに再実行する）
+    last_query: Mutex<Option<String>>,
+    // 直前に推論へ渡したプロンプト（/tokens でセクションごとの内訳を表示する）
+    last_prompt: Mutex<Option<String>>,
     preview_prompt: bool,
     confirm_writes: bool,
     tool_only: bool,
+    allow_shell_intent: bool,
+    // ファイルを書き換えず、書き込み予定の内容（パッチ）を表示するだけにする
+    dry_run_writes: bool,
+    // 既定のシステムプロンプトを置き換える内容（--system-prompt / RKLLM_SYSTEM_PROMPT）
+    system_prompt: Option<String>,
+    // 応答言語（--response-language > RKLLM_LANGUAGE > [llm] response_language）
+    response_language: Option<String>,
+    // 毎回のプロンプトの <files> に含めるテキスト（--include-context）
+    extra_context: Vec<String>,
+    // 書き込んだファイルを言語ごとのフォーマッタで整形する（--auto-format）
+    auto_format: bool,
+    // 相対パスのファイル出力を書き込む基準ディレクトリ（--output-dir）
+    output_dir: Option<String>,
+    // ステータス行やターミナルのタイトルに表示するモデル名（--model-alias、既定はファイル名）
+    model_name: String,
+    model_path: String,
+    // 起動時にアスキーアートのバナーを表示する（--no-banner / RKLLM_NO_BANNER=1 で無効）
+    show_banner: bool,
     config: AppConfig,
     execution_dir: String,
 }
 
+/// `ChatSession` のビルダー
+///
+/// ```ignore
+/// let session = ChatSessionBuilder::new(model_path)
+///     .mcp_config(Some(path))
+///     .confirm_writes(false)
+///     .build()
+///     .await?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct ChatSessionBuilder {
+    model_path: String,
+    mcp_config_path: Option<PathBuf>,
+    preview_prompt: bool,
+    confirm_writes: bool,
+    tool_only: bool,
+    allow_shell_intent: bool,
+    dry_run_writes: bool,
+    preview_diff: bool,
+    system_prompt: Option<String>,
+    llm_overrides: LlmConfig,
+    max_context_tokens: Option<usize>,
+    context_reserved_tokens: Option<usize>,
+    max_tool_rounds: Option<usize>,
+    tool_result_max_bytes: Option<usize>,
+    max_file_depth: Option<usize>,
+    show_banner: bool,
+    execution_dir: Option<PathBuf>,
+    watch: Option<String>,
+    extra_context: Vec<String>,
+    auto_format: bool,
+    model_alias: Option<String>,
+    mcp_log_level: Option<LoggingLevel>,
+    output_dir: Option<String>,
+    mcp_client: Option<McpClient>,
+}
+
+impl Default for ChatSessionBuilder {
+    fn default() -> Self {
+        Self {
+            model_path: String::new(),
+            mcp_config_path: None,
+            preview_prompt: false,
+            confirm_writes: true,
+            tool_only: false,
+            allow_shell_intent: false,
+            dry_run_writes: false,
+            preview_diff: false,
+            system_prompt: None,
+            llm_overrides: LlmConfig::default(),
+            max_context_tokens: None,
+            context_reserved_tokens: None,
+            max_tool_rounds: None,
+            tool_result_max_bytes: None,
+            max_file_depth: None,
+            show_banner: true,
+            execution_dir: None,
+            watch: None,
+            extra_context: Vec::new(),
+            auto_format: false,
+            model_alias: None,
+            output_dir: None,
+            mcp_log_level: None,
+            mcp_client: None,
+        }
+    }
+}
+
+impl ChatSessionBuilder {
+    pub fn new(model_path: impl Into<String>) -> Self {
+        Self {
+            model_path: model_path.into(),
+            ..Default::default()
+        }
+    }
+
+    /// MCP 設定ファイルのパス
+    pub fn mcp_config(&mut self, path: Option<PathBuf>) -> &mut Self {
+        self.mcp_config_path = path;
+        self
+    }
+
+    /// 接続済みの MCP クライアントを使う（`mcp_config` より優先。主にテスト用）
+    pub fn mcp_client(&mut self, client: McpClient) -> &mut Self {
+        self.mcp_client = Some(client);
+        self
+    }
+
+    /// 送信前に組み立てたプロンプトを表示する
+    pub fn preview_prompt(&mut self, enabled: bool) -> &mut Self {
+        self.preview_prompt = enabled;
+        self
+    }
+
+    /// 書き込みのたびに確認する（既定: true）
+    pub fn confirm_writes(&mut self, enabled: bool) -> &mut Self {
+        self.confirm_writes = enabled;
+        self
+    }
+
+    /// ローカル書き込みを無効にし、MCP ツールのみを使う
+    pub fn tool_only(&mut self, enabled: bool) -> &mut Self {
+        self.tool_only = enabled;
+        self
+    }
+
+    /// シェルコマンド的な入力を MCP のシェルツールで直接実行する
+    pub fn allow_shell_intent(&mut self, enabled: bool) -> &mut Self {
+        self.allow_shell_intent = enabled;
+        self
+    }
+
+    /// 書き込まずに書き込み予定の内容だけを表示する
+    pub fn dry_run_writes(&mut self, enabled: bool) -> &mut Self {
+        self.dry_run_writes = enabled;
+        self
+    }
+
+    /// 書き込み前に差分を表示して確認する（設定ファイルの `preview_diff` と OR）
+    pub fn preview_diff(&mut self, enabled: bool) -> &mut Self {
+        self.preview_diff = enabled;
+        self
+    }
+
+    /// 既定のシステムプロンプトを置き換える（未指定なら `RKLLM_SYSTEM_PROMPT`）
+    pub fn system_prompt(&mut self, prompt: Option<String>) -> &mut Self {
+        self.system_prompt = prompt;
+        self
+    }
+
+    /// 設定ファイル `[llm]` より優先する推論パラメータ
+    pub fn llm_overrides(&mut self, overrides: LlmConfig) -> &mut Self {
+        self.llm_overrides = overrides;
+        self
+    }
+
+    /// プロンプト全体のトークン上限（環境変数より優先）
+    pub fn max_context_tokens(&mut self, tokens: Option<usize>) -> &mut Self {
+        self.max_context_tokens = tokens;
+        self
+    }
+
+    /// 応答用に空けておくトークン数（環境変数より優先）
+    pub fn context_reserved_tokens(&mut self, tokens: Option<usize>) -> &mut Self {
+        self.context_reserved_tokens = tokens;
+        self
+    }
+
+    /// 1ターンのツール呼び出しラウンド上限（環境変数・設定ファイルより優先）
+    pub fn max_tool_rounds(&mut self, rounds: Option<usize>) -> &mut Self {
+        self.max_tool_rounds = rounds;
+        self
+    }
+
+    /// プロンプトに戻すツール結果の最大バイト数（設定ファイルより優先）
+    pub fn tool_result_max_bytes(&mut self, bytes: Option<usize>) -> &mut Self {
+        self.tool_result_max_bytes = bytes;
+        self
+    }
+
+    /// ディレクトリをたどる階層の上限（設定ファイルより優先）
+    pub fn max_file_depth(&mut self, depth: Option<usize>) -> &mut Self {
+        self.max_file_depth = depth;
+        self
+    }
+
+    /// 起動時のアスキーアートのバナーを表示するかどうか（`RKLLM_NO_BANNER=1` でも無効になる）
+    pub fn show_banner(&mut self, enabled: bool) -> &mut Self {
+        self.show_banner = enabled;
+        self
+    }
+
+    /// 作業ディレクトリ（未指定時は起動時のカレントディレクトリ）
+    ///
+    /// カレントディレクトリの変更は呼び出し側（`main`）で行う。ここではプロンプトや
+    /// MCP ツールの `cwd` に使うパスとして記録するだけ。
+    pub fn execution_dir(&mut self, dir: Option<PathBuf>) -> &mut Self {
+        self.execution_dir = dir;
+        self
+    }
+
+    /// 変更を監視するファイルの glob（変更のたびに直前の質問を再実行する）
+    pub fn watch(&mut self, pattern: Option<String>) -> &mut Self {
+        self.watch = pattern;
+        self
+    }
+
+    /// 毎回のプロンプトに参照用の文脈として含めるテキスト（ファイルに書かずに渡したい内容）
+    pub fn extra_context(&mut self, snippets: Vec<String>) -> &mut Self {
+        self.extra_context = snippets;
+        self
+    }
+
+    /// 書き込んだファイルを拡張子に応じて rustfmt / black / prettier で整形するか
+    pub fn auto_format(&mut self, enabled: bool) -> &mut Self {
+        self.auto_format = enabled;
+        self
+    }
+
+    /// ステータス行などに表示するモデル名（未指定ならモデルファイル名の拡張子を除いた部分）
+    pub fn model_alias(&mut self, alias: Option<String>) -> &mut Self {
+        self.model_alias = alias;
+        self
+    }
+
+    /// 接続後に MCP サーバへ送るログレベル（`logging/setLevel`、logging 対応サーバのみ）
+    pub fn mcp_log_level(&mut self, level: Option<LoggingLevel>) -> &mut Self {
+        self.mcp_log_level = level;
+        self
+    }
+
+    /// 相対パスのファイル出力を書き込む基準ディレクトリ（絶対パスの出力はそのまま）
+    pub fn output_dir(&mut self, dir: Option<String>) -> &mut Self {
+        self.output_dir = dir;
+        self
+    }
+
+    /// 設定を検証し、モデルと MCP クライアントを初期化してセッションを作る
+    ///
+    /// # エラー
+    /// - モデルファイルが存在しない、または RKLLM の初期化に失敗
+    /// - `tool_only` なのに MCP サーバーに接続できない
+    /// - MCP サーバーに接続できず、ユーザーが中断を選んだ
+    pub async fn build(&self) -> Result<ChatSession> {
+        // モックの応答を使う場合はモデルファイルを読み込まない
+        if crate::llm::mock_response_from_env().is_none()
+            && !std::path::Path::new(&self.model_path).exists()
+        {
+            anyhow::bail!("Model file not found: {}", self.model_path);
+        }
+
+        let model_path = self.model_path.clone();
+        let mcp_config_path = self.mcp_config_path.clone();
+        let preview_prompt = self.preview_prompt;
+        let confirm_writes = self.confirm_writes;
+        let tool_only = self.tool_only;
+        let allow_shell_intent = self.allow_shell_intent;
+        let dry_run_writes = self.dry_run_writes;
+        let preview_diff = self.preview_diff;
+        let system_prompt = self.system_prompt.clone();
+        let llm_overrides = self.llm_overrides.clone();
+        let max_context_tokens = self.max_context_tokens;
+        let context_reserved_tokens = self.context_reserved_tokens;
+
+        // CLI 指定は環境変数より優先する（既に環境変数から読み込んでいても上書きする）
+        if let Some(tokens) = max_context_tokens.filter(|v| *v > 0) {
+            set_max_context_tokens(tokens);
+        }
+        if let Some(tokens) = context_reserved_tokens.filter(|v| *v > 0) {
+            set_context_reserved_tokens(tokens);
+        }
+
+        // セッション固有の上書き（CLI フラグ）を反映するため複製する
+        let mut app_config = AppConfig::global().clone();
+        app_config.preview_diff |= preview_diff;
+        // CLI フラグ > RKLLM_MAX_TOOL_ROUNDS > 設定ファイル [tools] max_rounds の順に適用
+        if let Some(rounds) = self
+            .max_tool_rounds
+            .or_else(max_tool_rounds_from_env)
+            .filter(|v| *v > 0)
+        {
+            app_config.max_tool_rounds = rounds;
+        }
+        if let Some(bytes) = self.tool_result_max_bytes.filter(|v| *v > 0) {
+            app_config.tool_result_max_bytes = bytes;
+        }
+        if let Some(depth) = self.max_file_depth.filter(|v| *v > 0) {
+            app_config.max_file_depth = depth;
+        }
+        if app_config.audit_writes {
+            file_ops::enable_write_audit(&model_path);
+        }
+        file_ops::set_list_hidden_files(app_config.list_hidden_files);
+        file_ops::set_atomic_writes(app_config.atomic_writes);
+        intent::set_custom_keywords(
+            &app_config.intent_write_keywords,
+            &app_config.intent_read_keywords,
+        );
+
+        let response_language = llm_overrides
+            .response_language
+            .clone()
+            .or_else(|| {
+                std::env::var("RKLLM_LANGUAGE")
+                    .ok()
+                    .filter(|v| !v.trim().is_empty())
+            })
+            .or_else(|| app_config.llm.response_language.clone());
+
+        // CLI フラグ > 設定ファイル [llm] > RKLLMConfig::default() の順に適用
+        let llm = llm_overrides.or(&app_config.llm);
+        let mut config = RKLLMConfig {
+            model_path,
+            ..Default::default()
+        };
+        if let Some(temperature) = llm.temperature {
+            config.temperature = temperature;
+        }
+        if let Some(top_k) = llm.top_k {
+            config.top_k = top_k;
+        }
+        if let Some(top_p) = llm.top_p {
+            config.top_p = top_p;
+        }
+        if let Some(repeat_penalty) = llm.repeat_penalty {
+            config.repeat_penalty = repeat_penalty;
+        }
+        if let Some(max_context_len) = llm.max_context_len {
+            config.max_context_len = max_context_len;
+        }
+        if let Some(max_new_tokens) = llm.max_new_tokens {
+            config.max_new_tokens = max_new_tokens;
+        }
+        if let Some(cpu_mask) = llm.cpu_mask {
+            config.cpu_mask = cpu_mask;
+            // コア数が未指定ならマスクのビット数に合わせる
+            config.cpu_count = cpu_mask.count_ones() as i8;
+        }
+        if let Some(cpu_count) = llm.cpu_count {
+            config.cpu_count = cpu_count;
+        }
+        if let Some(batch_size) = llm.batch_size {
+            config.batch_size = batch_size;
+        }
+
+        let rkllm = RKLLM::new(config).context("Failed to initialize RKLLM")?;
+
+        // Initialize MCP client if config file is provided
+        let mcp_config_provided = mcp_config_path.is_some();
+        let mcp_client = if let Some(client) = &self.mcp_client {
+            Some(client.clone())
+        } else if let Some(config_path) = mcp_config_path {
+            if config_path.exists() {
+                println!("Loading MCP configuration from: {}", config_path.display());
+                match McpConfig::load(&config_path) {
+                    Ok(mcp_config) => {
+                        if !mcp_config.is_empty() {
+                            match McpClient::new(mcp_config).await {
+                                // 全サーバーへの接続に失敗した場合は MCP なしとして扱う
+                                Ok(client) if client.server_names().is_empty() => None,
+                                Ok(client) => Some(client),
+                                Err(e) => {
+                                    eprintln!("Failed to initialize MCP client: {}", e);
+                                    None
+                                }
+                            }
+                        } else {
+                            println!("[MCP: Configuration file is empty]");
+                            None
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to load MCP configuration: {}", e);
+                        None
+                    }
+                }
+            } else {
+                eprintln!("MCP configuration file not found: {}", config_path.display());
+                None
+            }
+        } else {
+            None
+        };
+        if let (Some(client), Some(level)) = (&mcp_client, self.mcp_log_level) {
+            for server in client.server_names() {
+                let supports_logging = client
+                    .server_capabilities(server)
+                    .is_some_and(|capabilities| capabilities.logging.is_some());
+                if supports_logging && let Err(e) = client.set_log_level(server, level).await {
+                    eprintln!("[MCP: {:#}]", e);
+                }
+            }
+        }
+
+        let system_prompt = system_prompt.or_else(|| {
+            std::env::var("RKLLM_SYSTEM_PROMPT")
+                .ok()
+                .filter(|v| !v.trim().is_empty())
+        });
+
+        let show_banner =
+            self.show_banner && std::env::var("RKLLM_NO_BANNER").ok().as_deref() != Some("1");
+
+        let execution_dir = self
+            .execution_dir
+            .clone()
+            .or_else(|| std::env::current_dir().ok())
+            .unwrap_or_else(|| PathBuf::from("."))
+            .to_string_lossy()
+            .to_string();
+        let watch = self
+            .watch
+            .as_deref()
+            .map(|pattern| FileWatch::start(pattern, Path::new(&execution_dir)))
+            .transpose()?;
+
+        let session = ChatSession {
+            rkllm,
+            mcp_client: mcp_client.map(RwLock::new),
+            tool_detector: ToolCallDetector::new(&app_config.tool_custom_patterns),
+            last_ctrl_c: Arc::new(Mutex::new(None)),
+            inference_cancel: Arc::new(Mutex::new(None)),
+            sigint_watcher: std::sync::Once::new(),
+            shutdown_requested: Arc::new(AtomicBool::new(false)),
+            suspended: Arc::new(AtomicBool::new(false)),
+            headless: AtomicBool::new(false),
+            preferred_write_tool: Mutex::new(None),
+            written_files: Mutex::new(Vec::new()),
+            last_error: Mutex::new(None),
+            file_cache: Mutex::new(file_ops::FileCache::new()),
+            ignore_rules: app_config
+                .ignore_gitignore
+                .then(|| file_ops::IgnoreRules::new(Path::new(&execution_dir))),
+            always_confirmed: Mutex::new(HashSet::new()),
+            tool_usage: Mutex::new(HashMap::new()),
+            continuation: Mutex::new(None),
+            watch: Mutex::new(watch),
+            last_query: Mutex::new(None),
+            last_prompt: Mutex::new(None),
+            preview_prompt,
+            confirm_writes,
+            tool_only,
+            allow_shell_intent,
+            dry_run_writes,
+            system_prompt,
+            response_language,
+            extra_context: self.extra_context.clone(),
+            auto_format: self.auto_format,
+            output_dir: self.output_dir.clone(),
+            model_name: model_display_name(&self.model_path, self.model_alias.as_deref()),
+            model_path: self.model_path.clone(),
+            show_banner,
+            config: app_config,
+            execution_dir,
+        };
+
+        if session.tool_only && session.mcp_client.is_none() {
+            anyhow::bail!("--tool-only requires MCP tools. Please provide a valid --mcp-config.");
+        }
+
+        if mcp_config_provided && session.mcp_client.is_none() && !confirm_continue_without_mcp()? {
+            anyhow::bail!("Aborted: MCP config provided but no servers connected.");
+        }
+
+        Ok(session)
+    }
+}
+
+/// `process_tool_calls` の結果
+#[derive(Default)]
+struct ToolRoundOutcome {
+    results: Vec<ToolResult>,
+    // 同一ツールの繰り返し呼び出し（または許可されないツール）を無視した
+    blocked_repeat: bool,
+    // 同じツールが上限回数失敗したため、これ以上ツールを呼ばせない
+    gave_up: bool,
+}
+
+/// `use_temp_staging` のサーバで内容を一時ファイル経由で渡すサイズ（100 KiB 超）
+const TEMP_STAGING_THRESHOLD: usize = 100 * 1024;
+
+/// MCP 書き込みツールに渡す引数を作る
+///
+/// # 引数
+/// * `path` - 書き込み先のパス
+/// * `content` - 書き込む内容
+/// * `source_path` - 内容を書き出した一時ファイル（指定時は `content` の代わりに渡す）
+fn write_tool_args(path: &str, content: &str, source_path: Option<&str>) -> serde_json::Value {
+    match source_path {
+        Some(source_path) => json!({ "path": path, "source_path": source_path }),
+        None => json!({ "path": path, "content": content }),
+    }
+}
+
+/// 1ターン内で同じツールが失敗してよい回数
+const MAX_TOOL_FAILURES: usize = 2;
+
+/// ツールの失敗回数を記録し、上限に達したらモデルへ返す打ち切りの結果を作る
+fn record_tool_failure(counts: &mut HashMap<String, usize>, name: &str) -> Option<ToolResult> {
+    let count = counts.entry(name.to_string()).or_insert(0);
+    *count += 1;
+    (*count >= MAX_TOOL_FAILURES).then(|| {
+        ChatSession::tool_result_json(
+            name,
+            false,
+            json!({
+                "error": format!(
+                    "Tool {} failed twice. Do not call it again. Provide a response without this tool.",
+                    name
+                )
+            }),
+        )
+    })
+}
+
+/// ツール結果を `max_bytes` 以内に切り詰め、末尾に `[...truncated...]` を付ける
+///
+/// # 戻り値
+/// 切り詰めた場合は true
+fn truncate_tool_output(output: &mut String, max_bytes: usize) -> bool {
+    if output.len() <= max_bytes {
+        return false;
+    }
+    let mut end = max_bytes;
+    while !output.is_char_boundary(end) {
+        end -= 1;
+    }
+    output.truncate(end);
+    output.push_str("\n[...truncated...]");
+    true
+}
+
 #[derive(Copy, Clone)]
 enum ToolCallAllowance {
     All,
@@ -52,6 +636,18 @@ struct InputBuffer {
     cursor: usize,
     // 垂直移動時に保持したい表示上の列
     preferred_col: Option<usize>,
+    // layout_positions の結果キャッシュ（内容が変わるたびに破棄する）
+    layout_cache: RefCell<Option<LayoutCache>>,
+    // 端末が双方向テキストを並べ替えて表示する前提でカーソル列を求める（RKLLM_BIDI=1）
+    bidi_reorder: bool,
+}
+
+/// `InputBuffer::layout_positions` のキャッシュ（キーは各幅）
+struct LayoutCache {
+    prompt_width: usize,
+    indent_width: usize,
+    term_width: usize,
+    positions: Vec<(usize, usize)>,
 }
 
 impl InputBuffer {
@@ -60,11 +656,14 @@ impl InputBuffer {
     }
 
     fn insert_str(&mut self, s: &str) {
-        for g in s.graphemes(true) {
+        // macOS からの貼り付けは NFD（結合文字が分解された形）のことがあるため NFC にそろえる
+        let normalized: String = s.nfc().collect();
+        for g in normalized.graphemes(true) {
             self.graphemes.insert(self.cursor, g.to_string());
             self.cursor += 1;
         }
         self.preferred_col = None;
+        self.invalidate_layout();
     }
 
     fn backspace(&mut self) -> bool {
@@ -74,6 +673,7 @@ impl InputBuffer {
         self.cursor -= 1;
         self.graphemes.remove(self.cursor);
         self.preferred_col = None;
+        self.invalidate_layout();
         true
     }
 
@@ -83,9 +683,14 @@ impl InputBuffer {
         }
         self.graphemes.remove(self.cursor);
         self.preferred_col = None;
+        self.invalidate_layout();
         true
     }
 
+    fn invalidate_layout(&mut self) {
+        self.layout_cache.get_mut().take();
+    }
+
     fn move_left(&mut self) -> bool {
         if self.cursor == 0 {
             return false;
@@ -109,6 +714,30 @@ impl InputBuffer {
         prompt_width: usize,
         indent_width: usize,
         term_width: usize,
+    ) -> Vec<(usize, usize)> {
+        if let Some(cache) = self.layout_cache.borrow().as_ref()
+            && cache.prompt_width == prompt_width
+            && cache.indent_width == indent_width
+            && cache.term_width == term_width
+        {
+            return cache.positions.clone();
+        }
+
+        let positions = self.compute_layout_positions(prompt_width, indent_width, term_width);
+        *self.layout_cache.borrow_mut() = Some(LayoutCache {
+            prompt_width,
+            indent_width,
+            term_width,
+            positions: positions.clone(),
+        });
+        positions
+    }
+
+    fn compute_layout_positions(
+        &self,
+        prompt_width: usize,
+        indent_width: usize,
+        term_width: usize,
     ) -> Vec<(usize, usize)> {
         let mut positions = Vec::with_capacity(self.graphemes.len() + 1);
         let mut row = 0usize;
@@ -132,9 +761,69 @@ impl InputBuffer {
             positions.push((row, col));
         }
 
+        if self.bidi_reorder {
+            self.reorder_positions_bidi(&mut positions);
+        }
         positions
     }
 
+    /// 論理順で求めたカーソル位置を、双方向アルゴリズムによる表示上の列に置き換える
+    ///
+    /// 各表示行の書記素を `unicode-bidi` で表示順に並べ、カーソルは直後の書記素の
+    /// 先頭側の端（RTL なら右端）、行末では直前の書記素の末尾側の端に置く。
+    /// 左から右へのテキストだけなら結果は変わらない。
+    fn reorder_positions_bidi(&self, positions: &mut [(usize, usize)]) {
+        // 各書記素の (行, 表示上の開始列, 幅, RTL か)。改行は None
+        let mut placed: Vec<Option<(usize, usize, usize, bool)>> = vec![None; self.graphemes.len()];
+
+        let mut k = 0;
+        while k < self.graphemes.len() {
+            if self.graphemes[k] == "\n" {
+                k += 1;
+                continue;
+            }
+            // 同じ表示行に並ぶ書記素をまとめる（positions[k + 1] は書記素 k の直後の位置）
+            let row = positions[k + 1].0;
+            let mut end = k;
+            while end < self.graphemes.len()
+                && self.graphemes[end] != "\n"
+                && positions[end + 1].0 == row
+            {
+                end += 1;
+            }
+            let widths: Vec<usize> = self.graphemes[k..end]
+                .iter()
+                .map(|g| UnicodeWidthStr::width(g.as_str()).max(1))
+                .collect();
+            let start_col = positions[k + 1].1 - widths[0];
+            for (offset, (col, rtl)) in visual_columns(&self.graphemes[k..end], &widths, start_col)
+                .into_iter()
+                .enumerate()
+            {
+                placed[k + offset] = Some((row, col, widths[offset], rtl));
+            }
+            k = end;
+        }
+
+        for (index, position) in positions.iter_mut().enumerate() {
+            let row = position.0;
+            let leading = placed
+                .get(index)
+                .copied()
+                .flatten()
+                .filter(|p| p.0 == row)
+                .map(|(_, col, w, rtl)| if rtl { col + w } else { col });
+            let trailing = index
+                .checked_sub(1)
+                .and_then(|prev| placed[prev])
+                .filter(|p| p.0 == row)
+                .map(|(_, col, w, rtl)| if rtl { col } else { col + w });
+            if let Some(col) = leading.or(trailing) {
+                position.1 = col;
+            }
+        }
+    }
+
     fn move_vertical(
         &mut self,
         delta_row: isize,
@@ -186,76 +875,6 @@ impl ChatSession {
     const INPUT_BG: Color = Color::Rgb { r: 58, g: 58, b: 58 };
     const INPUT_FG: Color = Color::White;
 
-    pub async fn new(
-        model_path: String,
-        mcp_config_path: Option<PathBuf>,
-        preview_prompt: bool,
-        confirm_writes: bool,
-        tool_only: bool,
-    ) -> Result<Self> {
-        let config = RKLLMConfig {
-            model_path,
-            ..Default::default()
-        };
-
-        let rkllm = RKLLM::new(config).context("Failed to initialize RKLLM")?;
-
-        // Initialize MCP client if config file is provided
-        let mcp_client = if let Some(config_path) = mcp_config_path {
-            if config_path.exists() {
-                println!("Loading MCP configuration from: {}", config_path.display());
-                match McpConfig::load(&config_path) {
-                    Ok(mcp_config) => {
-                        if !mcp_config.is_empty() {
-                            match McpClient::new(mcp_config).await {
-                                Ok(client) => Some(client),
-                                Err(e) => {
-                                    eprintln!("Failed to initialize MCP client: {}", e);
-                                    None
-                                }
-                            }
-                        } else {
-                            println!("[MCP: Configuration file is empty]");
-                            None
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("Failed to load MCP configuration: {}", e);
-                        None
-                    }
-                }
-            } else {
-                eprintln!("MCP configuration file not found: {}", config_path.display());
-                None
-            }
-        } else {
-            None
-        };
-
-        let execution_dir = std::env::current_dir()
-            .unwrap_or_else(|_| PathBuf::from("."))
-            .to_string_lossy()
-            .to_string();
-
-        let session = Self {
-            rkllm,
-            mcp_client,
-            tool_detector: ToolCallDetector::new(),
-            last_ctrl_c: Arc::new(Mutex::new(None)),
-            preview_prompt,
-            confirm_writes,
-            tool_only,
-            config: AppConfig::load(),
-            execution_dir,
-        };
-
-        if session.tool_only && session.mcp_client.is_none() {
-            anyhow::bail!("--tool-only requires MCP tools. Please provide a valid --mcp-config.");
-        }
-
-        Ok(session)
-    }
-
     pub async fn start(&self) -> Result<()> {
         unsafe {
             std::env::set_var("RKLLM_TUI", "1");
@@ -265,23 +884,156 @@ impl ChatSession {
         terminal::enable_raw_mode().context("Failed to enable raw mode")?;
         let mut stdout = stdout();
         execute!(stdout, EnableBracketedPaste).context("Failed to enable bracketed paste")?;
+        execute!(stdout, terminal::SetTitle(format!("rkllm-cli: {}", self.model_name)))?;
 
+        let sigterm_watcher = self.spawn_sigterm_watcher();
+        let sigcont_watcher = self.spawn_sigcont_watcher();
         let result = self.run_chat_loop(&mut stdout).await;
+        sigterm_watcher.abort();
+        sigcont_watcher.abort();
 
         execute!(stdout, DisableBracketedPaste).context("Failed to disable bracketed paste")?;
         terminal::disable_raw_mode().context("Failed to disable raw mode")?;
         println!();
+        if self.written_files.lock().is_ok_and(|files| !files.is_empty()) {
+            self.list_output_files(&mut stdout)?;
+        }
 
         result
     }
 
+    /// 1つのプロンプトを対話ループなしで処理し、最終的な応答を返す（ライブラリ利用向け）
+    ///
+    /// 対話モードの1ターンと同じく、ファイル検出、ツール呼び出し、ファイル操作まで行う。
+    /// ただし確認は求めず（書き込み確認はパイプ入力時と同じく `RKLLM_PIPE_AUTO_CONFIRM` に従い、
+    /// それ以外の確認は拒否として扱う）、状態表示も標準出力に書かない。警告とエラーは標準エラーに出す。
+    ///
+    /// # 引数
+    /// * `prompt` - ユーザー入力
+    /// * `on_chunk` - 生成されたテキストを受け取るコールバック（ストリーミング表示用）
+    ///
+    /// # 戻り値
+    /// ツール呼び出し後の追加推論を含めた最終的な応答
+    ///
+    /// # エラー
+    /// プロンプトがコンテキスト上限を超える場合や、最初の推論に失敗した場合
+    pub async fn run_once<F>(&self, prompt: &str, on_chunk: F) -> Result<String>
+    where
+        F: FnMut(&str) + Send + 'static,
+    {
+        self.headless.store(true, Ordering::SeqCst);
+        let result = self.run_turn(prompt, on_chunk).await;
+        self.headless.store(false, Ordering::SeqCst);
+        result.map(|output| output.response)
+    }
+
+    /// 状態表示を標準出力に書く（`run_once` の実行中は何も表示しない）
+    fn status(&self, message: impl std::fmt::Display) {
+        if !self.headless.load(Ordering::SeqCst) {
+            println!("{}", message);
+        }
+    }
+
+    /// 推論中のスピナーを表示し始める（`run_once` の実行中や、標準出力が端末でない場合は表示しない）
+    fn start_spinner(&self) -> Option<Spinner> {
+        (!self.headless.load(Ordering::SeqCst) && io::stdout().is_terminal())
+            .then(|| Spinner::start("Generating"))
+    }
+
+    /// ユーザーに確認を求められるか（`run_once` の実行中や、標準入力が端末でない場合は false）
+    fn can_prompt(&self) -> bool {
+        !self.headless.load(Ordering::SeqCst) && io::stdin().is_terminal()
+    }
+
+    /// 直前の推論に渡したプロンプト（ツール結果を含む追加推論ならその追加プロンプト）
+    pub fn last_prompt(&self) -> Option<String> {
+        self.last_prompt.lock().ok().and_then(|prompt| prompt.clone())
+    }
+
+    /// SIGTERM（`kill` や `systemctl stop`）を受け取ったら終了要求フラグを立てるタスクを起動する
+    ///
+    /// 推論中に受け取った場合は、そのターンが終わってから対話ループを抜ける。
+    fn spawn_sigterm_watcher(&self) -> tokio::task::JoinHandle<()> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let shutdown_requested = Arc::clone(&self.shutdown_requested);
+        tokio::spawn(async move {
+            let Ok(mut sigterm) = signal(SignalKind::terminate()) else {
+                return;
+            };
+            if sigterm.recv().await.is_some() {
+                shutdown_requested.store(true, Ordering::SeqCst);
+            }
+        })
+    }
+
+    /// SIGCONT（`fg` での再開）を受け取ったら、Ctrl+Z で一時停止する前の端末状態に戻すタスクを起動する
+    fn spawn_sigcont_watcher(&self) -> tokio::task::JoinHandle<()> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let suspended = Arc::clone(&self.suspended);
+        tokio::spawn(async move {
+            let Ok(mut sigcont) = signal(SignalKind::from_raw(libc::SIGCONT)) else {
+                return;
+            };
+            while sigcont.recv().await.is_some() {
+                let _ = resume_terminal(&suspended);
+            }
+        })
+    }
+
+    /// Ctrl+Z でプロセスを一時停止する（シェルのジョブ制御に戻る）
+    ///
+    /// raw モードではキー入力から SIGTSTP が送られないため、端末を通常の状態に戻してから自分に送る。
+    /// 再開後は SIGCONT のタスクか、ここでの後始末のどちらか先に動いた方が端末状態を戻す。
+    fn suspend(&self, stdout: &mut std::io::Stdout) -> Result<()> {
+        execute!(stdout, Print("\r\n"))?;
+        terminal::disable_raw_mode().context("Failed to disable raw mode")?;
+        stdout.flush()?;
+        execute!(stdout, DisableBracketedPaste).context("Failed to disable bracketed paste")?;
+        self.suspended.store(true, Ordering::SeqCst);
+
+        // SAFETY: 自プロセスへのシグナル送信のみで、メモリには触れない
+        unsafe {
+            libc::raise(libc::SIGTSTP);
+        }
+
+        resume_terminal(&self.suspended)
+    }
+
+    /// 終了要求を受け取っていれば終了メッセージを表示して true を返す
+    fn shutdown_if_requested(&self, stdout: &mut std::io::Stdout) -> Result<bool> {
+        if !self.shutdown_requested.load(Ordering::SeqCst) {
+            return Ok(false);
+        }
+        execute!(stdout, Print("\r\nShutting down...\r\n"))?;
+        Ok(true)
+    }
+
     async fn run_chat_loop(&self, stdout: &mut std::io::Stdout) -> Result<()> {
+        let has_response_suffix = response_affix_from_env("RKLLM_RESPONSE_SUFFIX").is_some();
         loop {
+            if self.shutdown_if_requested(stdout)? {
+                break;
+            }
+            self.print_mcp_log_messages(stdout).await?;
             self.print_status_line(stdout, "Ready")?;
 
             let input = match self.read_multiline_input(stdout)? {
                 Some(text) => text,
-                None => break,
+                None => {
+                    self.shutdown_if_requested(stdout)?;
+                    break;
+                }
+            };
+
+            // 入力待ちの間に届いたツール一覧の変更通知を反映してからターンを始める
+            self.refresh_changed_mcp_tools(stdout).await?;
+
+            // /prompt は MCP のプロンプトテンプレートを展開し、通常の入力として扱う
+            let input = match self.expand_prompt_command(stdout, input).await? {
+                Some(text) => text,
+                None => continue,
             };
 
             let trimmed = input.trim();
@@ -297,7 +1049,12 @@ impl ChatSession {
                 }
 
                 if command.eq_ignore_ascii_case("tools") {
-                    self.show_tools_command(stdout)?;
+                    self.show_tools_command(stdout).await?;
+                    continue;
+                }
+
+                if command.eq_ignore_ascii_case("status") {
+                    self.show_status_command(stdout).await?;
                     continue;
                 }
 
@@ -305,256 +1062,704 @@ impl ChatSession {
                     self.show_help_command(stdout)?;
                     continue;
                 }
-            }
 
-            let has_file_write_intent = has_file_operation_intent(&trimmed);
-            let has_file_read_intent = has_file_read_intent(&trimmed);
-            if self.tool_only && has_file_write_intent {
-                println!("\n[tool-only] Local file writes are disabled. Use MCP tools for any file outputs.");
+                if command.eq_ignore_ascii_case("model") {
+                    execute!(
+                        stdout,
+                        Print(format!("\r\n[Model: {} ({})]\r\n", self.model_name, self.model_path))
+                    )?;
+                    continue;
+                }
+
+                if command.eq_ignore_ascii_case("files") {
+                    self.list_output_files(stdout)?;
+                    continue;
+                }
+
+                if command.eq_ignore_ascii_case("tokens") {
+                    let last_prompt = self.last_prompt.lock().ok().and_then(|p| p.clone());
+                    let message = match last_prompt {
+                        Some(prompt) => format_token_breakdown(&prompt, max_context_tokens()),
+                        None => "[Tokens: no prompt has been sent yet]".to_string(),
+                    };
+                    execute!(stdout, Print(format!("\r\n{}\r\n", message.replace('\n', "\r\n"))))?;
+                    continue;
+                }
+
+                if let Some(path) = command.strip_prefix("format")
+                    && (path.is_empty() || path.starts_with(char::is_whitespace))
+                {
+                    self.format_command(stdout, path.trim())?;
+                    continue;
+                }
+
+                if let Some(pattern) = command.strip_prefix("watch")
+                    && (pattern.is_empty() || pattern.starts_with(char::is_whitespace))
+                {
+                    self.watch_command(stdout, pattern.trim())?;
+                    continue;
+                }
             }
 
-            let file_paths = if (has_file_write_intent || has_file_read_intent)
-                && !self.config.detect_extensions.is_empty()
-            {
-                file_detector::detect_file_paths_with_exts(&trimmed, &self.config.detect_extensions)
+            // `continue` は上限で打ち切られた応答の続きを、元の質問と応答を添えて生成させる
+            let pending = if trimmed.eq_ignore_ascii_case("continue") {
+                self.continuation.lock().ok().and_then(|mut c| c.take())
             } else {
-                Vec::new()
+                None
             };
+            let query = pending
+                .as_ref()
+                .map_or_else(|| trimmed.to_string(), |p| p.query.clone());
+            if let Ok(mut last_query) = self.last_query.lock() {
+                *last_query = Some(query.clone());
+            }
+            // 意図の判定はユーザーが入力した文に対して行い、合成した続きのプロンプトは推論にだけ渡す
+            let continue_input = pending.as_ref().map(Continuation::prompt);
+            let turn_input = continue_input.as_deref().unwrap_or(trimmed);
 
-            // ファイル読み込み（既存ファイルのみ）、未存在は出力ターゲットとして扱う
-            let mut provided_files = std::collections::HashMap::new();
-            let mut output_targets = Vec::new();
-            let mut files = Vec::new();
-            let mut errors = Vec::new();
-
-            if !file_paths.is_empty() {
-                // 入出力の推定: ファイル操作意図があり、2つ以上のファイルが指定された場合は
-                // 先頭を入力、それ以降を出力ターゲットとして扱う。
-                // 単一ファイルかつファイル操作意図が強い場合（保存/書き込みなどを含む）は出力優先。
-                let mut input_candidates = Vec::new();
-                let mut output_candidates = Vec::new();
-
-                if has_file_write_intent && file_paths.len() >= 2 {
-                    let mut iter = file_paths.iter();
-                    if let Some(first) = iter.next() {
-                        input_candidates.push(first.clone());
-                    }
-                    for p in iter {
-                        output_candidates.push(p.clone());
+            if has_undo_intent(trimmed) && self.handle_undo_intent()? {
+                continue;
+            }
+            file_ops::begin_write_turn();
+
+            let has_file_write_intent = has_file_operation_intent(trimmed) > 0.0;
+
+            if self.allow_shell_intent
+                && !has_file_write_intent
+                && has_shell_command_intent(trimmed)
+                && self.handle_shell_intent(trimmed).await?
+            {
+                continue;
+            }
+
+            terminal::disable_raw_mode().context("Failed to disable raw mode")?;
+            let turn = self
+                .run_turn(turn_input, |text| {
+                    print!("{}", text);
+                    let _ = io::stdout().flush();
+                })
+                .await;
+            let (truncated, continuation, elapsed, tool_time, error) = match turn {
+                Ok(output) => {
+                    if has_response_suffix {
+                        println!();
                     }
-                } else if has_file_write_intent && prefers_output_only(&trimmed) {
-                    output_candidates.extend(file_paths.clone());
-                } else {
-                    input_candidates.extend(file_paths.clone());
+                    (
+                        output.truncated,
+                        output.token_limit_hit.then(|| {
+                            // `continue` を重ねても続きのプロンプトがコンテキストの半分を超えないようにする
+                            Continuation::new(
+                                query,
+                                pending.map(|p| p.response).unwrap_or_default() + &output.response,
+                                max_context_tokens().saturating_sub(context_reserved_tokens()) / 2,
+                            )
+                        }),
+                        Some(output.inference_time),
+                        Some(output.tool_time),
+                        None,
+                    )
+                }
+                Err(e) => {
+                    eprintln!("\n{:#}", e);
+                    (false, None, None, None, Some(format!("{:#}", e)))
+                }
+            };
+            // 失敗は次のターンのプロンプトに <last_error> として伝え、再試行で同じ失敗を避けさせる
+            let error = error.or_else(|| {
+                truncated.then(|| {
+                    "The previous response was cut off by the inference timeout. Give a shorter answer."
+                        .to_string()
+                })
+            });
+            if let Ok(mut last_error) = self.last_error.lock() {
+                *last_error = error;
+            }
+            if truncated {
+                execute!(
+                    stdout,
+                    SetForegroundColor(Color::Yellow),
+                    Print("[Response truncated: inference timeout]\n"),
+                    ResetColor
+                )?;
+            }
+            if continuation.is_some() {
+                execute!(
+                    stdout,
+                    SetForegroundColor(Color::Yellow),
+                    Print("[Response truncated at token limit. Type 'continue' to generate more]\n"),
+                    ResetColor
+                )?;
+            }
+            if let Ok(mut pending) = self.continuation.lock() {
+                *pending = continuation;
+            }
+            // ターン中の変更（モデル自身の書き込みを含む）では再実行しない
+            self.take_watch_change();
+            self.print_separator(Color::DarkGrey, elapsed, tool_time);
+            terminal::enable_raw_mode().context("Failed to enable raw mode")?;
+            if self.shutdown_if_requested(stdout)? {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 1ターン分の処理（意図判定、ファイル検出、プロンプト構築、推論、ツール呼び出し、ファイル操作）を行う
+    ///
+    /// # 引数
+    /// * `input` - ユーザー入力
+    /// * `on_chunk` - 生成されたテキストを受け取るコールバック
+    ///
+    /// `RKLLM_RESPONSE_PREFIX` / `RKLLM_RESPONSE_SUFFIX` はターンごとに1回だけ、表示と返す応答の両方に付ける。
+    async fn run_turn<F>(&self, input: &str, on_chunk: F) -> Result<TurnOutput>
+    where
+        F: FnMut(&str) + Send + 'static,
+    {
+        let trimmed = input.trim();
+        let environment = self.environment_info();
+        let file_write_score = has_file_operation_intent(trimmed);
+        let has_file_write_intent = file_write_score > 0.0;
+        let has_file_read_intent = has_file_read_intent(trimmed);
+
+        if self.tool_only && has_file_write_intent {
+            self.status("\n[tool-only] Local file writes are disabled. Use MCP tools for any file outputs.");
+        }
+
+        // 書き込み意図は閾値以上のときだけファイル検出に使う（"write a poem" などの誤検出を避ける）
+        let mut file_paths = if (file_write_score >= self.config.intent_threshold || has_file_read_intent)
+            && !self.config.detect_extensions.is_empty()
+        {
+            file_detector::detect_file_paths_with_exts(
+                trimmed,
+                &self.config.detect_extensions,
+                &self.config.file_detection_deny_patterns,
+            )
+            .into_iter()
+            .map(|found| found.path)
+            .collect()
+        } else {
+            Vec::new()
+        };
+        // `-`（標準入力）はパイプ入力のときだけ読む。端末から読むと入力待ちで止まってしまう
+        if io::stdin().is_terminal() {
+            file_paths.retain(|path| path != file_ops::STDIN_PATH);
+        }
+
+        // ファイル読み込み（既存ファイルのみ）、未存在は出力ターゲットとして扱う
+        let mut provided_files = std::collections::HashMap::new();
+        let mut output_targets = Vec::new();
+        let mut files = Vec::new();
+        let mut errors = Vec::new();
+
+        if !file_paths.is_empty() {
+            // 入出力の推定: ファイル操作意図があり、2つ以上のファイルが指定された場合は
+            // 先頭を入力、それ以降を出力ターゲットとして扱う。
+            // 単一ファイルかつファイル操作意図が強い場合（保存/書き込みなどを含む）は出力優先。
+            let mut input_candidates = Vec::new();
+            let mut output_candidates = Vec::new();
+
+            if has_file_write_intent && file_paths.len() >= 2 {
+                let mut iter = file_paths.iter();
+                if let Some(first) = iter.next() {
+                    input_candidates.push(first.clone());
                 }
+                for p in iter {
+                    output_candidates.push(p.clone());
+                }
+            } else if has_file_write_intent && prefers_output_only(trimmed) {
+                output_candidates.extend(file_paths.clone());
+            } else {
+                input_candidates.extend(file_paths.clone());
+            }
 
-                println!("\n[Detected files: {}]", file_paths.join(", "));
+            // 標準入力は書き込み先にならない
+            output_candidates.retain(|path| path != file_ops::STDIN_PATH);
 
-                for path in &input_candidates {
-                    if file_ops::file_exists(path) {
-                        match file_ops::read_file(path) {
-                            Ok(content) => {
-                                provided_files.insert(content.original_path.clone(), content.content.clone());
-                                files.push(content);
-                            }
-                            Err(e) => errors.push((path.clone(), e.to_string())),
+            self.status(format!("\n[Detected files: {}]", file_paths.join(", ")));
+
+            for path in &input_candidates {
+                if self
+                    .ignore_rules
+                    .as_ref()
+                    .is_some_and(|rules| rules.is_ignored(path))
+                {
+                    if std::env::var("RKLLM_DEBUG_FILES").is_ok() {
+                        eprintln!("[Ignored by .gitignore/.rkllmignore: {}]", path);
+                    }
+                    continue;
+                }
+                if path == file_ops::STDIN_PATH {
+                    match file_ops::read_file(path) {
+                        Ok(content) => {
+                            provided_files.insert(content.original_path.clone(), content.content.clone());
+                            files.push(content);
                         }
-                    } else {
-                        errors.push((path.clone(), "File not found".to_string()));
+                        Err(e) => errors.push((path.clone(), e.to_string())),
+                    }
+                } else if file_ops::file_exists(path) {
+                    let read = match self.file_cache.lock() {
+                        Ok(mut cache) => cache.get_or_read(path),
+                        Err(_) => file_ops::read_file(path),
+                    };
+                    match read {
+                        Ok(content) => {
+                            provided_files.insert(content.original_path.clone(), content.content.clone());
+                            files.push(content);
+                        }
+                        Err(e) => errors.push((path.clone(), e.to_string())),
                     }
+                } else {
+                    errors.push((path.clone(), "File not found".to_string()));
                 }
+            }
 
-                output_targets.extend(output_candidates);
+            output_targets.extend(output_candidates);
 
-                if !files.is_empty() {
-                    println!("[Successfully loaded {} file(s)]", files.len());
+            for file in &files {
+                self.status(format!(
+                    "[Loaded: {} ({} lines, {:.1} KB)]",
+                    file.original_path,
+                    file.line_count(),
+                    file.size_bytes() as f64 / 1024.0
+                ));
+            }
+            for (path, error) in &errors {
+                eprintln!("[Error loading '{}': {}]", path, error);
+            }
+            if !output_targets.is_empty() {
+                self.status(format!(
+                    "[Treating as output targets (not loaded): {}]",
+                    output_targets.join(", ")
+                ));
+            }
+        }
+
+        let tool_info = self.build_tool_info().await;
+        let last_error = self.last_error.lock().ok().and_then(|error| error.clone());
+
+        let prompt_build = build_prompt_with_context_limit(&PromptParts {
+            user_input: trimmed,
+            files: &files,
+            errors: &errors,
+            tool_info: tool_info.as_deref(),
+            output_targets: &output_targets,
+            has_file_op_intent: has_file_write_intent,
+            file_writes_enabled: !self.tool_only,
+            execution_dir: &self.execution_dir,
+            system_prompt: self.system_prompt.as_deref(),
+            environment: Some(&environment),
+            last_error: last_error.as_deref(),
+            response_language: self.response_language.as_deref(),
+            extra_context: &self.extra_context,
+            ..Default::default()
+        });
+        for notice in &prompt_build.notices {
+            self.status(notice);
+        }
+        if prompt_build.overflow {
+            anyhow::bail!(
+                "[Context] Prompt exceeds max context. Reduce input or set --max-context-tokens / RKLLM_MAX_CONTEXT_TOKENS."
+            );
+        }
+        let prompt = prompt_build.prompt;
+        if !self.headless.load(Ordering::SeqCst) {
+            Self::print_context_summary(
+                &mut io::stdout(),
+                estimate_tokens_v2(&prompt),
+                max_context_tokens(),
+            )?;
+        }
+        if self.preview_prompt || std::env::var("RKLLM_DEBUG_PROMPT").is_ok() {
+            eprintln!("\n[DEBUG prompt length={}]", prompt.len());
+            eprintln!("{}", prompt);
+        }
+
+        self.status("");
+        // 応答の前後に付ける文字列（ツール呼び出しやファイル出力の解析対象には含めない）
+        let response_prefix = response_affix_from_env("RKLLM_RESPONSE_PREFIX");
+        let response_suffix = response_affix_from_env("RKLLM_RESPONSE_SUFFIX");
+        // 追加推論の出力も同じコールバックへ渡すため共有する
+        let on_chunk = Arc::new(Mutex::new(with_prefix(response_prefix.clone(), on_chunk)));
+        let on_chunk_ref = Arc::clone(&on_chunk);
+        let mut truncated = false;
+        let mut token_limit_hit = false;
+        let started = Instant::now();
+        // 最初のトークンを表示する前にスピナーを消す
+        let spinner = self.start_spinner();
+        let first_spinner = spinner.clone();
+        // TTFT（プリフィルの待ち時間）は最初のトークンが届いた時点で、応答より先に表示する
+        let show_ttft = !self.headless.load(Ordering::SeqCst);
+        let mut waiting_first_token = true;
+        let first_result = self
+            .run_inference(&prompt, move |text| {
+                if let Some(spinner) = &first_spinner {
+                    spinner.stop();
                 }
-                for (path, error) in &errors {
-                    eprintln!("[Error loading '{}': {}]", path, error);
+                if waiting_first_token && !text.is_empty() {
+                    waiting_first_token = false;
+                    if show_ttft {
+                        let _ = execute!(
+                            io::stdout(),
+                            SetForegroundColor(Color::DarkGrey),
+                            Print(format!("[TTFT: {:.1}s]\n", started.elapsed().as_secs_f64())),
+                            ResetColor
+                        );
+                    }
                 }
-                if !output_targets.is_empty() {
-                    println!(
-                        "[Treating as output targets (not loaded): {}]",
-                        output_targets.join(", ")
-                    );
+                if let Ok(mut callback) = on_chunk_ref.lock() {
+                    callback(text);
+                }
+            })
+            .map(|output| {
+                token_limit_hit = output.response_was_truncated;
+                output.text
+            });
+        if let Some(spinner) = &spinner {
+            spinner.stop();
+        }
+        let mut inference_time = started.elapsed();
+        let mut response = recover_timeout(first_result, &mut truncated)
+            .context("Error during inference")?;
+        self.status("");
+
+        let mut tool_rounds = 0usize;
+        let mut tool_time = Duration::ZERO;
+        let mut seen_tool_calls: HashSet<String> = HashSet::new();
+        let mut tool_retry_count: HashMap<String, usize> = HashMap::new();
+        // 同じツールが繰り返し失敗した場合、その旨を伝えた追加推論を最後にツール処理を打ち切る
+        let mut stop_tools = false;
+        loop {
+            // ファイル操作を処理（ユーザーに意図がある場合のみ）
+            if has_file_write_intent {
+                if self.tool_only {
+                    if let Err(e) = self
+                        .process_file_operations_via_tools(
+                            &response,
+                            &provided_files,
+                            &output_targets,
+                        )
+                        .await
+                    {
+                        eprintln!("\nError processing file operations via MCP tools: {}", e);
+                    }
+                } else if let Err(e) = self.process_file_operations(
+                    &response,
+                    &provided_files,
+                    &output_targets,
+                ) {
+                    eprintln!("\nError processing file operations: {}", e);
                 }
             }
 
-            let tool_info = self.build_tool_info();
+            if stop_tools {
+                break;
+            }
 
-            let prompt_build = build_prompt_with_context_limit(
-                &trimmed,
-                &files,
-                &errors,
-                tool_info.as_deref(),
-                &output_targets,
-                has_file_write_intent,
-                !self.tool_only,
-                &[],
-            );
-            for notice in &prompt_build.notices {
-                println!(
-                    "[Truncated file content: {} ({} -> {} tokens)]",
-                    notice.path, notice.original_tokens, notice.kept_tokens
+            let allowance = if tool_rounds == 0 {
+                ToolCallAllowance::All
+            } else {
+                ToolCallAllowance::WriteOnly
+            };
+            let ToolRoundOutcome {
+                results: tool_results,
+                blocked_repeat,
+                gave_up,
+            } = match self
+                .process_tool_calls(&response, &mut seen_tool_calls, &mut tool_retry_count, allowance)
+                .await
+            {
+                Ok(result) => result,
+                Err(e) => {
+                    eprintln!("\nError processing tool calls: {}", e);
+                    ToolRoundOutcome::default()
+                }
+                };
+            stop_tools = gave_up;
+            tool_time += Duration::from_millis(tool_results.iter().map(|r| r.latency_ms).sum());
+            if blocked_repeat {
+                eprintln!("\n[Repeated tool call blocked]");
+                break;
+            }
+            if tool_results.is_empty() {
+                break;
+            }
+
+            tool_rounds += 1;
+            if tool_rounds >= self.config.max_tool_rounds {
+                eprintln!(
+                    "\n[Tool limit ({}) reached: set --max-tool-rounds or RKLLM_MAX_TOOL_ROUNDS to increase]",
+                    self.config.max_tool_rounds
                 );
+                break;
             }
-            if prompt_build.overflow {
+
+            let followup_build = build_prompt_with_context_limit(&PromptParts {
+                user_input: trimmed,
+                files: &files,
+                errors: &errors,
+                tool_info: tool_info.as_deref(),
+                output_targets: &output_targets,
+                has_file_op_intent: has_file_write_intent,
+                file_writes_enabled: !self.tool_only,
+                tool_results: &tool_results,
+                execution_dir: &self.execution_dir,
+                system_prompt: self.system_prompt.as_deref(),
+                environment: Some(&environment),
+                last_error: last_error.as_deref(),
+                response_language: self.response_language.as_deref(),
+                extra_context: &self.extra_context,
+                ..Default::default()
+            });
+            for notice in &followup_build.notices {
+                self.status(notice);
+            }
+            if followup_build.overflow {
                 eprintln!(
-                    "[Context] Prompt exceeds max context. Reduce input or set RKLLM_MAX_CONTEXT_TOKENS."
+                    "[Context] Prompt exceeds max context. Reduce input or set --max-context-tokens / RKLLM_MAX_CONTEXT_TOKENS."
                 );
-                continue;
+                break;
             }
-            let prompt = prompt_build.prompt;
+            let followup_prompt = followup_build.prompt;
             if self.preview_prompt || std::env::var("RKLLM_DEBUG_PROMPT").is_ok() {
-                eprintln!("\n[DEBUG prompt length={}]", prompt.len());
-                eprintln!("{}", prompt);
+                eprintln!("\n[DEBUG prompt length={}]", followup_prompt.len());
+                eprintln!("{}", followup_prompt);
             }
 
-            terminal::disable_raw_mode().context("Failed to disable raw mode")?;
-            print!("\n");
-            io::stdout().flush().unwrap();
-            match self.rkllm.run(&prompt, |text| {
-                print!("{}", text);
-                let _ = io::stdout().flush();
-            }) {
-                Ok(mut response) => {
-                    println!();
-
-                    let mut tool_rounds = 0usize;
-                    let mut seen_tool_calls: HashSet<String> = HashSet::new();
-                    loop {
-                        // ファイル操作を処理（ユーザーに意図がある場合のみ）
-                        if has_file_write_intent {
-                            if self.tool_only {
-                                if let Err(e) = self
-                                    .process_file_operations_via_tools(
-                                        &response,
-                                        &provided_files,
-                                        &output_targets,
-                                    )
-                                    .await
-                                {
-                                    eprintln!("\nError processing file operations via MCP tools: {}", e);
-                                }
-                            } else if let Err(e) = self.process_file_operations(
-                                &response,
-                                &provided_files,
-                                &output_targets,
-                            ) {
-                                eprintln!("\nError processing file operations: {}", e);
-                            }
-                        }
+            let buffered = Arc::new(Mutex::new(String::new()));
+            let buffered_ref = Arc::clone(&buffered);
+            let started = Instant::now();
+            // 追加推論の出力はまとめて表示するため、終わるまでスピナーを出しておく
+            let spinner = self.start_spinner();
+            let followup_result = self
+                .run_inference(&followup_prompt, move |text| {
+                    if let Ok(mut buf) = buffered_ref.lock() {
+                        buf.push_str(text);
+                    }
+                })
+                .map(|output| {
+                    token_limit_hit = output.response_was_truncated;
+                    output.text
+                });
+            if let Some(spinner) = &spinner {
+                spinner.stop();
+            }
+            inference_time += started.elapsed();
+            match recover_timeout(followup_result, &mut truncated) {
+                Ok(next_response) => {
+                    let display = buffered
+                        .lock()
+                        .map(|buf| Self::strip_tool_calls(&buf))
+                        .unwrap_or_default();
+                    if let Ok(mut callback) = on_chunk.lock() {
+                        callback(&display);
+                    }
+                    self.status("");
+                    response = next_response;
+                }
+                Err(e) => {
+                    // 中断された場合も、それまでに生成された部分を表示する
+                    if let Some(cancelled) = e.downcast_ref::<InferenceCancelled>()
+                        && let Ok(mut callback) = on_chunk.lock()
+                    {
+                        callback(&Self::strip_tool_calls(&cancelled.0));
+                    }
+                    eprintln!("\nError during inference: {}", e);
+                    break;
+                }
+            }
+        }
 
-                        let allowance = if tool_rounds == 0 {
-                            ToolCallAllowance::All
-                        } else {
-                            ToolCallAllowance::WriteOnly
-                        };
-                        let (tool_results, blocked_repeat) = match self
-                            .process_tool_calls(&response, &mut seen_tool_calls, allowance)
-                            .await
-                        {
-                            Ok(result) => result,
-                            Err(e) => {
-                                eprintln!("\nError processing tool calls: {}", e);
-                                (Vec::new(), false)
-                            }
-                            };
-                        if blocked_repeat {
-                            eprintln!("\n[Repeated tool call blocked]");
-                            break;
-                        }
-                        if tool_results.is_empty() {
-                            break;
-                        }
+        if let Some(suffix) = &response_suffix
+            && let Ok(mut callback) = on_chunk.lock()
+        {
+            callback(suffix);
+        }
+        let response = format!(
+            "{}{}{}",
+            response_prefix.unwrap_or_default(),
+            response,
+            response_suffix.unwrap_or_default()
+        );
 
-                        tool_rounds += 1;
-                        if tool_rounds >= 3 {
-                            eprintln!("\n[Tool call limit reached]");
-                            break;
-                        }
+        Ok(TurnOutput {
+            response,
+            truncated,
+            token_limit_hit,
+            inference_time,
+            tool_time,
+        })
+    }
 
-                        let followup_build = build_prompt_with_context_limit(
-                            &trimmed,
-                            &files,
-                            &errors,
-                            tool_info.as_deref(),
-                            &output_targets,
-                            has_file_write_intent,
-                            !self.tool_only,
-                            &tool_results,
-                        );
-                        for notice in &followup_build.notices {
-                            println!(
-                                "[Truncated file content: {} ({} -> {} tokens)]",
-                                notice.path, notice.original_tokens, notice.kept_tokens
-                            );
-                        }
-                        if followup_build.overflow {
-                            eprintln!(
-                                "[Context] Prompt exceeds max context. Reduce input or set RKLLM_MAX_CONTEXT_TOKENS."
-                            );
-                            break;
-                        }
-                        let followup_prompt = followup_build.prompt;
-                        if self.preview_prompt || std::env::var("RKLLM_DEBUG_PROMPT").is_ok() {
-                            eprintln!("\n[DEBUG prompt length={}]", followup_prompt.len());
-                            eprintln!("{}", followup_prompt);
-                        }
+    /// Ctrl+C で中断できるようにして推論を実行する
+    ///
+    /// 中断された場合は `InferenceCancelled`（それまでの部分応答を含む）を返す。
+    fn run_inference<F>(&self, prompt: &str, callback: F) -> Result<RunOutput>
+    where
+        F: FnMut(&str) + Send + 'static,
+    {
+        if let Ok(mut last_prompt) = self.last_prompt.lock() {
+            *last_prompt = Some(prompt.to_string());
+        }
+        // ライブラリとして使う場合は、呼び出し側の SIGINT 処理を置き換えない
+        if !self.headless.load(Ordering::SeqCst) {
+            self.sigint_watcher.call_once(|| self.spawn_sigint_watcher());
+        }
+        let cancel = CancellationToken::new();
+        if let Ok(mut current) = self.inference_cancel.lock() {
+            *current = Some(cancel.clone());
+        }
+        let result = self.rkllm.run(prompt, &cancel, callback);
+        if let Ok(mut current) = self.inference_cancel.lock() {
+            *current = None;
+        }
+        result
+    }
 
-                        let buffered = Arc::new(Mutex::new(String::new()));
-                        let buffered_ref = Arc::clone(&buffered);
-                        match self.rkllm.run(&followup_prompt, move |text| {
-                            if let Ok(mut buf) = buffered_ref.lock() {
-                                buf.push_str(text);
-                            }
-                        }) {
-                            Ok(next_response) => {
-                                let display = buffered
-                                    .lock()
-                                    .map(|buf| Self::strip_tool_calls(&buf))
-                                    .unwrap_or_default();
-                                print!("{}", display);
-                                let _ = io::stdout().flush();
-                                println!();
-                                response = next_response;
-                            }
-                            Err(e) => {
-                                eprintln!("\nError during inference: {}", e);
-                                break;
-                            }
-                        }
+    /// SIGINT を受け取ったら実行中の推論を中断するタスクを起動する（セッションで1回だけ）
+    ///
+    /// `tokio::signal::ctrl_c()` を一度呼ぶと既定の SIGINT 処理には戻らないため、
+    /// 推論中でなければ既定と同じく終了する。
+    fn spawn_sigint_watcher(&self) {
+        let current = Arc::clone(&self.inference_cancel);
+        tokio::spawn(async move {
+            while tokio::signal::ctrl_c().await.is_ok() {
+                let cancel = current.lock().ok().and_then(|cancel| cancel.clone());
+                match cancel {
+                    Some(cancel) => cancel.cancel(),
+                    None => {
+                        let _ = terminal::disable_raw_mode();
+                        std::process::exit(130);
                     }
                 }
-                Err(e) => {
-                    eprintln!("\nError during inference: {}", e);
-                }
             }
-            self.print_separator(Color::DarkGrey);
+        });
+    }
+
+    /// プロンプトに埋め込む実行環境の情報
+    fn environment_info(&self) -> EnvironmentInfo {
+        EnvironmentInfo {
+            os: std::env::consts::OS.to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+            shell: std::env::var("SHELL").ok().filter(|v| !v.is_empty()),
+            cwd: self.execution_dir.clone(),
+        }
+    }
+
+    /// シェル実行の意図がある入力を、LLM 推論を介さず MCP のシェルツールへ直接渡す
+    ///
+    /// # 戻り値
+    /// シェルツールで処理した場合はtrue（通常のプロンプト処理は行わない）
+    async fn handle_shell_intent(&self, input: &str) -> Result<bool> {
+        let Some(mcp_client) = &self.mcp_client else {
+            return Ok(false);
+        };
+        let mcp_client = mcp_client.read().await;
+        let tools = mcp_client.list_all_tools();
+        let Some(shell_tool_name) = Self::select_shell_tool_name(&tools) else {
+            return Ok(false);
+        };
+
+        // バッククォートで囲まれたコマンドだけを実行する（文章をそのまま実行しない）
+        let Some(command) = Self::extract_shell_command(input) else {
+            return Ok(false);
+        };
+        terminal::disable_raw_mode().context("Failed to disable raw mode")?;
+        let message = format!(
+            "\n[Shell intent] Run via tool '{}': {} (y/N/a=always): ",
+            shell_tool_name, command
+        );
+        if !self.prompt_confirm_always(&shell_tool_name, &message)? {
+            println!("[Shell command cancelled]");
+            self.print_separator(Color::DarkGrey, None, None);
             terminal::enable_raw_mode().context("Failed to enable raw mode")?;
+            return Ok(true);
         }
 
-        Ok(())
+        match mcp_client
+            .call_tool(&shell_tool_name, json!({ "command": command }))
+            .await
+        {
+            Ok(result) => {
+                if result.success {
+                    println!("\n[Tool '{}' output:]", shell_tool_name);
+                    println!("{}", result.output);
+                } else {
+                    eprintln!("\n[Tool '{}' failed:]", shell_tool_name);
+                    eprintln!("{}", result.output);
+                }
+            }
+            Err(e) => {
+                eprintln!("\n[Failed to call tool '{}': {}]", shell_tool_name, e);
+            }
+        }
+
+        self.print_separator(Color::DarkGrey, None, None);
+        terminal::enable_raw_mode().context("Failed to enable raw mode")?;
+        Ok(true)
+    }
+
+    /// 直前のターンで書き込んだファイルを、確認の上で書き込み前の状態に戻す
+    ///
+    /// # 戻り値
+    /// 取り消し対象があり入力を処理した場合は true（false なら通常の推論に回す）
+    fn handle_undo_intent(&self) -> Result<bool> {
+        let files = file_ops::restorable_files();
+        if files.is_empty() {
+            return Ok(false);
+        }
+
+        terminal::disable_raw_mode().context("Failed to disable raw mode")?;
+        let handled = undo_files(&files, |message| self.prompt_confirm(message))?;
+        if handled {
+            self.print_separator(Color::DarkGrey, None, None);
+        }
+        terminal::enable_raw_mode().context("Failed to enable raw mode")?;
+        Ok(handled)
+    }
+
+    /// 入力からバッククォートで囲まれたコマンド文字列を取り出す（なければ None）
+    fn extract_shell_command(input: &str) -> Option<String> {
+        SHELL_COMMAND_PATTERN
+            .captures(input)
+            .and_then(|cap| cap.get(1))
+            .map(|m| m.as_str().trim().to_string())
+            .filter(|command| !command.is_empty())
     }
 
     fn read_multiline_input(&self, stdout: &mut std::io::Stdout) -> Result<Option<String>> {
         let prompt_width = UnicodeWidthStr::width(Self::PROMPT);
         let indent_width = UnicodeWidthStr::width(Self::INDENT);
 
-        // プロンプト行を起点に、毎回カーソルを戻して全体再描画する。
+        // プロンプト行を起点に、毎回カーソルを戻して再描画する（変化した行のみ書き換える）。
         let mut rendered_rows: usize = 1; // プロンプトのみの1行
-        let mut buffer = InputBuffer::default();
+        let mut buffer = InputBuffer {
+            bidi_reorder: std::env::var("RKLLM_BIDI").ok().as_deref() == Some("1"),
+            ..Default::default()
+        };
         let (pos_col, pos_row) = cursor::position().unwrap_or((0, 0));
         let _ = pos_col;
         let anchor_col = 0;
         let mut anchor_row = pos_row;
         let mut cursor_row_offset: u16 = 0;
+        let mut rendered = RenderedInput::default();
+        let style = InputStyle {
+            prompt: Self::PROMPT,
+            indent: Self::INDENT,
+            prompt_width,
+            indent_width,
+            prompt_bg: Self::PROMPT_BG,
+            prompt_fg: Self::PROMPT_FG,
+            input_bg: Self::INPUT_BG,
+            input_fg: Self::INPUT_FG,
+        };
 
             let redraw = |stdout: &mut std::io::Stdout,
                       rendered_rows: &mut usize,
                       buffer: &InputBuffer,
                       anchor_row: &mut u16,
-                      cursor_row_offset: &mut u16|
+                      cursor_row_offset: &mut u16,
+                      rendered: &mut RenderedInput|
          -> Result<()> {
             let (_, current_row) = cursor::position().unwrap_or((*anchor_row, 0));
             *anchor_row = current_row.saturating_sub(*cursor_row_offset);
@@ -562,28 +1767,35 @@ impl ChatSession {
             execute!(stdout, cursor::MoveTo(anchor_col, *anchor_row))?;
             let term_width = terminal::size().map(|(w, _)| w as usize).unwrap_or(80).max(1);
             let (rows_used, cursor_pos) =
-                render_input(
-                    stdout,
-                    Self::PROMPT,
-                    Self::INDENT,
-                    prompt_width,
-                    indent_width,
-                    term_width,
-                    buffer,
-                    Self::PROMPT_BG,
-                    Self::PROMPT_FG,
-                    Self::INPUT_BG,
-                    Self::INPUT_FG,
-                )?;
+                render_input(stdout, &style, term_width, buffer, *anchor_row, rendered)?;
             *rendered_rows = rows_used;
             *cursor_row_offset = cursor_pos.0 as u16;
             Ok(())
         };
 
-        redraw(stdout, &mut rendered_rows, &buffer, &mut anchor_row, &mut cursor_row_offset)?;
+        redraw(stdout, &mut rendered_rows, &buffer, &mut anchor_row, &mut cursor_row_offset, &mut rendered)?;
+        let mut debounce = RedrawDebounce::default();
 
         loop {
-            if event::poll(Duration::from_millis(100))? {
+            if self.shutdown_requested.load(Ordering::SeqCst) {
+                return Ok(None);
+            }
+            // 入力途中でなければ、監視中のファイルの変更で直前の質問を再実行する
+            if buffer.graphemes.is_empty()
+                && let Some(changed) = self.take_watch_change()
+                && let Some(query) = self.last_query.lock().ok().and_then(|q| q.clone())
+            {
+                execute!(
+                    stdout,
+                    Print(format!(
+                        "\r\n[Watch: re-running after change to {}]\r\n",
+                        self.display_path(&changed)
+                    ))
+                )?;
+                return Ok(Some(query));
+            }
+            let timeout = debounce.poll_timeout(Instant::now(), Duration::from_millis(100));
+            if event::poll(timeout)? {
                 match event::read()? {
                     Event::Key(key_event) => match key_event {
                         KeyEvent {
@@ -610,7 +1822,8 @@ impl ChatSession {
                                 ResetColor
                             )?;
                             rendered_rows = 1;
-                            redraw(stdout, &mut rendered_rows, &buffer, &mut anchor_row, &mut cursor_row_offset)?;
+                            rendered = RenderedInput::default();
+                            redraw(stdout, &mut rendered_rows, &buffer, &mut anchor_row, &mut cursor_row_offset, &mut rendered)?;
                         }
                         KeyEvent {
                             code: KeyCode::Char('d'),
@@ -619,13 +1832,32 @@ impl ChatSession {
                         } => {
                             return Ok(None);
                         }
+                        KeyEvent {
+                            code: KeyCode::Char('z'),
+                            modifiers: KeyModifiers::CONTROL,
+                            ..
+                        } => {
+                            self.suspend(stdout)?;
+                            // 再開後は入力中の内容をプロンプトごと描き直す
+                            execute!(
+                                stdout,
+                                SetBackgroundColor(Self::PROMPT_BG),
+                                SetForegroundColor(Self::PROMPT_FG),
+                                Print(Self::PROMPT),
+                                ResetColor
+                            )?;
+                            rendered_rows = 1;
+                            cursor_row_offset = 0;
+                            rendered = RenderedInput::default();
+                            redraw(stdout, &mut rendered_rows, &buffer, &mut anchor_row, &mut cursor_row_offset, &mut rendered)?;
+                        }
                         KeyEvent {
                             code: KeyCode::Char('j'),
                             modifiers,
                             ..
                         } if modifiers.contains(KeyModifiers::CONTROL) => {
                             buffer.insert_str("\n");
-                            redraw(stdout, &mut rendered_rows, &buffer, &mut anchor_row, &mut cursor_row_offset)?;
+                            redraw(stdout, &mut rendered_rows, &buffer, &mut anchor_row, &mut cursor_row_offset, &mut rendered)?;
                         }
                         KeyEvent {
                             code: KeyCode::Enter,
@@ -633,14 +1865,15 @@ impl ChatSession {
                             ..
                         } => {
                             buffer.insert_str("\n");
-                            redraw(stdout, &mut rendered_rows, &buffer, &mut anchor_row, &mut cursor_row_offset)?;
+                            redraw(stdout, &mut rendered_rows, &buffer, &mut anchor_row, &mut cursor_row_offset, &mut rendered)?;
                         }
                         KeyEvent {
                             code: KeyCode::Enter,
                             modifiers: KeyModifiers::NONE,
                             ..
                         } => {
-                            redraw(stdout, &mut rendered_rows, &buffer, &mut anchor_row, &mut cursor_row_offset)?;
+                            // 保留中の入力も含め、送信前に必ず最終状態を描画する
+                            redraw(stdout, &mut rendered_rows, &buffer, &mut anchor_row, &mut cursor_row_offset, &mut rendered)?;
                             execute!(stdout, Print("\r\n"))?;
                             return Ok(Some(buffer.to_string()));
                         }
@@ -648,32 +1881,32 @@ impl ChatSession {
                             code: KeyCode::Backspace,
                             ..
                         } => {
-                            if buffer.backspace() {
-                                redraw(stdout, &mut rendered_rows, &buffer, &mut anchor_row, &mut cursor_row_offset)?;
+                            if buffer.backspace() && debounce.should_redraw(Instant::now()) {
+                                redraw(stdout, &mut rendered_rows, &buffer, &mut anchor_row, &mut cursor_row_offset, &mut rendered)?;
                             }
                         }
                         KeyEvent {
                             code: KeyCode::Delete,
                             ..
                         } => {
-                            if buffer.delete() {
-                                redraw(stdout, &mut rendered_rows, &buffer, &mut anchor_row, &mut cursor_row_offset)?;
+                            if buffer.delete() && debounce.should_redraw(Instant::now()) {
+                                redraw(stdout, &mut rendered_rows, &buffer, &mut anchor_row, &mut cursor_row_offset, &mut rendered)?;
                             }
                         }
                         KeyEvent {
                             code: KeyCode::Left,
                             ..
                         } => {
-                            if buffer.move_left() {
-                                redraw(stdout, &mut rendered_rows, &buffer, &mut anchor_row, &mut cursor_row_offset)?;
+                            if buffer.move_left() && debounce.should_redraw(Instant::now()) {
+                                redraw(stdout, &mut rendered_rows, &buffer, &mut anchor_row, &mut cursor_row_offset, &mut rendered)?;
                             }
                         }
                         KeyEvent {
                             code: KeyCode::Right,
                             ..
                         } => {
-                            if buffer.move_right() {
-                                redraw(stdout, &mut rendered_rows, &buffer, &mut anchor_row, &mut cursor_row_offset)?;
+                            if buffer.move_right() && debounce.should_redraw(Instant::now()) {
+                                redraw(stdout, &mut rendered_rows, &buffer, &mut anchor_row, &mut cursor_row_offset, &mut rendered)?;
                             }
                         }
                         KeyEvent {
@@ -682,7 +1915,7 @@ impl ChatSession {
                         } => {
                             let term_width = terminal::size().map(|(w, _)| w as usize).unwrap_or(80).max(1);
                             if buffer.move_vertical(-1, prompt_width, indent_width, term_width) {
-                                redraw(stdout, &mut rendered_rows, &buffer, &mut anchor_row, &mut cursor_row_offset)?;
+                                redraw(stdout, &mut rendered_rows, &buffer, &mut anchor_row, &mut cursor_row_offset, &mut rendered)?;
                             }
                         }
                         KeyEvent {
@@ -691,7 +1924,7 @@ impl ChatSession {
                         } => {
                             let term_width = terminal::size().map(|(w, _)| w as usize).unwrap_or(80).max(1);
                             if buffer.move_vertical(1, prompt_width, indent_width, term_width) {
-                                redraw(stdout, &mut rendered_rows, &buffer, &mut anchor_row, &mut cursor_row_offset)?;
+                                redraw(stdout, &mut rendered_rows, &buffer, &mut anchor_row, &mut cursor_row_offset, &mut rendered)?;
                             }
                         }
                         KeyEvent {
@@ -700,24 +1933,48 @@ impl ChatSession {
                             ..
                         } => {
                             buffer.insert_str(&c.to_string());
-                            redraw(stdout, &mut rendered_rows, &buffer, &mut anchor_row, &mut cursor_row_offset)?;
+                            if debounce.should_redraw(Instant::now()) {
+                                redraw(stdout, &mut rendered_rows, &buffer, &mut anchor_row, &mut cursor_row_offset, &mut rendered)?;
+                            }
                         }
                         _ => {}
                     },
                     Event::Paste(content) => {
                         let normalized = content.replace("\r\n", "\n").replace('\r', "\n");
                         buffer.insert_str(&normalized);
-                        redraw(stdout, &mut rendered_rows, &buffer, &mut anchor_row, &mut cursor_row_offset)?;
+                        redraw(stdout, &mut rendered_rows, &buffer, &mut anchor_row, &mut cursor_row_offset, &mut rendered)?;
+                    }
+                    Event::Resize(_, _) => {
+                        // 幅が変わると折り返し位置が変わるため、すぐに描き直してカーソル位置を合わせる
+                        redraw(stdout, &mut rendered_rows, &buffer, &mut anchor_row, &mut cursor_row_offset, &mut rendered)?;
                     }
                     _ => {}
                 }
                 stdout.flush()?;
+            } else if debounce.take_pending(Instant::now()) {
+                // 入力が途切れたら保留していた再描画を行う
+                redraw(stdout, &mut rendered_rows, &buffer, &mut anchor_row, &mut cursor_row_offset, &mut rendered)?;
+                stdout.flush()?;
             }
         }
     }
 
     fn print_banner(&self) {
         let mut stdout = stdout();
+        if self.show_banner {
+            Self::print_banner_art(&mut stdout);
+        }
+        execute!(
+            stdout,
+            SetForegroundColor(Color::DarkGrey),
+            Print("Rockchip NPU Agentic CLI\n\n"),
+            ResetColor
+        )
+        .ok();
+    }
+
+    /// RKLLM のアスキーアートを表示する
+    fn print_banner_art(stdout: &mut std::io::Stdout) {
         let lines = [
             [
                 ("██████ ", Color::Red),    // R
@@ -781,22 +2038,18 @@ impl ChatSession {
             }
             execute!(stdout, ResetColor, Print("\n")).ok();
         }
-        execute!(
-            stdout,
-            SetForegroundColor(Color::DarkGrey),
-            Print("Rockchip NPU Agentic CLI\n\n"),
-            ResetColor
-        )
-        .ok();
     }
 
     fn print_status_line(&self, stdout: &mut std::io::Stdout, status: &str) -> Result<()> {
         let mcp = if self.mcp_client.is_some() { "on" } else { "off" };
         let mode = if self.tool_only { "tool-only" } else { "chat" };
-        let line = format!(
-            "[Dir: {} | Status: {} | MCP: {} | Mode: {}]",
-            self.execution_dir, status, mcp, mode
+        let mut line = format!(
+            "[Model: {} | Dir: {} | Status: {} | MCP: {} | Mode: {}]",
+            self.model_name, self.execution_dir, status, mcp, mode
         );
+        if let Some(output_dir) = &self.output_dir {
+            line.push_str(&format!(" [Output dir: {}]", output_dir));
+        }
         execute!(
             stdout,
             ResetColor,
@@ -810,12 +2063,35 @@ impl ChatSession {
         Ok(())
     }
 
-    fn show_tools_command(&self, stdout: &mut std::io::Stdout) -> Result<()> {
+    /// プロンプトのトークン使用量を `[Context: 1234 / 4096 tokens (30%)]` の形で表示する
+    ///
+    /// 80% を超えたら黄色、95% を超えたら赤で表示する。
+    fn print_context_summary(
+        stdout: &mut std::io::Stdout,
+        tokens_used: usize,
+        tokens_max: usize,
+    ) -> Result<()> {
+        let percent = tokens_used * 100 / tokens_max.max(1);
+        execute!(
+            stdout,
+            SetForegroundColor(context_usage_color(percent)),
+            Print(format!(
+                "[Context: {} / {} tokens ({}%)]",
+                tokens_used, tokens_max, percent
+            )),
+            ResetColor,
+            Print("\r\n")
+        )?;
+        Ok(())
+    }
+
+    async fn show_tools_command(&self, stdout: &mut std::io::Stdout) -> Result<()> {
         execute!(stdout, Print("\r\n"))?;
         let Some(mcp_client) = &self.mcp_client else {
             execute!(stdout, Print("[No MCP client configured]\r\n"))?;
             return Ok(());
         };
+        let mcp_client = mcp_client.read().await;
         let tools = mcp_client.list_all_tools();
         if tools.is_empty() {
             execute!(stdout, Print("[No tools available]\r\n"))?;
@@ -843,6 +2119,239 @@ impl ChatSession {
         Ok(())
     }
 
+    /// 入力待ちや推論の間に MCP サーバから届いたログ（--mcp-log-level）を表示する
+    ///
+    /// TUI の表示を崩さないよう、受信時には表示せずここでまとめて出す。
+    async fn print_mcp_log_messages(&self, stdout: &mut std::io::Stdout) -> Result<()> {
+        let Some(mcp_client) = &self.mcp_client else {
+            return Ok(());
+        };
+        for line in mcp_client.read().await.take_log_messages() {
+            execute!(
+                stdout,
+                SetForegroundColor(Color::DarkGrey),
+                Print(format!("{}\r\n", line)),
+                ResetColor
+            )?;
+        }
+        Ok(())
+    }
+
+    /// `notifications/tools/list_changed` を送ってきた MCP サーバのツール一覧を取り直す
+    async fn refresh_changed_mcp_tools(&self, stdout: &mut std::io::Stdout) -> Result<()> {
+        let Some(mcp_client) = &self.mcp_client else {
+            return Ok(());
+        };
+        let updated = mcp_client.write().await.handle_notifications().await;
+        for name in updated {
+            execute!(
+                stdout,
+                Print(format!("[MCP: Tool list updated for '{}']\r\n", name))
+            )?;
+        }
+        Ok(())
+    }
+
+    /// 接続中の MCP サーバの生存状況を表示する
+    async fn show_status_command(&self, stdout: &mut std::io::Stdout) -> Result<()> {
+        execute!(stdout, Print("\r\n"))?;
+        let Some(mcp_client) = &self.mcp_client else {
+            execute!(stdout, Print("[No MCP client configured]\r\n"))?;
+            return Ok(());
+        };
+        let mcp_client = mcp_client.read().await;
+        let names = mcp_client.server_names();
+        if names.is_empty() {
+            execute!(stdout, Print("[No MCP servers connected]\r\n"))?;
+            return Ok(());
+        }
+
+        execute!(
+            stdout,
+            SetForegroundColor(Color::Cyan),
+            Print("MCP Servers:\r\n"),
+            ResetColor
+        )?;
+        let name_width = names.iter().map(|name| UnicodeWidthStr::width(*name)).max().unwrap_or(0);
+        for name in names {
+            let padding = " ".repeat(name_width - UnicodeWidthStr::width(name));
+            let (mark, label, color) = if mcp_client.is_server_alive(name).await {
+                ("✓", "alive", Color::Green)
+            } else {
+                ("✗", "dead", Color::Red)
+            };
+            execute!(
+                stdout,
+                Print(format!("  {}{}   ", name, padding)),
+                SetForegroundColor(color),
+                Print(format!("{} {}\r\n", mark, label)),
+                ResetColor
+            )?;
+        }
+        execute!(stdout, Print("\r\n"))?;
+        Ok(())
+    }
+
+    /// `/prompt <name> [key=value ...]` を MCP サーバーのプロンプトテンプレートで置き換える
+    ///
+    /// # 戻り値
+    /// * `Some(text)` - 次のユーザー入力として扱うテキスト（/prompt 以外はそのまま）
+    /// * `None` - プロンプトを展開できなかった（メッセージ表示済み）
+    async fn expand_prompt_command(
+        &self,
+        stdout: &mut std::io::Stdout,
+        input: String,
+    ) -> Result<Option<String>> {
+        let Some(rest) = input
+            .trim()
+            .strip_prefix("/prompt")
+            .filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+        else {
+            return Ok(Some(input));
+        };
+
+        execute!(stdout, Print("\r\n"))?;
+        let Some(mcp_client) = &self.mcp_client else {
+            execute!(stdout, Print("[No MCP client configured]\r\n"))?;
+            return Ok(None);
+        };
+        let mcp_client = mcp_client.read().await;
+
+        let mut parts = rest.split_whitespace();
+        let Some(name) = parts.next() else {
+            let prompts = mcp_client.list_all_prompts();
+            if prompts.is_empty() {
+                execute!(stdout, Print("[No MCP prompts available]\r\n"))?;
+            } else {
+                execute!(
+                    stdout,
+                    SetForegroundColor(Color::Cyan),
+                    Print("MCP Prompts:\r\n"),
+                    ResetColor
+                )?;
+                for (server, prompt) in prompts {
+                    let description = prompt.description.as_deref().unwrap_or("(no description)");
+                    execute!(
+                        stdout,
+                        Print(format!("  {}::{} - {}\r\n", server, prompt.name, description))
+                    )?;
+                }
+            }
+            return Ok(None);
+        };
+
+        let mut arguments = HashMap::new();
+        for part in parts {
+            let Some((key, value)) = part.split_once('=') else {
+                execute!(
+                    stdout,
+                    SetForegroundColor(Color::Red),
+                    Print(format!("[Invalid prompt argument '{}': expected key=value]\r\n", part)),
+                    ResetColor
+                )?;
+                return Ok(None);
+            };
+            arguments.insert(key.to_string(), value.to_string());
+        }
+
+        match mcp_client.get_prompt(name, arguments).await {
+            Ok(result) => {
+                let text = result.to_text();
+                if text.trim().is_empty() {
+                    execute!(stdout, Print(format!("[Prompt '{}' returned no text]\r\n", name)))?;
+                    return Ok(None);
+                }
+                execute!(
+                    stdout,
+                    SetForegroundColor(Color::DarkGrey),
+                    Print(format!("[Prompt: {}]\r\n", name)),
+                    Print(text.replace('\n', "\r\n")),
+                    Print("\r\n"),
+                    ResetColor
+                )?;
+                Ok(Some(text))
+            }
+            Err(e) => {
+                execute!(
+                    stdout,
+                    SetForegroundColor(Color::Red),
+                    Print(format!("[Failed to get prompt: {:#}]\r\n", e)),
+                    ResetColor
+                )?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// このセッションで書き込んだファイルを表形式で表示する（新規は黄、更新は水色）
+    fn list_output_files(&self, stdout: &mut std::io::Stdout) -> Result<()> {
+        execute!(stdout, Print("\r\n"))?;
+        let files = self
+            .written_files
+            .lock()
+            .map(|files| files.clone())
+            .unwrap_or_default();
+        if files.is_empty() {
+            execute!(stdout, Print("[No files written in this session]\r\n"))?;
+            return Ok(());
+        }
+
+        execute!(
+            stdout,
+            SetForegroundColor(Color::Cyan),
+            Print("Files written in this session:\r\n"),
+            ResetColor
+        )?;
+        let path_width = files
+            .iter()
+            .map(|file| UnicodeWidthStr::width(file.path.as_str()))
+            .max()
+            .unwrap_or(0);
+        for file in &files {
+            let padding = " ".repeat(path_width - UnicodeWidthStr::width(file.path.as_str()));
+            let color = match file.operation {
+                WriteKind::Created => Color::Yellow,
+                WriteKind::Updated | WriteKind::Patched | WriteKind::Appended => Color::Cyan,
+            };
+            execute!(
+                stdout,
+                Print(format!(
+                    "  {}{}  {:>8} B  {}  ",
+                    file.path,
+                    padding,
+                    file.size,
+                    file.timestamp.format("%H:%M:%S")
+                )),
+                SetForegroundColor(color),
+                Print(format!("{}\r\n", file.operation.label())),
+                ResetColor
+            )?;
+        }
+        execute!(stdout, Print("\r\n"))?;
+        Ok(())
+    }
+
+    /// 書き込みに成功したファイルをセッションの一覧に追加し、外部ツールへ通知する
+    ///
+    /// `--auto-format` が有効でフォーマッタのある拡張子なら、先に整形してから記録する。
+    fn record_written_file(&self, path: &str, size: usize, operation: WriteKind) {
+        let size = if self.auto_format && formatters::formatter_for(Path::new(path)).is_some() {
+            self.status(format_result_message(path, formatters::format_file(path)));
+            std::fs::metadata(path).map_or(size, |meta| meta.len() as usize)
+        } else {
+            size
+        };
+        events::emit_file_write(path, size);
+        if let Ok(mut files) = self.written_files.lock() {
+            files.push(WrittenFile {
+                path: path.to_string(),
+                size: size as u64,
+                timestamp: chrono::Local::now(),
+                operation,
+            });
+        }
+    }
+
     fn show_help_command(&self, stdout: &mut std::io::Stdout) -> Result<()> {
         execute!(stdout, Print("\r\n"))?;
         execute!(
@@ -853,26 +2362,119 @@ impl ChatSession {
         )?;
         execute!(stdout, Print("  /help   - Show this help message\r\n"))?;
         execute!(stdout, Print("  /tools  - List available MCP tools\r\n"))?;
+        execute!(stdout, Print("  /status - Show whether MCP servers are alive\r\n"))?;
+        execute!(stdout, Print("  /files  - List files written in this session\r\n"))?;
+        execute!(stdout, Print("  /model  - Show the model name and file path\r\n"))?;
+        execute!(stdout, Print("  /tokens - Show the token breakdown of the last prompt\r\n"))?;
+        execute!(stdout, Print("  /format <path> - Format a file with rustfmt, black or prettier\r\n"))?;
+        execute!(stdout, Print("  /watch <glob> - Re-run the last query when matching files change ('/watch stop' ends it)\r\n"))?;
+        execute!(stdout, Print("  /prompt <name> [key=value ...] - Send an MCP prompt template\r\n"))?;
         execute!(stdout, Print("  /quit   - Exit the application (also '/exit')\r\n"))?;
+        execute!(stdout, Print("  continue - Generate more after a response cut off at the token limit\r\n"))?;
         execute!(stdout, Print("\r\n"))?;
         Ok(())
     }
 
-    fn print_separator(&self, color: Color) {
+    /// `/watch <glob>` で監視を開始し、`/watch stop` で止める（引数なしなら状態を表示）
+    fn watch_command(&self, stdout: &mut std::io::Stdout, pattern: &str) -> Result<()> {
+        let Ok(mut watch) = self.watch.lock() else {
+            return Ok(());
+        };
+        let message = if pattern.is_empty() {
+            match watch.as_ref() {
+                Some(current) => format!("[Watch: watching {}]", current.pattern()),
+                None => "[Watch: not watching any files]".to_string(),
+            }
+        } else if pattern.eq_ignore_ascii_case("stop") {
+            match watch.take() {
+                Some(stopped) => format!("[Watch: stopped watching {}]", stopped.pattern()),
+                None => "[Watch: not watching any files]".to_string(),
+            }
+        } else {
+            match FileWatch::start(pattern, Path::new(&self.execution_dir)) {
+                Ok(started) => {
+                    *watch = Some(started);
+                    format!("[Watch: watching {}]", pattern)
+                }
+                Err(e) => format!("[Watch: {:#}]", e),
+            }
+        };
+        execute!(stdout, Print(format!("\r\n{}\r\n", message)))?;
+        Ok(())
+    }
+
+    /// `/format <path>` でファイルを言語ごとのフォーマッタで整形する
+    fn format_command(&self, stdout: &mut std::io::Stdout, path: &str) -> Result<()> {
+        let message = if path.is_empty() {
+            "[Usage: /format <path>]".to_string()
+        } else if !file_ops::file_exists(path) {
+            format!("[Format warning: {}: file not found]", path)
+        } else {
+            format_result_message(path, formatters::format_file(path))
+        };
+        execute!(stdout, Print(format!("\r\n{}\r\n", message)))?;
+        Ok(())
+    }
+
+    /// 監視中のファイルに変更があれば、そのパスを取り出す
+    fn take_watch_change(&self) -> Option<PathBuf> {
+        self.watch
+            .lock()
+            .ok()
+            .and_then(|watch| watch.as_ref().and_then(FileWatch::take_change))
+    }
+
+    /// 作業ディレクトリ内のパスは相対パスで表示する
+    fn display_path(&self, path: &Path) -> String {
+        path.strip_prefix(&self.execution_dir)
+            .unwrap_or(path)
+            .display()
+            .to_string()
+    }
+
+    /// unified diff を色付きで表示する（追加は緑、削除は赤、それ以外は灰色）
+    fn print_diff(&self, diff: &str) {
+        if self.headless.load(Ordering::SeqCst) {
+            return;
+        }
+        for line in diff.lines() {
+            let color = if line.starts_with("+++") || line.starts_with("---") {
+                Color::DarkGrey
+            } else if line.starts_with('+') {
+                Color::Green
+            } else if line.starts_with('-') {
+                Color::Red
+            } else if line.starts_with("@@") {
+                Color::Cyan
+            } else {
+                Color::DarkGrey
+            };
+            print!("{}{}{}\r\n", SetForegroundColor(color), line, ResetColor);
+        }
+    }
+
+    /// 区切り線を表示する。`elapsed` があれば右端に推論時間（例: ` 3.2s `）を添える
+    fn print_separator(&self, color: Color, elapsed: Option<Duration>, tool_time: Option<Duration>) {
         let width = if let Ok((cols, _)) = terminal::size() {
             cols as usize
         } else {
             80
         };
+        let label = match (elapsed, tool_time.filter(|t| !t.is_zero())) {
+            (Some(d), Some(t)) => format!(" {:.1}s (tools {:.1}s) ", d.as_secs_f64(), t.as_secs_f64()),
+            (Some(d), None) => format!(" {:.1}s ", d.as_secs_f64()),
+            (None, _) => String::new(),
+        };
         print!("{}", SetForegroundColor(color));
-        print!("{}", "─".repeat(width));
+        print!("{}{}", "─".repeat(width.saturating_sub(label.width())), label);
         print!("{}", ResetColor);
         print!("\r\n");
     }
 
     fn strip_tool_calls(text: &str) -> String {
         // Remove <tool_call ...>...</tool_call> blocks from display output.
-        let pattern = Regex::new(r#"(?s)<tool_call\s+name="[^"]+"\s*>.*?</tool_call>"#)
+        // Tag case and spaces around `=` vary between models.
+        let pattern = Regex::new(r#"(?si)<tool_call\s+name\s*=\s*"[^"]+"\s*>.*?</tool_call>"#)
             .unwrap();
         pattern.replace_all(text, "").to_string()
     }
@@ -884,6 +2486,46 @@ impl ChatSession {
         format!("<tool_call name=\"{}\">\n{}\n</tool_call>\n", tool.name, pretty)
     }
 
+    /// ツールの引数一覧（必須/任意・型・説明）を1行ずつ組み立てる
+    ///
+    /// 例: `  path: string (required) - File path to read`
+    fn build_tool_schema_block(tool: &Tool) -> String {
+        let Some(properties) = &tool.input_schema.properties else {
+            return String::new();
+        };
+        let required: HashSet<&str> = tool
+            .input_schema
+            .required
+            .as_ref()
+            .map(|keys| keys.iter().map(|k| k.as_str()).collect())
+            .unwrap_or_default();
+
+        // 必須 → 任意の順、それぞれ名前順
+        let mut keys: Vec<&String> = properties.keys().collect();
+        keys.sort_by_key(|key| (!required.contains(key.as_str()), key.as_str()));
+
+        let mut block = String::new();
+        for key in keys {
+            let schema = &properties[key];
+            let type_name = schema
+                .get("type")
+                .and_then(|v| v.as_str())
+                .unwrap_or("any");
+            let requirement = if required.contains(key.as_str()) {
+                "required"
+            } else {
+                "optional"
+            };
+            block.push_str(&format!("  {}: {} ({})", key, type_name, requirement));
+            if let Some(desc) = schema.get("description").and_then(|v| v.as_str()) {
+                block.push_str(&format!(" - {}", desc));
+            }
+            block.push('\n');
+        }
+
+        block
+    }
+
     fn build_sample_arguments(tool: &Tool) -> serde_json::Value {
         let mut map = serde_json::Map::new();
         let mut added = false;
@@ -947,23 +2589,81 @@ impl ChatSession {
         }
     }
 
-    fn build_tool_info(&self) -> Option<String> {
+    async fn build_tool_info(&self) -> Option<String> {
         let Some(mcp_client) = &self.mcp_client else {
             return None;
         };
+        let mcp_client = mcp_client.read().await;
         let tools = mcp_client.list_all_tools();
+        let supported: HashMap<&str, Vec<&str>> = tools
+            .iter()
+            .filter_map(|(server, _)| {
+                let capabilities = mcp_client.server_capabilities(server)?;
+                Some((*server, capabilities.supported()))
+            })
+            .collect();
+        let usage = self
+            .tool_usage
+            .lock()
+            .map(|usage| usage.clone())
+            .unwrap_or_default();
+        Self::format_tool_info(tools, &supported, &usage)
+    }
+
+    /// ツール一覧をプロンプト用の説明文にする
+    ///
+    /// 同じツール構成なら常に同じプロンプトになるよう、(サーバー名, ツール名) 順に並べる。
+    /// サーバーごとのブロックの先頭に `[supports: tools, resources]` のように対応機能を添える。
+    /// 失敗したことのあるツールには呼び出し回数と失敗回数を添え、
+    /// セッション中に呼び出したツールを `## Tool Usage This Session` にまとめる。
+    ///
+    /// # 引数
+    /// * `tools` - (サーバー名, ツール) の一覧
+    /// * `supported` - サーバー名ごとの対応機能名
+    /// * `usage` - ツール名ごとの (呼び出し回数, 失敗回数)
+    ///
+    /// # 戻り値
+    /// ツールがなければ None
+    fn format_tool_info(
+        mut tools: Vec<(&str, &Tool)>,
+        supported: &HashMap<&str, Vec<&str>>,
+        usage: &HashMap<String, (usize, usize)>,
+    ) -> Option<String> {
         if tools.is_empty() {
             return None;
         }
+        sort_tools(&mut tools);
 
         let mut info = String::from("\n## Available Tools\n\n");
         info.push_str("Available tools (short list):\n\n");
 
-        for (_server_name, tool) in &tools {
-            info.push_str(&format!("### {}\n", tool.name));
+        let mut current_server = None;
+        for (server_name, tool) in &tools {
+            if current_server != Some(*server_name) {
+                current_server = Some(*server_name);
+                info.push_str(&format!("Server: {}", server_name));
+                if let Some(features) = supported.get(server_name)
+                    && !features.is_empty()
+                {
+                    info.push_str(&format!(" [supports: {}]", features.join(", ")));
+                }
+                info.push_str("\n\n");
+            }
+            match usage.get(&tool.name) {
+                Some(&(calls, errors)) if errors > 0 => info.push_str(&format!(
+                    "### {} ({} calls, {} errors - may be unreliable)\n",
+                    tool.name, calls, errors
+                )),
+                _ => info.push_str(&format!("### {}\n", tool.name)),
+            }
             if let Some(desc) = &tool.description {
                 info.push_str(&format!("{}\n", desc));
             }
+            let schema_block = ChatSession::build_tool_schema_block(tool);
+            if !schema_block.is_empty() {
+                info.push_str("\nParameters:\n");
+                info.push_str(&schema_block);
+            }
             let sample_block = ChatSession::build_tool_sample_block(tool);
             info.push_str("\nSample:\n");
             info.push_str(&sample_block);
@@ -976,6 +2676,16 @@ impl ChatSession {
         info.push_str("}\n");
         info.push_str("</tool_call>\n");
 
+        if !usage.is_empty() {
+            let mut names: Vec<&String> = usage.keys().collect();
+            names.sort();
+            info.push_str("\n## Tool Usage This Session\n\n");
+            for name in names {
+                let (calls, errors) = usage[name];
+                info.push_str(&format!("- {}: {} calls, {} errors\n", name, calls, errors));
+            }
+        }
+
         Some(info)
     }
 
@@ -987,41 +2697,98 @@ impl ChatSession {
     /// # 戻り値
     /// ユーザーが'y'を入力した場合はtrue、それ以外はfalse
     fn confirm_overwrite(&self, path: &str) -> Result<bool> {
-        self.prompt_confirm(&format!(
-            "\n[File '{}' already exists. Overwrite? (y/N): ",
+        self.prompt_confirm_always(
+            path,
+            &format!(
+                "\n[File '{}' already exists. Overwrite? (y/N/a=always): ",
+                path
+            ),
+        )
+    }
+
+    /// 絶対パスへの書き込みの確認（`confirm_writes` に関わらず常に求める）
+    ///
+    /// # 戻り値
+    /// 相対パス、またはユーザーが'y'を入力した場合は true。
+    /// 確認できない場合（標準入力が端末でない、または `run_once` の実行中）は false（書き込まない）
+    fn confirm_absolute_path_write(&self, path: &str) -> Result<bool> {
+        if !file_output_parser::is_absolute_write_path(path) {
+            return Ok(true);
+        }
+        if !self.can_prompt() {
+            self.status(format!("[Skipped absolute path write (non-TTY): {}]", path));
+            return Ok(false);
+        }
+        let confirmed = self.prompt_confirm(&format!(
+            "\n[WARNING: Absolute path write: {}. This may affect system files. Confirm? (y/N)] ",
             path
-        ))
+        ))?;
+        if !confirmed {
+            self.status(format!("[Skipped absolute path write: {}]", path));
+        }
+        Ok(confirmed)
     }
 
     /// 書き込み確認（--confirm-writes 用）
     fn confirm_write(&self, path: &str, exists: bool) -> Result<bool> {
+        // パイプ入力や `run_once` では確認に答えられないため、環境変数で決める
+        if !self.can_prompt() {
+            let confirmed = std::env::var("RKLLM_PIPE_AUTO_CONFIRM").is_ok_and(|v| v == "1");
+            if confirmed {
+                self.status(format!("[Auto-confirmed write: {}]", path));
+            } else {
+                self.status(format!("[Skipped write (non-TTY): {}]", path));
+            }
+            return Ok(confirmed);
+        }
+
         let prefix = if exists {
             "[File exists]"
         } else {
             "[Write]"
         };
-        self.prompt_confirm(&format!("\n{} '{}' ? (y/N): ", prefix, path))
+        self.prompt_confirm_always(path, &format!("\n{} '{}' ? (y/N/a=always): ", prefix, path))
     }
 
+    /// y/N の確認（`run_once` の実行中は尋ねずに拒否する）
     fn prompt_confirm(&self, message: &str) -> Result<bool> {
-        print!("{}", message);
-        io::stdout().flush()?;
-
-        // 一時的にraw modeを無効化
-        let was_raw_mode = terminal::is_raw_mode_enabled()?;
-        if was_raw_mode {
-            terminal::disable_raw_mode()?;
+        if self.headless.load(Ordering::SeqCst) {
+            return Ok(false);
         }
+        Ok(prompt_line(message)?.trim().eq_ignore_ascii_case("y"))
+    }
 
-        let mut input = String::new();
-        io::stdin().read_line(&mut input)?;
+    /// このセッションで 'a'（always）と答えた対象かどうか
+    fn is_always_confirmed(&self, key: &str) -> bool {
+        self.always_confirmed
+            .lock()
+            .is_ok_and(|confirmed| confirmed.contains(key))
+    }
 
-        // raw modeを元に戻す
-        if was_raw_mode {
-            terminal::enable_raw_mode()?;
+    /// 'a'（always）を受け付ける確認
+    ///
+    /// 'a' と答えた `key` はセッション中は確認せずに承認する。
+    /// `run_once` の実行中は承認済みでなければ尋ねずに拒否する。
+    ///
+    /// # 戻り値
+    /// 'y' / 'a' を入力した場合、または `key` が承認済みの場合は true
+    fn prompt_confirm_always(&self, key: &str, message: &str) -> Result<bool> {
+        if self.is_always_confirmed(key) {
+            return Ok(true);
+        }
+        if self.headless.load(Ordering::SeqCst) {
+            return Ok(false);
+        }
+        match ConfirmAnswer::parse(&prompt_line(message)?) {
+            ConfirmAnswer::Yes => Ok(true),
+            ConfirmAnswer::Always => {
+                if let Ok(mut confirmed) = self.always_confirmed.lock() {
+                    confirmed.insert(key.to_string());
+                }
+                Ok(true)
+            }
+            ConfirmAnswer::No => Ok(false),
         }
-
-        Ok(input.trim().eq_ignore_ascii_case("y"))
     }
 
     /// tool-only モード時にファイル操作を MCP ツールに委譲する
@@ -1035,16 +2802,17 @@ impl ChatSession {
             eprintln!("[tool-only] MCP client is not available.");
             return Ok(());
         };
+        let mcp_client = mcp_client.read().await;
 
         let mut operations = file_output_parser::parse_file_operations(output);
         if operations.is_empty() {
             return Ok(());
         }
 
-        println!(
+        self.status(format!(
             "\n[Detected {} file operation(s) (tool-only)]",
             operations.len()
-        );
+        ));
 
         // 入力と同一内容はスキップ
         operations = operations
@@ -1055,10 +2823,10 @@ impl ChatSession {
                         .iter()
                         .find(|(_, content)| contents_equal(content, &op.content))
                 {
-                    println!(
+                    self.status(format!(
                         "[Skipped unchanged (matches input {}): {}]",
                         input_path, op.path
-                    );
+                    ));
                     return false;
                 }
                 true
@@ -1066,7 +2834,7 @@ impl ChatSession {
             .collect();
 
         if operations.is_empty() {
-            println!("[No file operations after filtering unchanged content]");
+            self.status("[No file operations after filtering unchanged content]");
             return Ok(());
         }
 
@@ -1078,30 +2846,82 @@ impl ChatSession {
                 .all(|op| provided_files.contains_key(&op.path));
             if all_input_paths {
                 for op in operations.iter_mut() {
-                    println!("[Remap {} -> {}]", op.path, target);
+                    self.status(format!("[Remap {} -> {}]", op.path, target));
                     op.path = target.clone();
                 }
             }
         }
+        let operations = self.resolve_output_paths(operations)?;
 
         let tools = mcp_client.list_all_tools();
-        let Some(write_tool_name) = Self::select_write_tool_name(&tools) else {
+        let candidates = Self::select_write_tool_name(&tools);
+        let Some(write_tool_name) = self.choose_write_tool(&candidates)? else {
             eprintln!("[tool-only] No suitable MCP write tool found. Skipping file outputs.");
             return Ok(());
         };
+        let (write_server, write_tool) = write_tool_name
+            .split_once("::")
+            .unwrap_or(("", write_tool_name.as_str()));
+        let temp_staging = mcp_client.uses_temp_staging(write_server);
 
         for op in operations {
-            let args = json!({
-                "path": op.path,
-                "content": op.content,
-            });
+            // MCP の書き込みツールはテキストのみを受け付けるため、バイナリ出力は扱わない
+            if op.encoding == FileOperationEncoding::Base64 {
+                eprintln!("[tool-only] Skipped binary (base64) output: {}", op.path);
+                continue;
+            }
+            if op.operation_type == FileOperationType::Patch {
+                eprintln!("[tool-only] Skipped patch output: {}", op.path);
+                continue;
+            }
+
+            if self.dry_run_writes {
+                self.status(format!(
+                    "[Dry run] Would write via tool '{}': {} ({} bytes)",
+                    write_tool_name,
+                    op.path,
+                    op.content.len()
+                ));
+                continue;
+            }
+
+            // 大きな出力は一時ファイルに書き出し、パスだけを渡す（呼び出しが終わるまで保持する）
+            let staged = if temp_staging && op.content.len() > TEMP_STAGING_THRESHOLD {
+                let ext = Path::new(&op.path)
+                    .extension()
+                    .map(|ext| format!(".{}", ext.to_string_lossy()))
+                    .unwrap_or_default();
+                match file_ops::create_temp_file(&op.content, &ext) {
+                    Ok(staged) => Some(staged),
+                    Err(e) => {
+                        eprintln!("[tool-only] Failed to stage {}: {}", op.path, e);
+                        None
+                    }
+                }
+            } else {
+                None
+            };
+            let existed = Path::new(&op.path).exists();
+            let args = write_tool_args(
+                &op.path,
+                &op.content,
+                staged.as_ref().map(|(_, path)| path.as_str()),
+            );
 
-            match mcp_client.call_tool(&write_tool_name, args).await {
+            match mcp_client
+                .call_tool_on_server(write_server, write_tool, args)
+                .await
+            {
                 Ok(result) => {
                     if result.success {
-                        println!(
+                        self.status(format!(
                             "[tool-only] Wrote via tool '{}': {}",
                             write_tool_name, op.path
+                        ));
+                        self.record_written_file(
+                            &op.path,
+                            op.content.len(),
+                            WriteKind::for_tool(write_tool, existed),
                         );
                     } else {
                         eprintln!(
@@ -1122,6 +2942,32 @@ impl ChatSession {
         Ok(())
     }
 
+    /// 書き込み先のパスを確定する
+    ///
+    /// 絶対パスかどうかはモデルが書いたパスで判定し、書き込む前に確認する（拒否されたものは除く）。
+    /// `--output-dir` が指定されていれば相対パスをその下に置き、`..` で外に出るものは除く。
+    /// ドライランでは書き込まないため確認しない。
+    fn resolve_output_paths(&self, operations: Vec<FileOperation>) -> Result<Vec<FileOperation>> {
+        let mut resolved = Vec::with_capacity(operations.len());
+        for mut op in operations {
+            if op.is_absolute_path() {
+                if !self.dry_run_writes && !self.confirm_absolute_path_write(&op.path)? {
+                    continue;
+                }
+            } else if let Some(output_dir) = &self.output_dir {
+                match join_output_dir(output_dir, &op.path) {
+                    Some(path) => op.path = path,
+                    None => {
+                        eprintln!("[Skipped output outside --output-dir: {}]", op.path);
+                        continue;
+                    }
+                }
+            }
+            resolved.push(op);
+        }
+        Ok(resolved)
+    }
+
     /// LLMの応答からファイル操作を処理する
     ///
     /// # 引数
@@ -1134,13 +2980,20 @@ impl ChatSession {
         provided_files: &std::collections::HashMap<String, String>,
         output_targets: &[String],
     ) -> Result<()> {
-        let mut operations = file_output_parser::parse_file_operations(output);
+        let operations = file_output_parser::parse_file_operations(output);
 
         if operations.is_empty() {
             return Ok(());
         }
 
-        println!("\n[Detected {} file operation(s)]", operations.len());
+        self.status(format!("\n[Detected {} file operation(s)]", operations.len()));
+
+        // パッチは既存ファイルへの差分なので、内容比較やリマップの対象外
+        let (patch_operations, mut operations): (Vec<_>, Vec<_>) = operations
+            .into_iter()
+            .partition(|op| op.operation_type == FileOperationType::Patch);
+        let patch_operations = self.resolve_output_paths(patch_operations)?;
+        self.process_patch_operations(&patch_operations)?;
 
         // 入力と同一内容はスキップ
         operations = operations
@@ -1151,10 +3004,10 @@ impl ChatSession {
                         .iter()
                         .find(|(_, content)| contents_equal(content, &op.content))
                 {
-                    println!(
+                    self.status(format!(
                         "[Skipped unchanged (matches input {}): {}]",
                         input_path, op.path
-                    );
+                    ));
                     return false;
                 }
                 true
@@ -1162,7 +3015,7 @@ impl ChatSession {
             .collect();
 
         if operations.is_empty() {
-            println!("[No file operations after filtering unchanged content]");
+            self.status("[No file operations after filtering unchanged content]");
             return Ok(());
         }
 
@@ -1174,37 +3027,116 @@ impl ChatSession {
                 .all(|op| provided_files.contains_key(&op.path));
             if all_input_paths {
                 for op in operations.iter_mut() {
-                    println!("[Remap {} -> {}]", op.path, target);
+                    self.status(format!("[Remap {} -> {}]", op.path, target));
                     op.path = target.clone();
                 }
             }
         }
 
+        let operations = self.resolve_output_paths(operations)?;
+
+        // パッチは処理済みなので、残りは通常のファイル作成・上書き（バイナリも応答の順に扱う）
         for op in operations {
-            match op.operation_type {
-                file_output_parser::FileOperationType::Create => {
-                    let exists = file_ops::file_exists(&op.path);
-
-                    // 書き込み前の確認
-                    if self.confirm_writes {
-                        if !self.confirm_write(&op.path, exists)? {
-                            println!("[Skipped by confirm: {}]", op.path);
-                            continue;
-                        }
-                    } else if exists && !self.confirm_overwrite(&op.path)? {
-                        println!("[Skipped: {}]", op.path);
+            // モデルは base64 を折り返して出力することがあるため空白を除去してからデコード
+            let binary = if op.encoding == FileOperationEncoding::Base64 {
+                let encoded: String = op.content.split_whitespace().collect();
+                match BASE64_STANDARD.decode(encoded) {
+                    Ok(bytes) => Some(bytes),
+                    Err(e) => {
+                        eprintln!("[Error decoding base64 for '{}': {}]", op.path, e);
                         continue;
                     }
+                }
+            } else {
+                None
+            };
+            let size = binary.as_ref().map_or(op.content.len(), Vec::len);
 
-                    // ファイルを書き込む
-                    match file_ops::write_file(&op.path, &op.content, false) {
-                        Ok(_) => {
-                            println!("[Created/Updated: {}]", op.path);
-                        }
-                        Err(e) => {
-                            eprintln!("[Error writing '{}': {}]", op.path, e);
-                        }
-                    }
+            if self.dry_run_writes {
+                self.status(format!("[Dry run] Would write: {} ({} bytes)", op.path, size));
+                continue;
+            }
+
+            let exists = file_ops::file_exists(&op.path);
+
+            // 書き込み前の確認（差分プレビュー時は差分を見てから確認する。バイナリはサイズとハッシュを見せる）
+            if self.config.preview_diff {
+                let preview = match &binary {
+                    Some(bytes) => file_ops::summarize_binary_change(&op.path, bytes)
+                        .map(|summary| self.status(format!("[Binary {}: {}]", op.path, summary))),
+                    None => file_ops::diff_file(&op.path, &op.content)
+                        .map(|diff| self.print_diff(&diff)),
+                };
+                if let Err(e) = preview {
+                    eprintln!("[Could not diff '{}': {}]", op.path, e);
+                }
+                if !self.prompt_confirm_always(
+                    &op.path,
+                    &format!("\n[Apply changes to '{}'? (y/N/a=always): ", op.path),
+                )? {
+                    self.status(format!("[Skipped after diff: {}]", op.path));
+                    continue;
+                }
+            } else if self.confirm_writes {
+                if !self.confirm_write(&op.path, exists)? {
+                    self.status(format!("[Skipped by confirm: {}]", op.path));
+                    continue;
+                }
+            } else if exists && !self.confirm_overwrite(&op.path)? {
+                self.status(format!("[Skipped: {}]", op.path));
+                continue;
+            }
+
+            // ファイルを書き込む
+            let written = match &binary {
+                Some(bytes) => file_ops::write_file_bytes_with_mode(&op.path, bytes, op.mode)
+                    .map(|_| format!("[Created/Updated: {} ({} bytes)]", op.path, size)),
+                None => file_ops::write_file_with_mode(&op.path, &op.content, op.mode)
+                    .map(|_| format!("[Created/Updated: {}]", op.path)),
+            };
+            match written {
+                Ok(message) => {
+                    self.status(message);
+                    self.record_written_file(&op.path, size, WriteKind::from_existed(exists));
+                }
+                Err(e) => {
+                    eprintln!("[Error writing '{}': {}]", op.path, e);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// unified diff のパッチを既存ファイルに適用する（`--dry-run-writes` 時は表示のみ）
+    ///
+    /// # 引数
+    /// * `operations` - `<patch path="...">` のファイル操作
+    fn process_patch_operations(&self, operations: &[FileOperation]) -> Result<()> {
+        for op in operations {
+            if self.dry_run_writes {
+                self.status(format!("[Dry run] Patch for {}:", op.path));
+                self.status(op.content.trim_matches('\n'));
+                continue;
+            }
+
+            if !file_ops::file_exists(&op.path) {
+                eprintln!("[Error patching '{}': file does not exist]", op.path);
+                continue;
+            }
+            if self.confirm_writes && !self.confirm_write(&op.path, true)? {
+                self.status(format!("[Skipped by confirm: {}]", op.path));
+                continue;
+            }
+
+            match file_ops::apply_patch(&op.path, &op.content) {
+                Ok(_) => {
+                    self.status(format!("[Patched: {}]", op.path));
+                    let size = std::fs::metadata(&op.path).map_or(0, |meta| meta.len() as usize);
+                    self.record_written_file(&op.path, size, WriteKind::Patched);
+                }
+                Err(e) => {
+                    eprintln!("[Error patching '{}': {:#}]", op.path, e);
                 }
             }
         }
@@ -1220,18 +3152,20 @@ impl ChatSession {
         &self,
         output: &str,
         seen_tool_calls: &mut HashSet<String>,
+        tool_retry_count: &mut HashMap<String, usize>,
         allowance: ToolCallAllowance,
-    ) -> Result<(Vec<ToolResult>, bool)> {
+    ) -> Result<ToolRoundOutcome> {
         let tool_calls = self.tool_detector.detect(output);
 
         if tool_calls.is_empty() {
-            return Ok((Vec::new(), false));
+            return Ok(ToolRoundOutcome::default());
         }
 
-        println!("\n[Detected {} tool call(s)]", tool_calls.len());
+        self.status(format!("\n[Detected {} tool call(s)]", tool_calls.len()));
 
         let mut results = Vec::new();
         let mut blocked_repeat = false;
+        let mut gave_up = false;
 
         for call in tool_calls {
             if matches!(allowance, ToolCallAllowance::WriteOnly) && call.name != "write_file" {
@@ -1239,30 +3173,45 @@ impl ChatSession {
                 continue;
             }
 
-            if seen_tool_calls.contains(&call.name) {
+            let call_key = match &call.server {
+                Some(server) => format!("{}::{}", server, call.name),
+                None => call.name.clone(),
+            };
+            if seen_tool_calls.contains(&call_key) {
                 blocked_repeat = true;
                 continue;
             }
-            seen_tool_calls.insert(call.name.clone());
+            seen_tool_calls.insert(call_key.clone());
+            let results_before = results.len();
 
-            match call.name.as_str() {
-                "read_file" => {
+            // server::tool 形式の呼び出しは組み込みツールではなく指定サーバーへ送る
+            match (call.server.as_deref(), call.name.as_str()) {
+                (None, "read_file") => {
                     results.push(self.handle_read_file_tool_call(&call));
                 }
-                "write_file" => {
+                (None, "write_file") => {
                     results.push(self.handle_write_file_tool_call(&call)?);
                 }
-                _ => {
+                (server, _) => {
                     if let Some(client) = &self.mcp_client {
-                        match client.call_tool(&call.name, call.arguments).await {
+                        let client = client.read().await;
+                        let call_result = match server {
+                            Some(server) => {
+                                client
+                                    .call_tool_on_server(server, &call.name, call.arguments)
+                                    .await
+                            }
+                            None => client.call_tool(&call.name, call.arguments).await,
+                        };
+                        match call_result {
                             Ok(mut result) => {
                                 result.name = call.name.clone();
                                 if result.success {
                                     if result.output.trim().is_empty() {
-                                        println!("\n[Tool '{}' success with empty output]", call.name);
+                                        self.status(format!("\n[Tool '{}' success with empty output]", call.name));
                                     } else {
-                                        println!("\n[Tool '{}' output:]", call.name);
-                                        println!("{}", result.output);
+                                        self.status(format!("\n[Tool '{}' output:]", call.name));
+                                        self.status(&result.output);
                                     }
                                 } else {
                                     eprintln!("\n[Tool '{}' failed:]", call.name);
@@ -1289,9 +3238,45 @@ impl ChatSession {
                     }
                 }
             }
+
+            let failed = results.len() > results_before && results.last().is_some_and(|r| !r.success);
+            if results.len() > results_before
+                && let Ok(mut usage) = self.tool_usage.lock()
+            {
+                let entry = usage.entry(call.name.clone()).or_insert((0, 0));
+                entry.0 += 1;
+                if failed {
+                    entry.1 += 1;
+                }
+            }
+            if failed {
+                // 失敗した呼び出しは引数を直した再試行を許す
+                seen_tool_calls.remove(&call_key);
+                if let Some(give_up) = record_tool_failure(tool_retry_count, &call.name) {
+                    eprintln!("\n[Tool '{}' failed twice; not calling it again]", call.name);
+                    results.push(give_up);
+                    gave_up = true;
+                    break;
+                }
+            }
+        }
+
+        // 巨大な出力で次のプロンプトがコンテキストを溢れさせないよう切り詰める
+        for result in &mut results {
+            let original_len = result.output.len();
+            if truncate_tool_output(&mut result.output, self.config.tool_result_max_bytes) {
+                eprintln!(
+                    "\n[Tool '{}' output truncated: {} -> {} bytes]",
+                    result.name, original_len, self.config.tool_result_max_bytes
+                );
+            }
         }
 
-        Ok((results, blocked_repeat))
+        Ok(ToolRoundOutcome {
+            results,
+            blocked_repeat,
+            gave_up,
+        })
     }
 
     fn handle_read_file_tool_call(&self, call: &ToolCall) -> ToolResult {
@@ -1309,6 +3294,35 @@ impl ChatSession {
             );
         };
 
+        // ディレクトリが指定された場合は中身の代わりにツリー形式の一覧を返す
+        if file_ops::is_directory(&path) {
+            let limit = self.config.max_file_depth;
+            let requested = call
+                .arguments
+                .get("max_depth")
+                .and_then(|v| v.as_u64())
+                .map(|v| v as usize);
+            if let Some(requested) = requested.filter(|v| *v > limit) {
+                eprintln!(
+                    "[Directory depth {} exceeds --max-file-depth; listing {} levels]",
+                    requested, limit
+                );
+            }
+            let max_depth = requested.map_or(limit, |v| v.min(limit));
+            return match file_ops::list_directory(&path, max_depth) {
+                Ok(tree) => Self::tool_result_json(
+                    "read_file",
+                    true,
+                    json!({"path": path, "content": format!("[Directory listing: {}]\n{}", path, tree)}),
+                ),
+                Err(e) => Self::tool_result_json(
+                    "read_file",
+                    false,
+                    json!({"path": path, "error": e.to_string()}),
+                ),
+            };
+        }
+
         match file_ops::read_file(&path) {
             Ok(content) => Self::tool_result_json(
                 "read_file",
@@ -1358,6 +3372,14 @@ impl ChatSession {
             ));
         };
 
+        if !self.confirm_absolute_path_write(&path)? {
+            return Ok(Self::tool_result_json(
+                "write_file",
+                false,
+                json!({"path": path, "skipped": true}),
+            ));
+        }
+
         let exists = file_ops::file_exists(&path);
         if self.confirm_writes {
             if !self.confirm_write(&path, exists)? {
@@ -1376,11 +3398,14 @@ impl ChatSession {
         }
 
         match file_ops::write_file(&path, &content, false) {
-            Ok(_) => Ok(Self::tool_result_json(
-                "write_file",
-                true,
-                json!({"path": path, "written": true}),
-            )),
+            Ok(_) => {
+                self.record_written_file(&path, content.len(), WriteKind::from_existed(exists));
+                Ok(Self::tool_result_json(
+                    "write_file",
+                    true,
+                    json!({"path": path, "written": true}),
+                ))
+            }
             Err(e) => Ok(Self::tool_result_json(
                 "write_file",
                 false,
@@ -1395,14 +3420,18 @@ impl ChatSession {
             name: name.to_string(),
             success,
             output,
+            latency_ms: 0,
         }
     }
 
     /// MCPツールから書き込み用ツール名を推定する
-    fn select_write_tool_name(tools: &[(&str, &Tool)]) -> Option<String> {
-        let mut best: Option<(u8, String)> = None;
+    ///
+    /// # 戻り値
+    /// `(ランク, "server::tool")` の候補をランク順（小さいほど優先、同順位は設定順）に並べたもの
+    fn select_write_tool_name(tools: &[(&str, &Tool)]) -> Vec<(u8, String)> {
+        let mut candidates = Vec::new();
 
-        for (_server, tool) in tools {
+        for (server, tool) in tools {
             let name_lower = tool.name.to_lowercase();
             let rank = if name_lower == "write_file" || name_lower == "writefile" {
                 Some(0)
@@ -1418,6 +3447,96 @@ impl ChatSession {
                 None
             };
 
+            if let Some(r) = rank {
+                candidates.push((r, format!("{}::{}", server, tool.name)));
+            }
+        }
+
+        candidates.sort_by_key(|(rank, _)| *rank);
+        candidates
+    }
+
+    /// 書き込みツールの候補から使うツールを決める
+    ///
+    /// 完全一致（ランク0）の候補が複数ある場合はユーザーに選ばせ、選択をセッション中保持する。
+    ///
+    /// # 戻り値
+    /// `"server::tool"` 形式のツール名（候補がなければNone）
+    fn choose_write_tool(&self, candidates: &[(u8, String)]) -> Result<Option<String>> {
+        let Some((best_rank, best_name)) = candidates.first() else {
+            return Ok(None);
+        };
+
+        if let Ok(preferred) = self.preferred_write_tool.lock()
+            && let Some(name) = preferred.as_ref()
+            && candidates.iter().any(|(_, candidate)| candidate == name)
+        {
+            return Ok(Some(name.clone()));
+        }
+
+        let tied: Vec<&String> = candidates
+            .iter()
+            .filter(|(rank, _)| *rank == 0 && rank == best_rank)
+            .map(|(_, name)| name)
+            .collect();
+        if tied.len() < 2 || self.headless.load(Ordering::SeqCst) {
+            return Ok(Some(best_name.clone()));
+        }
+
+        let choices = tied
+            .iter()
+            .enumerate()
+            .map(|(i, name)| format!("{}) {}", i + 1, name))
+            .collect::<Vec<_>>()
+            .join("  ");
+        let numbers = (1..=tied.len())
+            .map(|i| i.to_string())
+            .collect::<Vec<_>>()
+            .join("/");
+        let answer = prompt_line(&format!(
+            "[Multiple write tools found: {}. Choose ({}): ",
+            choices, numbers
+        ))?;
+        let selected = match answer.trim().parse::<usize>() {
+            Ok(n) if (1..=tied.len()).contains(&n) => tied[n - 1].clone(),
+            _ => {
+                self.status(format!("[Invalid choice; using {}]", tied[0]));
+                tied[0].clone()
+            }
+        };
+
+        if let Ok(mut preferred) = self.preferred_write_tool.lock() {
+            *preferred = Some(selected.clone());
+        }
+        Ok(Some(selected))
+    }
+
+    /// MCPツールからシェル実行用ツール名を推定する
+    fn select_shell_tool_name(tools: &[(&str, &Tool)]) -> Option<String> {
+        let mut best: Option<(u8, String)> = None;
+
+        for (_server, tool) in tools {
+            let name_lower = tool.name.to_lowercase();
+            let rank = if matches!(
+                name_lower.as_str(),
+                "run_command" | "execute_command" | "shell" | "bash" | "exec"
+            ) {
+                Some(0)
+            } else if name_lower.contains("shell")
+                || name_lower.contains("command")
+                || name_lower.contains("exec")
+            {
+                Some(1)
+            } else if let Some(props) = &tool.input_schema.properties {
+                if props.contains_key("command") {
+                    Some(2)
+                } else {
+                    None
+                }
+            } else {
+                None
+            };
+
             if let Some(r) = rank {
                 let should_replace = best.as_ref().map(|(current, _)| r < *current).unwrap_or(true);
                 if should_replace {
@@ -1426,101 +3545,368 @@ impl ChatSession {
             }
         }
 
-        best.map(|(_, name)| name)
+        best.map(|(_, name)| name)
+    }
+
+}
+
+/// 応答の前後に付ける文字列を環境変数から読み込む（空文字は未設定と同じ）
+fn response_affix_from_env(var: &str) -> Option<String> {
+    std::env::var(var).ok().filter(|v| !v.is_empty())
+}
+
+/// 最初の空でないチャンクの前に `prefix` を1回だけ渡すコールバックにする（ターンごとに作る）
+fn with_prefix<F>(mut prefix: Option<String>, mut on_chunk: F) -> impl FnMut(&str) + Send + 'static
+where
+    F: FnMut(&str) + Send + 'static,
+{
+    move |text| {
+        if !text.is_empty()
+            && let Some(prefix) = prefix.take()
+        {
+            on_chunk(&prefix);
+        }
+        on_chunk(text);
+    }
+}
+
+/// `RKLLM_MAX_TOOL_ROUNDS` からツール呼び出しラウンド上限を読む
+fn max_tool_rounds_from_env() -> Option<usize> {
+    std::env::var("RKLLM_MAX_TOOL_ROUNDS")
+        .ok()
+        .and_then(|v| v.trim().parse::<usize>().ok())
+}
+
+/// MCP 設定が指定されたのにサーバーへ接続できなかったことを警告し、続行するか確認する
+///
+/// # 戻り値
+/// 続行する場合は true（空入力や非対話環境でも続行する）
+fn confirm_continue_without_mcp() -> Result<bool> {
+    let mut stdout = stdout();
+    execute!(
+        stdout,
+        SetForegroundColor(Color::Yellow),
+        Print("[Warning: MCP config provided but no servers connected. Tool calls will fail.]\n"),
+        ResetColor
+    )?;
+
+    let input = prompt_line("Continue without MCP tools? (Y/n): ")?;
+    Ok(!input.trim().eq_ignore_ascii_case("n"))
+}
+
+/// 取り消せるファイルを表示し、確認できたら直近の書き込み前の状態に戻す
+///
+/// # 戻り値
+/// 書き戻した場合は true。断られた場合は false（入力は通常の質問としてモデルに渡す）
+fn undo_files(files: &[PathBuf], confirm: impl FnOnce(&str) -> Result<bool>) -> Result<bool> {
+    println!("\n[Undo] The following file(s) can be restored to their state before the last write:");
+    for path in files {
+        println!("  - {}", path.display());
+    }
+
+    if !confirm("[Undo] Restore these files? (y/N): ")? {
+        println!("[Undo cancelled]");
+        return Ok(false);
+    }
+    for path in files {
+        match file_ops::restore_backup(path) {
+            Ok(()) => println!("[Restored: {}]", path.display()),
+            Err(e) => eprintln!("[Error restoring {}: {}]", path.display(), e),
+        }
+    }
+    Ok(true)
+}
+
+/// メッセージを表示して1行入力を受け取る
+///
+/// 標準入力が端末でない場合は入力を待たずに空文字列を返す（各確認の既定の答えになる）。
+fn prompt_line(message: &str) -> Result<String> {
+    print!("{}", message);
+    io::stdout().flush()?;
+
+    if !io::stdin().is_terminal() {
+        println!();
+        return Ok(String::new());
+    }
+
+    // 一時的にraw modeを無効化
+    let was_raw_mode = terminal::is_raw_mode_enabled()?;
+    if was_raw_mode {
+        terminal::disable_raw_mode()?;
+    }
+
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+
+    // raw modeを元に戻す
+    if was_raw_mode {
+        terminal::enable_raw_mode()?;
+    }
+
+    Ok(input)
+}
+
+/// 入力欄を再描画する最小間隔（60fps）。速いタイピングでの再描画を間引く
+const REDRAW_DEBOUNCE: Duration = Duration::from_millis(16);
+
+/// 入力欄の再描画の間引き
+///
+/// 前回の再描画から `REDRAW_DEBOUNCE` 経たないうちの再描画は保留し、
+/// 間隔が空いたところでまとめて1回描画する。
+#[derive(Debug, Default)]
+struct RedrawDebounce {
+    last_redraw: Option<Instant>,
+    pending: bool,
+}
+
+impl RedrawDebounce {
+    /// 今すぐ再描画してよいか（false の場合は保留として記録する）
+    fn should_redraw(&mut self, now: Instant) -> bool {
+        let due = self
+            .last_redraw
+            .is_none_or(|last| now.duration_since(last) >= REDRAW_DEBOUNCE);
+        if due {
+            self.last_redraw = Some(now);
+            self.pending = false;
+        } else {
+            self.pending = true;
+        }
+        due
+    }
+
+    /// 入力待ちの最大時間（保留中の再描画があれば、その期限まで）
+    fn poll_timeout(&self, now: Instant, idle: Duration) -> Duration {
+        match self.last_redraw {
+            Some(last) if self.pending => {
+                REDRAW_DEBOUNCE.saturating_sub(now.duration_since(last))
+            }
+            _ => idle,
+        }
+    }
+
+    /// 保留中の再描画を取り出す（ある場合は再描画した扱いにする）
+    fn take_pending(&mut self, now: Instant) -> bool {
+        if !self.pending {
+            return false;
+        }
+        self.pending = false;
+        self.last_redraw = Some(now);
+        true
+    }
+}
+
+/// 入力欄の描画スタイル（プロンプト・インデントの文字列と表示幅、配色）
+struct InputStyle<'a> {
+    prompt: &'a str,
+    indent: &'a str,
+    prompt_width: usize,
+    indent_width: usize,
+    prompt_bg: Color,
+    prompt_fg: Color,
+    input_bg: Color,
+    input_fg: Color,
+}
+
+/// 前回描画した入力欄の各表示行（差分描画用）
+#[derive(Default)]
+struct RenderedInput {
+    lines: Vec<String>,
+    term_width: usize,
+}
+
+/// 1表示行分の書記素（論理順）について、表示上の開始列と RTL かどうかを求める
+///
+/// # 引数
+/// * `graphemes` - 改行を含まない1表示行分の書記素
+/// * `widths` - 各書記素の表示幅
+/// * `start_col` - 行の先頭の列（プロンプトやインデントの直後）
+fn visual_columns(graphemes: &[String], widths: &[usize], start_col: usize) -> Vec<(usize, bool)> {
+    let text = graphemes.concat();
+    let bidi = BidiInfo::new(&text, None);
+    let Some(paragraph) = bidi.paragraphs.first() else {
+        return vec![(start_col, false); graphemes.len()];
+    };
+    let (levels, runs) = bidi.visual_runs(paragraph, paragraph.range.clone());
+
+    let mut offsets = Vec::with_capacity(graphemes.len());
+    let mut offset = 0;
+    for g in graphemes {
+        offsets.push(offset);
+        offset += g.len();
     }
 
+    let mut columns = vec![(start_col, false); graphemes.len()];
+    let mut col = start_col;
+    for run in runs {
+        let rtl = levels[run.start].is_rtl();
+        let mut members: Vec<usize> = (0..graphemes.len())
+            .filter(|&i| run.contains(&offsets[i]))
+            .collect();
+        if rtl {
+            members.reverse();
+        }
+        for i in members {
+            columns[i] = (col, rtl);
+            col += widths[i];
+        }
+    }
+    columns
 }
 
-fn render_input(
-    stdout: &mut std::io::Stdout,
+/// 入力欄を表示行単位に分割する（先頭・末尾はパディング行、2行目はプロンプト付き）
+fn layout_input_lines(
     prompt: &str,
     indent: &str,
     prompt_width: usize,
     indent_width: usize,
     term_width: usize,
     buffer: &InputBuffer,
-    prompt_bg: Color,
-    prompt_fg: Color,
-    input_bg: Color,
-    input_fg: Color,
-) -> Result<(usize, (usize, usize))> {
-    let positions = buffer.layout_positions(prompt_width, indent_width, term_width);
-    let cursor_pos = positions
-        .get(buffer.cursor)
-        .copied()
-        .unwrap_or((0, prompt_width));
-
-    execute!(
-        stdout,
-        cursor::MoveToColumn(0),
-        terminal::Clear(terminal::ClearType::FromCursorDown)
-    )?;
-    prepare_input_line(stdout, term_width, input_bg, input_fg)?;
-    execute!(stdout, Print("\r\n"))?;
-    prepare_input_line(stdout, term_width, input_bg, input_fg)?;
-    execute!(
-        stdout,
-        SetBackgroundColor(prompt_bg),
-        SetForegroundColor(prompt_fg),
-        Print(prompt),
-        SetBackgroundColor(input_bg),
-        SetForegroundColor(input_fg)
-    )?;
-
+) -> Vec<String> {
+    let mut lines = vec![String::new(), prompt.to_string()];
     let mut col = prompt_width;
-    let mut rows_used = 1usize;
 
     for grapheme in &buffer.graphemes {
         if grapheme == "\n" {
-            fill_input_line(stdout, term_width, col, input_bg, input_fg)?;
-            execute!(stdout, Print("\r\n"))?;
-            prepare_input_line(stdout, term_width, input_bg, input_fg)?;
-            execute!(
-                stdout,
-                SetBackgroundColor(input_bg),
-                SetForegroundColor(input_fg),
-                Print(indent)
-            )?;
-            rows_used += 1;
+            lines.push(indent.to_string());
             col = indent_width;
             continue;
         }
 
         let w = UnicodeWidthStr::width(grapheme.as_str()).max(1);
         if col + w > term_width {
-            fill_input_line(stdout, term_width, col, input_bg, input_fg)?;
-            execute!(stdout, Print("\r\n"))?;
-            prepare_input_line(stdout, term_width, input_bg, input_fg)?;
-            execute!(
-                stdout,
-                SetBackgroundColor(input_bg),
-                SetForegroundColor(input_fg),
-                Print(indent)
-            )?;
-            rows_used += 1;
+            lines.push(indent.to_string());
             col = indent_width;
         }
 
-        execute!(stdout, Print(grapheme))?;
+        if let Some(line) = lines.last_mut() {
+            line.push_str(grapheme);
+        }
         col += w;
     }
 
-    fill_input_line(stdout, term_width, col, input_bg, input_fg)?;
-    execute!(stdout, Print("\r\n"))?;
+    lines.push(String::new());
+    lines
+}
+
+/// 表示行を1行分描画する（カーソルは行頭にある前提。`prompt` 指定時は先頭をプロンプト色で描く）
+fn emit_input_line(
+    stdout: &mut std::io::Stdout,
+    line: &str,
+    prompt: Option<(&str, Color, Color)>,
+    term_width: usize,
+    input_bg: Color,
+    input_fg: Color,
+) -> Result<()> {
     prepare_input_line(stdout, term_width, input_bg, input_fg)?;
-    execute!(stdout, ResetColor)?;
+    let body = if let Some((prompt, prompt_bg, prompt_fg)) = prompt {
+        execute!(
+            stdout,
+            SetBackgroundColor(prompt_bg),
+            SetForegroundColor(prompt_fg),
+            Print(prompt)
+        )?;
+        line.strip_prefix(prompt).unwrap_or(line)
+    } else {
+        line
+    };
+    execute!(
+        stdout,
+        SetBackgroundColor(input_bg),
+        SetForegroundColor(input_fg),
+        Print(body)
+    )?;
+    let col = UnicodeWidthStr::width(line);
+    if !line.is_empty() {
+        fill_input_line(stdout, term_width, col, input_bg, input_fg)?;
+    }
+    Ok(())
+}
+
+/// 入力欄を描画する
+///
+/// 行数が増えない限り、前回描画から変化した表示行だけを書き換えてちらつきを抑える。
+/// 行数が増える（スクロールが必要になりうる）場合や端末幅が変わった場合は全体を再描画する。
+fn render_input(
+    stdout: &mut std::io::Stdout,
+    style: &InputStyle,
+    term_width: usize,
+    buffer: &InputBuffer,
+    anchor_row: u16,
+    rendered: &mut RenderedInput,
+) -> Result<(usize, (usize, usize))> {
+    let InputStyle {
+        prompt,
+        indent,
+        prompt_width,
+        indent_width,
+        prompt_bg,
+        prompt_fg,
+        input_bg,
+        input_fg,
+    } = *style;
+    let positions = buffer.layout_positions(prompt_width, indent_width, term_width);
+    let cursor_pos = positions
+        .get(buffer.cursor)
+        .copied()
+        .unwrap_or((0, prompt_width));
+    let lines = layout_input_lines(prompt, indent, prompt_width, indent_width, term_width, buffer);
 
     let padding_rows = 1usize;
-    let bottom_padding = 1usize;
     let cursor_row = cursor_pos.0 + padding_rows;
-    let current_row = padding_rows + rows_used.saturating_sub(1) + bottom_padding;
-    let rows_above_cursor = current_row.saturating_sub(cursor_row);
-    if rows_above_cursor > 0 {
-        execute!(stdout, cursor::MoveUp(rows_above_cursor as u16))?;
+    let full_redraw = rendered.lines.is_empty()
+        || rendered.term_width != term_width
+        || lines.len() > rendered.lines.len();
+
+    if full_redraw {
+        execute!(
+            stdout,
+            cursor::MoveToColumn(0),
+            terminal::Clear(terminal::ClearType::FromCursorDown)
+        )?;
+        for (i, line) in lines.iter().enumerate() {
+            if i > 0 {
+                execute!(stdout, Print("\r\n"))?;
+            }
+            let lead = (i == padding_rows).then_some((prompt, prompt_bg, prompt_fg));
+            emit_input_line(stdout, line, lead, term_width, input_bg, input_fg)?;
+        }
+        execute!(stdout, ResetColor)?;
+
+        let current_row = lines.len() - 1;
+        let rows_above_cursor = current_row.saturating_sub(cursor_row);
+        if rows_above_cursor > 0 {
+            execute!(stdout, cursor::MoveUp(rows_above_cursor as u16))?;
+        }
+        execute!(stdout, cursor::MoveToColumn(cursor_pos.1 as u16))?;
+    } else {
+        for (i, line) in lines.iter().enumerate() {
+            if rendered.lines.get(i) == Some(line) {
+                continue;
+            }
+            execute!(stdout, cursor::MoveTo(0, anchor_row + i as u16))?;
+            let lead = (i == padding_rows).then_some((prompt, prompt_bg, prompt_fg));
+            emit_input_line(stdout, line, lead, term_width, input_bg, input_fg)?;
+        }
+        execute!(stdout, ResetColor)?;
+        // 行数が減った分の古い行を消す
+        for i in lines.len()..rendered.lines.len() {
+            execute!(
+                stdout,
+                cursor::MoveTo(0, anchor_row + i as u16),
+                terminal::Clear(terminal::ClearType::CurrentLine)
+            )?;
+        }
+        execute!(
+            stdout,
+            cursor::MoveTo(cursor_pos.1 as u16, anchor_row + cursor_row as u16)
+        )?;
     }
-    execute!(stdout, cursor::MoveToColumn(cursor_pos.1 as u16))?;
+
     stdout.flush()?;
-    Ok((rows_used + padding_rows + bottom_padding, (cursor_row, cursor_pos.1)))
+    let rows = lines.len();
+    rendered.lines = lines;
+    rendered.term_width = term_width;
+    Ok((rows, (cursor_row, cursor_pos.1)))
 }
 
 fn fill_input_line(
@@ -1562,10 +3948,31 @@ fn prepare_input_line(
     Ok(())
 }
 
-/// 改行差分や末尾空白を無視して内容一致を判定
+/// 推論タイムアウトを部分応答として扱う（部分応答にも完結したツール呼び出しが含まれうるため）
+fn recover_timeout(result: Result<String>, truncated: &mut bool) -> Result<String> {
+    match result {
+        Err(e) => match e.downcast::<InferenceTimeout>() {
+            Ok(timeout) => {
+                *truncated = true;
+                Ok(timeout.partial_output)
+            }
+            Err(e) => Err(e),
+        },
+        ok => ok,
+    }
+}
+
+/// 改行コード（`\r\n` / `\r` / `\n`）と行末の空白の違いを無視して内容を比較する
 fn contents_equal(a: &str, b: &str) -> bool {
     fn normalize(s: &str) -> String {
-        s.replace("\r\n", "\n").trim_end().to_string()
+        let unified = s.replace("\r\n", "\n").replace('\r', "\n");
+        unified
+            .lines()
+            .map(str::trim_end)
+            .collect::<Vec<_>>()
+            .join("\n")
+            .trim_end()
+            .to_string()
     }
     normalize(a) == normalize(b)
 }
@@ -1576,39 +3983,188 @@ struct TruncationNotice {
     kept_tokens: usize,
 }
 
+impl std::fmt::Display for TruncationNotice {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kept = if self.kept_tokens == 0 {
+            "dropped"
+        } else {
+            "kept head+tail"
+        };
+        write!(
+            f,
+            "[Truncated: {} ({} → {} tokens, {})]",
+            self.path, self.original_tokens, self.kept_tokens, kept
+        )
+    }
+}
+
+/// セッション中に書き込んだファイル（`/files` で表示）
+#[derive(Debug, Clone)]
+struct WrittenFile {
+    path: String,
+    size: u64,
+    timestamp: chrono::DateTime<chrono::Local>,
+    operation: WriteKind,
+}
+
+/// 書き込みの種類
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum WriteKind {
+    Created,
+    Updated,
+    Patched,
+    Appended,
+}
+
+impl WriteKind {
+    fn from_existed(existed: bool) -> Self {
+        if existed { Self::Updated } else { Self::Created }
+    }
+
+    /// MCP ツールでの書き込みの種類を決める（名前に append を含むツールは追記とみなす）
+    fn for_tool(tool_name: &str, existed: bool) -> Self {
+        if tool_name.to_lowercase().contains("append") {
+            Self::Appended
+        } else {
+            Self::from_existed(existed)
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Self::Created => "created",
+            Self::Updated => "updated",
+            Self::Patched => "patched",
+            Self::Appended => "appended",
+        }
+    }
+}
+
+/// 推論中のステータス行に付けるスピナーのフレーム
+const SPINNER_FRAMES: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+
+/// スピナーのフレームを進める間隔
+const SPINNER_INTERVAL: Duration = Duration::from_millis(100);
+
+/// 推論中に `[Status: Generating ⠋]` のステータス行を描き直し続けるスレッド
+///
+/// クローンは同じスレッドを共有するため、推論のコールバックからも `stop` で止められる。
+#[derive(Clone)]
+struct Spinner {
+    spinning: Arc<AtomicBool>,
+    handle: Arc<Mutex<Option<std::thread::JoinHandle<()>>>>,
+}
+
+impl Spinner {
+    /// `status` にスピナーのフレームを付けて描画し始める
+    fn start(status: &str) -> Self {
+        let spinning = Arc::new(AtomicBool::new(true));
+        let running = Arc::clone(&spinning);
+        let status = status.to_string();
+        let handle = std::thread::spawn(move || {
+            let mut stdout = io::stdout();
+            let mut spin_frame = 0usize;
+            while running.load(Ordering::SeqCst) {
+                let frame = SPINNER_FRAMES[spin_frame % SPINNER_FRAMES.len()];
+                let _ = execute!(
+                    stdout,
+                    cursor::MoveToColumn(0),
+                    terminal::Clear(terminal::ClearType::CurrentLine),
+                    SetForegroundColor(Color::DarkGrey),
+                    Print(format!("[Status: {} {}]", status, frame)),
+                    ResetColor
+                );
+                spin_frame += 1;
+                // stop() で起こされたらすぐに抜ける
+                std::thread::park_timeout(SPINNER_INTERVAL);
+            }
+            let _ = execute!(
+                stdout,
+                cursor::MoveToColumn(0),
+                terminal::Clear(terminal::ClearType::CurrentLine)
+            );
+        });
+        Self {
+            spinning,
+            handle: Arc::new(Mutex::new(Some(handle))),
+        }
+    }
+
+    /// スピナーを止めてステータス行を消す（止めた後に呼んでも何もしない）
+    fn stop(&self) {
+        let Some(handle) = self.handle.lock().ok().and_then(|mut handle| handle.take()) else {
+            return;
+        };
+        self.spinning.store(false, Ordering::SeqCst);
+        handle.thread().unpark();
+        let _ = handle.join();
+    }
+}
+
+/// `run_turn` の結果
+struct TurnOutput {
+    response: String,
+    // 推論タイムアウトで応答が途中までになった
+    truncated: bool,
+    // 最後の応答が max_new_tokens で打ち切られた
+    token_limit_hit: bool,
+    // 追加推論を含めた推論時間の合計
+    inference_time: Duration,
+    // MCP ツール呼び出しにかかった時間の合計
+    tool_time: Duration,
+}
+
+/// トークン上限で打ち切られた応答と、その元の質問
+struct Continuation {
+    query: String,
+    // これまでに生成された応答（`continue` を重ねた分を連結したもの）
+    response: String,
+}
+
+impl Continuation {
+    /// 応答は末尾の `max_tokens`（推定値）分だけを残す
+    fn new(query: String, response: String, max_tokens: usize) -> Self {
+        let response = take_tail_by_tokens(&response, max_tokens).to_string();
+        Self { query, response }
+    }
+
+    /// 応答の続きを生成させる入力を組み立てる（末尾50文字を続きの起点として示す）
+    fn prompt(&self) -> String {
+        let tail_start = self
+            .response
+            .char_indices()
+            .rev()
+            .nth(49)
+            .map_or(0, |(idx, _)| idx);
+        format!(
+            "{}\n\nYour previous answer was cut off at the token limit:\n{}\n\nContinue from: {}",
+            self.query,
+            self.response,
+            &self.response[tail_start..]
+        )
+    }
+}
+
 struct PromptWithLimit {
     prompt: String,
     notices: Vec<TruncationNotice>,
     overflow: bool,
 }
 
-static MAX_CONTEXT_TOKENS: OnceCell<usize> = OnceCell::new();
-static CONTEXT_RESERVED_TOKENS: OnceCell<usize> = OnceCell::new();
+// 0 は未設定（初回の参照時に環境変数から読み込む）
+static MAX_CONTEXT_TOKENS: AtomicUsize = AtomicUsize::new(0);
+static CONTEXT_RESERVED_TOKENS: AtomicUsize = AtomicUsize::new(0);
 
-fn build_prompt_with_context_limit(
-    user_input: &str,
-    files: &[file_ops::FileContent],
-    errors: &[(String, String)],
-    tool_info: Option<&str>,
-    output_targets: &[String],
-    has_file_op_intent: bool,
-    file_writes_enabled: bool,
-    tool_results: &[ToolResult],
-) -> PromptWithLimit {
+fn build_prompt_with_context_limit(parts: &PromptParts) -> PromptWithLimit {
     let max_tokens = max_context_tokens();
     let reserved_tokens = context_reserved_tokens();
 
-    let base_prompt = build_chat_prompt(
-        user_input,
-        &[],
-        errors,
-        tool_info,
-        output_targets,
-        has_file_op_intent,
-        file_writes_enabled,
-        tool_results,
-    );
-    let base_tokens = estimate_tokens(&base_prompt);
+    let base_prompt = build_chat_prompt(&PromptParts {
+        files: &[],
+        prior_turns: &[],
+        ..*parts
+    });
+    let base_tokens = estimate_tokens_v2(&base_prompt);
     if base_tokens >= max_tokens {
         return PromptWithLimit {
             prompt: base_prompt,
@@ -1618,18 +4174,20 @@ fn build_prompt_with_context_limit(
     }
 
     let budget_tokens = max_tokens.saturating_sub(base_tokens + reserved_tokens);
-    let (trimmed_files, notices) = truncate_files_to_budget(files, budget_tokens);
-    let prompt = build_chat_prompt(
-        user_input,
-        &trimmed_files,
-        errors,
-        tool_info,
-        output_targets,
-        has_file_op_intent,
-        file_writes_enabled,
-        tool_results,
+    let history = trim_history_to_budget(parts.prior_turns, parts.files, budget_tokens);
+    let history_tokens: usize = history.iter().map(estimate_turn_tokens).sum();
+    let file_budget_tokens = budget_tokens.saturating_sub(history_tokens);
+    let (trimmed_files, notices) = truncate_files_to_budget(
+        parts.files,
+        file_budget_tokens,
+        AppConfig::global().max_tokens_per_file,
     );
-    let overflow = estimate_tokens(&prompt) > max_tokens;
+    let prompt = build_chat_prompt(&PromptParts {
+        files: &trimmed_files,
+        prior_turns: history,
+        ..*parts
+    });
+    let overflow = estimate_tokens_v2(&prompt) > max_tokens;
     if overflow {
         return PromptWithLimit {
             prompt: base_prompt,
@@ -1645,9 +4203,50 @@ fn build_prompt_with_context_limit(
     }
 }
 
+/// 会話履歴1往復分の概算トークン数（<turn> タグ分を含む）
+fn estimate_turn_tokens((user, assistant): &(String, String)) -> usize {
+    const TURN_TAG_TOKENS: usize = 16;
+    estimate_tokens_v2(user) + estimate_tokens_v2(assistant) + TURN_TAG_TOKENS
+}
+
+/// 会話履歴を予算内に収まるよう古い往復から削る
+///
+/// 履歴とファイルの合計が予算を超える場合、予算をそれぞれのトークン数に比例して配分する。
+fn trim_history_to_budget<'a>(
+    prior_turns: &'a [(String, String)],
+    files: &[file_ops::FileContent],
+    budget_tokens: usize,
+) -> &'a [(String, String)] {
+    let turn_tokens: Vec<usize> = prior_turns.iter().map(estimate_turn_tokens).collect();
+    let history_tokens: usize = turn_tokens.iter().sum();
+    let file_tokens: usize = files
+        .iter()
+        .map(|file| estimate_tokens_v2(&file.content))
+        .sum();
+
+    let history_budget = if history_tokens + file_tokens <= budget_tokens {
+        history_tokens
+    } else {
+        budget_tokens * history_tokens / (history_tokens + file_tokens)
+    };
+
+    let mut kept_tokens = history_tokens;
+    let mut start = 0usize;
+    while kept_tokens > history_budget && start < prior_turns.len() {
+        kept_tokens -= turn_tokens[start];
+        start += 1;
+    }
+    &prior_turns[start..]
+}
+
+/// ファイル内容を予算内に収まるよう切り詰める
+///
+/// 予算はファイルのトークン数に比例して配分する。`max_tokens_per_file` が指定されていれば
+/// 各ファイルの配分をその値で頭打ちにし、余った分を他のファイルへ再配分する。
 fn truncate_files_to_budget(
     files: &[file_ops::FileContent],
     budget_tokens: usize,
+    max_tokens_per_file: Option<usize>,
 ) -> (Vec<file_ops::FileContent>, Vec<TruncationNotice>) {
     if files.is_empty() {
         return (Vec::new(), Vec::new());
@@ -1655,7 +4254,7 @@ fn truncate_files_to_budget(
 
     let original_tokens: Vec<usize> = files
         .iter()
-        .map(|file| estimate_tokens(&file.content))
+        .map(|file| estimate_tokens_v2(&file.content))
         .collect();
     let total_tokens: usize = original_tokens.iter().sum();
 
@@ -1672,34 +4271,33 @@ fn truncate_files_to_budget(
         return (Vec::new(), notices);
     }
 
-    if total_tokens <= budget_tokens {
-        return (files.to_vec(), Vec::new());
-    }
+    // 各ファイルが受け取れる上限（上限指定がなければ元のトークン数）
+    let limits: Vec<usize> = original_tokens
+        .iter()
+        .map(|&tokens| max_tokens_per_file.map_or(tokens, |cap| tokens.min(cap)))
+        .collect();
+    let total_limited: usize = limits.iter().sum();
 
-    let mut allocations = vec![0usize; files.len()];
-    if total_tokens > 0 {
-        for i in 0..files.len() {
-            allocations[i] = budget_tokens * original_tokens[i] / total_tokens;
-        }
+    if total_tokens <= budget_tokens && total_limited == total_tokens {
+        return (files.to_vec(), Vec::new());
     }
 
-    let allocated: usize = allocations.iter().sum();
-    let mut remainder = budget_tokens.saturating_sub(allocated);
-    let mut order: Vec<usize> = (0..files.len()).collect();
-    order.sort_by_key(|&i| Reverse(original_tokens[i]));
-    for idx in order {
-        if remainder == 0 {
-            break;
-        }
-        allocations[idx] += 1;
-        remainder -= 1;
-    }
+    let allocations = if total_limited <= budget_tokens {
+        limits
+    } else {
+        allocate_capped(&original_tokens, &limits, budget_tokens)
+    };
 
     let mut trimmed_files = Vec::with_capacity(files.len());
     let mut notices = Vec::new();
 
     for (idx, file) in files.iter().enumerate() {
         let limit_tokens = allocations[idx];
+        // 配分が元のサイズ以上なら切り詰めない（上限指定時、他のファイルだけが対象になる場合）
+        if limit_tokens >= original_tokens[idx] {
+            trimmed_files.push(file.clone());
+            continue;
+        }
         if limit_tokens == 0 {
             notices.push(TruncationNotice {
                 path: file.original_path.clone(),
@@ -1727,106 +4325,383 @@ fn truncate_files_to_budget(
     (trimmed_files, notices)
 }
 
+/// 予算をトークン数に比例して配分する。上限に達したファイルは上限で固定し、
+/// 残りの予算を他のファイルで改めて比例配分する（合計上限は予算を超えている前提）
+fn allocate_capped(original_tokens: &[usize], limits: &[usize], budget_tokens: usize) -> Vec<usize> {
+    let mut allocations = vec![0usize; original_tokens.len()];
+    let mut open: Vec<usize> = (0..original_tokens.len()).collect();
+    let mut remaining = budget_tokens;
+
+    while !open.is_empty() {
+        // 空ファイルだけが残った場合も 0 除算しない（配分は 0 のまま上限に達する）
+        let weight: usize = open.iter().map(|&i| original_tokens[i]).sum::<usize>().max(1);
+        let capped: Vec<usize> = open
+            .iter()
+            .copied()
+            .filter(|&i| remaining * original_tokens[i] / weight >= limits[i])
+            .collect();
+        if capped.is_empty() {
+            for &i in &open {
+                allocations[i] = remaining * original_tokens[i] / weight;
+            }
+            break;
+        }
+        for &i in &capped {
+            allocations[i] = limits[i];
+            remaining -= limits[i];
+        }
+        open.retain(|i| !capped.contains(i));
+    }
+
+    // 切り捨てで余った分は大きいファイルから1ずつ配る
+    let allocated: usize = allocations.iter().sum();
+    let mut remainder = budget_tokens.saturating_sub(allocated);
+    let mut order: Vec<usize> = (0..original_tokens.len()).collect();
+    order.sort_by_key(|&i| Reverse(original_tokens[i]));
+    for idx in order {
+        if remainder == 0 {
+            break;
+        }
+        if allocations[idx] < limits[idx] {
+            allocations[idx] += 1;
+            remainder -= 1;
+        }
+    }
+
+    allocations
+}
+
+/// ファイル内容を `limit_tokens`（`estimate_tokens_v2` の推定値）に収まるよう先頭と末尾を残して切り詰める
 fn truncate_file_content(content: &str, limit_tokens: usize) -> (String, usize, bool) {
     if content.is_empty() || limit_tokens == 0 {
         return (String::new(), 0, !content.is_empty());
     }
 
-    let max_bytes = limit_tokens.saturating_mul(3).max(1);
-    if content.as_bytes().len() <= max_bytes {
-        return (content.to_string(), estimate_tokens(content), false);
+    let total_tokens = estimate_tokens_v2(content);
+    if total_tokens <= limit_tokens {
+        return (content.to_string(), total_tokens, false);
     }
 
     let marker = "\n[...truncated...]\n";
-    let marker_bytes = marker.as_bytes().len();
-    let keep_bytes = max_bytes.saturating_sub(marker_bytes);
-    if keep_bytes == 0 {
-        let head = take_head_by_bytes(content, max_bytes);
-        let kept_tokens = estimate_tokens(head);
+    let keep_tokens = limit_tokens.saturating_sub(estimate_tokens_v2(marker));
+    if keep_tokens == 0 {
+        let head = take_head_by_tokens(content, limit_tokens);
+        let kept_tokens = estimate_tokens_v2(head);
         return (head.to_string(), kept_tokens, true);
     }
 
-    let head_bytes = keep_bytes * 2 / 3;
-    let tail_bytes = keep_bytes.saturating_sub(head_bytes);
-    let head = take_head_by_bytes(content, head_bytes);
-    let tail = if tail_bytes > 0 {
-        take_tail_by_bytes(content, tail_bytes)
-    } else {
-        ""
-    };
+    let head_tokens = keep_tokens * 2 / 3;
+    let tail_tokens = keep_tokens.saturating_sub(head_tokens);
+    let head = take_head_by_tokens(content, head_tokens);
+    let tail = take_tail_by_tokens(content, tail_tokens);
     let truncated = format!("{}{}{}", head, marker, tail);
-    let kept_tokens = estimate_tokens(&truncated);
+    let kept_tokens = estimate_tokens_v2(&truncated);
     (truncated, kept_tokens, true)
 }
 
-fn take_head_by_bytes(text: &str, max_bytes: usize) -> &str {
-    if max_bytes == 0 || text.is_empty() {
-        return "";
+/// 推定トークン数が `max_tokens` 以下になる最長の先頭部分（文字境界で二分探索する）
+fn take_head_by_tokens(text: &str, max_tokens: usize) -> &str {
+    let boundaries: Vec<usize> = text
+        .char_indices()
+        .map(|(idx, _)| idx)
+        .chain(std::iter::once(text.len()))
+        .collect();
+    // 先頭部分の推定トークン数は長くなるほど増える（減らない）
+    let count = boundaries.partition_point(|&end| estimate_tokens_v2(&text[..end]) <= max_tokens);
+    &text[..boundaries[count.saturating_sub(1)]]
+}
+
+/// 推定トークン数が `max_tokens` 以下になる最長の末尾部分（文字境界で二分探索する）
+fn take_tail_by_tokens(text: &str, max_tokens: usize) -> &str {
+    let boundaries: Vec<usize> = text
+        .char_indices()
+        .map(|(idx, _)| idx)
+        .chain(std::iter::once(text.len()))
+        .collect();
+    // 末尾部分の推定トークン数は開始位置が後ろになるほど減る（増えない）
+    let start = boundaries.partition_point(|&start| estimate_tokens_v2(&text[start..]) > max_tokens);
+    &text[boundaries[start]..]
+}
+
+/// プロンプトのセクション（タグ名, 表示名）。`build_chat_prompt` が出力する順に並べる
+const PROMPT_SECTIONS: &[(&str, &str)] = &[
+    ("system", "system"),
+    ("tools", "tools"),
+    ("files", "files"),
+    ("output_targets", "targets"),
+    ("tool_results", "tool_results"),
+    ("conversation_history", "history"),
+    ("environment", "environment"),
+    ("last_error", "last_error"),
+    ("user_input", "input"),
+];
+
+/// プロンプトをセクションごとに分け、それぞれの概算トークン数を求める
+///
+/// `<files>` はファイルごとの内訳も返す（`file <path>` の名前で、`files` の直後に並ぶ）。
+///
+/// # 引数
+/// * `prompt` - `build_chat_prompt` で組み立てたプロンプト
+///
+/// # 戻り値
+/// (表示名, トークン数) の一覧（プロンプトにないセクションは含めない）
+fn prompt_token_breakdown(prompt: &str) -> Vec<(String, usize)> {
+    let mut breakdown = Vec::new();
+    let mut rest = prompt;
+    for (tag, label) in PROMPT_SECTIONS {
+        let open = format!("<{}>", tag);
+        let close = format!("</{}>", tag);
+        let Some(start) = rest.find(&open) else {
+            continue;
+        };
+        // ユーザー入力は最後のセクションなので、入力中の閉じタグに惑わされないよう末尾から探す
+        let end = if *tag == "user_input" {
+            rest.rfind(&close)
+        } else {
+            rest[start..].find(&close).map(|i| start + i)
+        };
+        let Some(end) = end.filter(|end| *end >= start) else {
+            continue;
+        };
+        let body = &rest[start + open.len()..end];
+        breakdown.push((label.to_string(), estimate_tokens_v2(body)));
+        if *tag == "files" {
+            breakdown.extend(file_token_breakdown(body));
+        }
+        rest = &rest[end + close.len()..];
     }
-    if text.as_bytes().len() <= max_bytes {
-        return text;
+    breakdown
+}
+
+/// `<files>` の中身をファイルごとの (`file <path>`, トークン数) に分ける
+fn file_token_breakdown(files: &str) -> Vec<(String, usize)> {
+    FILE_SECTION_PATTERN
+        .captures_iter(files)
+        .map(|caps| (format!("file {}", &caps[1]), estimate_tokens_v2(&caps[2])))
+        .collect()
+}
+
+/// `/tokens` の表示を作る
+///
+/// 1行目にセクションごとの合計、2行目以降にファイルごとの内訳を並べる。
+///
+/// # 引数
+/// * `prompt` - 直前に推論へ渡したプロンプト
+/// * `max_tokens` - コンテキストの上限トークン数
+fn format_token_breakdown(prompt: &str, max_tokens: usize) -> String {
+    let breakdown = prompt_token_breakdown(prompt);
+    let mut summary: Vec<String> = breakdown
+        .iter()
+        .filter(|(label, _)| !label.starts_with("file "))
+        .map(|(label, tokens)| format!("{}: {} tokens", label, tokens))
+        .collect();
+    summary.push(format!("total: {} / {}", estimate_tokens_v2(prompt), max_tokens));
+
+    let mut message = format!("[Tokens] {}", summary.join(" | "));
+    for (label, tokens) in breakdown.iter().filter(|(label, _)| label.starts_with("file ")) {
+        message.push_str(&format!("\n  {}: {} tokens", label, tokens));
     }
-    let mut end = 0usize;
-    for (idx, ch) in text.char_indices() {
-        let next = idx + ch.len_utf8();
-        if next > max_bytes {
-            break;
+    message
+}
+
+/// Ctrl+Z で一時停止していた場合に raw モードと bracketed paste を元に戻す
+///
+/// SIGCONT のタスクと一時停止した側の両方から呼ばれるため、戻すのは最初の1回だけにする。
+fn resume_terminal(suspended: &AtomicBool) -> Result<()> {
+    if !suspended.swap(false, Ordering::SeqCst) {
+        return Ok(());
+    }
+    terminal::enable_raw_mode().context("Failed to enable raw mode")?;
+    execute!(stdout(), EnableBracketedPaste).context("Failed to enable bracketed paste")?;
+    Ok(())
+}
+
+/// 文字種ごとにトークン数を概算する
+///
+/// 英数字の連続は 4 文字で 1 トークン、CJK 文字は 1 文字 1 トークン、記号は 0.5 トークン、
+/// それ以外の Unicode 文字は 3 バイトで 1 トークンとして数え、合計を切り上げる。空白は数えない。
+fn estimate_tokens_v2(text: &str) -> usize {
+    if text.is_empty() {
+        return 0;
+    }
+
+    // 0.5 トークン単位を扱うため、半トークン単位で集計する
+    let mut halves = 0usize;
+    let mut other_bytes = 0usize;
+    let mut alnum_run = 0usize;
+
+    for ch in text.chars() {
+        if ch.is_ascii_alphanumeric() {
+            alnum_run += 1;
+            continue;
+        }
+        halves += alnum_run.div_ceil(4) * 2;
+        alnum_run = 0;
+
+        if ch.is_whitespace() {
+            continue;
+        }
+        if is_cjk_char(ch) {
+            halves += 2;
+        } else if ch.is_ascii_punctuation() || is_cjk_punctuation(ch) {
+            halves += 1;
+        } else {
+            other_bytes += ch.len_utf8();
         }
-        end = next;
     }
-    &text[..end]
+    halves += alnum_run.div_ceil(4) * 2;
+    halves += other_bytes.div_ceil(3) * 2;
+
+    halves.div_ceil(2)
 }
 
-fn take_tail_by_bytes(text: &str, max_bytes: usize) -> &str {
-    if max_bytes == 0 || text.is_empty() {
-        return "";
+fn is_cjk_char(ch: char) -> bool {
+    matches!(
+        ch as u32,
+        0x3040..=0x309F      // Hiragana
+            | 0x30A0..=0x30FF // Katakana
+            | 0x3400..=0x4DBF // CJK Unified Ideographs Extension A
+            | 0x4E00..=0x9FFF // CJK Unified Ideographs
+            | 0xAC00..=0xD7AF // Hangul Syllables
+            | 0xF900..=0xFAFF // CJK Compatibility Ideographs
+            | 0x20000..=0x2FFFF
+    )
+}
+
+fn is_cjk_punctuation(ch: char) -> bool {
+    matches!(ch as u32, 0x3000..=0x303F | 0xFF00..=0xFF65)
+}
+
+/// 確認プロンプトへの回答
+#[derive(Debug, PartialEq, Eq)]
+enum ConfirmAnswer {
+    Yes,
+    No,
+    /// このセッション中は同じ対象を再確認しない
+    Always,
+}
+
+impl ConfirmAnswer {
+    /// 入力を解釈する（'y' / 'a' 以外はすべて No）
+    fn parse(input: &str) -> Self {
+        match input.trim().to_ascii_lowercase().as_str() {
+            "y" => Self::Yes,
+            "a" => Self::Always,
+            _ => Self::No,
+        }
     }
-    if text.as_bytes().len() <= max_bytes {
-        return text;
+}
+
+/// 相対パスを `output_dir` 基準にする
+///
+/// `.` と `..` を解決し、`output_dir` の外に出るパス（および絶対パスと `~` で始まるパス）は None
+fn join_output_dir(output_dir: &str, path: &str) -> Option<String> {
+    if path.starts_with('~') {
+        return None;
     }
-    let start_target = text.as_bytes().len().saturating_sub(max_bytes);
-    for (idx, _) in text.char_indices() {
-        if idx >= start_target {
-            return &text[idx..];
+    let mut parts = Vec::new();
+    for component in Path::new(path).components() {
+        match component {
+            std::path::Component::Normal(part) => parts.push(part),
+            std::path::Component::CurDir => {}
+            std::path::Component::ParentDir => {
+                parts.pop()?;
+            }
+            std::path::Component::RootDir | std::path::Component::Prefix(_) => return None,
         }
     }
-    ""
+    let mut joined = PathBuf::from(output_dir);
+    joined.extend(parts);
+    Some(joined.to_string_lossy().into_owned())
 }
 
-fn estimate_tokens(text: &str) -> usize {
-    if text.is_empty() {
-        return 0;
+/// 表示用のモデル名（エイリアスがなければモデルファイル名から拡張子を除いたもの）
+fn model_display_name(model_path: &str, alias: Option<&str>) -> String {
+    alias
+        .map(str::trim)
+        .filter(|alias| !alias.is_empty())
+        .map(str::to_string)
+        .or_else(|| {
+            Path::new(model_path)
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+        })
+        .unwrap_or_else(|| model_path.to_string())
+}
+
+/// 整形結果の表示（成功は "[Formatted: ...]"、失敗はフォーマッタの標準エラー出力を添えた警告）
+fn format_result_message(path: &str, result: Result<()>) -> String {
+    match result {
+        Ok(()) => format!("[Formatted: {} (saved)]", path),
+        Err(e) => format!("[Format warning: {}: {:#}]", path, e),
+    }
+}
+
+/// コンテキスト使用率（%）に応じた表示色
+fn context_usage_color(percent: usize) -> Color {
+    match percent {
+        0..=80 => Color::DarkGrey,
+        81..=95 => Color::Yellow,
+        _ => Color::Red,
     }
-    let bytes = text.as_bytes().len();
-    (bytes + 2) / 3
 }
 
 fn max_context_tokens() -> usize {
-    *MAX_CONTEXT_TOKENS.get_or_init(|| {
-        std::env::var("RKLLM_MAX_CONTEXT_TOKENS")
-            .ok()
-            .and_then(|v| v.parse::<usize>().ok())
-            .filter(|v| *v > 0)
-            .unwrap_or(4096)
-    })
+    load_or_init(&MAX_CONTEXT_TOKENS, "RKLLM_MAX_CONTEXT_TOKENS", 4096)
+}
+
+/// プロンプト全体のトークン上限を変える（`--max-context-tokens`。0 は無視する）
+pub fn set_max_context_tokens(n: usize) {
+    if n > 0 {
+        MAX_CONTEXT_TOKENS.store(n, Ordering::Relaxed);
+    }
 }
 
 fn context_reserved_tokens() -> usize {
-    *CONTEXT_RESERVED_TOKENS.get_or_init(|| {
-        std::env::var("RKLLM_CONTEXT_RESERVED_TOKENS")
-            .ok()
-            .and_then(|v| v.parse::<usize>().ok())
-            .filter(|v| *v > 0)
-            .unwrap_or(256)
-    })
+    load_or_init(&CONTEXT_RESERVED_TOKENS, "RKLLM_CONTEXT_RESERVED_TOKENS", 256)
+}
+
+/// 応答用に確保するトークン数を変える（`--context-reserved-tokens`。0 は無視する）
+pub fn set_context_reserved_tokens(n: usize) {
+    if n > 0 {
+        CONTEXT_RESERVED_TOKENS.store(n, Ordering::Relaxed);
+    }
+}
+
+/// 設定済みの値を返す。未設定なら環境変数 `var`（なければ `default`）で初期化する
+fn load_or_init(value: &AtomicUsize, var: &str, default: usize) -> usize {
+    let current = value.load(Ordering::Relaxed);
+    if current > 0 {
+        return current;
+    }
+    let initial = std::env::var(var)
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(default);
+    // 同時に set された値があればそちらを優先する
+    match value.compare_exchange(0, initial, Ordering::Relaxed, Ordering::Relaxed) {
+        Ok(_) => initial,
+        Err(set) => set,
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::ChatSession;
+    use super::{
+        context_usage_color, contents_equal, estimate_tokens_v2, format_token_breakdown, prompt_token_breakdown, format_result_message, layout_input_lines, model_display_name, record_tool_failure, recover_timeout,
+        allocate_capped, trim_history_to_budget, Continuation, truncate_file_content, truncate_files_to_budget, truncate_tool_output,
+        ChatSession, ConfirmAnswer, RedrawDebounce, REDRAW_DEBOUNCE, join_output_dir, load_or_init,
+        write_tool_args, with_prefix, undo_files, WriteKind,
+        ChatSessionBuilder, InputBuffer,
+    };
+    use crate::llm::InferenceTimeout;
     use crate::mcp::types::{Tool, ToolInputSchema};
     use serde_json::json;
     use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
 
     #[test]
     fn build_sample_arguments_prefers_required() {
@@ -1868,6 +4743,84 @@ mod tests {
         assert_eq!(obj.get("example"), Some(&json!("value")));
     }
 
+    #[test]
+    fn truncate_tool_output_respects_limit_and_char_boundary() {
+        let mut short = "ok".to_string();
+        assert!(!truncate_tool_output(&mut short, 8));
+        assert_eq!(short, "ok");
+
+        let mut long = "あいう".to_string(); // 9 bytes
+        assert!(truncate_tool_output(&mut long, 4));
+        assert_eq!(long, "あ\n[...truncated...]");
+    }
+
+    #[test]
+    fn format_tool_info_sorts_by_server_then_name() {
+        let make_tool = |name: &str| Tool {
+            name: name.to_string(),
+            description: None,
+            input_schema: ToolInputSchema {
+                schema_type: "object".to_string(),
+                properties: None,
+                required: None,
+                additional_properties: None,
+            },
+        };
+        let write = make_tool("write_file");
+        let read = make_tool("read_file");
+        let query = make_tool("query");
+
+        let supported = HashMap::from([("fs", vec!["tools", "resources"])]);
+        let info = ChatSession::format_tool_info(
+            vec![("fs", &write), ("db", &query), ("fs", &read)],
+            &supported,
+            &HashMap::new(),
+        )
+        .unwrap();
+
+        let query_pos = info.find("### query").unwrap();
+        let read_pos = info.find("### read_file").unwrap();
+        let write_pos = info.find("### write_file").unwrap();
+        assert!(query_pos < read_pos && read_pos < write_pos);
+        assert!(info.contains("Server: db\n"));
+        assert!(info.contains("Server: fs [supports: tools, resources]\n"));
+        assert!(ChatSession::format_tool_info(Vec::new(), &supported, &HashMap::new()).is_none());
+        assert!(!info.contains("## Tool Usage This Session"));
+    }
+
+    #[test]
+    fn format_tool_info_annotates_failing_tools() {
+        let make_tool = |name: &str| Tool {
+            name: name.to_string(),
+            description: None,
+            input_schema: ToolInputSchema {
+                schema_type: "object".to_string(),
+                properties: None,
+                required: None,
+                additional_properties: None,
+            },
+        };
+        let fetch = make_tool("fetch_url");
+        let search = make_tool("search");
+        let usage = HashMap::from([
+            ("fetch_url".to_string(), (3, 2)),
+            ("search".to_string(), (1, 0)),
+        ]);
+
+        let info = ChatSession::format_tool_info(
+            vec![("web", &fetch), ("web", &search)],
+            &HashMap::new(),
+            &usage,
+        )
+        .unwrap();
+
+        assert!(info.contains("### fetch_url (3 calls, 2 errors - may be unreliable)\n"));
+        assert!(info.contains("### search\n"));
+        assert!(info.contains(
+            "## Tool Usage This Session\n\n- fetch_url: 3 calls, 2 errors\n- search: 1 calls, 0 errors\n"
+        ));
+    }
+
     #[test]
     fn build_tool_sample_block_contains_wrappers() {
         let mut props = HashMap::new();
@@ -1889,6 +4842,32 @@ mod tests {
         assert!(block.contains("</tool_call>"));
     }
 
+    #[test]
+    fn build_tool_schema_block_lists_required_then_optional() {
+        let mut props = HashMap::new();
+        props.insert(
+            "path".to_string(),
+            json!({"type": "string", "description": "File path to read"}),
+        );
+        props.insert("encoding".to_string(), json!({"type": "string"}));
+        let tool = Tool {
+            name: "read_file".to_string(),
+            description: None,
+            input_schema: ToolInputSchema {
+                schema_type: "object".to_string(),
+                properties: Some(props),
+                required: Some(vec!["path".to_string()]),
+                additional_properties: None,
+            },
+        };
+
+        let block = ChatSession::build_tool_schema_block(&tool);
+        assert_eq!(
+            block,
+            "  path: string (required) - File path to read\n  encoding: string (optional)\n"
+        );
+    }
+
     #[test]
     fn select_write_tool_prefers_exact_match() {
         let mut props = HashMap::new();
@@ -1923,7 +4902,40 @@ mod tests {
             .map(|(server, tool)| (*server, tool))
             .collect();
         let selected = ChatSession::select_write_tool_name(&wrapped);
-        assert_eq!(selected.as_deref(), Some("write_file"));
+        assert_eq!(selected.first(), Some(&(0, "fs::write_file".to_string())));
+    }
+
+    #[test]
+    fn select_write_tool_keeps_ties_in_server_order() {
+        let make_tool = |name: &str| Tool {
+            name: name.to_string(),
+            description: None,
+            input_schema: ToolInputSchema {
+                schema_type: "object".to_string(),
+                properties: None,
+                required: None,
+                additional_properties: None,
+            },
+        };
+        let tools = [
+            ("server1", make_tool("write_file_v2")),
+            ("server1", make_tool("write_file")),
+            ("server2", make_tool("write_file")),
+        ];
+
+        let wrapped: Vec<(&str, &Tool)> = tools
+            .iter()
+            .map(|(server, tool)| (*server, tool))
+            .collect();
+        let selected = ChatSession::select_write_tool_name(&wrapped);
+        assert_eq!(
+            selected,
+            vec![
+                (0, "server1::write_file".to_string()),
+                (0, "server2::write_file".to_string()),
+                (1, "server1::write_file_v2".to_string()),
+            ]
+        );
     }
 
     #[test]
@@ -1951,6 +4963,488 @@ mod tests {
             .map(|(server, tool)| (*server, tool))
             .collect();
         let selected = ChatSession::select_write_tool_name(&wrapped);
-        assert_eq!(selected.as_deref(), Some("store"));
+        assert_eq!(selected, vec![(2, "fs::store".to_string())]);
+    }
+
+    #[test]
+    fn select_shell_tool_prefers_exact_match() {
+        let mut props = HashMap::new();
+        props.insert("command".to_string(), json!({"type": "string"}));
+
+        let tools = [
+            ("sys", Tool {
+                name: "invoke".to_string(),
+                description: None,
+                input_schema: ToolInputSchema {
+                    schema_type: "object".to_string(),
+                    properties: Some(props.clone()),
+                    required: None,
+                    additional_properties: None,
+                },
+            }),
+            ("sys", Tool {
+                name: "run_command".to_string(),
+                description: None,
+                input_schema: ToolInputSchema {
+                    schema_type: "object".to_string(),
+                    properties: Some(props.clone()),
+                    required: None,
+                    additional_properties: None,
+                },
+            }),
+        ];
+
+        let wrapped: Vec<(&str, &Tool)> = tools
+            .iter()
+            .map(|(server, tool)| (*server, tool))
+            .collect();
+        let selected = ChatSession::select_shell_tool_name(&wrapped);
+        assert_eq!(selected.as_deref(), Some("run_command"));
+    }
+
+    #[test]
+    fn recover_timeout_returns_partial_output() {
+        let mut truncated = false;
+        let err: anyhow::Error = InferenceTimeout {
+            partial_output: "partial".to_string(),
+            timeout: Duration::from_secs(1),
+        }
+        .into();
+        let result = recover_timeout(Err(err), &mut truncated);
+        assert_eq!(result.unwrap(), "partial");
+        assert!(truncated);
+
+        let mut truncated = false;
+        let result = recover_timeout(Err(anyhow::anyhow!("boom")), &mut truncated);
+        assert!(result.is_err());
+        assert!(!truncated);
+    }
+
+    #[test]
+    fn contents_equal_ignores_line_endings_and_trailing_spaces() {
+        assert!(contents_equal("a\r\nb\r\n", "a\nb\n"));
+        assert!(contents_equal("a\rb\r", "a\nb"));
+        assert!(contents_equal("a\r\nb\rc\n", "a\nb\nc"));
+        assert!(contents_equal("a  \nb\t\n", "a\nb"));
+        assert!(!contents_equal("a\nb", "a\n b"));
+        assert!(!contents_equal("a\n\nb", "a\nb"));
+    }
+
+    #[test]
+    fn record_tool_failure_gives_up_on_second_failure() {
+        let mut counts = HashMap::new();
+        assert!(record_tool_failure(&mut counts, "search").is_none());
+        assert!(record_tool_failure(&mut counts, "fetch").is_none());
+
+        let give_up = record_tool_failure(&mut counts, "search").unwrap();
+        assert!(!give_up.success);
+        assert!(give_up.output.contains("Tool search failed twice"));
+    }
+
+    #[test]
+    fn undo_files_declined_leaves_files_and_passes_input_on() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("notes.txt");
+        std::fs::write(&path, "current").unwrap();
+
+        let handled = undo_files(std::slice::from_ref(&path), |_| Ok(false)).unwrap();
+
+        assert!(!handled);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "current");
+    }
+
+    #[test]
+    fn extract_shell_command_prefers_backticks() {
+        assert_eq!(
+            ChatSession::extract_shell_command("`ls -la` を実行して").as_deref(),
+            Some("ls -la")
+        );
+        assert_eq!(ChatSession::extract_shell_command("please run cargo build"), None);
+        assert_eq!(ChatSession::extract_shell_command("`  ` を実行して"), None);
+    }
+
+    #[test]
+    fn strip_tool_calls_removes_plain_block() {
+        let text = "before <tool_call name=\"foo\">{\"a\": 1}</tool_call> after";
+        assert_eq!(ChatSession::strip_tool_calls(text), "before  after");
+    }
+
+    #[test]
+    fn strip_tool_calls_allows_spaces_around_equals() {
+        let text = "before <tool_call name = \"foo\">\n{}\n</tool_call> after";
+        assert_eq!(ChatSession::strip_tool_calls(text), "before  after");
+    }
+
+    #[test]
+    fn strip_tool_calls_is_case_insensitive() {
+        let text = "before <TOOL_CALL NAME=\"foo\">{}</TOOL_CALL> after";
+        assert_eq!(ChatSession::strip_tool_calls(text), "before  after");
+
+        let text = "before <Tool_Call name=\"foo\">{}</tool_call> after";
+        assert_eq!(ChatSession::strip_tool_calls(text), "before  after");
+    }
+
+    #[test]
+    fn layout_input_lines_wraps_and_pads() {
+        let mut buffer = InputBuffer::default();
+        buffer.insert_str("abcdef\ngh");
+        let lines = layout_input_lines("> ", "  ", 2, 2, 6, &buffer);
+        assert_eq!(lines, vec!["", "> abcd", "  ef", "  gh", ""]);
+    }
+
+    #[test]
+    fn layout_positions_cache_is_invalidated_on_edit() {
+        let mut buffer = InputBuffer::default();
+        buffer.insert_str("ab");
+        assert_eq!(buffer.layout_positions(2, 2, 80), vec![(0, 2), (0, 3), (0, 4)]);
+        assert!(buffer.layout_cache.borrow().is_some());
+
+        buffer.backspace();
+        assert!(buffer.layout_cache.borrow().is_none());
+        assert_eq!(buffer.layout_positions(2, 2, 80), vec![(0, 2), (0, 3)]);
+        assert_eq!(buffer.layout_positions(2, 2, 2), vec![(0, 2), (1, 3)]);
+    }
+
+    #[test]
+    fn insert_str_normalizes_nfd_to_nfc() {
+        let mut buffer = InputBuffer::default();
+        // "がぎ" を NFD（か + 濁点の結合文字）で貼り付ける
+        buffer.insert_str("\u{304B}\u{3099}\u{304D}\u{3099}");
+        assert_eq!(buffer.to_string(), "がぎ");
+        assert_eq!(buffer.graphemes.len(), 2);
+        assert_eq!(buffer.cursor, 2);
+        assert_eq!(buffer.layout_positions(2, 2, 80).last(), Some(&(0, 6)));
+    }
+
+    #[test]
+    fn layout_positions_bidi_reorders_rtl_text() {
+        let mut buffer = InputBuffer {
+            bidi_reorder: true,
+            ..Default::default()
+        };
+        buffer.insert_str("ab");
+        assert_eq!(buffer.layout_positions(2, 2, 80), vec![(0, 2), (0, 3), (0, 4)]);
+
+        // ヘブライ文字は右から左に並ぶため、論理順で進むほどカーソルは左へ移る
+        let mut buffer = InputBuffer {
+            bidi_reorder: true,
+            ..Default::default()
+        };
+        buffer.insert_str("אבג");
+        assert_eq!(
+            buffer.layout_positions(2, 2, 80),
+            vec![(0, 5), (0, 4), (0, 3), (0, 2)]
+        );
+
+        buffer.bidi_reorder = false;
+        buffer.invalidate_layout();
+        assert_eq!(
+            buffer.layout_positions(2, 2, 80),
+            vec![(0, 2), (0, 3), (0, 4), (0, 5)]
+        );
+    }
+
+    #[test]
+    fn continuation_prompt_quotes_last_50_chars() {
+        let continuation = Continuation {
+            query: "Explain ownership".to_string(),
+            response: format!("{}{}", "x".repeat(10), "所有権".repeat(20)),
+        };
+        let prompt = continuation.prompt();
+        assert!(prompt.starts_with("Explain ownership\n\n"));
+        assert!(prompt.contains(&continuation.response));
+        let tail = prompt.rsplit("Continue from: ").next().unwrap();
+        assert_eq!(tail.chars().count(), 50);
+        assert!(!tail.contains('x'));
+    }
+
+    #[test]
+    fn continuation_keeps_only_the_tail_within_budget() {
+        let response = format!("{}{}", "head ".repeat(100), "tail ".repeat(10));
+        let continuation = Continuation::new("q".to_string(), response.clone(), 20);
+        assert!(estimate_tokens_v2(&continuation.response) <= 20);
+        assert!(continuation.response.ends_with("tail "));
+        assert!(response.ends_with(&continuation.response));
+
+        let short = Continuation::new("q".to_string(), "short".to_string(), 20);
+        assert_eq!(short.response, "short");
+    }
+
+    #[test]
+    fn context_usage_color_warns_near_limit() {
+        assert_eq!(context_usage_color(30), crossterm::style::Color::DarkGrey);
+        assert_eq!(context_usage_color(80), crossterm::style::Color::DarkGrey);
+        assert_eq!(context_usage_color(81), crossterm::style::Color::Yellow);
+        assert_eq!(context_usage_color(96), crossterm::style::Color::Red);
+    }
+
+    #[test]
+    fn join_output_dir_keeps_paths_inside_output_dir() {
+        assert_eq!(
+            join_output_dir("generated", "src/lib.rs").as_deref(),
+            Some("generated/src/lib.rs")
+        );
+        assert_eq!(
+            join_output_dir("/abs/out", "./src/../lib.rs").as_deref(),
+            Some("/abs/out/lib.rs")
+        );
+        assert_eq!(join_output_dir("generated", "../../x"), None);
+        assert_eq!(join_output_dir("generated", "src/../../x"), None);
+        assert_eq!(join_output_dir("generated", "/tmp/out.txt"), None);
+        assert_eq!(join_output_dir("generated", "~/notes.md"), None);
+    }
+
+    #[test]
+    fn write_tool_args_uses_source_path_when_staged() {
+        assert_eq!(
+            write_tool_args("out.txt", "hello", None),
+            json!({ "path": "out.txt", "content": "hello" })
+        );
+        assert_eq!(
+            write_tool_args("out.txt", "hello", Some("/tmp/staged.txt")),
+            json!({ "path": "out.txt", "source_path": "/tmp/staged.txt" })
+        );
+    }
+
+    #[test]
+    fn write_kind_for_tool_detects_append_tools() {
+        assert_eq!(WriteKind::for_tool("write_file", false), WriteKind::Created);
+        assert_eq!(WriteKind::for_tool("write_file", true), WriteKind::Updated);
+        assert_eq!(WriteKind::for_tool("append_file", true), WriteKind::Appended);
+        assert_eq!(WriteKind::for_tool("appendToFile", false), WriteKind::Appended);
+    }
+
+    #[test]
+    fn load_or_init_prefers_set_value_over_default() {
+        let value = AtomicUsize::new(0);
+        assert_eq!(load_or_init(&value, "RKLLM_TEST_UNSET_TOKENS", 4096), 4096);
+
+        value.store(8192, Ordering::Relaxed);
+        assert_eq!(load_or_init(&value, "RKLLM_TEST_UNSET_TOKENS", 4096), 8192);
+    }
+
+    #[test]
+    fn redraw_debounce_defers_rapid_redraws() {
+        let start = Instant::now();
+        let mut debounce = RedrawDebounce::default();
+        let idle = Duration::from_millis(100);
+
+        assert!(debounce.should_redraw(start));
+        assert!(!debounce.take_pending(start));
+        assert_eq!(debounce.poll_timeout(start, idle), idle);
+
+        let soon = start + Duration::from_millis(5);
+        assert!(!debounce.should_redraw(soon));
+        assert_eq!(debounce.poll_timeout(soon, idle), Duration::from_millis(11));
+        assert!(debounce.take_pending(soon));
+        assert!(!debounce.take_pending(soon));
+
+        assert!(debounce.should_redraw(soon + REDRAW_DEBOUNCE));
+    }
+
+    #[test]
+    fn confirm_answer_accepts_always() {
+        assert_eq!(ConfirmAnswer::parse("y\n"), ConfirmAnswer::Yes);
+        assert_eq!(ConfirmAnswer::parse(" A \n"), ConfirmAnswer::Always);
+        assert_eq!(ConfirmAnswer::parse("\n"), ConfirmAnswer::No);
+        assert_eq!(ConfirmAnswer::parse("yes"), ConfirmAnswer::No);
+    }
+
+    #[test]
+    fn with_prefix_emits_prefix_once_before_first_chunk() {
+        let received = Arc::new(Mutex::new(String::new()));
+        let sink = Arc::clone(&received);
+        let mut on_chunk = with_prefix(Some("[AI] ".to_string()), move |text| {
+            sink.lock().unwrap().push_str(text);
+        });
+
+        on_chunk("");
+        on_chunk("hello\n");
+        on_chunk("world");
+
+        assert_eq!(*received.lock().unwrap(), "[AI] hello\nworld");
+    }
+
+    #[test]
+    fn model_display_name_prefers_alias_over_file_stem() {
+        let path = "/home/user/models/Qwen2.5-7B-Instruct.rkllm";
+        assert_eq!(model_display_name(path, Some("qwen7b")), "qwen7b");
+        assert_eq!(model_display_name(path, None), "Qwen2.5-7B-Instruct");
+        assert_eq!(model_display_name(path, Some("  ")), "Qwen2.5-7B-Instruct");
+    }
+
+    #[test]
+    fn format_result_message_reports_success_and_stderr() {
+        assert_eq!(format_result_message("a.rs", Ok(())), "[Formatted: a.rs (saved)]");
+        assert_eq!(
+            format_result_message("a.py", Err(anyhow::anyhow!("cannot parse"))),
+            "[Format warning: a.py: cannot parse]"
+        );
+    }
+
+    #[test]
+    fn format_token_breakdown_lists_sections_and_files() {
+        let prompt = "<system>\nBe brief.\n</system>\n\n<files>\n<file path=\"a.rs\">\nfn main() {}\n</file>\n\n<file path=\"b.txt\">\nhello world\n</file>\n\n</files>\n\n<user_input>\nexplain </files>\n</user_input>";
+
+        let breakdown = prompt_token_breakdown(prompt);
+        let labels: Vec<&str> = breakdown.iter().map(|(label, _)| label.as_str()).collect();
+        assert_eq!(labels, ["system", "files", "file a.rs", "file b.txt", "input"]);
+
+        let message = format_token_breakdown(prompt, 4096);
+        assert!(message.starts_with("[Tokens] system: "));
+        assert!(message.contains(&format!("total: {} / 4096", estimate_tokens_v2(prompt))));
+        assert!(message.contains("\n  file a.rs: "));
+    }
+
+    #[test]
+    fn estimate_tokens_v2_english() {
+        let tokens = estimate_tokens_v2("The quick brown fox jumps over the lazy dog.");
+        assert_eq!(tokens, 13);
+        assert_eq!(estimate_tokens_v2(""), 0);
+    }
+
+    #[test]
+    fn estimate_tokens_v2_japanese() {
+        assert_eq!(estimate_tokens_v2("今日は良い天気です。"), 10);
+    }
+
+    #[test]
+    fn truncate_file_content_fits_token_limit() {
+        let content = "今日は良い天気です。".repeat(50) + &"word ".repeat(200);
+        let (truncated, kept_tokens, was_truncated) = truncate_file_content(&content, 100);
+        assert!(was_truncated);
+        assert!(kept_tokens <= 100, "kept {} tokens", kept_tokens);
+        assert_eq!(estimate_tokens_v2(&truncated), kept_tokens);
+        assert!(truncated.starts_with("今日は"));
+        assert!(truncated.ends_with("word "));
+        assert!(truncated.contains("[...truncated...]"));
+
+        let (kept, _, was_truncated) = truncate_file_content("short text", 100);
+        assert!(!was_truncated);
+        assert_eq!(kept, "short text");
+    }
+
+    #[test]
+    fn estimate_tokens_v2_mixed() {
+        assert_eq!(estimate_tokens_v2("README.md を日本語に翻訳して"), 13);
+    }
+
+    #[test]
+    fn trim_history_drops_oldest_turns_first() {
+        let turns: Vec<(String, String)> = (0..4)
+            .map(|i| (format!("question {}", i), "answer ".repeat(20)))
+            .collect();
+
+        assert_eq!(trim_history_to_budget(&turns, &[], 10_000).len(), 4);
+
+        let kept = trim_history_to_budget(&turns, &[], 70);
+        assert!(!kept.is_empty() && kept.len() < 4);
+        assert_eq!(kept.last().unwrap().0, "question 3");
+    }
+
+    #[test]
+    fn allocate_capped_redistributes_surplus() {
+        // 上限なしの比例配分: 9000 と 1000 に 1000 を配分
+        assert_eq!(allocate_capped(&[9000, 1000], &[9000, 1000], 1000), vec![900, 100]);
+        // 大きいファイルを 500 で頭打ちにし、余りを小さいファイルへ回す
+        assert_eq!(allocate_capped(&[9000, 1000], &[500, 1000], 1000), vec![500, 500]);
+        // 頭打ちにした余りは残りのファイルで比例配分する
+        assert_eq!(
+            allocate_capped(&[9000, 1000, 1000], &[500, 1000, 1000], 1500),
+            vec![500, 500, 500]
+        );
+    }
+
+    #[test]
+    fn truncate_files_to_budget_applies_per_file_cap() {
+        let files = vec![
+            crate::file_ops::FileContent {
+                content: "a".repeat(3000),
+                original_path: "big.txt".to_string(),
+            },
+            crate::file_ops::FileContent {
+                content: "b".repeat(30),
+                original_path: "small.txt".to_string(),
+            },
+        ];
+
+        let (kept, notices) = truncate_files_to_budget(&files, 10_000, None);
+        assert_eq!(kept[0].content, files[0].content);
+        assert!(notices.is_empty());
+
+        // 予算内でも上限を超えるファイルは切り詰める
+        let (kept, notices) = truncate_files_to_budget(&files, 10_000, Some(100));
+        assert!(kept[0].content.len() < files[0].content.len());
+        assert_eq!(kept[1].content, files[1].content);
+        assert_eq!(notices.len(), 1);
+        assert_eq!(notices[0].path, "big.txt");
+        let message = notices[0].to_string();
+        assert!(message.starts_with("[Truncated: big.txt ("));
+        assert!(message.ends_with(" tokens, kept head+tail)]"));
+    }
+
+    #[tokio::test]
+    async fn builder_rejects_missing_model() {
+        let err = ChatSessionBuilder::new("/nonexistent/model.rkllm")
+            .confirm_writes(false)
+            .tool_only(true)
+            .build()
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("Model file not found"));
+    }
+
+    mod prop {
+        use super::*;
+        use proptest::prelude::*;
+
+        #[derive(Debug, Clone)]
+        enum Op {
+            Insert(String),
+            Backspace,
+            Delete,
+            Left,
+            Right,
+            Vertical(isize),
+        }
+
+        // 1書記素が1文字になる文字だけを使う（ASCII、改行、CJK、ひらがな、絵文字）
+        const TEXT: &str = "[a-zA-Z0-9 \n一-龥ぁ-ゖ😀-🙏]{0,8}";
+
+        fn op() -> impl Strategy<Value = Op> {
+            prop_oneof![
+                TEXT.prop_map(Op::Insert),
+                Just(Op::Backspace),
+                Just(Op::Delete),
+                Just(Op::Left),
+                Just(Op::Right),
+                (-2isize..=2).prop_map(Op::Vertical),
+            ]
+        }
+
+        proptest! {
+            #[test]
+            fn input_buffer_keeps_cursor_in_bounds(
+                initial in TEXT,
+                ops in proptest::collection::vec(op(), 0..40),
+                term_width in 4usize..40,
+            ) {
+                let mut buffer = InputBuffer::default();
+                buffer.insert_str(&initial);
+                for op in ops {
+                    match op {
+                        Op::Insert(text) => buffer.insert_str(&text),
+                        Op::Backspace => { buffer.backspace(); }
+                        Op::Delete => { buffer.delete(); }
+                        Op::Left => { buffer.move_left(); }
+                        Op::Right => { buffer.move_right(); }
+                        Op::Vertical(delta) => { buffer.move_vertical(delta, 2, 2, term_width); }
+                    }
+                    prop_assert!(buffer.cursor <= buffer.graphemes.len());
+                    prop_assert!(buffer.to_string().len() <= buffer.graphemes.len() * 4);
+                }
+            }
+        }
     }
 }
@@ -1,18 +1,140 @@
-use crate::file_detector::default_extensions;
+use crate::file_detector::{default_deny_patterns, default_extensions};
+use crate::intent::DEFAULT_FILE_INTENT_THRESHOLD;
+use crate::tool_detector::CustomPattern;
 use directories::ProjectDirs;
 use serde::Deserialize;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
 #[derive(Debug, Clone)]
 pub struct AppConfig {
     pub detect_extensions: Vec<String>,
+    /// 拡張子に関わらず読み込まないパスの glob パターン（`[files] deny_patterns`）
+    pub file_detection_deny_patterns: Vec<String>,
+    /// `.gitignore` / `.rkllmignore` に一致するファイルを読み込み対象から除外する
+    pub ignore_gitignore: bool,
+    /// ファイル書き込みを監査ログ（writes.log）に記録する
+    pub audit_writes: bool,
+    /// 書き込み前に現在の内容との差分を表示し、確認を求める（`[files] preview_diff`）
+    pub preview_diff: bool,
+    /// ディレクトリ一覧に隠しファイルを含める（`[files] list_hidden`）
+    pub list_hidden_files: bool,
+    /// 一時ファイルへの書き込みとリネームでファイルを原子的に更新する（`[files] atomic_writes`）
+    pub atomic_writes: bool,
+    /// プロンプトに含める1ファイルあたりのトークン上限（`[files] max_tokens_per_file`）
+    pub max_tokens_per_file: Option<usize>,
+    /// ディレクトリをたどる階層の上限（`[files] max_depth`、`--max-file-depth`）
+    pub max_file_depth: usize,
+    /// 書き込み意図として追加で扱うキーワード（`[intent] write_keywords`）
+    pub intent_write_keywords: Vec<String>,
+    /// 読み込み意図として追加で扱うキーワード（`[intent] read_keywords`）
+    pub intent_read_keywords: Vec<String>,
+    /// ファイル検出を行う書き込み意図スコアの閾値（`[intent] threshold`）
+    pub intent_threshold: f32,
+    /// 推論パラメータの既定値（`[llm]`）。未指定の項目は `RKLLMConfig::default()` を使う
+    pub llm: LlmConfig,
+    /// 1ターンで実行するツール呼び出しラウンドの上限（`[tools] max_rounds`）
+    pub max_tool_rounds: usize,
+    /// プロンプトに戻すツール結果の最大バイト数（`[tools] result_max_bytes`）
+    pub tool_result_max_bytes: usize,
+    /// 組み込み以外のツール呼び出し形式（`[tools] custom_patterns`）
+    pub tool_custom_patterns: Vec<CustomPattern>,
 }
 
+/// 推論パラメータ（`[llm]` セクション、または CLI フラグ）
+#[derive(Debug, Clone, Default, Deserialize, PartialEq)]
+pub struct LlmConfig {
+    pub temperature: Option<f32>,
+    pub top_k: Option<i32>,
+    pub top_p: Option<f32>,
+    pub repeat_penalty: Option<f32>,
+    pub max_context_len: Option<i32>,
+    pub max_new_tokens: Option<i32>,
+    /// 推論に使う CPU コアのビットマスク（設定ファイルでは `"0xf0"` のような16進文字列）
+    #[serde(default, deserialize_with = "deserialize_cpu_mask")]
+    pub cpu_mask: Option<u32>,
+    /// 推論に使う CPU コア数
+    pub cpu_count: Option<i8>,
+    /// プロンプト処理（prefill）のバッチサイズ
+    pub batch_size: Option<u8>,
+    /// 応答に使う言語（例: `"English"`）。未指定なら日本語
+    pub response_language: Option<String>,
+}
+
+/// `"0xf0"` / `"f0"` 形式の16進文字列を CPU マスクとして解釈する（CLI の value_parser 兼用）
+pub fn parse_cpu_mask(value: &str) -> Result<u32, String> {
+    let trimmed = value.trim();
+    let digits = trimmed
+        .strip_prefix("0x")
+        .or_else(|| trimmed.strip_prefix("0X"))
+        .unwrap_or(trimmed);
+    let mask = u32::from_str_radix(digits, 16)
+        .map_err(|e| format!("invalid CPU mask '{}': {}", value, e))?;
+    if mask == 0 {
+        return Err(format!("invalid CPU mask '{}': no CPUs selected", value));
+    }
+    Ok(mask)
+}
+
+fn deserialize_cpu_mask<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value: Option<String> = Option::deserialize(deserializer)?;
+    value
+        .map(|v| parse_cpu_mask(&v).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+impl LlmConfig {
+    /// 未指定の項目を `fallback` の値で補う（CLI フラグ > 設定ファイルの順に優先するため）
+    pub fn or(self, fallback: &LlmConfig) -> LlmConfig {
+        LlmConfig {
+            temperature: self.temperature.or(fallback.temperature),
+            top_k: self.top_k.or(fallback.top_k),
+            top_p: self.top_p.or(fallback.top_p),
+            repeat_penalty: self.repeat_penalty.or(fallback.repeat_penalty),
+            max_context_len: self.max_context_len.or(fallback.max_context_len),
+            max_new_tokens: self.max_new_tokens.or(fallback.max_new_tokens),
+            cpu_mask: self.cpu_mask.or(fallback.cpu_mask),
+            cpu_count: self.cpu_count.or(fallback.cpu_count),
+            batch_size: self.batch_size.or(fallback.batch_size),
+            response_language: self
+                .response_language
+                .or_else(|| fallback.response_language.clone()),
+        }
+    }
+}
+
+/// ディレクトリをたどる階層の上限の既定値
+pub const DEFAULT_MAX_FILE_DEPTH: usize = 3;
+
+/// ツール呼び出しラウンド上限の既定値
+pub const DEFAULT_MAX_TOOL_ROUNDS: usize = 3;
+
+/// ツール結果の最大バイト数の既定値
+pub const DEFAULT_TOOL_RESULT_MAX_BYTES: usize = 8192;
+
 impl Default for AppConfig {
     fn default() -> Self {
         AppConfig {
             detect_extensions: default_extensions(),
+            file_detection_deny_patterns: default_deny_patterns(),
+            ignore_gitignore: false,
+            audit_writes: false,
+            preview_diff: false,
+            list_hidden_files: false,
+            atomic_writes: true,
+            max_tokens_per_file: None,
+            max_file_depth: DEFAULT_MAX_FILE_DEPTH,
+            intent_write_keywords: Vec::new(),
+            intent_read_keywords: Vec::new(),
+            intent_threshold: DEFAULT_FILE_INTENT_THRESHOLD,
+            llm: LlmConfig::default(),
+            max_tool_rounds: DEFAULT_MAX_TOOL_ROUNDS,
+            tool_result_max_bytes: DEFAULT_TOOL_RESULT_MAX_BYTES,
+            tool_custom_patterns: Vec::new(),
         }
     }
 }
@@ -20,62 +142,384 @@ impl Default for AppConfig {
 #[derive(Deserialize, Default)]
 struct FilesConfig {
     detect_extensions: Option<Vec<String>>,
+    deny_patterns: Option<Vec<String>>,
+    ignore_gitignore: Option<bool>,
+    audit_writes: Option<bool>,
+    preview_diff: Option<bool>,
+    list_hidden: Option<bool>,
+    atomic_writes: Option<bool>,
+    max_tokens_per_file: Option<usize>,
+    max_depth: Option<usize>,
+}
+
+#[derive(Deserialize, Default)]
+struct IntentConfig {
+    write_keywords: Option<Vec<String>>,
+    read_keywords: Option<Vec<String>>,
+    threshold: Option<f32>,
+}
+
+#[derive(Deserialize, Default)]
+struct ToolsConfig {
+    max_rounds: Option<usize>,
+    result_max_bytes: Option<usize>,
+    custom_patterns: Option<Vec<CustomPattern>>,
 }
 
 #[derive(Deserialize, Default)]
 struct RawConfig {
     files: Option<FilesConfig>,
+    intent: Option<IntentConfig>,
+    llm: Option<LlmConfig>,
+    tools: Option<ToolsConfig>,
 }
 
+/// プロセス全体で共有する設定（最初の `AppConfig::global()` で読み込む）
+static GLOBAL: OnceLock<AppConfig> = OnceLock::new();
+
 impl AppConfig {
-    pub fn load() -> Self {
+    /// 設定ファイルを一度だけ読み込み、以降は同じ値を返す
+    pub fn global() -> &'static AppConfig {
+        GLOBAL.get_or_init(AppConfig::load)
+    }
+
+    /// 設定ファイルを読み込む
+    ///
+    /// `~/.config/rkllm-cli/config.toml`、`RKLLM_CONFIG` の順に読み込んでマージする（後の値が優先）。
+    /// 解釈できないファイルは無視し、マージ後の設定に問題があれば既定値を使う。
+    fn load() -> Self {
         let mut config = AppConfig::default();
 
-        if let Some(path) = config_path() {
-            match fs::read_to_string(&path) {
-                Ok(content) => {
-                    match toml::from_str::<RawConfig>(&content) {
-                        Ok(raw) => {
-                            if let Some(files) = raw.files {
-                                if let Some(exts) = normalize_exts(files.detect_extensions) {
-                                    config.detect_extensions = exts;
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!(
-                                "[Config] Failed to parse config file '{}': {} (falling back to defaults)",
-                                path.display(),
-                                e
-                            );
-                        }
-                    }
-                }
-                Err(e) => {
-                    if std::env::var("RKLLM_DEBUG_CONFIG").is_ok() {
-                        eprintln!(
-                            "[Config] Could not read config file '{}': {} (using defaults)",
-                            path.display(),
-                            e
-                        );
-                    }
-                }
-            }
-
-            if std::env::var("RKLLM_DEBUG_CONFIG").is_ok() {
+        let paths = config_path();
+        if paths.is_empty() {
+            return config;
+        }
+        if let Some(raw) = load_merged(&paths) {
+            config.apply(raw);
+        }
+
+        if std::env::var("RKLLM_DEBUG_CONFIG").is_ok() {
+            eprintln!(
+                "[Config] Loaded detect_extensions: {:?}",
+                config.detect_extensions
+            );
+        }
+
+        config
+    }
+
+    /// 設定ファイルで指定された値で上書きする
+    fn apply(&mut self, raw: RawConfig) {
+        if let Some(files) = raw.files {
+            if let Some(exts) = normalize_exts(files.detect_extensions) {
+                self.detect_extensions = exts;
+            }
+            if let Some(patterns) = files.deny_patterns {
+                self.file_detection_deny_patterns = patterns;
+            }
+            if let Some(ignore) = files.ignore_gitignore {
+                self.ignore_gitignore = ignore;
+            }
+            if let Some(audit) = files.audit_writes {
+                self.audit_writes = audit;
+            }
+            if let Some(preview) = files.preview_diff {
+                self.preview_diff = preview;
+            }
+            if let Some(hidden) = files.list_hidden {
+                self.list_hidden_files = hidden;
+            }
+            if let Some(atomic) = files.atomic_writes {
+                self.atomic_writes = atomic;
+            }
+            if files.max_tokens_per_file.is_some() {
+                self.max_tokens_per_file = files.max_tokens_per_file;
+            }
+            if let Some(depth) = files.max_depth {
+                self.max_file_depth = depth;
+            }
+        }
+        if let Some(intent) = raw.intent {
+            if let Some(keywords) = intent.write_keywords {
+                self.intent_write_keywords = keywords;
+            }
+            if let Some(keywords) = intent.read_keywords {
+                self.intent_read_keywords = keywords;
+            }
+            if let Some(threshold) = intent.threshold {
+                self.intent_threshold = threshold;
+            }
+        }
+        if let Some(llm) = raw.llm {
+            self.llm = llm;
+        }
+        if let Some(tools) = raw.tools {
+            if let Some(rounds) = tools.max_rounds {
+                self.max_tool_rounds = rounds;
+            }
+            if let Some(bytes) = tools.result_max_bytes {
+                self.tool_result_max_bytes = bytes;
+            }
+            if let Some(patterns) = tools.custom_patterns {
+                self.tool_custom_patterns = patterns;
+            }
+        }
+    }
+}
+
+impl RawConfig {
+    /// `other` で指定された値を優先して2つの設定を合わせる（後から読み込んだファイルが優先）
+    fn merge(self, other: RawConfig) -> RawConfig {
+        RawConfig {
+            files: merge_section(self.files, other.files, FilesConfig::merge),
+            intent: merge_section(self.intent, other.intent, IntentConfig::merge),
+            llm: merge_section(self.llm, other.llm, |base, over| over.or(&base)),
+            tools: merge_section(self.tools, other.tools, ToolsConfig::merge),
+        }
+    }
+}
+
+impl FilesConfig {
+    fn merge(self, other: FilesConfig) -> FilesConfig {
+        FilesConfig {
+            detect_extensions: other.detect_extensions.or(self.detect_extensions),
+            deny_patterns: other.deny_patterns.or(self.deny_patterns),
+            ignore_gitignore: other.ignore_gitignore.or(self.ignore_gitignore),
+            audit_writes: other.audit_writes.or(self.audit_writes),
+            preview_diff: other.preview_diff.or(self.preview_diff),
+            list_hidden: other.list_hidden.or(self.list_hidden),
+            atomic_writes: other.atomic_writes.or(self.atomic_writes),
+            max_tokens_per_file: other.max_tokens_per_file.or(self.max_tokens_per_file),
+            max_depth: other.max_depth.or(self.max_depth),
+        }
+    }
+}
+
+impl IntentConfig {
+    fn merge(self, other: IntentConfig) -> IntentConfig {
+        IntentConfig {
+            write_keywords: other.write_keywords.or(self.write_keywords),
+            read_keywords: other.read_keywords.or(self.read_keywords),
+            threshold: other.threshold.or(self.threshold),
+        }
+    }
+}
+
+impl ToolsConfig {
+    fn merge(self, other: ToolsConfig) -> ToolsConfig {
+        ToolsConfig {
+            max_rounds: other.max_rounds.or(self.max_rounds),
+            result_max_bytes: other.result_max_bytes.or(self.result_max_bytes),
+            custom_patterns: other.custom_patterns.or(self.custom_patterns),
+        }
+    }
+}
+
+/// 両方にあるセクションは項目ごとに `merge` し、片方にしかなければそれを使う
+fn merge_section<T>(base: Option<T>, other: Option<T>, merge: impl FnOnce(T, T) -> T) -> Option<T> {
+    match (base, other) {
+        (Some(base), Some(other)) => Some(merge(base, other)),
+        (base, other) => other.or(base),
+    }
+}
+
+/// 設定ファイルを順に読み込んでマージし、マージ後の設定を検証する
+///
+/// ファイルをまたぐ組み合わせ（`cpu_mask` と `cpu_count` など）も検証できるよう、
+/// 検証はファイルごとではなくマージ後に一度だけ行う。
+///
+/// # 戻り値
+/// 検証エラーがある場合は None（理由は標準エラー出力に表示する）
+fn load_merged(paths: &[PathBuf]) -> Option<RawConfig> {
+    let raw = paths
+        .iter()
+        .filter_map(|path| load_raw(path))
+        .fold(RawConfig::default(), RawConfig::merge);
+
+    let errors = validate(&raw);
+    if errors.is_empty() {
+        return Some(raw);
+    }
+    let files = paths
+        .iter()
+        .map(|path| format!("'{}'", path.display()))
+        .collect::<Vec<_>>()
+        .join(", ");
+    eprintln!(
+        "[Config] Invalid config in {} ({} error(s), using defaults):",
+        files,
+        errors.len()
+    );
+    for error in errors {
+        eprintln!("  - {}", error);
+    }
+    None
+}
+
+/// 設定ファイルを1つ読み込む（検証はマージ後に `load_merged` で行う）
+///
+/// # 戻り値
+/// 読み込めない、解釈できない場合は None（理由は標準エラー出力に表示する）
+fn load_raw(path: &Path) -> Option<RawConfig> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) => {
+            // 既定の場所にファイルがないのは普通なので、デバッグ時のみ表示する
+            if std::env::var("RKLLM_DEBUG_CONFIG").is_ok() || is_env_config_path(path) {
                 eprintln!(
-                    "[Config] Loaded detect_extensions: {:?}",
-                    config.detect_extensions
+                    "[Config] Could not read config file '{}': {} (ignoring it)",
+                    path.display(),
+                    e
                 );
             }
+            return None;
         }
+    };
 
-        config
+    match toml::from_str::<RawConfig>(&content) {
+        Ok(raw) => Some(raw),
+        Err(e) => {
+            eprintln!(
+                "[Config] Failed to parse config file '{}': {} (ignoring it)",
+                path.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+/// 設定値を検証し、問題をすべて人が読める形で返す（空なら問題なし）
+fn validate(raw: &RawConfig) -> Vec<String> {
+    let mut errors = Vec::new();
+
+    if let Some(files) = &raw.files {
+        for ext in files.detect_extensions.iter().flatten() {
+            let trimmed = ext.trim();
+            if trimmed.is_empty() || !trimmed.chars().all(|c| c.is_ascii_alphanumeric()) {
+                errors.push(format!(
+                    "[files] detect_extensions: '{}' must be alphanumeric (without the leading dot)",
+                    ext
+                ));
+            }
+        }
+        for pattern in files.deny_patterns.iter().flatten() {
+            if let Err(e) = glob::Pattern::new(pattern) {
+                errors.push(format!("[files] deny_patterns: invalid glob '{}': {}", pattern, e));
+            }
+        }
+        if files.max_tokens_per_file == Some(0) {
+            errors.push("[files] max_tokens_per_file: must be >= 1".to_string());
+        }
+        if files.max_depth == Some(0) {
+            errors.push("[files] max_depth: must be >= 1".to_string());
+        }
+    }
+
+    if let Some(intent) = &raw.intent {
+        let keywords = [
+            ("write_keywords", &intent.write_keywords),
+            ("read_keywords", &intent.read_keywords),
+        ];
+        for (key, list) in keywords {
+            if list.iter().flatten().any(|kw| kw.trim().is_empty()) {
+                errors.push(format!("[intent] {}: keywords must not be empty", key));
+            }
+        }
+        if let Some(v) = intent.threshold
+            && !(0.0..=1.0).contains(&v)
+        {
+            errors.push(format!("[intent] threshold: {} must be in [0, 1]", v));
+        }
+    }
+
+    if let Some(llm) = &raw.llm {
+        if let Some(v) = llm.temperature
+            && !(v.is_finite() && v >= 0.0)
+        {
+            errors.push(format!("[llm] temperature: {} must be >= 0", v));
+        }
+        if let Some(v) = llm.top_k
+            && v < 1
+        {
+            errors.push(format!("[llm] top_k: {} must be >= 1", v));
+        }
+        if let Some(v) = llm.top_p
+            && !(v > 0.0 && v <= 1.0)
+        {
+            errors.push(format!("[llm] top_p: {} must be in (0, 1]", v));
+        }
+        if let Some(v) = llm.repeat_penalty
+            && !(v.is_finite() && v > 0.0)
+        {
+            errors.push(format!("[llm] repeat_penalty: {} must be > 0", v));
+        }
+        if let Some(v) = llm.max_context_len
+            && v < 1
+        {
+            errors.push(format!("[llm] max_context_len: {} must be >= 1", v));
+        }
+        if let Some(v) = llm.max_new_tokens
+            && v < 1
+            && v != -1
+        {
+            errors.push(format!("[llm] max_new_tokens: {} must be >= 1 (or -1 for unlimited)", v));
+        }
+        if let Some(v) = llm.cpu_count {
+            let max = llm.cpu_mask.map_or(32, |mask| mask.count_ones() as i8);
+            if v < 1 || v > max {
+                errors.push(format!("[llm] cpu_count: {} must be between 1 and {}", v, max));
+            }
+        }
+        if let Some(v) = llm.batch_size
+            && !(1..=100).contains(&v)
+        {
+            errors.push(format!("[llm] batch_size: {} must be between 1 and 100", v));
+        }
     }
+
+    if let Some(tools) = &raw.tools {
+        if let Some(v) = tools.max_rounds
+            && v < 1
+        {
+            errors.push(format!("[tools] max_rounds: {} must be >= 1", v));
+        }
+        if let Some(v) = tools.result_max_bytes
+            && v < 1
+        {
+            errors.push(format!("[tools] result_max_bytes: {} must be >= 1", v));
+        }
+        for pattern in tools.custom_patterns.iter().flatten() {
+            if pattern.open.trim().is_empty() || pattern.close.trim().is_empty() {
+                errors.push(format!(
+                    "[tools] custom_patterns: open '{}' and close '{}' must not be empty",
+                    pattern.open, pattern.close
+                ));
+            }
+        }
+    }
+
+    errors
+}
+
+/// 読み込む設定ファイルの候補（XDG の設定ファイル、`RKLLM_CONFIG` の順。後の方が優先）
+fn config_path() -> Vec<PathBuf> {
+    ProjectDirs::from("", "", "rkllm-cli")
+        .map(|dirs| dirs.config_dir().join("config.toml"))
+        .into_iter()
+        .chain(env_config_path())
+        .collect()
+}
+
+/// `RKLLM_CONFIG` で指定された設定ファイル
+fn env_config_path() -> Option<PathBuf> {
+    std::env::var_os("RKLLM_CONFIG")
+        .filter(|v| !v.is_empty())
+        .map(PathBuf::from)
 }
 
-fn config_path() -> Option<PathBuf> {
-    ProjectDirs::from("", "", "rkllm-cli").map(|dirs| dirs.config_dir().join("config.toml"))
+fn is_env_config_path(path: &Path) -> bool {
+    env_config_path().is_some_and(|env_path| env_path == path)
 }
 
 fn normalize_exts(exts: Option<Vec<String>>) -> Option<Vec<String>> {
@@ -121,6 +565,11 @@ mod tests {
         }
     }
 
+    #[test]
+    fn global_is_loaded_once() {
+        assert!(std::ptr::eq(AppConfig::global(), AppConfig::global()));
+    }
+
     #[test]
     fn normalize_accepts_custom_and_dedupes() {
         let exts = Some(vec!["RS".into(), "toml".into(), "rs".into(), "invalid-ext".into()]);
@@ -135,4 +584,133 @@ mod tests {
         let normalized = normalize_exts(Some(vec![])).unwrap();
         assert!(normalized.is_empty());
     }
+
+    #[test]
+    fn ignore_gitignore_defaults_to_false() {
+        let cfg = AppConfig::default();
+        assert!(!cfg.ignore_gitignore);
+
+        let raw: RawConfig = toml::from_str("[files]\nignore_gitignore = true\n").unwrap();
+        assert_eq!(raw.files.unwrap().ignore_gitignore, Some(true));
+    }
+
+    #[test]
+    fn parse_intent_keywords() {
+        let raw: RawConfig = toml::from_str(
+            "[intent]\nwrite_keywords = [\"speichern\", \"créer\"]\nread_keywords = [\"lesen\"]\n",
+        )
+        .unwrap();
+        let intent = raw.intent.unwrap();
+        assert_eq!(
+            intent.write_keywords,
+            Some(vec!["speichern".to_string(), "créer".to_string()])
+        );
+        assert_eq!(intent.read_keywords, Some(vec!["lesen".to_string()]));
+    }
+
+    #[test]
+    fn parse_llm_section_and_cli_precedence() {
+        let raw: RawConfig = toml::from_str(
+            "[llm]\ntemperature = 0.8\ntop_k = 64\ntop_p = 0.95\nrepeat_penalty = 1.1\nmax_context_len = 4096\nmax_new_tokens = 2048\nresponse_language = \"English\"\n",
+        )
+        .unwrap();
+        let file = raw.llm.unwrap();
+        assert_eq!(file.temperature, Some(0.8));
+        assert_eq!(file.top_k, Some(64));
+        assert_eq!(file.max_new_tokens, Some(2048));
+
+        let cli = LlmConfig {
+            temperature: Some(0.2),
+            ..Default::default()
+        };
+        let merged = cli.or(&file);
+        assert_eq!(merged.temperature, Some(0.2));
+        assert_eq!(merged.top_k, Some(64));
+        assert_eq!(merged.repeat_penalty, Some(1.1));
+        assert_eq!(merged.response_language.as_deref(), Some("English"));
+    }
+
+    #[test]
+    fn validate_reports_every_error() {
+        let raw: RawConfig = toml::from_str(
+            "[files]\ndetect_extensions = [\"rs\", \".md\"]\ndeny_patterns = [\"[\"]\nmax_tokens_per_file = 0\n\
+             [intent]\nthreshold = 1.5\n\
+             [llm]\ntop_p = 1.5\nbatch_size = 0\ncpu_mask = \"0x3\"\ncpu_count = 4\n\
+             [tools]\nmax_rounds = 0\nresult_max_bytes = 0\ncustom_patterns = [{ open = \"\", close = \">>>\" }]\n",
+        )
+        .unwrap();
+        let errors = validate(&raw);
+        assert_eq!(errors.len(), 10, "{:?}", errors);
+        assert!(errors.iter().any(|e| e.contains("'.md'")));
+        assert!(errors.iter().any(|e| e.starts_with("[llm] cpu_count")));
+
+        let raw: RawConfig = toml::from_str(
+            "[files]\ndetect_extensions = [\"rs\"]\nmax_tokens_per_file = 1000\n[llm]\ntemperature = 0.7\nmax_new_tokens = -1\n\
+             [tools]\nmax_rounds = 10\nresult_max_bytes = 16384\n\
+             custom_patterns = [{ open = \"<<<TOOL: {name}>>>\", close = \"<<<END_TOOL>>>\", body_format = \"json\" }]\n",
+        )
+        .unwrap();
+        assert!(validate(&raw).is_empty());
+    }
+
+    #[test]
+    fn parse_cpu_mask_from_config_and_cli() {
+        let raw: RawConfig = toml::from_str("[llm]\ncpu_mask = \"0xf0\"\ncpu_count = 4\nbatch_size = 8\n").unwrap();
+        let llm = raw.llm.unwrap();
+        assert_eq!(llm.cpu_mask, Some(0xf0));
+        assert_eq!(llm.cpu_count, Some(4));
+        assert_eq!(llm.batch_size, Some(8));
+
+        assert_eq!(parse_cpu_mask("0F"), Ok(0x0f));
+        assert!(parse_cpu_mask("0x0").is_err());
+        assert!(parse_cpu_mask("zz").is_err());
+        assert!(toml::from_str::<RawConfig>("[llm]\ncpu_mask = \"nope\"\n").is_err());
+    }
+
+    #[test]
+    fn merge_prefers_later_values_per_field() {
+        let xdg: RawConfig = toml::from_str(
+            "[files]\naudit_writes = true\npreview_diff = true\n[llm]\ntemperature = 0.2\ntop_k = 10\n",
+        )
+        .unwrap();
+        let env: RawConfig =
+            toml::from_str("[files]\npreview_diff = false\n[llm]\ntop_k = 40\n[tools]\nmax_rounds = 7\n")
+                .unwrap();
+
+        let mut config = AppConfig::default();
+        config.apply(xdg.merge(env));
+
+        assert!(config.audit_writes);
+        assert!(!config.preview_diff);
+        assert_eq!(config.llm.temperature, Some(0.2));
+        assert_eq!(config.llm.top_k, Some(40));
+        assert_eq!(config.max_tool_rounds, 7);
+    }
+
+    #[test]
+    fn load_raw_skips_invalid_files() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let valid = dir.path().join("valid.toml");
+        fs::write(&valid, "[tools]\nmax_rounds = 5\n").unwrap();
+        let invalid = dir.path().join("invalid.toml");
+        fs::write(&invalid, "[tools\n").unwrap();
+
+        assert_eq!(load_raw(&valid).unwrap().tools.unwrap().max_rounds, Some(5));
+        assert!(load_raw(&invalid).is_none());
+        assert!(load_raw(&dir.path().join("missing.toml")).is_none());
+    }
+
+    #[test]
+    fn load_merged_validates_across_files() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let xdg = dir.path().join("xdg.toml");
+        fs::write(&xdg, "[llm]\ncpu_mask = \"0x3\"\n").unwrap();
+        let env = dir.path().join("env.toml");
+        fs::write(&env, "[llm]\ncpu_count = 4\n").unwrap();
+
+        // それぞれは正しいが、合わせると cpu_count が cpu_mask の CPU 数を超える
+        assert!(load_merged(std::slice::from_ref(&xdg)).is_some());
+        assert!(load_merged(std::slice::from_ref(&env)).is_some());
+        assert!(load_merged(&[xdg, env]).is_none());
+    }
 }
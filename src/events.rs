@@ -0,0 +1,109 @@
+//! IDE などの外部ツール向けに、ファイル書き込みなどのイベントを JSON で通知するモジュール
+//!
+//! 環境変数 `RKLLM_EVENTS_FIFO` に名前付きパイプ（FIFO）のパスを設定すると、
+//! 1イベントにつき1行の JSON を書き込む。通常のファイルを指定した場合は追記する（なければ作成する）。
+
+use serde_json::json;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+
+/// イベントの書き込み先 FIFO を指定する環境変数
+pub const EVENTS_FIFO_ENV: &str = "RKLLM_EVENTS_FIFO";
+
+/// FIFO（または通常のファイル）にイベントを1行の JSON として追記する
+///
+/// FIFO はノンブロッキングで開くため、読み手が接続していない場合やパイプが
+/// 詰まっている場合は何もせずに戻る（チャットの処理は止めない）。
+///
+/// # 引数
+/// * `fifo_path` - 書き込み先の FIFO またはファイルのパス
+/// * `event` - 送信するイベント
+pub fn emit_event(fifo_path: &str, event: &serde_json::Value) {
+    let Ok(mut fifo) = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(fifo_path)
+    else {
+        return;
+    };
+    let _ = fifo.write_all(format!("{}\n", event).as_bytes());
+}
+
+/// ファイル書き込みのイベント（`file_write`）を通知する（`RKLLM_EVENTS_FIFO` 未設定なら何もしない）
+///
+/// # 引数
+/// * `path` - 書き込んだファイルのパス
+/// * `bytes` - 書き込んだバイト数
+pub fn emit_file_write(path: &str, bytes: usize) {
+    let Some(fifo_path) = std::env::var(EVENTS_FIFO_ENV).ok().filter(|v| !v.is_empty()) else {
+        return;
+    };
+    let event = json!({
+        "event": "file_write",
+        "path": path,
+        "bytes": bytes,
+        "timestamp": chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, false),
+    });
+    emit_event(&fifo_path, &event);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::io::Read;
+    use tempfile::TempDir;
+
+    fn make_fifo(dir: &TempDir) -> String {
+        let path = dir.path().join("events.fifo");
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_emit_event_without_reader_is_skipped() {
+        let dir = TempDir::new().unwrap();
+        let fifo = make_fifo(&dir);
+        // 読み手がいなくてもブロックせずに戻る
+        emit_event(&fifo, &json!({"event": "file_write"}));
+    }
+
+    #[test]
+    fn test_emit_event_appends_to_regular_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("events.log");
+        let path = path.to_str().unwrap();
+
+        emit_event(path, &json!({"event": "file_write", "path": "a.txt"}));
+        emit_event(path, &json!({"event": "file_write", "path": "b.txt"}));
+
+        let content = std::fs::read_to_string(path).unwrap();
+        let paths: Vec<String> = content
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["path"].to_string())
+            .collect();
+        assert_eq!(paths, vec![r#""a.txt""#, r#""b.txt""#]);
+    }
+
+    #[test]
+    fn test_emit_event_writes_json_line() {
+        let dir = TempDir::new().unwrap();
+        let fifo = make_fifo(&dir);
+        let mut reader = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&fifo)
+            .unwrap();
+
+        emit_event(&fifo, &json!({"event": "file_write", "path": "a.txt", "bytes": 3}));
+
+        let mut line = String::new();
+        reader.read_to_string(&mut line).unwrap();
+        let event: serde_json::Value = serde_json::from_str(line.trim_end()).unwrap();
+        assert_eq!(event["path"], "a.txt");
+        assert_eq!(event["bytes"], 3);
+    }
+}
@@ -64,24 +64,43 @@ impl Default for RKLLMExtendParam {
 #[repr(C)]
 #[derive(Debug)]
 pub struct RKLLMParam {
+    /// Path to the `.rkllm` model file (NUL-terminated)
     pub model_path: *const c_char,
+    /// Maximum number of tokens in the context window (prompt + generated)
     pub max_context_len: i32,
+    /// Maximum number of tokens generated per call
     pub max_new_tokens: i32,
+    /// Sample only from the `top_k` most likely tokens (>= 1; 1 = greedy)
     pub top_k: i32,
+    /// Tokens kept when the context overflows (-1 = keep all context tokens)
     pub n_keep: i32,                 // NEW in v1.2: Context keep count (comes after top_k)
+    /// Nucleus sampling: sample from the smallest set whose probability reaches `top_p` (0.0-1.0; 1.0 = disabled)
     pub top_p: c_float,
+    /// Sampling temperature (>= 0.0; lower is more deterministic)
     pub temperature: c_float,
+    /// Penalty for repeating recent tokens (1.0 = disabled, > 1.0 discourages repeats)
     pub repeat_penalty: c_float,
+    /// Penalty proportional to how often a token has appeared (0.0 = disabled)
     pub frequency_penalty: c_float,
+    /// Flat penalty for any token that has already appeared (0.0 = disabled)
     pub presence_penalty: c_float,
+    /// Mirostat sampling mode (0 = disabled, 1 = Mirostat, 2 = Mirostat 2.0)
     pub mirostat: i32,
+    /// Mirostat target entropy; lower values give more focused output
     pub mirostat_tau: c_float,
+    /// Mirostat learning rate (how fast it adapts toward `mirostat_tau`)
     pub mirostat_eta: c_float,
+    /// Non-zero to omit special tokens (e.g. `<|im_end|>`) from the decoded text
     pub skip_special_token: c_int,
+    /// Non-zero to run inference asynchronously (rkllm_run returns immediately)
     pub is_async: c_int,
+    /// Marker inserted before image embeddings in multimodal prompts
     pub img_start: *const c_char,
+    /// Marker inserted after image embeddings in multimodal prompts
     pub img_end: *const c_char,
+    /// Placeholder token for image content in multimodal prompts
     pub img_content: *const c_char,
+    /// Platform-specific settings (CPU affinity, batch size, ...)
     pub extend_param: RKLLMExtendParam,
 }
 
@@ -205,4 +224,26 @@ unsafe extern "C" {
     pub fn rkllm_load_lora(handle: RKLLMHandleT, adapter: *const RKLLMLoraAdapter) -> c_int;
 
     pub fn rkllm_load_prompt_cache(handle: RKLLMHandleT, path: *const c_char) -> c_int;
+
+    pub fn rkllm_abort(handle: RKLLMHandleT) -> c_int;
+}
+
+/// Signature of the optional `rkllm_version` export
+type RKLLMVersionFn = unsafe extern "C" fn() -> *const c_char;
+
+/// Version string reported by librkllmrt, if the loaded library exports `rkllm_version`
+///
+/// The symbol is looked up at runtime so that libraries without it still link.
+pub fn rkllm_version() -> Option<String> {
+    let symbol = unsafe { libc::dlsym(libc::RTLD_DEFAULT, c"rkllm_version".as_ptr()) };
+    if symbol.is_null() {
+        return None;
+    }
+
+    let version_fn: RKLLMVersionFn = unsafe { std::mem::transmute(symbol) };
+    let version = unsafe { version_fn() };
+    if version.is_null() {
+        return None;
+    }
+    Some(unsafe { std::ffi::CStr::from_ptr(version) }.to_string_lossy().into_owned())
 }
@@ -1,7 +1,11 @@
+use glob::Pattern;
+use indexmap::IndexMap;
 use regex::Regex;
 use std::collections::HashSet;
 use std::sync::OnceLock;
 
+use crate::file_ops::STDIN_PATH;
+
 /// ユーザー入力からファイルパスを検出するモジュール
 
 /// ファイルパスの正規表現パターンを取得
@@ -10,19 +14,43 @@ use std::sync::OnceLock;
 /// - 絶対パス: /home/user/file.txt
 /// - ホームディレクトリ: ~/file.txt
 /// - ファイル名のみ: README.md, Cargo.toml
+/// - Windows 形式: src\main.rs, C:\Users\foo\file.txt（検出後に `/` 区切りへ正規化）
 fn file_path_pattern() -> &'static Regex {
     static PATTERN: OnceLock<Regex> = OnceLock::new();
     PATTERN.get_or_init(|| {
         // ファイルパスのパターン:
-        // - オプションで ~/ または / または ./ で開始
+        // - オプションで ~/ または / または ./ または C:\ で開始
         // - または、相対パスとして英数字で開始
-        // - その後、ASCII英数字、ハイフン、アンダースコア、ドット、スラッシュが続く
+        // - その後、ASCII英数字、ハイフン、アンダースコア、ドット、スラッシュ（またはバックスラッシュ）が続く
         // - 最後に拡張子（ドット + ASCII英数字）
         // - 日本語などの非ASCII文字は含まない
-        Regex::new(r"(?:~/|/|\./)?[A-Za-z0-9_\-.]+(?:/[A-Za-z0-9_\-.]+)*\.[A-Za-z0-9]+").unwrap()
+        Regex::new(
+            r"(?:[A-Za-z]:\\|~/|/|\./|\.\\)?[A-Za-z0-9_\-.]+(?:[/\\][A-Za-z0-9_\-.]+)*\.[A-Za-z0-9]+",
+        )
+        .unwrap()
     })
 }
 
+/// 引用符で囲まれたパスのパターン: "my file.txt" / 'my file.rs'
+fn quoted_path_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| Regex::new(r#""([^"\n]+)"|'([^'\n]+)'"#).unwrap())
+}
+
+/// バージョン番号のパターン: 2.0.0, v1.2.3, 3.14, 1.0.0-beta
+fn version_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| Regex::new(r"(?i)^v?\d+\.\d+(?:\.\d+)?(?:-[a-z0-9]+)?$").unwrap())
+}
+
+/// 標準入力を表すトークン: 単語として書かれた `@stdin`、または入力の最後の引数としての `-`
+///
+/// 文中の `-`（"foo - bar" など）は区切りとして使われるため対象にしない。
+fn stdin_token_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| Regex::new(r"(?:^|\s)(@stdin\b|-\s*$)").unwrap())
+}
+
 /// デフォルトで検出対象とする拡張子
 pub const DEFAULT_EXTENSIONS: &[&str] = &[
     "rs", "toml", "md", "json", "yaml", "yml", "ts", "js", "py", "go", "sh", "txt", "c", "cpp",
@@ -33,13 +61,31 @@ pub fn default_extensions() -> Vec<String> {
     DEFAULT_EXTENSIONS.iter().map(|s| s.to_string()).collect()
 }
 
+/// 拡張子に関わらず読み込まないパスのデフォルトパターン（機密情報の混入防止）
+pub const DEFAULT_DENY_PATTERNS: &[&str] = &[".env", "*.secret", "**/.ssh/*", "**/credentials*"];
+
+pub fn default_deny_patterns() -> Vec<String> {
+    DEFAULT_DENY_PATTERNS.iter().map(|s| s.to_string()).collect()
+}
+
+/// 入力中で検出したファイルパスと、その位置
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilePathContext {
+    /// 検出したパス（区切り文字は `/` に正規化済み）
+    pub path: String,
+    /// 入力文字列中の開始位置（バイト）
+    pub start: usize,
+    /// 入力文字列中の終了位置（バイト、この位置は含まない）
+    pub end: usize,
+}
+
 /// ユーザー入力からファイルパスを抽出する
 ///
 /// # 引数
 /// * `input` - ユーザーの入力文字列
 ///
 /// # 戻り値
-/// 検出されたファイルパスのベクトル（重複を除く）
+/// 検出されたファイルパスのベクトル（重複を除き、入力中の出現順）
 ///
 /// # 例
 /// ```
@@ -49,11 +95,22 @@ pub fn default_extensions() -> Vec<String> {
 #[cfg(test)]
 pub fn detect_file_paths(input: &str) -> Vec<String> {
     let defaults = default_extensions();
-    detect_file_paths_with_exts(input, &defaults)
+    detect_file_paths_with_exts(input, &defaults, &default_deny_patterns())
+        .into_iter()
+        .map(|found| found.path)
+        .collect()
 }
 
 /// 許可された拡張子リストに基づいてパスを抽出する
-pub fn detect_file_paths_with_exts(input: &str, allowed_exts: &[String]) -> Vec<String> {
+///
+/// `deny_patterns`（glob）に一致するパスは検出結果から除外する。
+/// 先頭のファイルを入力、以降を出力ターゲットとして扱う呼び出し元があるため、
+/// 結果は入力文字列中の出現位置の順に並べる。同じパスが複数回現れた場合は最初の位置を返す。
+pub fn detect_file_paths_with_exts(
+    input: &str,
+    allowed_exts: &[String],
+    deny_patterns: &[String],
+) -> Vec<FilePathContext> {
     if allowed_exts.is_empty() {
         return Vec::new();
     }
@@ -64,30 +121,142 @@ pub fn detect_file_paths_with_exts(input: &str, allowed_exts: &[String]) -> Vec<
         .collect();
 
     let pattern = file_path_pattern();
-    let mut paths = Vec::new();
-    let mut seen = HashSet::new();
+
+    // 引用符付きのパス（空白を含むもの）を優先し、その範囲内の部分一致は無視する。
+    // アポストロフィ（"Don't ... it's"）による見かけの引用は除外範囲にしない
+    let mut found = quoted_path_matches(input, &allowed);
+    let quoted_spans: Vec<(usize, usize)> =
+        found.iter().map(|&(start, end, _)| (start, end)).collect();
 
     for cap in pattern.find_iter(input) {
-        let path = cap.as_str();
+        if quoted_spans
+            .iter()
+            .any(|&(start, end)| cap.start() >= start && cap.end() <= end)
+        {
+            continue;
+        }
+        let path = normalize_separators(cap.as_str());
         if !path.chars().any(|c| c.is_ascii_alphabetic()) {
             continue;
         }
-        // 拡張子にアルファベットが含まれない（例: ChatGPT-image1.5）ケースは除外
-        if let Some(ext) = path.rsplit('.').next() {
-            if !ext.chars().any(|c| c.is_ascii_alphabetic()) {
-                continue;
-            }
-            if !allowed.contains(&ext.to_ascii_lowercase()) {
-                continue;
-            }
+        if !has_allowed_ext(&path, &allowed) {
+            continue;
         }
-        // 重複を除外
-        if seen.insert(path.to_string()) {
-            paths.push(path.to_string());
+        if is_version_like(&path) || is_followed_by_version(&input[cap.end()..]) {
+            continue;
         }
+        found.push((cap.start(), cap.end(), path));
+    }
+
+    // `@stdin` と末尾の `-` は拡張子に関わらず標準入力として扱う
+    if let Some(m) = stdin_token_pattern().captures(input).and_then(|cap| cap.get(1)) {
+        let end = m.start() + m.as_str().trim_end().len();
+        found.push((m.start(), end, STDIN_PATH.to_string()));
     }
 
+    // 出現位置順に並べてから重複を除外する
+    found.sort_by_key(|(start, _, _)| *start);
+    let mut paths: IndexMap<String, (usize, usize)> = IndexMap::new();
+    for (start, end, path) in found {
+        paths.entry(path).or_insert((start, end));
+    }
+
+    let deny: Vec<Pattern> = deny_patterns
+        .iter()
+        .filter_map(|p| Pattern::new(p).ok())
+        .collect();
+    paths.retain(|path, _| {
+        if is_denied(path, &deny) {
+            eprintln!("[Skipped sensitive file: {}]", path);
+            return false;
+        }
+        true
+    });
+
     paths
+        .into_iter()
+        .map(|(path, (start, end))| FilePathContext { path, start, end })
+        .collect()
+}
+
+/// パス全体またはファイル名が拒否パターンに一致するか判定する
+fn is_denied(path: &str, deny: &[Pattern]) -> bool {
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    deny.iter()
+        .any(|pattern| pattern.matches(path) || pattern.matches(file_name))
+}
+
+/// 引用符（" または '）で囲まれたパスを抽出する
+///
+/// 空白を含むパス（例: `"my file.txt"`）に対応する。
+/// 拡張子が `allowed_exts` に含まれるもののみを返す。
+#[cfg(test)]
+pub fn detect_quoted_paths(input: &str, allowed_exts: &[String]) -> Vec<String> {
+    let allowed: HashSet<String> = allowed_exts
+        .iter()
+        .map(|s| s.to_ascii_lowercase())
+        .collect();
+
+    let paths: indexmap::IndexSet<String> = quoted_path_matches(input, &allowed)
+        .into_iter()
+        .map(|(_, _, path)| path)
+        .collect();
+    paths.into_iter().collect()
+}
+
+/// 引用符で囲まれたパスを、入力中の開始・終了位置とともに抽出する
+fn quoted_path_matches(input: &str, allowed: &HashSet<String>) -> Vec<(usize, usize, String)> {
+    let mut matches = Vec::new();
+    for cap in quoted_path_pattern().captures_iter(input) {
+        let Some(inner) = cap.get(1).or_else(|| cap.get(2)) else {
+            continue;
+        };
+        let path = normalize_separators(inner.as_str().trim());
+        if path.is_empty() || !path.contains('.') {
+            continue;
+        }
+        if has_allowed_ext(&path, allowed) {
+            matches.push((inner.start(), inner.end(), path));
+        }
+    }
+
+    matches
+}
+
+/// バージョン番号のような文字列（`2.0.0`, `v1.2.3`）や、ファイル名部分が数字だけのものか判定する
+fn is_version_like(path: &str) -> bool {
+    let file_name = path.rsplit('/').next().unwrap_or(path);
+    let stem = file_name.split('.').next().unwrap_or(file_name);
+    (!stem.is_empty() && stem.chars().all(|c| c.is_ascii_digit()))
+        || version_pattern().is_match(file_name)
+}
+
+/// 直後の語がバージョン番号か判定する（`node.js 18.0.0` のような製品名 + バージョンを除外する）
+fn is_followed_by_version(rest: &str) -> bool {
+    if !rest.starts_with(char::is_whitespace) {
+        return false;
+    }
+    rest.split_whitespace()
+        .next()
+        .map(|word| word.trim_end_matches(|c: char| c.is_ascii_punctuation()))
+        .is_some_and(|word| version_pattern().is_match(word))
+}
+
+/// Windows 形式の区切り文字（`\`）を `/` に正規化する
+fn normalize_separators(path: &str) -> String {
+    path.replace('\\', "/")
+}
+
+/// 拡張子が許可リストに含まれるか判定する
+fn has_allowed_ext(path: &str, allowed: &HashSet<String>) -> bool {
+    let Some(ext) = path.rsplit('.').next() else {
+        return false;
+    };
+    // 拡張子にアルファベットが含まれない（例: ChatGPT-image1.5）ケースは除外
+    if !ext.chars().any(|c| c.is_ascii_alphabetic()) {
+        return false;
+    }
+    allowed.contains(&ext.to_ascii_lowercase())
 }
 
 #[cfg(test)]
@@ -152,6 +321,109 @@ mod tests {
         assert_eq!(paths.len(), 0);
     }
 
+    #[test]
+    fn test_double_quoted_path_with_spaces() {
+        let input = "\"my notes.txt\"を要約して";
+        let paths = detect_file_paths(input);
+        assert_eq!(paths, vec!["my notes.txt"]);
+    }
+
+    #[test]
+    fn test_single_quoted_path_with_spaces() {
+        let input = "Please read 'src/my module.rs' and explain it";
+        let paths = detect_file_paths(input);
+        assert_eq!(paths, vec!["src/my module.rs"]);
+    }
+
+    #[test]
+    fn test_quoted_and_unquoted_paths_merged() {
+        let input = "\"draft copy.md\" と README.md を比較して";
+        let paths = detect_file_paths(input);
+        assert_eq!(paths, vec!["draft copy.md", "README.md"]);
+    }
+
+    #[test]
+    fn test_apostrophes_do_not_hide_paths() {
+        let paths = detect_file_paths("Don't read main.rs, it's broken");
+        assert_eq!(paths, vec!["main.rs"]);
+    }
+
+    #[test]
+    fn test_quoted_text_without_extension_ignored() {
+        let defaults = default_extensions();
+        let paths = detect_quoted_paths("\"hello world\" と言って", &defaults);
+        assert!(paths.is_empty());
+    }
+
+    #[test]
+    fn test_backslash_path_normalized() {
+        let paths = detect_file_paths("src\\main.rsを確認");
+        assert_eq!(paths, vec!["src/main.rs"]);
+    }
+
+    #[test]
+    fn test_windows_drive_path_normalized() {
+        let paths = detect_file_paths("C:\\Users\\foo\\file.txt を読んで");
+        assert_eq!(paths, vec!["C:/Users/foo/file.txt"]);
+    }
+
+    #[test]
+    fn test_deny_patterns_skip_sensitive_files() {
+        let exts = vec!["env".to_string(), "secret".to_string(), "pub".to_string(), "json".to_string()];
+        let input = "config/.env と api.secret と ~/.ssh/id_rsa.pub と aws/credentials.json と data.json を読んで";
+        let paths = detect_file_paths_with_exts(input, &exts, &default_deny_patterns());
+        assert_eq!(path_strings(paths), vec!["data.json"]);
+
+        let paths = detect_file_paths_with_exts("api.secret を読んで", &exts, &[]);
+        assert_eq!(path_strings(paths), vec!["api.secret"]);
+    }
+
+    #[test]
+    fn test_paths_ordered_by_position() {
+        let paths = detect_file_paths("translate a.txt to b.txt");
+        assert_eq!(paths, vec!["a.txt", "b.txt"]);
+
+        let paths = detect_file_paths("README.md を \"draft copy.md\" に翻訳して");
+        assert_eq!(paths, vec!["README.md", "draft copy.md"]);
+    }
+
+    #[test]
+    fn test_version_strings_are_not_paths() {
+        let exts = vec!["js".to_string(), "md".to_string()];
+        assert!(detect_file_paths_with_exts("install node.js 18.0.0", &exts, &[]).is_empty());
+        assert!(detect_file_paths_with_exts("see 2024.md", &exts, &[]).is_empty());
+
+        assert_eq!(
+            path_strings(detect_file_paths_with_exts("edit node.js", &exts, &[])),
+            vec!["node.js"]
+        );
+    }
+
+    fn path_strings(found: Vec<FilePathContext>) -> Vec<String> {
+        found.into_iter().map(|found| found.path).collect()
+    }
+
+    #[test]
+    fn test_detected_paths_report_source_position() {
+        let input = "a.txt を \"draft copy.md\" に翻訳して a.txt";
+        let found = detect_file_paths_with_exts(input, &default_extensions(), &[]);
+
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0], FilePathContext { path: "a.txt".to_string(), start: 0, end: 5 });
+        assert_eq!(&input[found[1].start..found[1].end], "draft copy.md");
+        assert_eq!(found[1].path, "draft copy.md");
+    }
+
+    #[test]
+    fn test_stdin_dash_is_detected() {
+        assert_eq!(detect_file_paths("summarize @stdin and save to out.md"), vec!["-", "out.md"]);
+        assert_eq!(detect_file_paths("translate -"), vec!["-"]);
+        assert!(detect_file_paths("use --verbose or a-b").is_empty());
+        assert!(detect_file_paths("compare old - new behaviour").is_empty());
+        assert_eq!(detect_file_paths("notes.md - summarize it"), vec!["notes.md"]);
+        assert!(detect_file_paths("mail me@stdin.example").is_empty());
+    }
+
     #[test]
     fn test_duplicate_files() {
         let input = "main.rsとmain.rsを比較して";
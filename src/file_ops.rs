@@ -1,13 +1,52 @@
 use anyhow::{anyhow, Context, Result};
-use once_cell::sync::OnceCell;
+use directories::ProjectDirs;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::WalkBuilder;
+use indexmap::IndexMap;
+use once_cell::sync::{Lazy, OnceCell};
 use path_absolutize::*;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
 use std::env;
-use std::fs;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+use tempfile::TempPath;
 
 /// ファイル読み込みの最大サイズ（デフォルト: 1MB）。環境変数 `RKLLM_MAX_FILE_SIZE` で上書き可能。
 static MAX_FILE_SIZE: OnceCell<u64> = OnceCell::new();
 
+/// 最初に読み込んだ標準入力の内容（パイプは一度しか読めないため、2回目以降はこれを返す）
+static STDIN_CONTENT: OnceCell<std::result::Result<String, String>> = OnceCell::new();
+
+/// 書き込み監査ログに記録するモデルパス。設定されている場合のみ監査ログを出力する。
+static AUDIT_MODEL_PATH: OnceCell<String> = OnceCell::new();
+
+/// ディレクトリ一覧に隠しファイル（`.` で始まる名前）を含める（`[files] list_hidden`）
+static LIST_HIDDEN_FILES: AtomicBool = AtomicBool::new(false);
+
+/// 一時ファイル経由の原子的な書き込みを行う（`[files] atomic_writes`）
+static ATOMIC_WRITES: AtomicBool = AtomicBool::new(true);
+
+/// 直近のターンで書き込んだファイルの、書き込み前の内容（取り消し用）
+static WRITE_BACKUPS: Lazy<Mutex<WriteBackups>> = Lazy::new(|| Mutex::new(WriteBackups::default()));
+
+#[derive(Debug, Default)]
+struct WriteBackups {
+    /// 現在のターン番号（`begin_write_turn` で進む）
+    current_turn: u64,
+    /// `entries` を記録したターン番号
+    entries_turn: u64,
+    /// (書き込み先, 書き込み前の内容。None は新規作成)
+    entries: Vec<(PathBuf, Option<Vec<u8>>)>,
+}
+
+/// 標準入力を表すパス（`read_file("-")` は標準入力から読み込む）
+pub const STDIN_PATH: &str = "-";
+
 /// ファイル読み込みの結果
 #[derive(Debug, Clone)]
 pub struct FileContent {
@@ -17,6 +56,18 @@ pub struct FileContent {
     pub original_path: String,
 }
 
+impl FileContent {
+    /// 行数（改行の数 + 1）を返す
+    pub fn line_count(&self) -> usize {
+        self.content.matches('\n').count() + 1
+    }
+
+    /// 内容のバイト数を返す
+    pub fn size_bytes(&self) -> usize {
+        self.content.len()
+    }
+}
+
 /// ファイルパスを解決する
 ///
 /// # 引数
@@ -27,7 +78,7 @@ pub struct FileContent {
 ///
 /// # エラー
 /// パスの解決に失敗した場合
-fn resolve_path(path: &str) -> Result<PathBuf> {
+pub fn resolve_path(path: &str) -> Result<PathBuf> {
     // ~ を展開
     let expanded = shellexpand::tilde(path);
     let path = Path::new(expanded.as_ref());
@@ -40,6 +91,47 @@ fn resolve_path(path: &str) -> Result<PathBuf> {
     Ok(absolute.to_path_buf())
 }
 
+/// シンボリックリンクをたどる最大回数（Linux の MAXSYMLINKS と同じ）
+const MAX_SYMLINK_DEPTH: usize = 8;
+
+/// シンボリックリンクを `read_link` で1段ずつたどり、最終的な実体のパスを返す
+///
+/// # 引数
+/// * `path` - 解決済みの絶対パス
+///
+/// # 戻り値
+/// リンク先のパス（リンクでなければそのまま、リンク切れなら最後にたどれたリンク先）
+///
+/// # エラー
+/// リンクが循環している、またはたどる回数が `MAX_SYMLINK_DEPTH` を超えた場合
+fn resolve_symlinks(path: &Path) -> Result<PathBuf> {
+    let mut current = path.to_path_buf();
+    let mut seen = HashSet::new();
+
+    loop {
+        let is_symlink = fs::symlink_metadata(&current)
+            .map(|meta| meta.file_type().is_symlink())
+            .unwrap_or(false);
+        if !is_symlink {
+            return Ok(current);
+        }
+        if !seen.insert(current.clone()) || seen.len() > MAX_SYMLINK_DEPTH {
+            return Err(anyhow!("Symlink loop or depth exceeded: {}", path.display()));
+        }
+
+        let target = fs::read_link(&current)
+            .with_context(|| format!("Failed to read symlink: {}", current.display()))?;
+        let joined = match current.parent() {
+            Some(parent) if target.is_relative() => parent.join(target),
+            _ => target,
+        };
+        current = joined
+            .absolutize()
+            .context("Failed to resolve absolute path")?
+            .to_path_buf();
+    }
+}
+
 /// ファイルがテキストファイルかどうかを判定
 ///
 /// # 引数
@@ -78,6 +170,8 @@ fn is_text_file(path: &Path) -> bool {
 
 /// ファイルを読み込む
 ///
+/// `path` が `-` の場合は標準入力から読み込む（パイプ入力時のみ使える）。
+///
 /// # 引数
 /// * `path` - ファイルパス（相対パス、絶対パス、~を含むパス）
 ///
@@ -89,10 +183,22 @@ fn is_text_file(path: &Path) -> bool {
 /// - 読み込み権限がない
 /// - ファイルサイズが大きすぎる（1MB以上）
 /// - UTF-8でデコードできない（バイナリファイル）
+/// - シンボリックリンクが循環している、または深すぎる
 pub fn read_file(path: &str) -> Result<FileContent> {
+    if path == STDIN_PATH {
+        return read_stdin();
+    }
+
+    let data = read_text_bytes(path)?;
+    decode_text(path, data)
+}
+
+/// `read_file` のチェック（存在、種類、サイズ）を通してからファイルのバイト列を読み込む
+fn read_text_bytes(path: &str) -> Result<Vec<u8>> {
     // パスを解決
     let resolved_path = resolve_path(path)
         .with_context(|| format!("Failed to resolve path: {}", path))?;
+    let resolved_path = resolve_symlinks(&resolved_path)?;
 
     // ファイルの存在確認
     if !resolved_path.exists() {
@@ -125,9 +231,24 @@ pub fn read_file(path: &str) -> Result<FileContent> {
         ));
     }
 
-    // ファイルを読み込む
-    let content = fs::read_to_string(&resolved_path)
-        .with_context(|| format!("Failed to read file (not UTF-8 encoded?): {}", path))?;
+    // 確認後に大きくなった場合に備え、読み込む量も上限で打ち切る
+    let mut data = Vec::new();
+    fs::File::open(&resolved_path)
+        .with_context(|| format!("Failed to read file: {}", path))?
+        .take(max_size + 1)
+        .read_to_end(&mut data)
+        .with_context(|| format!("Failed to read file: {}", path))?;
+    if data.len() as u64 > max_size {
+        return Err(anyhow!("File is too large (max {} bytes): {}", max_size, path));
+    }
+    Ok(data)
+}
+
+/// 読み込んだバイト列を UTF-8 として `FileContent` にする（先頭の BOM は取り除く）
+fn decode_text(path: &str, data: Vec<u8>) -> Result<FileContent> {
+    let mut content = String::from_utf8(data)
+        .map_err(|_| anyhow!("Failed to read file (not UTF-8 encoded?): {}", path))?;
+    strip_bom(&mut content);
 
     Ok(FileContent {
         content,
@@ -135,6 +256,282 @@ pub fn read_file(path: &str) -> Result<FileContent> {
     })
 }
 
+/// 先頭の BOM（U+FEFF）を取り除く
+///
+/// Windows のエディタで保存した UTF-8 ファイルは BOM 付きのことが多く、
+/// そのままプロンプトに含めると内容の先頭に見えない文字が残る。
+fn strip_bom(content: &mut String) {
+    if content.starts_with('\u{FEFF}') {
+        content.drain(..'\u{FEFF}'.len_utf8());
+    }
+}
+
+/// ファイル内容の SHA-256 を16進文字列で返す
+///
+/// 全体をメモリに読み込まず、少しずつ読みながら計算する。
+///
+/// # 引数
+/// * `path` - ファイルパス（相対パス、絶対パス、~を含むパス）
+///
+/// # エラー
+/// パスの解決または読み込みに失敗した場合、ファイルが最大サイズ（`RKLLM_MAX_FILE_SIZE`）を超える場合
+#[allow(dead_code)]
+pub fn hash_file(path: &str) -> Result<String> {
+    let resolved_path = resolve_path(path)
+        .with_context(|| format!("Failed to resolve path: {}", path))?;
+    let resolved_path = resolve_symlinks(&resolved_path)?;
+    let file = fs::File::open(&resolved_path)
+        .with_context(|| format!("Failed to read file: {}", path))?;
+    hash_reader(file, max_file_size()).with_context(|| format!("Failed to hash file: {}", path))
+}
+
+/// `reader` の内容の SHA-256 を計算する（`max_size` バイトを超えたらエラー）
+fn hash_reader(reader: impl Read, max_size: u64) -> Result<String> {
+    let mut hasher = Sha256::new();
+    let copied = io::copy(&mut reader.take(max_size + 1), &mut hasher)?;
+    if copied > max_size {
+        return Err(anyhow!("File is too large (max {} bytes)", max_size));
+    }
+    Ok(to_hex(&hasher.finalize()))
+}
+
+/// `FileCache` に保持するファイル数の上限（超えたら古いものから捨てる）
+const MAX_CACHED_FILES: usize = 32;
+
+/// キャッシュした1ファイル分の情報
+#[derive(Debug)]
+struct CachedFile {
+    modified: Option<SystemTime>,
+    len: u64,
+    /// ファイルのバイト列（BOM を含む）の SHA-256
+    hash: String,
+    content: String,
+}
+
+impl CachedFile {
+    fn file_content(&self, path: &str) -> FileContent {
+        FileContent {
+            content: self.content.clone(),
+            original_path: path.to_string(),
+        }
+    }
+}
+
+/// セッション内で読み込んだファイルの内容を SHA-256 で管理するキャッシュ
+#[derive(Debug, Default)]
+pub struct FileCache {
+    /// パス → キャッシュした内容（挿入順。上限を超えたら先頭から捨てる）
+    entries: IndexMap<String, CachedFile>,
+}
+
+impl FileCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// ファイルが前回と同じならキャッシュした内容を返し、変わっていれば読み直す
+    ///
+    /// 更新時刻とサイズが同じなら読み込まずに再利用する。どちらかが変わっていても、
+    /// バイト列のハッシュが同じなら（`touch` されただけなど）キャッシュを使う。
+    /// 変わっていた場合も読み込みは1回だけで、そのバイト列からハッシュと内容を得る。
+    ///
+    /// # 引数
+    /// * `path` - ファイルパス
+    ///
+    /// # エラー
+    /// `read_file` と同じ
+    pub fn get_or_read(&mut self, path: &str) -> Result<FileContent> {
+        let metadata = resolve_path(path)
+            .and_then(|resolved| resolve_symlinks(&resolved))
+            .ok()
+            .and_then(|resolved| fs::metadata(resolved).ok());
+        let modified = metadata.as_ref().and_then(|m| m.modified().ok());
+        let len = metadata.as_ref().map_or(0, |m| m.len());
+
+        if let Some(cached) = self.entries.get(path)
+            && metadata.is_some()
+            && cached.modified == modified
+            && cached.len == len
+        {
+            return Ok(cached.file_content(path));
+        }
+
+        // 初回と変更時は read_file と同じサイズ・種類チェックを通して読み込む
+        let data = read_text_bytes(path)?;
+        let hash = sha256_hex(&data);
+        if let Some(cached) = self.entries.get_mut(path)
+            && cached.hash == hash
+        {
+            cached.modified = modified;
+            cached.len = len;
+            return Ok(cached.file_content(path));
+        }
+
+        let file = decode_text(path, data)?;
+        let cached = CachedFile {
+            modified,
+            len,
+            hash,
+            content: file.content.clone(),
+        };
+        self.entries.shift_remove(path);
+        if self.entries.len() >= MAX_CACHED_FILES {
+            self.entries.shift_remove_index(0);
+        }
+        self.entries.insert(path.to_string(), cached);
+        Ok(file)
+    }
+}
+
+/// 標準入力から読み込み、`original_path` が `-` の `FileContent` にする
+///
+/// 読み込むのはセッションで最初の1回だけで、以降は同じ内容（または同じエラー）を返す。
+///
+/// # エラー
+/// 最大サイズを超える場合、または UTF-8 でデコードできない場合
+fn read_stdin() -> Result<FileContent> {
+    let content = STDIN_CONTENT.get_or_init(|| {
+        read_from(io::stdin().lock(), max_file_size())
+            .map(|file| file.content)
+            .map_err(|e| format!("{:#}", e))
+    });
+    match content {
+        Ok(content) => Ok(FileContent {
+            content: content.clone(),
+            original_path: STDIN_PATH.to_string(),
+        }),
+        Err(e) => Err(anyhow!("{}", e)),
+    }
+}
+
+/// `reader` から最大 `max_size` バイトを読み込み、標準入力の `FileContent` にする
+fn read_from(reader: impl Read, max_size: u64) -> Result<FileContent> {
+    let mut data = Vec::new();
+    reader
+        .take(max_size + 1)
+        .read_to_end(&mut data)
+        .context("Failed to read from stdin")?;
+    if data.len() as u64 > max_size {
+        return Err(anyhow!("Input from stdin is too large (max {} bytes)", max_size));
+    }
+    let content = String::from_utf8(data)
+        .map_err(|_| anyhow!("Failed to read stdin (not UTF-8 encoded?)"))?;
+
+    Ok(FileContent {
+        content,
+        original_path: STDIN_PATH.to_string(),
+    })
+}
+
+/// ディレクトリ一覧に隠しファイルを含めるかどうかを設定する
+pub fn set_list_hidden_files(enabled: bool) {
+    LIST_HIDDEN_FILES.store(enabled, Ordering::SeqCst);
+}
+
+/// 一時ファイルとリネームによる原子的な書き込みを行うかどうかを設定する
+pub fn set_atomic_writes(enabled: bool) {
+    ATOMIC_WRITES.store(enabled, Ordering::SeqCst);
+}
+
+/// ディレクトリの内容を罫線付きのツリー形式で返す
+///
+/// # 引数
+/// * `path` - ディレクトリパス（相対パス、絶対パス、~を含むパス）
+/// * `max_depth` - たどる階層の深さ（1ならディレクトリ直下のみ）
+///
+/// # 戻り値
+/// 1行目にディレクトリ名、以降に `├── ` / `└── ` で始まるエントリを並べた文字列。
+/// ディレクトリ名には末尾に `/` を付ける
+///
+/// # エラー
+/// - パスが存在しない、またはディレクトリでない
+/// - ディレクトリを読み込めない
+pub fn list_directory(path: &str, max_depth: usize) -> Result<String> {
+    let resolved_path = resolve_path(path)
+        .with_context(|| format!("Failed to resolve path: {}", path))?;
+    if !resolved_path.is_dir() {
+        return Err(anyhow!("Not a directory: {}", path));
+    }
+
+    let mut tree = format!("{}/\n", path.trim_end_matches('/'));
+    let root = fs::canonicalize(&resolved_path)
+        .with_context(|| format!("Failed to resolve path: {}", path))?;
+    let mut walk = TreeWalk {
+        root: root.clone(),
+        show_hidden: LIST_HIDDEN_FILES.load(Ordering::SeqCst),
+        visited: HashSet::new(),
+    };
+    walk.enter(&root);
+    append_tree(&resolved_path, "", max_depth, &mut walk, &mut tree)?;
+    Ok(tree)
+}
+
+/// `list_directory` がたどる範囲と、たどり済みのディレクトリ
+struct TreeWalk {
+    root: PathBuf,
+    show_hidden: bool,
+    /// たどったディレクトリの (デバイス, inode)
+    visited: HashSet<(u64, u64)>,
+}
+
+impl TreeWalk {
+    /// ディレクトリに入ってよいか（実体がルートの外、またはたどり済みなら false）
+    fn enter(&mut self, dir: &Path) -> bool {
+        use std::os::unix::fs::MetadataExt;
+
+        let Ok(canonical) = fs::canonicalize(dir) else {
+            return false;
+        };
+        if !canonical.starts_with(&self.root) {
+            return false;
+        }
+        fs::metadata(&canonical).is_ok_and(|meta| self.visited.insert((meta.dev(), meta.ino())))
+    }
+}
+
+/// `list_directory` の再帰部分。`prefix` は親階層の罫線
+fn append_tree(
+    dir: &Path,
+    prefix: &str,
+    depth: usize,
+    walk: &mut TreeWalk,
+    tree: &mut String,
+) -> Result<()> {
+    if depth == 0 {
+        return Ok(());
+    }
+
+    let mut entries: Vec<_> = fs::read_dir(dir)
+        .with_context(|| format!("Failed to read directory: {}", dir.display()))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| walk.show_hidden || !entry.file_name().to_string_lossy().starts_with('.'))
+        .collect();
+    entries.sort_by_key(|entry| entry.file_name());
+
+    let count = entries.len();
+    for (i, entry) in entries.into_iter().enumerate() {
+        let last = i + 1 == count;
+        let name = entry.file_name().to_string_lossy().to_string();
+        // ディレクトリへのシンボリックリンクも表示するが、ルートの外や循環する先はたどらない
+        let is_dir = entry.path().is_dir();
+
+        tree.push_str(prefix);
+        tree.push_str(if last { "└── " } else { "├── " });
+        tree.push_str(&name);
+        if is_dir {
+            tree.push('/');
+        }
+        tree.push('\n');
+
+        if is_dir && depth > 1 && walk.enter(&entry.path()) {
+            let child_prefix = format!("{}{}", prefix, if last { "    " } else { "│   " });
+            append_tree(&entry.path(), &child_prefix, depth - 1, walk, tree)?;
+        }
+    }
+
+    Ok(())
+}
+
 fn max_file_size() -> u64 {
     *MAX_FILE_SIZE.get_or_init(|| {
         env::var("RKLLM_MAX_FILE_SIZE")
@@ -145,6 +542,81 @@ fn max_file_size() -> u64 {
     })
 }
 
+/// `.gitignore` / `.rkllmignore` の無視ルール（基準ディレクトリ以下のファイルをセッション開始時に一度だけ読む）
+pub struct IgnoreRules {
+    root: PathBuf,
+    // ルールファイルのあるディレクトリごとのルール（親が先。深いディレクトリのルールが優先）
+    matchers: Vec<Gitignore>,
+}
+
+impl IgnoreRules {
+    /// `root` 以下の各ディレクトリにある `.gitignore` と `.rkllmignore` を読み込む（同じディレクトリでは後者が優先）
+    ///
+    /// 無視されたディレクトリの中には入らない。読み込めないファイルや解釈できない行は無視する。
+    /// git リポジトリ外でも `.gitignore` を尊重する。
+    pub fn new(root: &Path) -> Self {
+        let root = root
+            .absolutize()
+            .map(|root| root.to_path_buf())
+            .unwrap_or_else(|_| root.to_path_buf());
+        let matchers = WalkBuilder::new(&root)
+            .hidden(false)
+            .require_git(false)
+            .add_custom_ignore_filename(".rkllmignore")
+            .filter_entry(|entry| {
+                entry.file_type().is_some_and(|t| t.is_dir()) && entry.file_name() != ".git"
+            })
+            .build()
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| Self::dir_matcher(entry.path()))
+            .collect();
+        Self { root, matchers }
+    }
+
+    /// `dir` 直下のルールファイルを読み込む（どちらもなければ None）
+    fn dir_matcher(dir: &Path) -> Option<Gitignore> {
+        let mut builder = GitignoreBuilder::new(dir);
+        let mut found = false;
+        for name in [".gitignore", ".rkllmignore"] {
+            let file = dir.join(name);
+            if file.is_file() {
+                let _ = builder.add(file);
+                found = true;
+            }
+        }
+        if !found {
+            return None;
+        }
+        builder.build().ok()
+    }
+
+    /// 無視ルールによって除外されるファイルかどうかを判定する
+    ///
+    /// # 引数
+    /// * `path` - ファイルパス（相対パス、絶対パス、~を含むパス）
+    ///
+    /// # 戻り値
+    /// ファイル自身か親ディレクトリ（`target/` など）が無視ルールに一致する場合はtrue。
+    /// 基準ディレクトリの外のパスは false
+    pub fn is_ignored(&self, path: &str) -> bool {
+        let Ok(resolved_path) = resolve_path(path) else {
+            return false;
+        };
+        if !resolved_path.starts_with(&self.root) {
+            return false;
+        }
+        let is_dir = resolved_path.is_dir();
+        // 最も深いディレクトリのルールから順に見て、最初に一致したもので決める
+        self.matchers
+            .iter()
+            .rev()
+            .filter(|matcher| resolved_path.starts_with(matcher.path()))
+            .map(|matcher| matcher.matched_path_or_any_parents(&resolved_path, is_dir))
+            .find(|matched| !matched.is_none())
+            .is_some_and(|matched| matched.is_ignore())
+    }
+}
+
 /// ファイルパスが安全かどうかをチェックする
 ///
 /// # 引数
@@ -180,7 +652,10 @@ fn check_path_safety(path: &str) -> Result<PathBuf> {
     Ok(resolved_path)
 }
 
-/// ファイルを書き込む
+/// 新規作成するファイルの既定のパーミッション（umask が適用される）
+pub const DEFAULT_FILE_MODE: u32 = 0o644;
+
+/// ファイルを書き込む（パーミッションは `DEFAULT_FILE_MODE`）
 ///
 /// # 引数
 /// * `path` - ファイルパス（相対パス、絶対パス、~を含むパス）
@@ -199,6 +674,69 @@ fn check_path_safety(path: &str) -> Result<PathBuf> {
 /// この関数は既存ファイルの上書き確認を行いません。
 /// 呼び出し側で確認を行う必要があります。
 pub fn write_file(path: &str, content: &str, _force: bool) -> Result<()> {
+    write_file_with_mode(path, content, None)
+}
+
+/// パーミッションを指定してファイルを書き込む
+///
+/// `mode` はファイルを作成するときに適用され、プロセスの umask で絞られる（未指定なら `DEFAULT_FILE_MODE`）。
+/// 原子的な書き込みで既存ファイルを上書きする場合、`mode` を指定したときだけ `mode` になり、
+/// 未指定なら既存ファイルのパーミッションを引き継ぐ。
+/// Unix 以外では `mode` を無視する。
+///
+/// # 引数
+/// * `path` - ファイルパス（相対パス、絶対パス、~を含むパス）
+/// * `content` - 書き込む内容
+/// * `mode` - パーミッション（`Some(0o755)` など。None なら既定）
+///
+/// # エラー
+/// `write_file` と同じ（パス安全性チェック、ディレクトリ作成、書き込みの失敗）
+pub fn write_file_with_mode(path: &str, content: &str, mode: Option<u32>) -> Result<()> {
+    write_file_bytes_with_mode(path, content.as_bytes(), mode)
+}
+
+/// 内容を一時ファイルに書き出す（MCP 書き込みツールへの受け渡し用）
+///
+/// 大きな出力を JSON-RPC メッセージに埋め込む代わりに、一時ファイルのパスを渡すために使う。
+/// 返した `TempPath` を drop すると一時ファイルは削除される。
+///
+/// # 引数
+/// * `content` - 書き込む内容
+/// * `ext` - 一時ファイルの拡張子（`.rs` など。不要なら空文字列）
+///
+/// # 戻り値
+/// 一時ファイルのハンドルとパス文字列
+///
+/// # エラー
+/// 一時ファイルの作成・書き込みに失敗した場合
+pub fn create_temp_file(content: &str, ext: &str) -> Result<(TempPath, String)> {
+    let mut file = tempfile::Builder::new()
+        .prefix("rkllm-cli-")
+        .suffix(ext)
+        .tempfile()
+        .context("Failed to create temporary file")?;
+    file.write_all(content.as_bytes())
+        .context("Failed to write temporary file")?;
+    let temp_path = file.into_temp_path();
+    let path = temp_path.to_string_lossy().to_string();
+    Ok((temp_path, path))
+}
+
+/// パーミッションを指定してバイト列を書き込む（`write_file_with_mode` のバイト列版）
+///
+/// # エラー
+/// `write_file` と同じ（パス安全性チェック、ディレクトリ作成、書き込みの失敗）
+pub fn write_file_bytes_with_mode(path: &str, content: &[u8], mode: Option<u32>) -> Result<()> {
+    let resolved_path = prepare_write_path(path)?;
+
+    // 取り消しに備えて書き込み前の内容を控える
+    record_backup(&resolved_path);
+
+    write_prepared(path, &resolved_path, content, mode)
+}
+
+/// 書き込み先の安全性を確認し、親ディレクトリを作成して解決済みのパスを返す
+fn prepare_write_path(path: &str) -> Result<PathBuf> {
     // パスの安全性をチェック
     let resolved_path = check_path_safety(path)
         .with_context(|| format!("Path safety check failed: {}", path))?;
@@ -210,14 +748,308 @@ pub fn write_file(path: &str, content: &str, _force: bool) -> Result<()> {
                 .with_context(|| format!("Failed to create directory: {:?}", parent))?;
         }
     }
+    Ok(resolved_path)
+}
 
+/// `prepare_write_path` 済みのパスに書き込み、監査ログに記録する
+fn write_prepared(path: &str, resolved_path: &Path, content: &[u8], mode: Option<u32>) -> Result<()> {
     // ファイルを書き込む
-    fs::write(&resolved_path, content)
+    store_file(resolved_path, content, mode)
         .with_context(|| format!("Failed to write file: {}", path))?;
 
+    // 監査ログ（有効時のみ）。書き込み自体は成功しているので失敗は警告に留める
+    if let Some(model_path) = AUDIT_MODEL_PATH.get()
+        && let Err(e) = audit_write(model_path, resolved_path, content)
+    {
+        eprintln!("[Audit] Failed to record write for '{}': {}", path, e);
+    }
+
+    Ok(())
+}
+
+/// 解決済みのパスに内容を書き込む（原子的な書き込みが有効なら一時ファイル経由）
+fn store_file(resolved_path: &Path, content: &[u8], mode: Option<u32>) -> std::io::Result<()> {
+    if ATOMIC_WRITES.load(Ordering::SeqCst) {
+        write_atomic(resolved_path, content, mode)
+    } else {
+        write_with_mode(resolved_path, content, mode.unwrap_or(DEFAULT_FILE_MODE))
+    }
+}
+
+/// `fs::write` と同じだが、ファイルを作成する場合は `mode` を使う（Unix のみ）
+fn write_with_mode(path: &Path, content: &[u8], mode: u32) -> std::io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode & 0o777);
+    #[cfg(not(unix))]
+    let _ = mode;
+    options.open(path)?.write_all(content)
+}
+
+/// `<path>.tmp.<pid>` に書き込んでから `<path>` へリネームする
+///
+/// 書き込み途中で中断されても対象ファイルが中途半端な内容にならない。
+/// シンボリックリンクはリンク先を置き換え、`mode` が未指定なら既存ファイルのパーミッションを引き継ぐ。
+/// リネームに失敗した場合（別ファイルシステムなど）は一時ファイルを削除し、直接書き込む。
+fn write_atomic(resolved_path: &Path, content: &[u8], mode: Option<u32>) -> std::io::Result<()> {
+    // リンク自体を通常ファイルで置き換えないよう、リンク先に対してリネームする
+    let target = match fs::symlink_metadata(resolved_path) {
+        Ok(meta) if meta.file_type().is_symlink() => fs::canonicalize(resolved_path)?,
+        _ => resolved_path.to_path_buf(),
+    };
+    let resolved_path = target.as_path();
+    let existing_permissions = match mode {
+        Some(_) => None,
+        None => fs::metadata(resolved_path).ok().map(|meta| meta.permissions()),
+    };
+    let mode = mode.unwrap_or(DEFAULT_FILE_MODE);
+
+    let mut tmp_name = resolved_path.as_os_str().to_os_string();
+    tmp_name.push(format!(".tmp.{}", std::process::id()));
+    let tmp_path = PathBuf::from(tmp_name);
+
+    // 前回の一時ファイルが残っていると mode が適用されないため消しておく
+    let _ = fs::remove_file(&tmp_path);
+    let written = write_with_mode(&tmp_path, content, mode).and_then(|()| match existing_permissions {
+        Some(permissions) => fs::set_permissions(&tmp_path, permissions),
+        None => Ok(()),
+    });
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+    if fs::rename(&tmp_path, resolved_path).is_err() {
+        let _ = fs::remove_file(&tmp_path);
+        return write_with_mode(resolved_path, content, mode);
+    }
     Ok(())
 }
 
+/// 新しいユーザーターンの開始を記録する
+///
+/// 次に書き込みが行われた時点で、前のターンのバックアップは破棄される。
+pub fn begin_write_turn() {
+    let mut backups = WRITE_BACKUPS.lock().unwrap_or_else(|e| e.into_inner());
+    backups.current_turn += 1;
+}
+
+fn record_backup(resolved_path: &Path) {
+    let mut backups = WRITE_BACKUPS.lock().unwrap_or_else(|e| e.into_inner());
+    if backups.entries_turn != backups.current_turn {
+        backups.entries.clear();
+        backups.entries_turn = backups.current_turn;
+    }
+    // 同じターンで複数回書き込んだ場合は、ターン開始前の内容を残す
+    if backups.entries.iter().any(|(path, _)| path == resolved_path) {
+        return;
+    }
+    let previous = fs::read(resolved_path).ok();
+    backups.entries.push((resolved_path.to_path_buf(), previous));
+}
+
+/// 直近の書き込みで変更され、元に戻せるファイルの一覧（書き込み順）
+pub fn restorable_files() -> Vec<PathBuf> {
+    let backups = WRITE_BACKUPS.lock().unwrap_or_else(|e| e.into_inner());
+    backups.entries.iter().map(|(path, _)| path.clone()).collect()
+}
+
+/// ファイルを直近の書き込み前の状態に戻す（新規作成だった場合は削除する）
+///
+/// 書き戻しも通常の書き込みと同じくパス安全性チェック、原子的な書き込み、監査ログを通す。
+///
+/// # 引数
+/// * `path` - `restorable_files` が返した書き込み先のパス
+///
+/// # エラー
+/// - バックアップが存在しない
+/// - パス安全性チェックに失敗
+/// - 書き戻し・削除に失敗
+pub fn restore_backup(path: &Path) -> Result<()> {
+    let mut backups = WRITE_BACKUPS.lock().unwrap_or_else(|e| e.into_inner());
+    let index = backups
+        .entries
+        .iter()
+        .position(|(p, _)| p == path)
+        .ok_or_else(|| anyhow!("No backup available for {}", path.display()))?;
+
+    let path_str = path.to_string_lossy();
+    match &backups.entries[index].1 {
+        Some(previous) => {
+            let resolved_path = prepare_write_path(&path_str)?;
+            write_prepared(&path_str, &resolved_path, previous, None)
+                .with_context(|| format!("Failed to restore file: {}", path.display()))?
+        }
+        None => {
+            let resolved_path = check_path_safety(&path_str)
+                .with_context(|| format!("Path safety check failed: {}", path.display()))?;
+            fs::remove_file(&resolved_path)
+                .with_context(|| format!("Failed to remove created file: {}", path.display()))?
+        }
+    }
+    backups.entries.remove(index);
+    Ok(())
+}
+
+/// 現在のファイル内容と書き込み予定の内容の unified diff を作る（プレビュー用）
+///
+/// # 引数
+/// * `path` - 書き込み先のパス（存在しない場合は空ファイルとの差分）
+/// * `new_content` - 書き込み予定の内容
+///
+/// # 戻り値
+/// unified diff 形式の文字列（差分がなければヘッダーのみ）
+///
+/// # エラー
+/// - パスが解決できない
+/// - 既存ファイルの読み込みに失敗
+pub fn diff_file(path: &str, new_content: &str) -> Result<String> {
+    let resolved_path = resolve_path(path)
+        .with_context(|| format!("Failed to resolve path: {}", path))?;
+    let original = if resolved_path.exists() {
+        fs::read_to_string(&resolved_path)
+            .with_context(|| format!("Failed to read file for diff: {}", path))?
+    } else {
+        String::new()
+    };
+
+    Ok(diffy::create_patch(&original, new_content).to_string())
+}
+
+/// バイナリの書き込み予定を、差分の代わりにサイズと SHA-256 で要約する（プレビュー用）
+///
+/// # 引数
+/// * `path` - 書き込み先のパス
+/// * `new_content` - 書き込み予定のバイト列
+///
+/// # 戻り値
+/// `10 bytes (sha256 0123456789ab) -> 12 bytes (sha256 ba9876543210)` の形式
+/// （存在しない場合は左側が `(new file)`）
+///
+/// # エラー
+/// パスが解決できない、または既存ファイルの読み込みに失敗した場合
+pub fn summarize_binary_change(path: &str, new_content: &[u8]) -> Result<String> {
+    let resolved_path = resolve_path(path)
+        .with_context(|| format!("Failed to resolve path: {}", path))?;
+    let new = format!(
+        "{} bytes (sha256 {})",
+        new_content.len(),
+        &sha256_hex(new_content)[..12]
+    );
+    if !resolved_path.exists() {
+        return Ok(format!("(new file) -> {}", new));
+    }
+
+    let original = fs::read(&resolved_path)
+        .with_context(|| format!("Failed to read file for diff: {}", path))?;
+    Ok(format!(
+        "{} bytes (sha256 {}) -> {}",
+        original.len(),
+        &sha256_hex(&original)[..12],
+        new
+    ))
+}
+
+/// 既存ファイルに unified diff を適用する
+///
+/// # 引数
+/// * `path` - パッチを適用するファイルのパス
+/// * `patch` - unified diff 形式のパッチ
+///
+/// # エラー
+/// - ファイルが存在しない、または読み込めない
+/// - パッチの形式が不正
+/// - パッチがファイル内容に適用できない
+/// - 書き込みに失敗（`write_file` と同じ）
+pub fn apply_patch(path: &str, patch: &str) -> Result<()> {
+    let resolved_path = check_path_safety(path)
+        .with_context(|| format!("Path safety check failed: {}", path))?;
+    let original = fs::read_to_string(&resolved_path)
+        .with_context(|| format!("Failed to read file to patch: {}", path))?;
+
+    let patched = patch_content(&original, patch)
+        .with_context(|| format!("Failed to apply patch to {}", path))?;
+    write_file(path, &patched, false)
+}
+
+/// パッチを文字列に適用する（モデル出力の前後の空行は除去し、末尾改行を補う）
+fn patch_content(original: &str, patch: &str) -> Result<String> {
+    let mut normalized = patch.trim_matches(|c| c == '\n' || c == '\r').to_string();
+    normalized.push('\n');
+
+    let parsed = diffy::Patch::from_str(&normalized).map_err(|e| anyhow!("Invalid patch: {}", e))?;
+    diffy::apply(original, &parsed).map_err(|e| anyhow!("Patch does not apply: {}", e))
+}
+
+/// 書き込み監査ログを有効化する（`audit_writes = true` の場合に起動時に呼び出す）
+///
+/// # 引数
+/// * `model_path` - ログに記録するモデルファイルのパス
+pub fn enable_write_audit(model_path: &str) {
+    let _ = AUDIT_MODEL_PATH.set(model_path.to_string());
+}
+
+/// 書き込み監査ログ（`~/.local/share/rkllm-cli/writes.log`）に1行追記する
+///
+/// 形式: `<ISO-8601 timestamp>\t<model_path>\t<resolved_path>\t<content_sha256>`
+///
+/// # 引数
+/// * `model_path` - 使用中のモデルファイルのパス
+/// * `resolved_path` - 書き込み先の絶対パス
+/// * `content` - 書き込んだ内容
+///
+/// # エラー
+/// - データディレクトリが決定できない
+/// - ログファイルの作成・追記に失敗
+pub fn audit_write(model_path: &str, resolved_path: &Path, content: &[u8]) -> Result<()> {
+    let log_path = audit_log_path().context("Failed to determine data directory")?;
+    append_audit_entry(&log_path, model_path, resolved_path, content)
+}
+
+fn audit_log_path() -> Option<PathBuf> {
+    ProjectDirs::from("", "", "rkllm-cli").map(|dirs| dirs.data_dir().join("writes.log"))
+}
+
+fn append_audit_entry(
+    log_path: &Path,
+    model_path: &str,
+    resolved_path: &Path,
+    content: &[u8],
+) -> Result<()> {
+    if let Some(parent) = log_path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory: {:?}", parent))?;
+    }
+
+    let timestamp = chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, false);
+    let hash = sha256_hex(content);
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path)
+        .with_context(|| format!("Failed to open audit log: {:?}", log_path))?;
+    writeln!(
+        file,
+        "{}\t{}\t{}\t{}",
+        timestamp,
+        model_path,
+        resolved_path.display(),
+        hash
+    )
+    .with_context(|| format!("Failed to append audit log: {:?}", log_path))?;
+
+    Ok(())
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    to_hex(&Sha256::digest(data))
+}
+
+fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
 /// ファイルが存在するかどうかをチェック
 ///
 /// # 引数
@@ -232,11 +1064,21 @@ pub fn file_exists(path: &str) -> bool {
     }
 }
 
+/// ディレクトリが存在するかどうかをチェック
+///
+/// # 引数
+/// * `path` - ディレクトリパス
+///
+/// # 戻り値
+/// ディレクトリが存在する場合はtrue
+pub fn is_directory(path: &str) -> bool {
+    resolve_path(path).is_ok_and(|resolved_path| resolved_path.is_dir())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use std::fs::File;
-    use std::io::Write;
     use tempfile::TempDir;
 
     #[test]
@@ -252,6 +1094,133 @@ mod tests {
         assert_eq!(content.content.trim(), "Hello, World!");
     }
 
+    #[test]
+    fn test_read_file_strips_bom() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("bom.txt");
+        let mut file = File::create(&file_path).unwrap();
+        file.write_all(b"\xEF\xBB\xBFHello").unwrap();
+
+        let content = read_file(file_path.to_str().unwrap()).unwrap();
+        assert_eq!(content.content, "Hello");
+    }
+
+    #[test]
+    fn test_file_content_line_count_and_size() {
+        let file = FileContent {
+            content: "fn main() {\n}".to_string(),
+            original_path: "main.rs".to_string(),
+        };
+        assert_eq!(file.line_count(), 2);
+        assert_eq!(file.size_bytes(), 13);
+    }
+
+    #[test]
+    fn test_hash_reader_streams_up_to_the_size_limit() {
+        assert_eq!(hash_reader(&b"first"[..], 5).unwrap(), sha256_hex(b"first"));
+        assert!(hash_reader(&b"second"[..], 5).is_err());
+    }
+
+    #[test]
+    fn test_file_cache_rereads_changed_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("cached.txt");
+        let path = file_path.to_str().unwrap();
+        fs::write(&file_path, "first").unwrap();
+
+        let mut cache = FileCache::new();
+        assert_eq!(cache.get_or_read(path).unwrap().content, "first");
+        assert_eq!(hash_file(path).unwrap(), sha256_hex(b"first"));
+        assert_eq!(cache.get_or_read(path).unwrap().content, "first");
+
+        fs::write(&file_path, "second").unwrap();
+        assert_eq!(cache.get_or_read(path).unwrap().content, "second");
+
+        // BOM は内容からは取り除かれるが、ハッシュはバイト列で比べるので再利用される
+        let bom_path = temp_dir.path().join("bom.txt");
+        fs::write(&bom_path, "\u{FEFF}text").unwrap();
+        let bom = bom_path.to_str().unwrap();
+        assert_eq!(cache.get_or_read(bom).unwrap().content, "text");
+        assert_eq!(cache.entries[bom].hash, sha256_hex("\u{FEFF}text".as_bytes()));
+        fs::write(&bom_path, "\u{FEFF}text").unwrap();
+        assert_eq!(cache.get_or_read(bom).unwrap().content, "text");
+    }
+
+    #[test]
+    fn test_file_cache_evicts_oldest_entries() {
+        let temp_dir = TempDir::new().unwrap();
+        let mut cache = FileCache::new();
+        let paths: Vec<String> = (0..=MAX_CACHED_FILES)
+            .map(|i| {
+                let path = temp_dir.path().join(format!("{}.txt", i));
+                fs::write(&path, i.to_string()).unwrap();
+                path.to_string_lossy().into_owned()
+            })
+            .collect();
+
+        for path in &paths {
+            cache.get_or_read(path).unwrap();
+        }
+
+        assert_eq!(cache.entries.len(), MAX_CACHED_FILES);
+        assert!(!cache.entries.contains_key(&paths[0]));
+        assert!(cache.entries.contains_key(&paths[MAX_CACHED_FILES]));
+    }
+
+    #[test]
+    fn test_list_directory_tree() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::create_dir_all(temp_dir.path().join("src/deep")).unwrap();
+        fs::write(temp_dir.path().join("src/main.rs"), "").unwrap();
+        fs::write(temp_dir.path().join("src/deep/hidden_by_depth.rs"), "").unwrap();
+        fs::write(temp_dir.path().join("README.md"), "").unwrap();
+        fs::write(temp_dir.path().join(".env"), "").unwrap();
+
+        let root = temp_dir.path().to_str().unwrap();
+        let tree = list_directory(root, 2).unwrap();
+        let expected = format!(
+            "{}/\n├── README.md\n└── src/\n    ├── deep/\n    └── main.rs\n",
+            root
+        );
+        assert_eq!(tree, expected);
+
+        assert!(list_directory(temp_dir.path().join("README.md").to_str().unwrap(), 2).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_list_directory_skips_symlink_cycles_and_outside_targets() {
+        let temp_dir = TempDir::new().unwrap();
+        let outside = TempDir::new().unwrap();
+        fs::write(outside.path().join("secret.txt"), "").unwrap();
+        fs::create_dir(temp_dir.path().join("real")).unwrap();
+        fs::write(temp_dir.path().join("real/lib.rs"), "").unwrap();
+        // real/loop -> real（循環）と ext -> ルート外のディレクトリは表示するがたどらない
+        std::os::unix::fs::symlink(temp_dir.path().join("real"), temp_dir.path().join("real/loop")).unwrap();
+        std::os::unix::fs::symlink(outside.path(), temp_dir.path().join("ext")).unwrap();
+        // link -> real はルート内なのでたどり、同じ実体の real はたどり済みなので中身を出さない
+        std::os::unix::fs::symlink(temp_dir.path().join("real"), temp_dir.path().join("link")).unwrap();
+
+        let root = temp_dir.path().to_str().unwrap();
+        let tree = list_directory(root, 3).unwrap();
+        let expected = format!(
+            "{}/\n├── ext/\n├── link/\n│   ├── lib.rs\n│   └── loop/\n└── real/\n",
+            root
+        );
+        assert_eq!(tree, expected);
+    }
+
+    #[test]
+    fn test_read_from_stdin_reader() {
+        let file = read_from("piped text\n".as_bytes(), 1024).unwrap();
+        assert_eq!(file.content, "piped text\n");
+        assert_eq!(file.original_path, "-");
+
+        let err = read_from("too long".as_bytes(), 4).unwrap_err();
+        assert!(err.to_string().contains("too large"));
+        assert!(read_from(&[0xff, 0xfe][..], 1024).is_err());
+    }
+
     #[test]
     fn test_read_file_not_found() {
         let result = read_file("/nonexistent/file.txt");
@@ -285,6 +1254,71 @@ mod tests {
         assert_eq!(content, "Test content");
     }
 
+    #[cfg(unix)]
+    #[test]
+    fn test_write_file_with_mode_sets_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let script = temp_dir.path().join("run.sh");
+        let secret = temp_dir.path().join("secret.env");
+
+        write_file_with_mode(script.to_str().unwrap(), "#!/bin/sh\n", Some(0o700)).unwrap();
+        fs::write(&secret, "old").unwrap();
+        write_file_with_mode(secret.to_str().unwrap(), "TOKEN=x\n", Some(0o600)).unwrap();
+
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&script), 0o700);
+        assert_eq!(mode(&secret), 0o600);
+        assert_eq!(fs::read_to_string(&secret).unwrap(), "TOKEN=x\n");
+    }
+
+    #[test]
+    fn test_write_atomic_leaves_no_temp_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("atomic.txt");
+        fs::write(&file_path, "old").unwrap();
+
+        write_atomic(&file_path, b"new", None).unwrap();
+
+        assert_eq!(fs::read_to_string(&file_path).unwrap(), "new");
+        let entries: Vec<_> = fs::read_dir(temp_dir.path()).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_atomic_keeps_permissions_and_symlinks() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let script = temp_dir.path().join("run.sh");
+        fs::write(&script, "old").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        let link = temp_dir.path().join("link.sh");
+        std::os::unix::fs::symlink(&script, &link).unwrap();
+
+        write_atomic(&link, b"new", None).unwrap();
+
+        assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert_eq!(fs::read_to_string(&script).unwrap(), "new");
+        assert_eq!(fs::metadata(&script).unwrap().permissions().mode() & 0o777, 0o755);
+
+        // 既定値と同じでも、明示した mode は既存ファイルに適用する
+        write_atomic(&script, b"newer", Some(DEFAULT_FILE_MODE)).unwrap();
+        assert_eq!(fs::metadata(&script).unwrap().permissions().mode() & 0o777, 0o644);
+    }
+
+    #[test]
+    fn test_create_temp_file_removed_on_drop() {
+        let (temp_path, path) = create_temp_file("staged", ".rs").unwrap();
+        assert!(path.ends_with(".rs"));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "staged");
+
+        drop(temp_path);
+        assert!(!Path::new(&path).exists());
+    }
+
     #[test]
     fn test_write_file_with_subdirectory() {
         let temp_dir = TempDir::new().unwrap();
@@ -327,4 +1361,167 @@ mod tests {
         // ファイルが存在する
         assert!(file_exists(file_path.to_str().unwrap()));
     }
+
+    #[test]
+    fn test_append_audit_entry() {
+        let temp_dir = TempDir::new().unwrap();
+        let log_path = temp_dir.path().join("logs").join("writes.log");
+        let target = temp_dir.path().join("out.txt");
+
+        append_audit_entry(&log_path, "/models/a.rkllm", &target, b"hello").unwrap();
+        append_audit_entry(&log_path, "/models/a.rkllm", &target, b"hello").unwrap();
+
+        let log = fs::read_to_string(&log_path).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let fields: Vec<&str> = lines[0].split('\t').collect();
+        assert_eq!(fields.len(), 4);
+        assert_eq!(fields[1], "/models/a.rkllm");
+        assert_eq!(fields[2], target.display().to_string());
+        assert_eq!(
+            fields[3],
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+    }
+
+    #[test]
+    fn test_is_ignored() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::write(temp_dir.path().join(".gitignore"), "*.log\n").unwrap();
+        fs::write(temp_dir.path().join(".rkllmignore"), "secret.txt\ntarget/\n").unwrap();
+        fs::create_dir_all(temp_dir.path().join("target/debug")).unwrap();
+        let kept = temp_dir.path().join("notes.txt");
+        let git_ignored = temp_dir.path().join("build.log");
+        let rkllm_ignored = temp_dir.path().join("secret.txt");
+        let nested_ignored = temp_dir.path().join("target/debug/out.txt");
+        for path in [&kept, &git_ignored, &rkllm_ignored, &nested_ignored] {
+            File::create(path).unwrap();
+        }
+
+        let rules = IgnoreRules::new(temp_dir.path());
+        assert!(!rules.is_ignored(kept.to_str().unwrap()));
+        assert!(rules.is_ignored(git_ignored.to_str().unwrap()));
+        assert!(rules.is_ignored(rkllm_ignored.to_str().unwrap()));
+        assert!(rules.is_ignored(nested_ignored.to_str().unwrap()));
+        assert!(!rules.is_ignored("/outside/build.log"));
+    }
+
+    #[test]
+    fn test_is_ignored_nested_gitignore() {
+        let temp_dir = TempDir::new().unwrap();
+        fs::write(temp_dir.path().join(".gitignore"), "*.log
+").unwrap();
+        fs::create_dir_all(temp_dir.path().join("sub/deep")).unwrap();
+        fs::write(temp_dir.path().join("sub/.gitignore"), "*.tmp
+!keep.log
+").unwrap();
+        let root_tmp = temp_dir.path().join("root.tmp");
+        let nested_tmp = temp_dir.path().join("sub/deep/cache.tmp");
+        let nested_log = temp_dir.path().join("sub/other.log");
+        let kept_log = temp_dir.path().join("sub/keep.log");
+        for path in [&root_tmp, &nested_tmp, &nested_log, &kept_log] {
+            File::create(path).unwrap();
+        }
+
+        let rules = IgnoreRules::new(temp_dir.path());
+        assert!(!rules.is_ignored(root_tmp.to_str().unwrap()));
+        assert!(rules.is_ignored(nested_tmp.to_str().unwrap()));
+        assert!(rules.is_ignored(nested_log.to_str().unwrap()));
+        assert!(!rules.is_ignored(kept_log.to_str().unwrap()));
+    }
+
+    #[test]
+    fn test_restore_backup_after_overwrite_and_create() {
+        let temp_dir = TempDir::new().unwrap();
+        let existing = temp_dir.path().join("existing.txt");
+        let created = temp_dir.path().join("created.txt");
+        fs::write(&existing, "before").unwrap();
+
+        begin_write_turn();
+        write_file(existing.to_str().unwrap(), "first", false).unwrap();
+        write_file(existing.to_str().unwrap(), "second", false).unwrap();
+        write_file(created.to_str().unwrap(), "new", false).unwrap();
+
+        let files = restorable_files();
+        let existing = resolve_path(existing.to_str().unwrap()).unwrap();
+        let created = resolve_path(created.to_str().unwrap()).unwrap();
+        assert!(files.contains(&existing));
+        assert!(files.contains(&created));
+
+        restore_backup(&existing).unwrap();
+        restore_backup(&created).unwrap();
+        assert_eq!(fs::read_to_string(&existing).unwrap(), "before");
+        assert!(!created.exists());
+        assert!(restore_backup(&created).is_err());
+    }
+
+    #[test]
+    fn test_diff_file() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("diff.txt");
+        fs::write(&file_path, "a\nb\n").unwrap();
+
+        let diff = diff_file(file_path.to_str().unwrap(), "a\nc\n").unwrap();
+        assert!(diff.contains("-b\n"));
+        assert!(diff.contains("+c\n"));
+        assert!(diff.contains(" a\n"));
+
+        let missing = temp_dir.path().join("missing.txt");
+        let diff = diff_file(missing.to_str().unwrap(), "new\n").unwrap();
+        assert!(diff.contains("+new\n"));
+    }
+
+    #[test]
+    fn test_summarize_binary_change() {
+        let temp_dir = TempDir::new().unwrap();
+        let file_path = temp_dir.path().join("image.bin");
+        let path = file_path.to_str().unwrap();
+
+        let summary = summarize_binary_change(path, b"new").unwrap();
+        assert!(summary.starts_with("(new file) -> 3 bytes (sha256 "));
+
+        fs::write(&file_path, b"old data").unwrap();
+        let summary = summarize_binary_change(path, b"new").unwrap();
+        let old_hash = &sha256_hex(b"old data")[..12];
+        assert!(summary.starts_with(&format!("8 bytes (sha256 {}) -> 3 bytes", old_hash)));
+    }
+
+    #[test]
+    fn test_read_file_through_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let temp_dir = TempDir::new().unwrap();
+        let target = temp_dir.path().join("target.txt");
+        fs::write(&target, "linked").unwrap();
+        symlink("target.txt", temp_dir.path().join("link1.txt")).unwrap();
+        symlink(temp_dir.path().join("link1.txt"), temp_dir.path().join("link2.txt")).unwrap();
+        let content = read_file(temp_dir.path().join("link2.txt").to_str().unwrap()).unwrap();
+        assert_eq!(content.content, "linked");
+
+        symlink("loop_b.txt", temp_dir.path().join("loop_a.txt")).unwrap();
+        symlink("loop_a.txt", temp_dir.path().join("loop_b.txt")).unwrap();
+        let err = read_file(temp_dir.path().join("loop_a.txt").to_str().unwrap()).unwrap_err();
+        assert!(err.to_string().contains("Symlink loop or depth exceeded"));
+
+        // target.txt <- chain0 <- chain1 <- ... <- chain8（9段）
+        let mut previous = "target.txt".to_string();
+        for i in 0..=MAX_SYMLINK_DEPTH {
+            let name = format!("chain{}.txt", i);
+            symlink(&previous, temp_dir.path().join(&name)).unwrap();
+            previous = name;
+        }
+        let err = read_file(temp_dir.path().join(&previous).to_str().unwrap()).unwrap_err();
+        assert!(err.to_string().contains("Symlink loop or depth exceeded"));
+    }
+
+    #[test]
+    fn test_patch_content() {
+        let original = "fn main() {\n    println!(\"Hello\");\n}\n";
+        let patch = "\n--- a/main.rs\n+++ b/main.rs\n@@ -1,3 +1,3 @@\n fn main() {\n-    println!(\"Hello\");\n+    println!(\"Hi\");\n }\n";
+
+        let patched = patch_content(original, patch).unwrap();
+        assert_eq!(patched, "fn main() {\n    println!(\"Hi\");\n}\n");
+
+        assert!(patch_content("unrelated\n", patch).is_err());
+    }
 }
@@ -1,4 +1,6 @@
 use regex::Regex;
+use std::collections::HashMap;
+use std::path::Path;
 use std::sync::OnceLock;
 
 /// LLMの出力からファイル操作を抽出する
@@ -9,20 +11,90 @@ pub struct FileOperation {
     pub path: String,
     /// ファイル内容
     pub content: String,
-    /// 操作の種類（現在は CREATE のみサポート）
+    /// 操作の種類（作成/上書き、またはパッチ適用）
     pub operation_type: FileOperationType,
+    /// ファイル内容のエンコーディング
+    pub encoding: FileOperationEncoding,
+    /// 新規作成するファイルのパーミッション（`mode="0755"` 属性。未指定なら既定の 0o644）
+    pub mode: Option<u32>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum FileOperationType {
     Create,
+    /// unified diff を既存ファイルに適用する
+    Patch,
 }
 
-/// XMLスタイルのファイルマーカーパターン: <file path="...">...</file>
+/// ファイル内容のエンコーディング
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileOperationEncoding {
+    /// テキストをそのまま書き込む
+    Plain,
+    /// base64 デコードしたバイト列を書き込む（バイナリ出力用）
+    Base64,
+}
+
+impl FileOperation {
+    /// 絶対パス（`~` で始まるホームディレクトリ基準のパスを含む）への書き込みか
+    ///
+    /// 作業ディレクトリの外、特にシステムファイルを書き換える恐れがあるため、
+    /// `confirm_writes` の設定に関わらず確認を求める判定に使う。
+    pub fn is_absolute_path(&self) -> bool {
+        is_absolute_write_path(&self.path)
+    }
+}
+
+/// 絶対パス（`~` で始まるホームディレクトリ基準のパスを含む）か
+///
+/// ツール呼び出し（`write_file`）の引数など、`FileOperation` 以外の書き込み先にも使う。
+pub fn is_absolute_write_path(path: &str) -> bool {
+    path.starts_with('~') || Path::new(path).is_absolute()
+}
+
+impl FileOperationEncoding {
+    fn from_attr(value: Option<&str>) -> Self {
+        match value {
+            Some(v) if v.trim().eq_ignore_ascii_case("base64") => Self::Base64,
+            _ => Self::Plain,
+        }
+    }
+}
+
+/// `mode` 属性の8進数表記（`0755`, `644` など）を解釈する。不正な値は None
+///
+/// モデルの出力は信頼できないため、setuid / setgid / sticky ビットを含む値も None にする。
+fn parse_mode_attr(value: &str) -> Option<u32> {
+    u32::from_str_radix(value.trim(), 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+}
+
+/// XMLスタイルのファイルマーカーパターン: <file path="..." [encoding="..."] [mode="..."]>...</file>
 fn xml_file_pattern() -> &'static Regex {
     static PATTERN: OnceLock<Regex> = OnceLock::new();
     PATTERN.get_or_init(|| {
-        Regex::new(r#"<file\s+path="([^"]+)"\s*>([\s\S]*?)</file>"#).unwrap()
+        Regex::new(r#"<file\s+path="([^"]+)"((?:\s+\w+="[^"]*")*)\s*>([\s\S]*?)</file>"#)
+            .unwrap()
+    })
+}
+
+/// `<file>` タグの追加属性から `name` の値を取り出す
+fn file_attr<'a>(attrs: &'a str, name: &str) -> Option<&'a str> {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    let pattern = PATTERN.get_or_init(|| Regex::new(r#"(\w+)="([^"]*)""#).unwrap());
+    pattern
+        .captures_iter(attrs)
+        .find(|cap| &cap[1] == name)
+        .and_then(|cap| cap.get(2))
+        .map(|m| m.as_str())
+}
+
+/// パッチマーカーパターン: <patch path="...">unified diff</patch>
+fn xml_patch_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| {
+        Regex::new(r#"<patch\s+path="([^"]+)"\s*>([\s\S]*?)</patch>"#).unwrap()
     })
 }
 
@@ -54,6 +126,29 @@ fn bracket_file_pattern() -> &'static Regex {
 /// </file>
 /// ```
 ///
+/// `encoding="base64"` 属性を付けると内容を base64 としてデコードして書き込む:
+/// ```text
+/// <file path="assets/icon.png" encoding="base64">iVBORw0KGgo...</file>
+/// ```
+///
+/// `mode="0755"` 属性で新規作成するファイルのパーミッションを指定できる（Unix のみ）:
+/// ```text
+/// <file path="scripts/build.sh" mode="0755">#!/bin/sh ...</file>
+/// ```
+///
+/// 既存ファイルへの差分は unified diff で指定できる:
+/// ```text
+/// <patch path="src/example.rs">
+/// --- a/src/example.rs
+/// +++ b/src/example.rs
+/// @@ -1,3 +1,3 @@
+///  fn main() {
+/// -    println!("Hello");
+/// +    println!("Hello, World!");
+///  }
+/// </patch>
+/// ```
+///
 /// 2. ブラケットスタイル:
 /// ```text
 /// [CREATE_FILE: src/example.rs]
@@ -70,11 +165,29 @@ pub fn parse_file_operations(output: &str) -> Vec<FileOperation> {
     // XMLスタイルのマーカーを検出
     let xml_pattern = xml_file_pattern();
     for cap in xml_pattern.captures_iter(output) {
-        if let (Some(path), Some(content)) = (cap.get(1), cap.get(2)) {
+        if let (Some(path), Some(content)) = (cap.get(1), cap.get(3)) {
+            let attrs = cap.get(2).map_or("", |m| m.as_str());
+            let encoding = FileOperationEncoding::from_attr(file_attr(attrs, "encoding"));
+            let mode = file_attr(attrs, "mode").and_then(parse_mode_attr);
             operations.push(FileOperation {
                 path: path.as_str().trim().to_string(),
-                content: content.as_str().to_string(),
+                content: normalize_file_content(content.as_str()),
                 operation_type: FileOperationType::Create,
+                encoding,
+                mode,
+            });
+        }
+    }
+
+    // パッチ（unified diff）を検出
+    for cap in xml_patch_pattern().captures_iter(output) {
+        if let (Some(path), Some(content)) = (cap.get(1), cap.get(2)) {
+            operations.push(FileOperation {
+                path: path.as_str().trim().to_string(),
+                content: normalize_file_content(content.as_str()),
+                operation_type: FileOperationType::Patch,
+                encoding: FileOperationEncoding::Plain,
+                mode: None,
             });
         }
     }
@@ -85,19 +198,138 @@ pub fn parse_file_operations(output: &str) -> Vec<FileOperation> {
         if let (Some(path), Some(content)) = (cap.get(1), cap.get(2)) {
             operations.push(FileOperation {
                 path: path.as_str().trim().to_string(),
-                content: content.as_str().to_string(),
+                content: normalize_file_content(content.as_str()),
                 operation_type: FileOperationType::Create,
+                encoding: FileOperationEncoding::Plain,
+                mode: None,
             });
         }
     }
 
-    operations
+    // 空のパスや NUL を含むパスには書き込めないため捨てる
+    operations.retain(|op| !op.path.is_empty() && !op.path.contains('\0'));
+    dedup_operations(operations)
+}
+
+/// ファイル内容がコードフェンスで囲まれている場合に取り除く
+///
+/// # 引数
+/// * `content` - マーカー内の内容
+///
+/// # 戻り値
+/// 先頭の `` ```[lang] `` 行と末尾の `` ``` `` を除いた内容（末尾は改行1つ）。
+/// フェンスがなければ元の内容をそのまま返す
+pub fn normalize_file_content(content: &str) -> String {
+    let Some(after_open) = content.trim_start().strip_prefix("```") else {
+        return content.to_string();
+    };
+    let Some((lang, body)) = after_open.split_once('\n') else {
+        return content.to_string();
+    };
+    // 言語指定（rust, c++, objective-c など）以外が続く行はフェンスとみなさない
+    if !lang
+        .trim_end()
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "+#-_.".contains(c))
+    {
+        return content.to_string();
+    }
+    let Some(body) = body.trim_end().strip_suffix("```") else {
+        return content.to_string();
+    };
+
+    let body = body.strip_suffix('\n').unwrap_or(body);
+    let body = body.strip_suffix('\r').unwrap_or(body);
+    format!("{}\n", body)
+}
+
+/// 同じパスへの出力が複数ある場合は最後のものだけを残す（先のものは後で上書きされるだけなので）
+///
+/// パッチは順に適用されるため重複扱いしない。
+fn dedup_operations(operations: Vec<FileOperation>) -> Vec<FileOperation> {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for op in operations.iter().filter(|op| op.operation_type == FileOperationType::Create) {
+        *counts.entry(op.path.clone()).or_insert(0) += 1;
+    }
+
+    let mut deduped = Vec::with_capacity(operations.len());
+    for op in operations {
+        if op.operation_type == FileOperationType::Patch {
+            deduped.push(op);
+            continue;
+        }
+        let remaining = counts.get_mut(&op.path).expect("path was counted above");
+        *remaining -= 1;
+        if *remaining == 0 {
+            deduped.push(op);
+        } else {
+            eprintln!("[Warning: duplicate file output for {}, using last]", op.path);
+        }
+    }
+    deduped
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_is_absolute_path() {
+        let op = |path: &str| FileOperation {
+            path: path.to_string(),
+            content: String::new(),
+            operation_type: FileOperationType::Create,
+            encoding: FileOperationEncoding::Plain,
+            mode: None,
+        };
+        assert!(op("/etc/cron.d/malicious").is_absolute_path());
+        assert!(op("~/.bashrc").is_absolute_path());
+        assert!(!op("src/main.rs").is_absolute_path());
+        assert!(!op("./out.txt").is_absolute_path());
+        assert!(is_absolute_write_path("~/.ssh/config"));
+        assert!(!is_absolute_write_path("notes/todo.md"));
+    }
+
+    #[test]
+    fn test_normalize_strips_code_fence() {
+        assert_eq!(
+            normalize_file_content("\n```rust\nfn main() {}\n```\n"),
+            "fn main() {}\n"
+        );
+        assert_eq!(normalize_file_content("```\nplain\n```"), "plain\n");
+        assert_eq!(normalize_file_content("```c++\nint x;\n```\n"), "int x;\n");
+        assert_eq!(
+            normalize_file_content("```objective-c\n@end\r\n```"),
+            "@end\n"
+        );
+    }
+
+    #[test]
+    fn test_normalize_keeps_unfenced_content() {
+        let content = "\nfn main() {}\n";
+        assert_eq!(normalize_file_content(content), content);
+        // 閉じフェンスがなければそのまま
+        let content = "```rust\nfn main() {}\n";
+        assert_eq!(normalize_file_content(content), content);
+        // 本文中のフェンスは残す
+        let content = "# Title\n```sh\nls\n```\n";
+        assert_eq!(normalize_file_content(content), content);
+    }
+
+    #[test]
+    fn test_parse_xml_style_with_fence() {
+        let output = "<file path=\"src/main.rs\">\n```rust\nfn main() {}\n```\n</file>";
+        let ops = parse_file_operations(output);
+        assert_eq!(ops.len(), 1);
+        assert_eq!(ops[0].content, "fn main() {}\n");
+    }
+
+    #[test]
+    fn test_parse_drops_empty_and_nul_paths() {
+        assert!(parse_file_operations("<file path=\" \">x</file>").is_empty());
+        assert!(parse_file_operations("<file path=\"a\0b\">x</file>").is_empty());
+    }
+
     #[test]
     fn test_parse_xml_style() {
         let output = r#"ファイルを作成します。
@@ -186,4 +418,80 @@ fn bracket_func() {}
         assert_eq!(ops[0].path, "src/xml_style.rs");
         assert_eq!(ops[1].path, "src/bracket_style.rs");
     }
+
+    #[test]
+    fn test_parse_base64_encoding() {
+        let output = r#"<file path="assets/data.bin" encoding="base64">AAEC/w==</file>
+<file path="notes.txt">plain</file>"#;
+
+        let ops = parse_file_operations(output);
+        assert_eq!(ops.len(), 2);
+        assert_eq!(ops[0].path, "assets/data.bin");
+        assert_eq!(ops[0].content, "AAEC/w==");
+        assert_eq!(ops[0].encoding, FileOperationEncoding::Base64);
+        assert_eq!(ops[1].encoding, FileOperationEncoding::Plain);
+    }
+
+    #[test]
+    fn test_parse_mode_attribute() {
+        let output = r#"<file path="build.sh" mode="0755">#!/bin/sh</file>
+<file path="secret.env" encoding="base64" mode="600">AAEC</file>
+<file path="bad.txt" mode="999">x</file>
+<file path="plain.txt">x</file>"#;
+
+        let ops = parse_file_operations(output);
+        assert_eq!(ops.len(), 4);
+        assert_eq!(ops[0].mode, Some(0o755));
+        assert_eq!(ops[1].mode, Some(0o600));
+        assert_eq!(ops[1].encoding, FileOperationEncoding::Base64);
+        assert_eq!(ops[2].mode, None);
+        assert_eq!(ops[3].mode, None);
+    }
+
+    #[test]
+    fn test_parse_mode_attribute_rejects_special_bits() {
+        let output = r#"<file path="suid" mode="4755">x</file>
+<file path="sgid" mode="2755">x</file>
+<file path="sticky" mode="1777">x</file>"#;
+
+        let ops = parse_file_operations(output);
+        assert_eq!(ops.len(), 3);
+        assert!(ops.iter().all(|op| op.mode.is_none()));
+    }
+
+    #[test]
+    fn test_duplicate_paths_keep_last() {
+        let output = r#"<file path="src/lib.rs">
+first
+</file>
+<file path="src/other.rs">
+other
+</file>
+<file path="src/lib.rs">
+second
+</file>"#;
+
+        let ops = parse_file_operations(output);
+        assert_eq!(ops.len(), 2);
+        assert_eq!(ops[0].path, "src/other.rs");
+        assert_eq!(ops[1].path, "src/lib.rs");
+        assert!(ops[1].content.contains("second"));
+    }
+
+    #[test]
+    fn test_parse_patch() {
+        let output = r#"<patch path="src/main.rs">
+--- a/src/main.rs
++++ b/src/main.rs
+@@ -1 +1 @@
+-old
++new
+</patch>"#;
+
+        let ops = parse_file_operations(output);
+        assert_eq!(ops.len(), 1);
+        assert_eq!(ops[0].path, "src/main.rs");
+        assert_eq!(ops[0].operation_type, FileOperationType::Patch);
+        assert!(ops[0].content.contains("+new"));
+    }
 }
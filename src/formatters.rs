@@ -0,0 +1,81 @@
+//! 生成されたファイルを言語ごとの外部フォーマッタで整形する
+//!
+//! 拡張子から言語を判定し、`rustfmt` / `black` / `prettier` をその場で実行する。
+
+use crate::file_ops;
+use anyhow::{anyhow, Context, Result};
+use std::io::ErrorKind;
+use std::path::Path;
+use std::process::Command;
+
+/// 拡張子に対応するフォーマッタのコマンドと引数（ファイルパスは `--` の後に渡す）
+///
+/// rustfmt のエディションは指定せず、プロジェクトの rustfmt.toml / Cargo.toml に従わせる。
+///
+/// # 戻り値
+/// 対応するフォーマッタがない拡張子の場合は None
+pub fn formatter_for(path: &Path) -> Option<(&'static str, &'static [&'static str])> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    match extension.as_str() {
+        "rs" => Some(("rustfmt", &[])),
+        "py" => Some(("black", &["--quiet"])),
+        "ts" | "tsx" | "js" | "jsx" | "json" => Some(("prettier", &["--write"])),
+        _ => None,
+    }
+}
+
+/// ファイルを拡張子に対応するフォーマッタで整形し、上書き保存する
+///
+/// # 引数
+/// * `path` - 整形するファイルのパス（`~` は展開し、絶対パスにしてから渡す）
+///
+/// # エラー
+/// 対応するフォーマッタがない場合、パスを解決できない場合、フォーマッタがインストールされていない場合、
+/// フォーマッタが失敗した場合（エラーメッセージはフォーマッタの標準エラー出力）
+pub fn format_file(path: &str) -> Result<()> {
+    let (program, args) = formatter_for(Path::new(path))
+        .ok_or_else(|| anyhow!("No formatter for this file type"))?;
+
+    let resolved = file_ops::resolve_path(path)?;
+
+    // `-` で始まるファイル名をオプションと解釈させない
+    let output = match Command::new(program).args(args).arg("--").arg(&resolved).output() {
+        Ok(output) => output,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Err(anyhow!("{} is not installed", program));
+        }
+        Err(e) => return Err(e).with_context(|| format!("Failed to run {}", program)),
+    };
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let message = stderr.trim();
+        return Err(if message.is_empty() {
+            anyhow!("{} exited with {}", program, output.status)
+        } else {
+            anyhow!("{}", message)
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formatter_for_detects_language_by_extension() {
+        assert_eq!(formatter_for(Path::new("src/main.rs")).unwrap().0, "rustfmt");
+        assert_eq!(formatter_for(Path::new("app.PY")).unwrap().0, "black");
+        for path in ["index.ts", "app.jsx", "package.json"] {
+            assert_eq!(formatter_for(Path::new(path)).unwrap().0, "prettier");
+        }
+        assert!(formatter_for(Path::new("notes.txt")).is_none());
+        assert!(formatter_for(Path::new("Makefile")).is_none());
+    }
+
+    #[test]
+    fn format_file_rejects_unsupported_files() {
+        let err = format_file("notes.txt").unwrap_err();
+        assert!(err.to_string().contains("No formatter"));
+    }
+}
@@ -1,7 +1,60 @@
 /// ファイル操作意図と出力優先度の判定をまとめたモジュール
-use once_cell::sync::Lazy;
+use once_cell::sync::{Lazy, OnceCell};
+use regex::Regex;
+use std::borrow::Cow;
 use std::collections::HashSet;
 
+/// ファイル検出を行う書き込み意図スコアの既定の閾値
+pub const DEFAULT_FILE_INTENT_THRESHOLD: f32 = 0.3;
+
+/// 一致数をスコアに変換する正規化係数（一致が4つあれば 1.0）
+const INTENT_NORMALIZATION: f32 = 4.0;
+
+/// 設定ファイル（`[intent]`）で追加されたキーワード
+#[derive(Debug, Default)]
+struct CustomKeywords {
+    write: Vec<String>,
+    read: Vec<String>,
+}
+
+static CUSTOM_KEYWORDS: OnceCell<CustomKeywords> = OnceCell::new();
+
+/// 設定ファイル由来の追加キーワードを登録する（起動時に一度だけ呼び出す）
+pub fn set_custom_keywords(write: &[String], read: &[String]) {
+    let _ = CUSTOM_KEYWORDS.set(CustomKeywords {
+        write: normalize_keywords(write),
+        read: normalize_keywords(read),
+    });
+}
+
+fn normalize_keywords(keywords: &[String]) -> Vec<String> {
+    keywords
+        .iter()
+        .map(|kw| kw.trim().to_lowercase())
+        .filter(|kw| !kw.is_empty())
+        .collect()
+}
+
+/// 小文字化した入力を返す
+///
+/// 入力ごと（将来はキー入力ごと）に呼ばれるため、ASCII のみの入力はバイト単位で判定し、
+/// 大文字を含まなければ割り当てなしでそのまま返す。
+fn to_lower(input: &str) -> Cow<'_, str> {
+    if input.is_ascii() {
+        if input.bytes().any(|b| b.is_ascii_uppercase()) {
+            Cow::Owned(input.to_ascii_lowercase())
+        } else {
+            Cow::Borrowed(input)
+        }
+    } else {
+        Cow::Owned(input.to_lowercase())
+    }
+}
+
+fn contains_any(input_lower: &str, keywords: &[String]) -> bool {
+    keywords.iter().any(|kw| input_lower.contains(kw.as_str()))
+}
+
 static STRONG_KEYWORDS: Lazy<HashSet<&'static str>> = Lazy::new(|| {
     [
         // 日本語
@@ -28,6 +81,10 @@ static FILE_OPERATION_PHRASES: Lazy<HashSet<&'static str>> = Lazy::new(|| {
     .collect()
 });
 
+// 拡張子付きのファイル名（例: test.txt, src/main.rs, メモ.txt）。名前部分は日本語などの文字も許す
+static FILE_NAME_PATTERN: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"[\p{L}\p{N}_.\-/]+\.[A-Za-z][A-Za-z0-9]{0,7}(?-u:\b)").unwrap());
+
 static FILE_READ_KEYWORDS: Lazy<HashSet<&'static str>> = Lazy::new(|| {
     [
         // 日本語
@@ -40,31 +97,129 @@ static FILE_READ_KEYWORDS: Lazy<HashSet<&'static str>> = Lazy::new(|| {
     .collect()
 });
 
-/// ファイル操作の意図が含まれているかを判定
-pub fn has_file_operation_intent(input: &str) -> bool {
-    let input_lower = input.to_lowercase();
+static SHELL_COMMAND_KEYWORDS: Lazy<HashSet<&'static str>> = Lazy::new(|| {
+    [
+        // 日本語
+        "実行", "実行して", "コマンド",
+        // 英語（単語単位で判定）。"run" / "make" は通常の文でも使われるため含めない
+        "execute", "shell", "bash",
+    ]
+    .into_iter()
+    .collect()
+});
+
+static SHELL_COMMAND_PHRASES: Lazy<HashSet<&'static str>> = Lazy::new(|| {
+    [
+        "cargo build", "cargo test", "cargo run", "npm run", "npm install", "git status",
+    ]
+    .into_iter()
+    .collect()
+});
+
+static UNDO_PHRASES: Lazy<HashSet<&'static str>> = Lazy::new(|| {
+    [
+        "元に戻して", "元に戻す", "もとに戻して", "取り消して", "取り消す", "とりけして",
+        "restore previous", "roll back",
+    ]
+    .into_iter()
+    .collect()
+});
+
+static UNDO_KEYWORDS: Lazy<HashSet<&'static str>> = Lazy::new(|| {
+    ["undo", "revert"].into_iter().collect()
+});
+
+/// ファイル操作（書き込み）意図の確からしさを 0.0〜1.0 で返す
+///
+/// 一致したキーワード・フレーズの数（ファイル名らしき語も1つと数える）を正規化する。
+/// 0.0 なら意図なし。単一のキーワードのみ（例: "write a poem"）は既定の閾値を下回る。
+pub fn has_file_operation_intent(input: &str) -> f32 {
+    let input_lower = to_lower(input);
 
-    STRONG_KEYWORDS
+    let keywords = STRONG_KEYWORDS
         .iter()
-        .any(|&kw| input_lower.contains(kw))
-        || FILE_OPERATION_PHRASES
+        .filter(|&&kw| input_lower.contains(kw))
+        .count();
+    let phrases = FILE_OPERATION_PHRASES
+        .iter()
+        .filter(|&&phrase| input_lower.contains(phrase))
+        .count();
+    let custom = CUSTOM_KEYWORDS.get().map_or(0, |custom| {
+        custom
+            .write
             .iter()
-            .any(|&phrase| input_lower.contains(phrase))
+            .filter(|kw| input_lower.contains(kw.as_str()))
+            .count()
+    });
+
+    let matches = keywords + phrases + custom;
+    if matches == 0 {
+        return 0.0;
+    }
+    let file_name = usize::from(FILE_NAME_PATTERN.is_match(input));
+
+    ((matches + file_name) as f32 / INTENT_NORMALIZATION).clamp(0.0, 1.0)
 }
 
 /// ファイル読み込みの意図が含まれているかを判定
 pub fn has_file_read_intent(input: &str) -> bool {
-    let input_lower = input.to_lowercase();
+    let input_lower = to_lower(input);
     FILE_READ_KEYWORDS
         .iter()
         .any(|&kw| input_lower.contains(kw))
+        || CUSTOM_KEYWORDS
+            .get()
+            .is_some_and(|custom| contains_any(&input_lower, &custom.read))
+}
+
+/// シェルコマンド実行の意図が含まれているかを判定
+pub fn has_shell_command_intent(input: &str) -> bool {
+    let input_lower = to_lower(input);
+
+    if SHELL_COMMAND_PHRASES
+        .iter()
+        .any(|&phrase| input_lower.contains(phrase))
+    {
+        return true;
+    }
+
+    // 英単語は部分一致だと誤検出が多い（例: "truncate" の "run"）ため単語単位で比較する
+    let words: HashSet<&str> = input_lower
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .collect();
+
+    SHELL_COMMAND_KEYWORDS.iter().any(|&kw| {
+        if kw.is_ascii() {
+            words.contains(kw)
+        } else {
+            input_lower.contains(kw)
+        }
+    })
+}
+
+/// 直前のファイル書き込みを取り消す意図が含まれているかを判定
+pub fn has_undo_intent(input: &str) -> bool {
+    let input_lower = to_lower(input);
+
+    if UNDO_PHRASES
+        .iter()
+        .any(|&phrase| input_lower.contains(phrase))
+    {
+        return true;
+    }
+
+    // "undo" は "undone" などにも含まれるため単語単位で比較する
+    input_lower
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .any(|w| UNDO_KEYWORDS.contains(w))
 }
 
 /// 出力専用と推定できるキーワードを含むか判定
 pub fn prefers_output_only(input: &str) -> bool {
     // has_file_operation_intent が真なら、強いキーワードはすでに検出済み。
     // ここでは「保存/書き込み/生成」系の語と file operation phrases を再利用して出力優先を判定する。
-    has_file_operation_intent(input)
+    has_file_operation_intent(input) > 0.0
 }
 
 #[cfg(test)]
@@ -89,4 +244,66 @@ mod tests {
         assert!(has_file_read_intent("proofread the text"));
         assert!(!has_file_read_intent("save the output"));
     }
+
+    #[test]
+    fn test_file_operation_intent_score() {
+        assert_eq!(has_file_operation_intent("hello"), 0.0);
+        // ファイル名だけでは意図とみなさない
+        assert_eq!(has_file_operation_intent("what is main.rs?"), 0.0);
+        assert!(has_file_operation_intent("write a poem") < DEFAULT_FILE_INTENT_THRESHOLD);
+        assert!(has_file_operation_intent("write a poem") > 0.0);
+        assert!(has_file_operation_intent("test.txtを作成して") >= DEFAULT_FILE_INTENT_THRESHOLD);
+        assert!(has_file_operation_intent("メモ.txt を作成して") >= DEFAULT_FILE_INTENT_THRESHOLD);
+        assert!(has_file_operation_intent("議事録_2024.md に保存して") >= DEFAULT_FILE_INTENT_THRESHOLD);
+        assert!(has_file_operation_intent("ファイルを書いて") >= DEFAULT_FILE_INTENT_THRESHOLD);
+        assert!(
+            has_file_operation_intent("create file notes.md")
+                > has_file_operation_intent("write a poem")
+        );
+        assert!(has_file_operation_intent("create file and save to file out.txt") <= 1.0);
+    }
+
+    #[test]
+    fn test_has_shell_command_intent() {
+        assert!(has_shell_command_intent("ls -la を実行して"));
+        assert!(has_shell_command_intent("このコマンドを流して"));
+        assert!(has_shell_command_intent("execute `ls -la`"));
+        assert!(!has_shell_command_intent("run the numbers again"));
+        assert!(!has_shell_command_intent("make a summary of this"));
+        assert!(has_shell_command_intent("please cargo build the project"));
+        assert!(!has_shell_command_intent("truncate the summary"));
+        assert!(!has_shell_command_intent("要約して"));
+        assert!(!has_shell_command_intent("hello"));
+    }
+
+    #[test]
+    fn test_has_undo_intent() {
+        assert!(has_undo_intent("さっきの変更を元に戻して"));
+        assert!(has_undo_intent("書き込みを取り消して"));
+        assert!(has_undo_intent("undo that"));
+        assert!(has_undo_intent("Please REVERT the last write"));
+        assert!(has_undo_intent("restore previous version"));
+        assert!(!has_undo_intent("the task is undone"));
+        assert!(!has_undo_intent("要約して"));
+    }
+
+    #[test]
+    fn test_to_lower_borrows_lowercase_ascii() {
+        assert!(matches!(to_lower("save to out.txt"), Cow::Borrowed(_)));
+        assert_eq!(to_lower("Save To OUT.txt"), "save to out.txt");
+        assert_eq!(to_lower("ÉCRIRE ファイル"), "écrire ファイル");
+    }
+
+    #[test]
+    fn test_custom_keywords_are_normalized_and_matched() {
+        let keywords = normalize_keywords(&[
+            " Speichern ".to_string(),
+            "".to_string(),
+            "créer".to_string(),
+        ]);
+        assert_eq!(keywords, vec!["speichern".to_string(), "créer".to_string()]);
+        assert!(contains_any(&"Bitte SPEICHERN".to_lowercase(), &keywords));
+        assert!(contains_any("créer un fichier", &keywords));
+        assert!(!contains_any("bonjour", &keywords));
+    }
 }
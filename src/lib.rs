@@ -0,0 +1,35 @@
+//! RKLLM CLI をライブラリとして利用するためのエントリポイント
+//!
+//! 公開 API は `ChatSessionBuilder` / `ChatSession` と MCP クライアント（`mcp`）のみ。
+//!
+//! ```ignore
+//! use rkllm_cli::ChatSessionBuilder;
+//!
+//! let session = ChatSessionBuilder::new("model.rkllm").build().await?;
+//! let response = session.run_once("Hello", |text| print!("{}", text)).await?;
+//! ```
+
+pub mod mcp;
+
+pub use chat::{ChatSession, ChatSessionBuilder};
+
+mod chat;
+mod events;
+mod file_detector;
+mod file_ops;
+mod formatters;
+mod llm;
+mod prompt_builder;
+mod watch;
+
+// 以下はバイナリ・ベンチマーク・ファジング用（ライブラリの公開 API ではない）
+#[doc(hidden)]
+pub mod config;
+#[doc(hidden)]
+pub mod ffi;
+#[doc(hidden)]
+pub mod file_output_parser;
+#[doc(hidden)]
+pub mod intent;
+#[doc(hidden)]
+pub mod tool_detector;
@@ -2,10 +2,13 @@ use crate::ffi::*;
 use anyhow::{anyhow, Context, Result};
 use libc::{c_int, c_void};
 use std::ffi::{CStr, CString};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Condvar, Mutex, mpsc};
 use std::env;
+use std::str::FromStr;
+use tokio_util::sync::CancellationToken;
 
 // Gemma chat template
 const GEMMA_TEMPLATE: &str = "<start_of_turn>user\n{prompt}<end_of_turn>\n<start_of_turn>model\n";
@@ -52,8 +55,16 @@ pub struct RKLLMConfig {
     pub mirostat_tau: f32,
     pub mirostat_eta: f32,
     pub skip_special_token: bool,
+    /// ライブラリが出力に漏らした特殊トークン（`<|im_end|>` など）を表示・応答から取り除く
+    pub strip_special_tokens: bool,
     pub template: ChatTemplate,
     pub infer_timeout: Duration,
+    /// 推論に使う CPU コアのビットマスク
+    pub cpu_mask: u32,
+    /// 推論に使う CPU コア数
+    pub cpu_count: i8,
+    /// プロンプト処理（prefill）のバッチサイズ（1〜100）
+    pub batch_size: u8,
 }
 
 impl Default for RKLLMConfig {
@@ -62,36 +73,301 @@ impl Default for RKLLMConfig {
             model_path: String::new(),
             max_context_len: 4096,
             max_new_tokens: 4096,
-            top_k: 64,              // default 1
-            top_p: 0.95,            // default 0.9
-            temperature: 1.0,       // default 0.8
-            repeat_penalty: 1.0,    // default 1.1
+            top_k: from_env_or("RKLLM_TOP_K", 64),                   // default 1
+            top_p: from_env_or("RKLLM_TOP_P", 0.95),                 // default 0.9
+            temperature: from_env_or("RKLLM_TEMPERATURE", 1.0),      // default 0.8
+            repeat_penalty: from_env_or("RKLLM_REPEAT_PENALTY", 1.0), // default 1.1
             frequency_penalty: 0.0,
             presence_penalty: 0.0,  // default 0.0
             mirostat: 0,
             mirostat_tau: 5.0,
             mirostat_eta: 0.1,
             skip_special_token: true,
+            strip_special_tokens: true,
             template: ChatTemplate::from_env(),
             infer_timeout: infer_timeout_from_env(),
+            // RK3588 の big コア (CPU4-7)
+            cpu_mask: (1 << 4) | (1 << 5) | (1 << 6) | (1 << 7),
+            cpu_count: 4,
+            batch_size: batch_size_from_env(),
         }
     }
 }
 
+/// コールバックスレッドへ渡すチャンクのバッファ上限（満杯時は送信側がブロックする）
+const CALLBACK_CHANNEL_CAPACITY: usize = 64;
+
+/// `n_batch` に指定できる範囲
+const BATCH_SIZE_RANGE: std::ops::RangeInclusive<u8> = 1..=100;
+
+/// モデルファイルとして妥当な最小サイズ（これ未満は破損や取り違えとみなす）
+const MIN_MODEL_FILE_SIZE: u64 = 1024 * 1024;
+
+/// モデルファイルが通常ファイルで、妥当なサイズを持つことを確認する
+///
+/// # エラー
+/// - ファイルが存在しない（リンク切れのシンボリックリンクを含む）
+/// - ディレクトリなど通常ファイルではない
+/// - サイズが 1MB 以下
+fn validate_model_file(model_path: &str) -> Result<()> {
+    let metadata = std::fs::metadata(model_path).map_err(|e| {
+        if e.kind() == std::io::ErrorKind::NotFound {
+            anyhow!("Model file not found (or is a broken symlink): {}", model_path)
+        } else {
+            anyhow!("Failed to read model file metadata '{}': {}", model_path, e)
+        }
+    })?;
+
+    if !metadata.is_file() {
+        return Err(anyhow!("Model path is not a regular file: {}", model_path));
+    }
+    if metadata.len() <= MIN_MODEL_FILE_SIZE {
+        return Err(anyhow!(
+            "Model file is too small ({} bytes); expected an .rkllm model larger than 1 MB: {}",
+            metadata.len(),
+            model_path
+        ));
+    }
+
+    Ok(())
+}
+
+/// サンプリングパラメータが明らかに不正でないことを確認する
+///
+/// # エラー
+/// - `temperature` が負（または NaN）
+/// - `top_k` が 1 未満
+/// - `top_p` が 0.0〜1.0 の範囲外
+/// - `max_context_len` が 1 未満
+fn validate_sampling_params(config: &RKLLMConfig) -> Result<()> {
+    if config.temperature.is_nan() || config.temperature < 0.0 {
+        return Err(anyhow!(
+            "Invalid temperature {}: must be 0.0 or greater",
+            config.temperature
+        ));
+    }
+    if config.top_k < 1 {
+        return Err(anyhow!("Invalid top_k {}: must be 1 or greater", config.top_k));
+    }
+    if !(0.0..=1.0).contains(&config.top_p) {
+        return Err(anyhow!(
+            "Invalid top_p {}: must be between 0.0 and 1.0",
+            config.top_p
+        ));
+    }
+    if config.max_context_len < 1 {
+        return Err(anyhow!(
+            "Invalid max_context_len {}: must be 1 or greater",
+            config.max_context_len
+        ));
+    }
+    // max_new_tokens は 0 以下（設定ファイルでは -1）を上限なしとして扱うため検証しない
+
+    Ok(())
+}
+
+/// ストリーミング送信をまとめる既定のバイト数
+const DEFAULT_FLUSH_THRESHOLD: usize = 8;
+
+/// 推論の結果
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunOutput {
+    /// 応答全体
+    pub text: String,
+    /// 生成トークン数が `max_new_tokens` に達した（応答が上限で打ち切られた可能性が高い）
+    pub response_was_truncated: bool,
+    /// 推論開始から最初の（空でない）トークンが届くまでの時間（TTFT）。トークンが出なければ None
+    pub time_to_first_token: Option<Duration>,
+}
+
+/// 推論がタイムアウトした場合のエラー（それまでに生成された部分応答を保持する）
+#[derive(Debug)]
+pub struct InferenceTimeout {
+    pub partial_output: String,
+    pub timeout: Duration,
+}
+
+impl std::fmt::Display for InferenceTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Inference timed out after {}s", self.timeout.as_secs())
+    }
+}
+
+impl std::error::Error for InferenceTimeout {}
+
+/// 推論がキャンセルされた場合のエラー（それまでに生成された部分応答を保持する）
+#[derive(Debug)]
+pub struct InferenceCancelled(pub String);
+
+impl std::fmt::Display for InferenceCancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Inference cancelled")
+    }
+}
+
+impl std::error::Error for InferenceCancelled {}
+
+/// キャンセル要求を確認する間隔
+const CANCEL_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// 出力から取り除く特殊トークン（`<|...|>` 形式はこれ以外も取り除く）
+const SPECIAL_TOKENS: &[&str] = &[
+    "<|im_start|>",
+    "<|im_end|>",
+    "<|endoftext|>",
+    "<bos>",
+    "<eos>",
+    "<start_of_turn>",
+    "<end_of_turn>",
+];
+
+/// `<|...|>` 形式の特殊トークン名の最大長
+const MAX_PIPE_TOKEN_NAME_LEN: usize = 32;
+
+/// ストリーミング出力から特殊トークンを取り除く
+///
+/// トークンがチャンクの境界で分割されても取り除けるよう、特殊トークンの先頭
+/// （`<`, `<|im_` など）になりうる末尾は次のチャンクが来るまで保留する。
+#[derive(Debug, Default)]
+struct SpecialTokenStripper {
+    held: String,
+}
+
+/// `<|...|>` 形式のトークンの照合結果
+enum PipeToken {
+    /// 完全なトークン（バイト長）
+    Complete(usize),
+    /// トークンの途中で終わっている
+    Partial,
+    NoMatch,
+}
+
+impl SpecialTokenStripper {
+    /// チャンクを受け取り、表示してよい部分を返す
+    fn push(&mut self, chunk: &str) -> String {
+        let text = std::mem::take(&mut self.held) + chunk;
+        let mut output = String::with_capacity(text.len());
+        let mut rest = text.as_str();
+
+        while let Some(lt) = rest.find('<') {
+            output.push_str(&rest[..lt]);
+            let candidate = &rest[lt..];
+
+            if let Some(token) = SPECIAL_TOKENS.iter().find(|t| candidate.starts_with(**t)) {
+                rest = &candidate[token.len()..];
+                continue;
+            }
+            match match_pipe_token(candidate) {
+                PipeToken::Complete(len) => {
+                    rest = &candidate[len..];
+                    continue;
+                }
+                PipeToken::Partial => {
+                    self.held = candidate.to_string();
+                    return output;
+                }
+                PipeToken::NoMatch => {}
+            }
+            if SPECIAL_TOKENS.iter().any(|t| t.starts_with(candidate)) {
+                self.held = candidate.to_string();
+                return output;
+            }
+
+            output.push('<');
+            rest = &candidate[1..];
+        }
+
+        output.push_str(rest);
+        output
+    }
+
+    /// 保留中の文字列を返す（推論終了時に呼ぶ）
+    fn finish(&mut self) -> String {
+        std::mem::take(&mut self.held)
+    }
+}
+
+/// `<|name|>` 形式の特殊トークンを照合する（`text` は `<` で始まる）
+fn match_pipe_token(text: &str) -> PipeToken {
+    let Some(body) = text.strip_prefix("<|") else {
+        return if text == "<" { PipeToken::Partial } else { PipeToken::NoMatch };
+    };
+    let name_len = body
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .unwrap_or(body.len());
+    if name_len > MAX_PIPE_TOKEN_NAME_LEN {
+        return PipeToken::NoMatch;
+    }
+    let after = &body[name_len..];
+    if name_len > 0 && after.starts_with("|>") {
+        PipeToken::Complete(2 + name_len + 2)
+    } else if after.is_empty() || (name_len > 0 && after == "|") {
+        PipeToken::Partial
+    } else {
+        PipeToken::NoMatch
+    }
+}
+
 struct CallbackContext {
     output_buffer: Vec<u8>,
+    // 送信待ちのチャンク（flush_threshold に達するか改行で送信）
+    pending: Vec<u8>,
+    flush_threshold: usize,
     is_finished: bool,
     has_error: bool,
-    sender: Option<mpsc::Sender<String>>,
+    // 以前のコールバックが panic して Mutex が poison された（バッファが不整合の可能性）
+    poisoned: bool,
+    sender: Option<mpsc::SyncSender<String>>,
+    // 特殊トークンを取り除く場合のみ Some
+    stripper: Option<SpecialTokenStripper>,
+    // RKLLMPerfStat が報告した生成トークン数
+    generate_tokens: i32,
+    // 推論開始時刻と、最初の空でないチャンクを受け取った時刻（TTFT の計測用）
+    started_at: Instant,
+    first_token_at: Option<Instant>,
 }
 
 impl CallbackContext {
-    fn new(sender: Option<mpsc::Sender<String>>) -> Self {
+    fn new(sender: Option<mpsc::SyncSender<String>>) -> Self {
         Self {
             output_buffer: Vec::new(),
+            pending: Vec::new(),
+            flush_threshold: DEFAULT_FLUSH_THRESHOLD,
             is_finished: false,
             has_error: false,
+            poisoned: false,
             sender,
+            stripper: None,
+            generate_tokens: 0,
+            started_at: Instant::now(),
+            first_token_at: None,
+        }
+    }
+
+    /// 推論開始から最初のトークンまでの時間
+    fn time_to_first_token(&self) -> Option<Duration> {
+        self.first_token_at.map(|at| at.duration_since(self.started_at))
+    }
+
+    /// 出力の終わりに、特殊トークンの判定で保留していた文字列を含めて残りを送る
+    fn finish_output(&mut self) {
+        if let Some(held) = self.stripper.as_mut().map(SpecialTokenStripper::finish)
+            && !held.is_empty()
+        {
+            emit_text_chunk(self, &held);
+        }
+        self.flush_pending();
+    }
+
+    /// 送信待ちのチャンクをまとめて送る
+    fn flush_pending(&mut self) {
+        if self.pending.is_empty() {
+            return;
+        }
+        let chunk = String::from_utf8_lossy(&self.pending).to_string();
+        self.pending.clear();
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(chunk);
         }
     }
 }
@@ -99,29 +375,120 @@ impl CallbackContext {
 struct CallbackState {
     context: Mutex<CallbackContext>,
     notify: Condvar,
+    // キャンセルされた（コールバックは -1 を返して推論の停止を求める）。ロックなしで参照する
+    cancelled: AtomicBool,
 }
 
 impl CallbackState {
-    fn new(sender: Option<mpsc::Sender<String>>) -> Self {
+    fn new(sender: Option<mpsc::SyncSender<String>>, strip_special_tokens: bool) -> Self {
+        let mut context = CallbackContext::new(sender);
+        if strip_special_tokens {
+            context.stripper = Some(SpecialTokenStripper::default());
+        }
         Self {
-            context: Mutex::new(CallbackContext::new(sender)),
+            context: Mutex::new(context),
             notify: Condvar::new(),
+            cancelled: AtomicBool::new(false),
         }
     }
 }
 
+/// rkllm_init で得たランタイムのハンドル
+///
+/// # Safety
+/// ハンドルはどのスレッドから使ってもよいが、`rkllm_run` を複数スレッドから同時に
+/// 呼んではならない。この不変条件は `RKLLM::run` が `is_running` で排他して保証する。
+/// `rkllm_abort` は実行中の推論を止めるためのもので、`rkllm_run` と並行して呼べる。
+struct RkllmHandle(RKLLMHandleT);
+
+unsafe impl Send for RkllmHandle {}
+unsafe impl Sync for RkllmHandle {}
+
+/// 推論バックエンド（実機の RKLLM ランタイム、またはハードウェアなしで動くモック）
+pub trait RkllmBackend: Send + Sync {
+    /// プロンプトで推論を実行し、応答全体を返す（ストリーミング中は `callback` に断片を渡す）
+    fn run(
+        &self,
+        prompt: &str,
+        cancel: &CancellationToken,
+        callback: Box<dyn FnMut(&str) + Send>,
+    ) -> Result<RunOutput>;
+}
+
 pub struct RKLLM {
-    handle: RKLLMHandleT,
+    backend: Box<dyn RkllmBackend>,
+}
+
+impl RKLLM {
+    /// 推論バックエンドを初期化する
+    ///
+    /// `RKLLM_MOCK_RESPONSE` が設定されている場合は、モデルを読み込まずにその文字列を
+    /// 応答として返すモックを使う（Rockchip 以外の環境でのテスト用）。
+    ///
+    /// # エラー
+    /// モデルファイルやパラメータが不正な場合、RKLLM の初期化に失敗した場合
+    pub fn new(config: RKLLMConfig) -> Result<Self> {
+        if let Some(response) = mock_response_from_env() {
+            if !is_tui_enabled() {
+                eprintln!("[RKLLM: using mock backend (RKLLM_MOCK_RESPONSE)]");
+            }
+            return Ok(Self::with_backend(MockRkllm::new(response)));
+        }
+        Ok(Self::with_backend(RealRkllm::new(config)?))
+    }
+
+    /// 任意のバックエンドで作る
+    pub fn with_backend(backend: impl RkllmBackend + 'static) -> Self {
+        Self {
+            backend: Box::new(backend),
+        }
+    }
+
+    /// プロンプトで推論を実行し、応答全体を返す（ストリーミング中は `callback` に断片を渡す）
+    ///
+    /// `cancel` がキャンセルされると推論を中断する。
+    ///
+    /// # エラー
+    /// - 別の推論が実行中（rkllm_run は同時に呼べない）
+    /// - 推論の失敗、タイムアウト（`InferenceTimeout`）
+    /// - キャンセル（`InferenceCancelled`、それまでの部分応答を含む）
+    pub fn run<F>(&self, prompt: &str, cancel: &CancellationToken, callback: F) -> Result<RunOutput>
+    where
+        F: FnMut(&str) + Send + 'static,
+    {
+        self.backend.run(prompt, cancel, Box::new(callback))
+    }
+}
+
+/// rkllm ランタイムを使うバックエンド
+struct RealRkllm {
+    handle: RkllmHandle,
     _model_path: CString,
     _img_start: CString,
     _img_end: CString,
     _img_content: CString,
     template: ChatTemplate,
     infer_timeout: Duration,
+    strip_special_tokens: bool,
+    max_new_tokens: i32,
+    // rkllm_run の実行中は true（同時実行を防ぐガードを兼ねる）
+    is_running: Arc<AtomicBool>,
 }
 
-impl RKLLM {
-    pub fn new(config: RKLLMConfig) -> Result<Self> {
+impl RealRkllm {
+    fn new(config: RKLLMConfig) -> Result<Self> {
+        // rkllm_init のエラーコードは原因が分かりにくいので、先にモデルファイルを検証する
+        validate_model_file(&config.model_path)?;
+        if !BATCH_SIZE_RANGE.contains(&config.batch_size) {
+            return Err(anyhow!(
+                "Invalid batch size {}: must be between {} and {}",
+                config.batch_size,
+                BATCH_SIZE_RANGE.start(),
+                BATCH_SIZE_RANGE.end()
+            ));
+        }
+        validate_sampling_params(&config)?;
+
         let model_path = CString::new(config.model_path.clone())
             .context("Failed to create CString for model path")?;
         let img_start = CString::new("").context("Failed to create CString for img_start")?;
@@ -147,8 +514,19 @@ impl RKLLM {
             img_start: img_start.as_ptr(),
             img_end: img_end.as_ptr(),
             img_content: img_content.as_ptr(),
-            extend_param: RKLLMExtendParam::default(),
+            extend_param: RKLLMExtendParam {
+                enabled_cpus_mask: config.cpu_mask,
+                enabled_cpus_num: config.cpu_count,
+                n_batch: config.batch_size,
+                ..RKLLMExtendParam::default()
+            },
         };
+        if !is_tui_enabled() {
+            println!(
+                "[NPU: using CPUs 0x{:x}, count={}]",
+                config.cpu_mask, config.cpu_count
+            );
+        }
 
         let mut handle: RKLLMHandleT = ptr::null_mut();
 
@@ -160,33 +538,49 @@ impl RKLLM {
         }
 
         Ok(Self {
-            handle,
+            handle: RkllmHandle(handle),
             _model_path: model_path,
             _img_start: img_start,
             _img_end: img_end,
             _img_content: img_content,
             template: config.template,
             infer_timeout: config.infer_timeout,
+            strip_special_tokens: config.strip_special_tokens,
+            max_new_tokens: config.max_new_tokens,
+            is_running: Arc::new(AtomicBool::new(false)),
         })
     }
+}
 
-    pub fn run<F>(&self, prompt: &str, mut callback: F) -> Result<String>
-    where
-        F: FnMut(&str) + Send + 'static,
-    {
+impl RkllmBackend for RealRkllm {
+    fn run(
+        &self,
+        prompt: &str,
+        cancel: &CancellationToken,
+        mut callback: Box<dyn FnMut(&str) + Send>,
+    ) -> Result<RunOutput> {
         // Apply chat template
         let formatted_prompt = self.template.apply(prompt);
         let prompt_cstring =
             CString::new(formatted_prompt).context("Failed to create CString for prompt")?;
         let role_cstring = CString::new("user").context("Failed to create CString for role")?;
 
-        let (sender, receiver) = mpsc::channel::<String>();
+        if self
+            .is_running
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return Err(anyhow!("RKLLM inference is already running"));
+        }
+
+        // 表示側が遅い場合は送信側（RKLLM のコールバック）をブロックさせ、バッファの無制限な増加を防ぐ
+        let (sender, receiver) = mpsc::sync_channel::<String>(CALLBACK_CHANNEL_CAPACITY);
         let callback_handle = std::thread::spawn(move || {
             while let Ok(chunk) = receiver.recv() {
                 callback(&chunk);
             }
         });
-        let shared_state = Arc::new(CallbackState::new(Some(sender)));
+        let shared_state = Arc::new(CallbackState::new(Some(sender), self.strip_special_tokens));
         let callback_state_ptr =
             Arc::into_raw(Arc::clone(&shared_state)) as *mut c_void;
 
@@ -206,9 +600,36 @@ impl RKLLM {
             keep_history: 0,  // Don't keep history between runs
         };
 
+        // rkllm_run は呼び出しスレッドをブロックするため、キャンセルは別スレッドで監視する
+        let run_finished = Arc::new(AtomicBool::new(false));
+        let cancel_watcher = {
+            let cancel = cancel.clone();
+            let state = Arc::clone(&shared_state);
+            let run_finished = Arc::clone(&run_finished);
+            let handle = RkllmHandle(self.handle.0);
+            std::thread::spawn(move || {
+                // フィールド単位ではなくハンドル全体（Send）をクロージャに移す
+                let handle = handle;
+                while !run_finished.load(Ordering::SeqCst) {
+                    if cancel.is_cancelled() {
+                        state.cancelled.store(true, Ordering::SeqCst);
+                        state.notify.notify_all();
+                        let _ = unsafe { rkllm_abort(handle.0) };
+                        break;
+                    }
+                    std::thread::park_timeout(CANCEL_POLL_INTERVAL);
+                }
+            })
+        };
+
         let ret = unsafe {
-            rkllm_run(self.handle, &input, &infer_param, callback_state_ptr)
+            rkllm_run(self.handle.0, &input, &infer_param, callback_state_ptr)
         };
+        self.is_running.store(false, Ordering::SeqCst);
+        run_finished.store(true, Ordering::SeqCst);
+        cancel_watcher.thread().unpark();
+        let _ = cancel_watcher.join();
+        let cancelled = shared_state.cancelled.load(Ordering::SeqCst);
 
         // Wait for callback to finish (Condvar with timeout)
         let start_time = std::time::Instant::now();
@@ -219,7 +640,7 @@ impl RKLLM {
             Err(poisoned) => poisoned.into_inner(),
         };
         loop {
-            if guard.is_finished || guard.has_error {
+            if guard.is_finished || guard.has_error || cancelled {
                 break;
             }
             let elapsed = start_time.elapsed();
@@ -238,9 +659,18 @@ impl RKLLM {
             };
         }
 
+        // キャンセル時は完了通知が来ないことがあるため、ここで残りを送ってコールバックスレッドを終わらせる。
+        // タイムアウト時も、特殊トークンの判定で保留していた末尾を部分応答に含める
+        let detached = timed_out || (cancelled && !guard.is_finished && !guard.has_error);
+        if cancelled || timed_out {
+            guard.finish_output();
+        }
+        if cancelled {
+            guard.sender.take();
+        }
         drop(guard);
 
-        if timed_out {
+        if detached {
             let shared_state_for_cleanup = Arc::clone(&shared_state);
             let callback_state_ptr = callback_state_ptr as usize;
             std::thread::spawn(move || {
@@ -258,7 +688,9 @@ impl RKLLM {
                     let _ = Arc::from_raw(callback_state_ptr as *const CallbackState);
                 }
             });
-            let _ = callback_handle;
+            if cancelled {
+                let _ = callback_handle.join();
+            }
         } else {
             unsafe {
                 let _ = Arc::from_raw(callback_state_ptr as *const CallbackState);
@@ -266,16 +698,28 @@ impl RKLLM {
             let _ = callback_handle.join();
         }
 
-        if ret != 0 {
-            return Err(anyhow!("Failed to run RKLLM inference: error code {}", ret));
-        }
-
         // Handle poisoned mutex gracefully
         let ctx = match shared_state.context.lock() {
             Ok(guard) => guard,
             Err(poisoned) => poisoned.into_inner(),
         };
 
+        // 中断により ret や状態がエラーになりうるため、キャンセルを先に判定する
+        if cancelled && !ctx.poisoned {
+            let output = String::from_utf8_lossy(&ctx.output_buffer).to_string();
+            return Err(InferenceCancelled(output).into());
+        }
+
+        if ret != 0 {
+            return Err(anyhow!("Failed to run RKLLM inference: error code {}", ret));
+        }
+
+        if ctx.poisoned {
+            return Err(anyhow!(
+                "Inference callback panicked (callback state poisoned); partial output discarded"
+            ));
+        }
+
         if ctx.has_error {
             return Err(anyhow!("Error occurred during inference"));
         }
@@ -283,10 +727,100 @@ impl RKLLM {
         // 収集した応答テキストを返す
         let output = String::from_utf8_lossy(&ctx.output_buffer).to_string();
 
-        Ok(output)
+        if timed_out {
+            return Err(InferenceTimeout {
+                partial_output: output,
+                timeout,
+            }
+            .into());
+        }
+
+        Ok(RunOutput {
+            text: output,
+            response_was_truncated: hit_token_limit(ctx.generate_tokens, self.max_new_tokens),
+            time_to_first_token: ctx.time_to_first_token(),
+        })
     }
 }
 
+/// モックが1トークンを流す間隔
+const MOCK_TOKEN_DELAY: Duration = Duration::from_millis(10);
+
+/// ハードウェアなしで動作確認するためのバックエンド（`RKLLM_MOCK_RESPONSE`）
+///
+/// `run` はプロンプトに関係なく固定の応答を、単語ごとに区切ってストリーミングする。
+pub struct MockRkllm {
+    response: String,
+    token_delay: Duration,
+    is_running: AtomicBool,
+}
+
+impl MockRkllm {
+    /// `response` を返すモックを作る
+    pub fn new(response: impl Into<String>) -> Self {
+        Self {
+            response: response.into(),
+            token_delay: MOCK_TOKEN_DELAY,
+            is_running: AtomicBool::new(false),
+        }
+    }
+
+    /// トークン間の待ち時間を変える（既定は 10ms）
+    #[cfg(test)]
+    pub fn with_token_delay(mut self, delay: Duration) -> Self {
+        self.token_delay = delay;
+        self
+    }
+}
+
+impl RkllmBackend for MockRkllm {
+    fn run(
+        &self,
+        _prompt: &str,
+        cancel: &CancellationToken,
+        mut callback: Box<dyn FnMut(&str) + Send>,
+    ) -> Result<RunOutput> {
+        if self
+            .is_running
+            .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return Err(anyhow!("RKLLM inference is already running"));
+        }
+
+        let started_at = Instant::now();
+        let mut time_to_first_token = None;
+        let mut output = String::new();
+        for token in self.response.split_inclusive(char::is_whitespace) {
+            if cancel.is_cancelled() {
+                self.is_running.store(false, Ordering::SeqCst);
+                return Err(InferenceCancelled(output).into());
+            }
+            time_to_first_token.get_or_insert_with(|| started_at.elapsed());
+            callback(token);
+            output.push_str(token);
+            std::thread::sleep(self.token_delay);
+        }
+
+        self.is_running.store(false, Ordering::SeqCst);
+        Ok(RunOutput {
+            text: output,
+            response_was_truncated: false,
+            time_to_first_token,
+        })
+    }
+}
+
+/// `RKLLM_MOCK_RESPONSE` が設定されていればモックの応答を返す
+pub fn mock_response_from_env() -> Option<String> {
+    env::var("RKLLM_MOCK_RESPONSE").ok()
+}
+
+/// 生成トークン数が上限に達したかどうか（上限が設定されていない場合は false）
+fn hit_token_limit(generate_tokens: i32, max_new_tokens: i32) -> bool {
+    max_new_tokens > 0 && generate_tokens >= max_new_tokens
+}
+
 fn infer_timeout_from_env() -> Duration {
     let secs = env::var("RKLLM_INFER_TIMEOUT_SECS")
         .ok()
@@ -296,10 +830,22 @@ fn infer_timeout_from_env() -> Duration {
     Duration::from_secs(secs)
 }
 
-impl Drop for RKLLM {
+fn batch_size_from_env() -> u8 {
+    from_env_or("RKLLM_BATCH_SIZE", 1)
+}
+
+/// 環境変数 `var` を `T` として読み込む。未設定または解釈できない場合は `default`
+fn from_env_or<T: FromStr>(var: &str, default: T) -> T {
+    env::var(var)
+        .ok()
+        .and_then(|v| v.trim().parse::<T>().ok())
+        .unwrap_or(default)
+}
+
+impl Drop for RealRkllm {
     fn drop(&mut self) {
         unsafe {
-            rkllm_destroy(self.handle);
+            rkllm_destroy(self.handle.0);
         }
     }
 }
@@ -328,19 +874,43 @@ unsafe fn callback_impl(
     }
 
     let shared_state = unsafe { &*(userdata as *const CallbackState) };
+    if shared_state.cancelled.load(Ordering::SeqCst) {
+        // キャンセル済み: -1 を返してライブラリに推論の停止を求める
+        return -1;
+    }
     let mut context = match shared_state.context.lock() {
         Ok(ctx) => ctx,
         Err(poisoned) => {
-            if !is_tui_enabled() {
-                eprintln!("[WARNING] Mutex was poisoned, recovering...");
+            // 以前のコールバックが panic している。出力バッファは信用できないのでエラー扱いにする
+            let mut context = poisoned.into_inner();
+            if !context.poisoned && !is_tui_enabled() {
+                eprintln!(
+                    "[WARNING] Callback state mutex was poisoned by an earlier panic (state: {:?}, buffered {} bytes); aborting response",
+                    state,
+                    context.output_buffer.len()
+                );
             }
-            poisoned.into_inner()
+            context.poisoned = true;
+            context.has_error = true;
+            context.pending.clear();
+            context.sender.take();
+            shared_state.notify.notify_all();
+            return 0;
         }
     };
 
+    // 性能統計は生成の進行に合わせて更新される。最後に報告された値を使う
+    if !result.is_null() {
+        let generate_tokens = unsafe { (*result).perf.generate_tokens };
+        if generate_tokens > 0 {
+            context.generate_tokens = generate_tokens;
+        }
+    }
+
     match state {
         LLMCallState::RkllmRunFinish => {
             let had_sender = context.sender.is_some();
+            context.finish_output();
             context.is_finished = true;
             context.sender.take();
             shared_state.notify.notify_all();
@@ -352,6 +922,7 @@ unsafe fn callback_impl(
         }
         LLMCallState::RkllmRunError => {
             let had_sender = context.sender.is_some();
+            context.finish_output();
             context.has_error = true;
             context.sender.take();
             shared_state.notify.notify_all();
@@ -372,7 +943,7 @@ unsafe fn callback_impl(
                 return 0;
             }
 
-            // logits/perf are provided by RKLLMResult but CLI ではストリーミングテキストのみを利用する。
+            // logits are provided by RKLLMResult but CLI ではストリーミングテキストと perf のみを利用する。
             // text is a null-terminated C string, use CStr to read it
             match unsafe { CStr::from_ptr(result_ref.text) }.to_str() {
                 Ok(text) => {
@@ -395,13 +966,33 @@ fn is_tui_enabled() -> bool {
     env::var("RKLLM_TUI").ok().as_deref() == Some("1")
 }
 
-/// Process a chunk of text - simply buffer and print it
+/// Process a chunk of text - strip special tokens, buffer it and forward coalesced chunks
 fn process_text_chunk(context: &mut CallbackContext, text: &str) {
+    if context.first_token_at.is_none() && !text.is_empty() {
+        context.first_token_at = Some(Instant::now());
+    }
+    match context.stripper.as_mut() {
+        Some(stripper) => {
+            let visible = stripper.push(text);
+            if !visible.is_empty() {
+                emit_text_chunk(context, &visible);
+            }
+        }
+        None => emit_text_chunk(context, text),
+    }
+}
+
+/// Buffer the text and forward it (coalesced) to the callback thread
+fn emit_text_chunk(context: &mut CallbackContext, text: &str) {
     // Buffer the output
     context.output_buffer.extend_from_slice(text.as_bytes());
 
-    if let Some(sender) = &context.sender {
-        let _ = sender.send(text.to_string());
+    if context.sender.is_some() {
+        // 1トークンごとの送信/flush を避けるため、しきい値か改行までまとめる
+        context.pending.extend_from_slice(text.as_bytes());
+        if context.pending.len() >= context.flush_threshold || text.contains('\n') {
+            context.flush_pending();
+        }
     } else {
         // Legacy behavior: Print directly
         use std::io::Write;
@@ -409,3 +1000,213 @@ fn process_text_chunk(context: &mut CallbackContext, text: &str) {
         let _ = std::io::stdout().flush();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_env_or_parses_or_falls_back() {
+        // SAFETY: テスト専用の変数名のみを操作する
+        unsafe {
+            env::set_var("RKLLM_TEST_FROM_ENV_OR", " 0.7 ");
+        }
+        assert_eq!(from_env_or("RKLLM_TEST_FROM_ENV_OR", 1.0f32), 0.7);
+        assert_eq!(from_env_or("RKLLM_TEST_FROM_ENV_OR", 64i32), 64);
+        assert_eq!(from_env_or("RKLLM_TEST_FROM_ENV_OR_UNSET", 64i32), 64);
+        unsafe {
+            env::remove_var("RKLLM_TEST_FROM_ENV_OR");
+        }
+    }
+
+    #[test]
+    fn process_text_chunk_coalesces_until_threshold() {
+        let (sender, receiver) = mpsc::sync_channel::<String>(CALLBACK_CHANNEL_CAPACITY);
+        let mut context = CallbackContext::new(Some(sender));
+
+        process_text_chunk(&mut context, "ab");
+        process_text_chunk(&mut context, "cd");
+        assert!(receiver.try_recv().is_err());
+
+        process_text_chunk(&mut context, "efgh");
+        assert_eq!(receiver.try_recv().unwrap(), "abcdefgh");
+
+        process_text_chunk(&mut context, "x\n");
+        assert_eq!(receiver.try_recv().unwrap(), "x\n");
+
+        process_text_chunk(&mut context, "tail");
+        context.flush_pending();
+        assert_eq!(receiver.try_recv().unwrap(), "tail");
+        assert_eq!(context.output_buffer, b"abcdefghx\ntail");
+    }
+
+    #[test]
+    fn process_text_chunk_records_first_non_empty_chunk() {
+        let (sender, _receiver) = mpsc::sync_channel::<String>(CALLBACK_CHANNEL_CAPACITY);
+        let mut context = CallbackContext::new(Some(sender));
+        context.started_at -= Duration::from_millis(100);
+        assert_eq!(context.time_to_first_token(), None);
+
+        process_text_chunk(&mut context, "");
+        assert_eq!(context.time_to_first_token(), None);
+
+        process_text_chunk(&mut context, "a");
+        let ttft = context.time_to_first_token().unwrap();
+        assert!(ttft >= Duration::from_millis(100));
+
+        std::thread::sleep(Duration::from_millis(5));
+        process_text_chunk(&mut context, "b");
+        assert_eq!(context.time_to_first_token(), Some(ttft));
+    }
+
+    #[test]
+    fn stripper_removes_tokens_split_across_chunks() {
+        let mut stripper = SpecialTokenStripper::default();
+        let mut output = String::new();
+        for chunk in ["Hello<|im", "_end|> wor", "ld<", "end_of_turn><eos>", " a<b", " <|x"] {
+            output.push_str(&stripper.push(chunk));
+        }
+        output.push_str(&stripper.finish());
+        assert_eq!(output, "Hello world a<b <|x");
+
+        let mut stripper = SpecialTokenStripper::default();
+        assert_eq!(stripper.push("<|custom_token|>x <div>"), "x <div>");
+    }
+
+    #[test]
+    fn process_text_chunk_strips_special_tokens_when_enabled() {
+        let (sender, receiver) = mpsc::sync_channel::<String>(CALLBACK_CHANNEL_CAPACITY);
+        let state = CallbackState::new(Some(sender), true);
+        let mut context = state.context.lock().unwrap();
+
+        process_text_chunk(&mut context, "answer\n<|im_");
+        assert_eq!(receiver.try_recv().unwrap(), "answer\n");
+        process_text_chunk(&mut context, "end|> <b");
+        context.finish_output();
+        assert_eq!(receiver.try_recv().unwrap(), " <b");
+        assert_eq!(context.output_buffer, b"answer\n <b");
+    }
+
+    #[test]
+    fn full_callback_channel_blocks_without_dropping_chunks() {
+        let (sender, receiver) = mpsc::sync_channel::<String>(CALLBACK_CHANNEL_CAPACITY);
+        let total = CALLBACK_CHANNEL_CAPACITY * 4;
+
+        let producer = std::thread::spawn(move || {
+            let mut context = CallbackContext::new(Some(sender));
+            for i in 0..total {
+                // 改行で即座に flush させ、チャネルを満杯にする
+                process_text_chunk(&mut context, &format!("{}\n", i));
+            }
+        });
+
+        // 送信側がバッファを埋めてブロックするまで待ってから読み始める
+        std::thread::sleep(Duration::from_millis(50));
+        let received: Vec<String> = receiver.iter().collect();
+        producer.join().unwrap();
+
+        let expected: Vec<String> = (0..total).map(|i| format!("{}\n", i)).collect();
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn callback_stops_inference_after_cancel() {
+        let state = CallbackState::new(None, false);
+        let userdata = &state as *const CallbackState as *mut c_void;
+
+        let ret = unsafe { callback_impl(ptr::null_mut(), userdata, LLMCallState::RkllmRunNormal) };
+        assert_eq!(ret, 0);
+
+        state.cancelled.store(true, Ordering::SeqCst);
+        let ret = unsafe { callback_impl(ptr::null_mut(), userdata, LLMCallState::RkllmRunNormal) };
+        assert_eq!(ret, -1);
+    }
+
+    #[test]
+    fn hit_token_limit_compares_against_max_new_tokens() {
+        assert!(hit_token_limit(4096, 4096));
+        assert!(!hit_token_limit(120, 4096));
+        // 上限なし（0 以下）は打ち切りとみなさない
+        assert!(!hit_token_limit(120, 0));
+    }
+
+    #[test]
+    fn rkllm_is_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<RKLLM>();
+    }
+
+    #[test]
+    fn mock_backend_streams_response_by_word() {
+        let rkllm = RKLLM::with_backend(MockRkllm::new("Hello mock\nworld").with_token_delay(Duration::ZERO));
+        let chunks = Arc::new(Mutex::new(Vec::new()));
+        let received = Arc::clone(&chunks);
+
+        let output = rkllm
+            .run("ignored", &CancellationToken::new(), move |chunk| {
+                received.lock().unwrap().push(chunk.to_string());
+            })
+            .unwrap();
+
+        assert_eq!(output.text, "Hello mock\nworld");
+        assert!(!output.response_was_truncated);
+        assert!(output.time_to_first_token.is_some());
+        assert_eq!(*chunks.lock().unwrap(), vec!["Hello ", "mock\n", "world"]);
+
+        let cancel = CancellationToken::new();
+        cancel.cancel();
+        let err = rkllm.run("ignored", &cancel, |_| {}).unwrap_err();
+        assert!(err.downcast_ref::<InferenceCancelled>().is_some());
+    }
+
+    #[test]
+    fn validate_sampling_params_rejects_invalid_values() {
+        let mut config = RKLLMConfig {
+            temperature: 0.8,
+            top_k: 1,
+            top_p: 0.9,
+            ..RKLLMConfig::default()
+        };
+        assert!(validate_sampling_params(&config).is_ok());
+
+        // -1 は上限なし
+        config.max_new_tokens = -1;
+        assert!(validate_sampling_params(&config).is_ok());
+
+        config.temperature = -0.1;
+        let err = validate_sampling_params(&config).unwrap_err();
+        assert!(err.to_string().contains("temperature"));
+
+        config.temperature = 0.8;
+        config.top_k = 0;
+        let err = validate_sampling_params(&config).unwrap_err();
+        assert!(err.to_string().contains("top_k"));
+
+        config.top_k = 1;
+        config.top_p = 1.5;
+        let err = validate_sampling_params(&config).unwrap_err();
+        assert!(err.to_string().contains("top_p"));
+    }
+
+    #[test]
+    fn validate_model_file_rejects_dirs_missing_and_tiny_files() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let dir_path = dir.path().to_str().unwrap();
+        let err = validate_model_file(dir_path).unwrap_err();
+        assert!(err.to_string().contains("not a regular file"));
+
+        let missing = dir.path().join("missing.rkllm");
+        let err = validate_model_file(missing.to_str().unwrap()).unwrap_err();
+        assert!(err.to_string().contains("not found"));
+
+        let tiny = dir.path().join("tiny.rkllm");
+        std::fs::write(&tiny, b"not a model").unwrap();
+        let err = validate_model_file(tiny.to_str().unwrap()).unwrap_err();
+        assert!(err.to_string().contains("too small"));
+
+        let model = dir.path().join("model.rkllm");
+        let file = std::fs::File::create(&model).unwrap();
+        file.set_len(MIN_MODEL_FILE_SIZE + 1).unwrap();
+        assert!(validate_model_file(model.to_str().unwrap()).is_ok());
+    }
+}
@@ -1,39 +1,79 @@
-mod chat;
-mod config;
-mod ffi;
-mod file_detector;
-mod file_ops;
-mod file_output_parser;
-mod intent;
-mod llm;
-mod mcp;
-mod prompt_builder;
-mod tool_detector;
-
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::{ArgAction, Parser, Subcommand};
-use std::path::PathBuf;
+use rkllm_cli::{config, ffi, mcp, ChatSessionBuilder};
+use std::path::{Path, PathBuf};
 
 #[derive(Parser)]
-#[command(name = "rkllm-cli")]
+#[command(name = "rkllm-cli", version)]
 #[command(about = "RKLLM CLI - Chat with LLM models on Rockchip NPU", long_about = None)]
+#[command(after_help = ENV_HELP)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
 }
 
+/// `--help` に表示する環境変数の一覧
+const ENV_HELP: &str = "\
+Environment variables:
+  RKLLM_TEMPERATURE              Sampling temperature (default: 1.0)
+  RKLLM_TOP_K                    Top-k sampling (default: 64)
+  RKLLM_TOP_P                    Top-p sampling (default: 0.95)
+  RKLLM_REPEAT_PENALTY           Repeat penalty (default: 1.0)
+  RKLLM_BATCH_SIZE               Prefill batch size, 1-100 (default: 1)
+  RKLLM_TEMPLATE                 Chat template: qwen | gemma
+  RKLLM_INFER_TIMEOUT_SECS       Generation timeout in seconds (default: 120)
+  RKLLM_MAX_FILE_SIZE            Maximum size of a loaded file in bytes (default: 1048576)
+  RKLLM_MAX_CONTEXT_TOKENS       Prompt token budget (default: 4096)
+  RKLLM_CONTEXT_RESERVED_TOKENS  Tokens kept free for the response (default: 256)
+  RKLLM_MAX_TOOL_ROUNDS          Tool-call rounds per turn (default: 3)
+  RKLLM_SYSTEM_PROMPT            Replacement system instructions
+  RKLLM_LANGUAGE                 Language for responses (default: Japanese)
+  RKLLM_RESPONSE_PREFIX          Text printed before every response (e.g. a disclaimer)
+  RKLLM_RESPONSE_SUFFIX          Text printed after every response
+  RKLLM_NO_BANNER=1              Skip the ASCII art banner
+  RKLLM_MCP_TRACE=1              Log MCP JSON-RPC traffic to stderr
+  RKLLM_DEBUG_PROMPT=1           Print the composed prompt
+  RKLLM_DEBUG_FILES=1            Log files skipped by .gitignore/.rkllmignore
+  RKLLM_CONFIG                   Extra config file merged over ~/.config/rkllm-cli/config.toml
+  RKLLM_DEBUG_CONFIG=1           Log config file loading
+  RKLLM_LOG_FILE                 Append diagnostic (stderr) output to this file
+  RKLLM_PIPE_AUTO_CONFIRM=1      Accept write confirmations when stdin is not a terminal
+  RKLLM_EVENTS_FIFO              Write a JSON line to this FIFO for every file write
+  RKLLM_BIDI=1                   Place the input cursor for terminals that reorder RTL text
+  RKLLM_MOCK_RESPONSE            Stream this text instead of running the model (testing without an NPU)
+
+The [llm] section of config.toml and CLI flags take precedence over RKLLM_TEMPERATURE,
+RKLLM_TOP_K, RKLLM_TOP_P, RKLLM_REPEAT_PENALTY and RKLLM_BATCH_SIZE.";
+
 #[derive(Subcommand)]
+// Parsed once at startup, so the size of the Chat variant does not matter
+#[allow(clippy::large_enum_variant)]
 enum Commands {
+    /// Show version information for the CLI, the RKLLM runtime and the board
+    Version,
+
+    /// Print a starter MCP config for the servers found on PATH (redirect it to a file)
+    ExportMcpConfig,
+
     /// Start an interactive chat session with the model
     Chat {
         /// Path to the RKLLM model file
         #[arg(short, long)]
         model: PathBuf,
 
+        /// Short model name for the status line and terminal title (default: the file stem)
+        #[arg(long, value_name = "NAME")]
+        model_alias: Option<String>,
+
         /// Path to MCP configuration file (optional)
         #[arg(long)]
         mcp_config: Option<PathBuf>,
 
+        /// Minimum level of log messages MCP servers send
+        /// (debug, info, notice, warning, error, critical, alert, emergency)
+        #[arg(long, value_name = "LEVEL")]
+        mcp_log_level: Option<mcp::types::LoggingLevel>,
+
         /// Print the composed prompt before sending it to the model
         #[arg(long)]
         preview_prompt: bool,
@@ -45,42 +85,237 @@ enum Commands {
         /// Disable local file writes and ignore file output markers (MCP tools only)
         #[arg(long)]
         tool_only: bool,
+
+        /// Run a backquoted command from a shell-like request via an MCP shell tool (asks y/N first)
+        #[arg(long)]
+        allow_shell_intent: bool,
+
+        /// Show file outputs and patches without writing anything
+        #[arg(long)]
+        dry_run_writes: bool,
+
+        /// Show a colored diff against the current file and confirm before each write
+        #[arg(long)]
+        preview_diff: bool,
+
+        /// Replace the default system instructions (also RKLLM_SYSTEM_PROMPT)
+        #[arg(long, conflicts_with = "system_prompt_file")]
+        system_prompt: Option<String>,
+
+        /// Read replacement system instructions from a file
+        #[arg(long)]
+        system_prompt_file: Option<PathBuf>,
+
+        /// Language for responses, e.g. "English" (overrides RKLLM_LANGUAGE and `[llm] response_language`)
+        #[arg(long)]
+        response_language: Option<String>,
+
+        /// Extra text added to every prompt as reference context (repeatable),
+        /// e.g. --include-context "$(cargo build 2>&1)"
+        #[arg(long = "include-context", value_name = "TEXT")]
+        include_context: Vec<String>,
+
+        /// Sampling temperature (overrides `[llm] temperature` in config.toml)
+        #[arg(long)]
+        temperature: Option<f32>,
+
+        /// Top-k sampling (overrides `[llm] top_k`)
+        #[arg(long)]
+        top_k: Option<i32>,
+
+        /// Top-p sampling (overrides `[llm] top_p`)
+        #[arg(long)]
+        top_p: Option<f32>,
+
+        /// Repeat penalty (overrides `[llm] repeat_penalty`)
+        #[arg(long)]
+        repeat_penalty: Option<f32>,
+
+        /// Maximum context length passed to the runtime (overrides `[llm] max_context_len`)
+        #[arg(long)]
+        max_context_len: Option<i32>,
+
+        /// Maximum number of generated tokens (overrides `[llm] max_new_tokens`)
+        #[arg(long)]
+        max_new_tokens: Option<i32>,
+
+        /// CPU cores to run on, as a hex mask such as 0xf0 (overrides `[llm] cpu_mask`)
+        #[arg(long, value_parser = config::parse_cpu_mask)]
+        cpu_mask: Option<u32>,
+
+        /// Number of CPU cores to run on (overrides `[llm] cpu_count`)
+        #[arg(long)]
+        cpu_count: Option<i8>,
+
+        /// Prompt-processing batch size, 1-100 (overrides `[llm] batch_size` and RKLLM_BATCH_SIZE).
+        /// Larger values speed up prefill at the cost of memory
+        #[arg(long)]
+        batch_size: Option<u8>,
+
+        /// Token budget for the composed prompt (overrides RKLLM_MAX_CONTEXT_TOKENS)
+        #[arg(long)]
+        max_context_tokens: Option<usize>,
+
+        /// Tokens kept free for the response (overrides RKLLM_CONTEXT_RESERVED_TOKENS)
+        #[arg(long)]
+        context_reserved_tokens: Option<usize>,
+
+        /// Maximum tool-call rounds per turn (overrides RKLLM_MAX_TOOL_ROUNDS and `[tools] max_rounds`)
+        #[arg(long)]
+        max_tool_rounds: Option<usize>,
+
+        /// Truncate tool outputs longer than this many bytes before adding them to the prompt
+        /// (overrides `[tools] result_max_bytes`, default: 8192)
+        #[arg(long)]
+        tool_result_max_bytes: Option<usize>,
+
+        /// Directory levels to descend when listing a directory (overrides `[files] max_depth`, default: 3)
+        #[arg(long, value_name = "N")]
+        max_file_depth: Option<usize>,
+
+        /// Working directory for the session (relative file paths are resolved against it)
+        #[arg(long)]
+        execution_dir: Option<PathBuf>,
+
+        /// Re-run the last query whenever a file matching this glob changes (also `/watch <glob>`)
+        #[arg(long, value_name = "GLOB")]
+        watch: Option<String>,
+
+        /// Run rustfmt / black / prettier on files the model writes (also `/format <path>`)
+        #[arg(long)]
+        auto_format: bool,
+
+        /// Write files with relative paths under this directory (absolute paths are unaffected)
+        #[arg(long, value_name = "DIR")]
+        output_dir: Option<String>,
+
+        /// Do not print the ASCII art banner at startup (also RKLLM_NO_BANNER=1)
+        #[arg(long)]
+        no_banner: bool,
+
+        /// Log every MCP JSON-RPC message to stderr (also RKLLM_MCP_TRACE=1)
+        #[arg(long)]
+        verbose: bool,
     },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    init_logging()?;
     let cli = Cli::parse();
 
     match cli.command {
+        Commands::Version => print_version(),
+        Commands::ExportMcpConfig => {
+            let cwd = std::env::current_dir().context("Failed to get current directory")?;
+            print!("{}", mcp::config::starter_config_toml_from_path(&cwd));
+        }
         Commands::Chat {
             model,
+            model_alias,
             mcp_config,
+            mcp_log_level,
             preview_prompt,
             confirm_writes,
             tool_only,
+            allow_shell_intent,
+            dry_run_writes,
+            preview_diff,
+            system_prompt,
+            system_prompt_file,
+            response_language,
+            include_context,
+            temperature,
+            top_k,
+            top_p,
+            repeat_penalty,
+            max_context_len,
+            max_new_tokens,
+            cpu_mask,
+            cpu_count,
+            batch_size,
+            max_context_tokens,
+            context_reserved_tokens,
+            max_tool_rounds,
+            tool_result_max_bytes,
+            max_file_depth,
+            execution_dir,
+            watch,
+            auto_format,
+            output_dir,
+            no_banner,
+            verbose,
         } => {
-            if !model.exists() {
-                eprintln!("Error: Model file not found: {}", model.display());
-                std::process::exit(1);
-            }
+            // 作業ディレクトリを変更する前に、他の相対パス引数を起動時のディレクトリ基準で確定させる
+            let (model, mcp_config, system_prompt_file) = match &execution_dir {
+                Some(_) => (
+                    std::path::absolute(&model)?,
+                    mcp_config.map(std::path::absolute).transpose()?,
+                    system_prompt_file.map(std::path::absolute).transpose()?,
+                ),
+                None => (model, mcp_config, system_prompt_file),
+            };
+            let execution_dir = execution_dir
+                .map(|dir| change_execution_dir(&dir))
+                .transpose()?;
 
             let model_path = model
                 .to_str()
                 .ok_or_else(|| anyhow::anyhow!("Invalid model path"))?
                 .to_string();
 
+            if verbose {
+                mcp::transport::tracing::enable_trace();
+            }
+
+            let system_prompt = match system_prompt_file {
+                Some(path) => Some(std::fs::read_to_string(&path).with_context(|| {
+                    format!("Failed to read system prompt file: {}", path.display())
+                })?),
+                None => system_prompt,
+            };
+
+            let llm_overrides = config::LlmConfig {
+                temperature,
+                top_k,
+                top_p,
+                repeat_penalty,
+                max_context_len,
+                max_new_tokens,
+                cpu_mask,
+                cpu_count,
+                batch_size,
+                response_language,
+            };
+
             println!("Loading model: {}", model_path);
             println!("Initializing RKLLM...");
 
-            let session = chat::ChatSession::new(
-                model_path,
-                mcp_config,
-                preview_prompt,
-                confirm_writes,
-                tool_only,
-            )
-            .await?;
+            let session = ChatSessionBuilder::new(model_path)
+                .mcp_config(mcp_config)
+                .mcp_log_level(mcp_log_level)
+                .preview_prompt(preview_prompt)
+                .confirm_writes(confirm_writes)
+                .tool_only(tool_only)
+                .allow_shell_intent(allow_shell_intent)
+                .dry_run_writes(dry_run_writes)
+                .preview_diff(preview_diff)
+                .system_prompt(system_prompt)
+                .llm_overrides(llm_overrides)
+                .max_context_tokens(max_context_tokens)
+                .context_reserved_tokens(context_reserved_tokens)
+                .max_tool_rounds(max_tool_rounds)
+                .tool_result_max_bytes(tool_result_max_bytes)
+                .max_file_depth(max_file_depth)
+                .show_banner(!no_banner)
+                .execution_dir(execution_dir)
+                .watch(watch)
+                .auto_format(auto_format)
+                .output_dir(output_dir)
+                .model_alias(model_alias)
+                .extra_context(include_context)
+                .build()
+                .await?;
 
             println!("Model loaded successfully!");
             println!();
@@ -91,3 +326,93 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+/// `--execution-dir` で指定されたディレクトリへカレントディレクトリを移す
+///
+/// # 引数
+/// * `dir` - 作業ディレクトリ（相対パスは起動時のディレクトリ基準）
+///
+/// # 戻り値
+/// 移動先の絶対パス
+///
+/// # エラー
+/// - パスが存在しない、またはディレクトリではない
+/// - カレントディレクトリの変更に失敗
+fn change_execution_dir(dir: &Path) -> Result<PathBuf> {
+    if !dir.is_dir() {
+        anyhow::bail!("Execution directory is not an existing directory: {}", dir.display());
+    }
+    let absolute = std::path::absolute(dir)
+        .with_context(|| format!("Failed to resolve execution directory: {}", dir.display()))?;
+    std::env::set_current_dir(&absolute)
+        .with_context(|| format!("Failed to change directory to {}", absolute.display()))?;
+    Ok(absolute)
+}
+
+/// `RKLLM_LOG_FILE` が設定されていれば、診断出力（stderr）をそのファイルへ追記する
+///
+/// 標準エラー出力のファイル記述子ごと差し替えるため、`eprintln!` に加えて
+/// librkllmrt や MCP サーバのログもファイルへ送られる。応答本文（stdout）はそのまま。
+///
+/// # エラー
+/// ファイルを開けない、または stderr を差し替えられない場合
+fn init_logging() -> Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let Some(path) = std::env::var_os("RKLLM_LOG_FILE").filter(|v| !v.is_empty()) else {
+        return Ok(());
+    };
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Failed to open log file: {}", path.to_string_lossy()))?;
+
+    // SAFETY: どちらも有効なファイル記述子。dup2 後は file を閉じても fd 2 は残る
+    let ret = unsafe { libc::dup2(file.as_raw_fd(), libc::STDERR_FILENO) };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to redirect stderr to {}", path.to_string_lossy()));
+    }
+
+    Ok(())
+}
+
+/// バージョン情報（CLI、librkllmrt、SoC、NPU 周波数）を表示する。取得できない項目は unknown とする
+fn print_version() {
+    println!("rkllm-cli {}", env!("CARGO_PKG_VERSION"));
+    println!(
+        "librkllmrt: {}",
+        ffi::rkllm_version().unwrap_or_else(|| "unknown".to_string())
+    );
+    println!("SoC: {}", soc_model().unwrap_or_else(|| "unknown".to_string()));
+    match npu_frequency_mhz() {
+        Some(mhz) => println!("NPU: {} MHz", mhz),
+        None => println!("NPU: unknown"),
+    }
+}
+
+/// `/proc/device-tree/compatible` から Rockchip の SoC 名（例: RK3588）を取り出す
+fn soc_model() -> Option<String> {
+    let compatible = std::fs::read("/proc/device-tree/compatible").ok()?;
+    // NUL 区切りで「ボード固有 → SoC」の順に並ぶので、最後の rockchip エントリを使う
+    compatible
+        .split(|&b| b == 0)
+        .filter_map(|entry| std::str::from_utf8(entry).ok())
+        .filter_map(|entry| entry.strip_prefix("rockchip,"))
+        .next_back()
+        .map(|soc| soc.to_ascii_uppercase())
+}
+
+/// `/sys/class/devfreq/*npu*/cur_freq`（Hz）から NPU の現在周波数を MHz で返す
+fn npu_frequency_mhz() -> Option<u64> {
+    std::fs::read_dir("/sys/class/devfreq")
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().contains("npu"))
+        .find_map(|entry| {
+            let freq = std::fs::read_to_string(entry.path().join("cur_freq")).ok()?;
+            freq.trim().parse::<u64>().ok()
+        })
+        .map(|hz| hz / 1_000_000)
+}
@@ -2,20 +2,53 @@
 // Based on Model Context Protocol Specification 2025-06-18
 
 use anyhow::{Context, Result};
+use indexmap::IndexMap;
 use std::collections::HashMap;
 use std::env;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use super::config::{McpConfig, ServerConfig, Transport};
-use super::transport::StdioTransport;
+#[cfg(any(test, feature = "test-util"))]
+use super::transport::MockTransport;
+use super::transport::{HttpTransport, StderrLogOptions, StdioTransport, TracingTransport};
 use super::types::*;
 
+/// Delay between retries of a failed tool call
+const TOOL_RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
 /// Connection to a single MCP server
+///
+/// Clones share the transport (and so the server process); the tool and
+/// prompt lists are copied.
+#[derive(Clone)]
 pub struct ServerConnection {
     pub name: String,
-    transport: StdioTransport,
+    transport: Arc<TracingTransport>,
     _server_info: Implementation,
     capabilities: ServerCapabilities,
     available_tools: Vec<Tool>,
+    available_prompts: Vec<Prompt>,
+    /// Retries for a failed tool call (0 unless `retry_on_error` is set)
+    tool_retries: usize,
+    /// Heartbeat status; false once the server stopped answering `$/ping`
+    heartbeat: Option<tokio::sync::watch::Receiver<bool>>,
+    /// Whether write tools accept `source_path` instead of inline content
+    temp_staging: bool,
+}
+
+// The transport is left out: it has no useful state to print and may hold credentials
+impl std::fmt::Debug for ServerConnection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServerConnection")
+            .field("name", &self.name)
+            .field("tool_count", &self.available_tools.len())
+            .field("prompt_count", &self.available_prompts.len())
+            .field("tool_retries", &self.tool_retries)
+            .field("heartbeat_alive", &self.heartbeat.as_ref().map(|alive| *alive.borrow()))
+            .field("temp_staging", &self.temp_staging)
+            .finish()
+    }
 }
 
 impl ServerConnection {
@@ -26,20 +59,89 @@ impl ServerConnection {
     /// 2. Receive server capabilities and info
     /// 3. Send 'initialized' notification
     /// 4. List available tools (if server supports tools)
+    /// 5. List available prompts (if server supports prompts)
     pub async fn new(config: ServerConfig) -> Result<Self> {
-        // Only stdio is supported currently
-        if config.transport != Transport::Stdio {
-            anyhow::bail!(
-                "Only stdio transport is supported (server: '{}')",
+        let transport = match config.transport {
+            Transport::Stdio => Self::spawn_stdio(&config).await?,
+            Transport::Http => {
+                let url = config.url.clone().with_context(|| {
+                    format!("Server '{}' uses the http transport but has no url", config.name)
+                })?;
+                let transport = HttpTransport::new(url, config.api_key.clone())?;
+                TracingTransport::http(transport, config.name.clone())
+            }
+            Transport::Sse => anyhow::bail!(
+                "SSE transport is not supported (server: '{}')",
                 config.name
+            ),
+        };
+
+        let mut connection = Self::connect(&config.name, transport).await?;
+        // The connection was just created, so nothing else shares the transport yet
+        if let Some(interval) = config.heartbeat_interval()
+            && let Some(transport) = Arc::get_mut(&mut connection.transport)
+        {
+            transport.start_heartbeat(interval);
+            connection.heartbeat = transport.alive_watch();
+        }
+        connection.temp_staging = config.use_temp_staging;
+        if config.retry_on_error {
+            connection.tool_retries = config.max_retries;
+        }
+        if let Some(required) = &config.required_tools {
+            connection.warn_missing_tools(required);
+        }
+        Ok(connection)
+    }
+
+    /// Names in `required` that the server does not provide
+    fn missing_tools<'a>(&self, required: &'a [String]) -> Vec<&'a str> {
+        required
+            .iter()
+            .filter(|name| !self.available_tools.iter().any(|tool| &tool.name == *name))
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Warn about required tools the server does not provide
+    ///
+    /// A missing tool usually means the wrong server version or a misconfiguration,
+    /// but the connection is still usable for the tools it does provide.
+    fn warn_missing_tools(&self, required: &[String]) {
+        if is_tui_enabled() {
+            return;
+        }
+        for tool in self.missing_tools(required) {
+            eprintln!(
+                "[MCP: Server '{}' is missing required tool '{}'. Expected tools: {}]",
+                self.name,
+                tool,
+                required.join(", ")
             );
         }
+    }
 
-        // Create transport
-        let transport = StdioTransport::new(&config.command, &config.args, config.env.as_ref())
-            .await
-            .with_context(|| format!("Failed to create transport for server '{}'", config.name))?;
+    /// Spawn the server process for a stdio connection
+    async fn spawn_stdio(config: &ServerConfig) -> Result<TracingTransport> {
+        let stderr_log = StderrLogOptions {
+            path: config.stderr_log_path.clone(),
+            level: config.stderr_log_level,
+        };
+        let transport = StdioTransport::with_stderr_log(
+            &config.name,
+            &config.command,
+            &config.args,
+            config.env.as_ref(),
+            stderr_log,
+        )
+        .await
+        .with_context(|| format!("Failed to create transport for server '{}'", config.name))?;
+
+        Ok(TracingTransport::new(transport, config.name.clone()))
+    }
 
+    /// Perform the initialization handshake over an already created transport
+    async fn connect(name: &str, transport: TracingTransport) -> Result<Self> {
         // Perform initialization handshake
         let init_params = InitializeParams::default();
 
@@ -60,7 +162,7 @@ impl ServerConnection {
             if !is_tui_enabled() {
                 eprintln!(
                     "[MCP: {}] Warning: Server uses protocol version {}, we use {}",
-                    config.name, init_result.protocol_version, PROTOCOL_VERSION
+                    name, init_result.protocol_version, PROTOCOL_VERSION
                 );
             }
         }
@@ -74,16 +176,20 @@ impl ServerConnection {
         if !is_tui_enabled() {
             println!(
                 "[MCP: Connected to '{}' ({} v{})]",
-                config.name, init_result.server_info.name, init_result.server_info.version
+                name, init_result.server_info.name, init_result.server_info.version
             );
         }
 
         let mut connection = Self {
-            name: config.name.clone(),
-            transport,
+            name: name.to_string(),
+            transport: Arc::new(transport),
             _server_info: init_result.server_info,
             capabilities: init_result.capabilities,
             available_tools: Vec::new(),
+            available_prompts: Vec::new(),
+            tool_retries: 0,
+            heartbeat: None,
+            temp_staging: false,
         };
 
         // List tools if server supports them
@@ -94,6 +200,14 @@ impl ServerConnection {
                 .context("Failed to list tools")?;
         }
 
+        // List prompts if server supports them; prompts are optional, so a failure
+        // leaves the list empty instead of failing the connection
+        if connection.capabilities.prompts.is_some()
+            && let Err(e) = connection.refresh_prompts().await
+        {
+            eprintln!("[MCP: {}] Warning: Failed to list prompts: {:#}", name, e);
+        }
+
         Ok(connection)
     }
 
@@ -146,6 +260,58 @@ impl ServerConnection {
         Ok(())
     }
 
+    /// Refresh the list of prompt templates from the server
+    async fn refresh_prompts(&mut self) -> Result<()> {
+        let response = self
+            .transport
+            .request("prompts/list", Some(serde_json::json!({})))
+            .await
+            .context("Failed to send prompts/list request")?;
+
+        let list_result: ListPromptsResult = serde_json::from_value(
+            response
+                .result
+                .context("prompts/list response missing result field")?,
+        )
+        .context("Failed to parse prompts/list response")?;
+
+        self.available_prompts = list_result.prompts;
+
+        if !self.available_prompts.is_empty() && !is_tui_enabled() {
+            println!(
+                "[MCP: Server '{}' provides {} prompt(s)]",
+                self.name,
+                self.available_prompts.len()
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Check if the server process is still running (and answering heartbeats, if enabled)
+    pub async fn is_alive(&self) -> bool {
+        if let Some(heartbeat) = &self.heartbeat
+            && !*heartbeat.borrow()
+        {
+            return false;
+        }
+        self.transport.is_alive().await
+    }
+
+    /// Act on the notifications the server sent since the last call
+    ///
+    /// Returns true if the tool list was refreshed because of `notifications/tools/list_changed`.
+    pub async fn handle_notifications(&mut self) -> Result<bool> {
+        let notifications = self.transport.take_server_notifications();
+        if !notifications.iter().any(|method| method == TOOLS_LIST_CHANGED) {
+            return Ok(false);
+        }
+        self.refresh_tools()
+            .await
+            .context("Failed to refresh tools after list_changed notification")?;
+        Ok(true)
+    }
+
     /// Call a tool on this server
     pub async fn call_tool(
         &self,
@@ -173,15 +339,113 @@ impl ServerConnection {
         Ok(call_result)
     }
 
+    /// Get a rendered prompt from this server
+    pub async fn get_prompt(
+        &self,
+        name: &str,
+        arguments: HashMap<String, String>,
+    ) -> Result<GetPromptResult> {
+        let params = GetPromptParams {
+            name: name.to_string(),
+            arguments: (!arguments.is_empty()).then_some(arguments),
+        };
+
+        let response = self
+            .transport
+            .request("prompts/get", Some(serde_json::to_value(&params)?))
+            .await
+            .with_context(|| format!("Failed to get prompt '{}' from server '{}'", name, self.name))?;
+
+        let prompt_result: GetPromptResult = serde_json::from_value(
+            response
+                .result
+                .context("prompts/get response missing result field")?,
+        )
+        .context("Failed to parse prompts/get response")?;
+
+        Ok(prompt_result)
+    }
+
+    /// Drain the log messages this server sent while the chat TUI was active
+    pub fn take_log_messages(&self) -> Vec<String> {
+        self.transport.take_log_messages()
+    }
+
     /// Get available tools
     pub fn tools(&self) -> &[Tool] {
         &self.available_tools
     }
+
+    /// Get available prompt templates
+    pub fn prompts(&self) -> &[Prompt] {
+        &self.available_prompts
+    }
+
+    /// Get the capabilities reported during initialization
+    pub fn capabilities(&self) -> &ServerCapabilities {
+        &self.capabilities
+    }
+
+    /// List resources from this server
+    ///
+    /// Servers that do not advertise the resources capability are not asked
+    /// and yield an empty list.
+    pub async fn list_resources(&self) -> Result<Vec<Resource>> {
+        if self.capabilities.resources.is_none() {
+            return Ok(Vec::new());
+        }
+
+        let params = ListResourcesParams::default();
+        let response = self
+            .transport
+            .request("resources/list", Some(serde_json::to_value(&params)?))
+            .await
+            .with_context(|| format!("Failed to list resources on server '{}'", self.name))?;
+
+        let list_result: ListResourcesResult = serde_json::from_value(
+            response
+                .result
+                .context("resources/list response missing result field")?,
+        )
+        .context("Failed to parse resources/list response")?;
+
+        Ok(list_result.resources)
+    }
+
+    /// Ask the server to send log messages at `level` and above
+    ///
+    /// Fails without sending a request if the server does not advertise the logging capability.
+    pub async fn set_log_level(&self, level: LoggingLevel) -> Result<()> {
+        if self.capabilities.logging.is_none() {
+            anyhow::bail!("Server '{}' does not support logging", self.name);
+        }
+
+        let params = SetLevelParams { level };
+        self.transport
+            .request("logging/setLevel", Some(serde_json::to_value(&params)?))
+            .await
+            .with_context(|| format!("Failed to set log level on server '{}'", self.name))?;
+        Ok(())
+    }
 }
 
 /// MCP Client managing multiple server connections
+///
+/// Cloning is cheap: the clone shares the server connections, so it can be
+/// handed to a background task to call tools without holding the session's lock.
+#[derive(Clone)]
 pub struct McpClient {
-    servers: HashMap<String, ServerConnection>,
+    // Keeps configuration order so tool lookup and listing are deterministic
+    servers: IndexMap<String, Arc<ServerConnection>>,
+}
+
+impl std::fmt::Debug for McpClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("McpClient")
+            .field("servers", &self.servers.values().collect::<Vec<_>>())
+            .field("tool_count", &self.list_all_tools().len())
+            .finish()
+    }
 }
 
 impl McpClient {
@@ -190,13 +454,19 @@ impl McpClient {
     /// This will attempt to connect to all configured servers.
     /// Servers that fail to connect will be logged and skipped.
     pub async fn new(config: McpConfig) -> Result<Self> {
-        let mut servers = HashMap::new();
+        let mut servers = IndexMap::new();
 
         for server_config in config.servers {
             let name = server_config.name.clone();
+            if server_config.is_disabled() {
+                if !is_tui_enabled() {
+                    println!("[MCP: Skipping disabled server '{}']", name);
+                }
+                continue;
+            }
             match ServerConnection::new(server_config).await {
                 Ok(connection) => {
-                    servers.insert(name, connection);
+                    servers.insert(name, Arc::new(connection));
                 }
                 Err(e) => {
                     if !is_tui_enabled() {
@@ -220,22 +490,53 @@ impl McpClient {
         Ok(Self { servers })
     }
 
+    /// Create a client with a single server answered by `transport`
+    ///
+    /// The transport must have the `initialize` response queued (and `tools/list`
+    /// if the server advertises tools). Used by tests that run without a real server.
+    #[cfg(any(test, feature = "test-util"))]
+    pub async fn connect_mock(name: &str, transport: MockTransport) -> Result<Self> {
+        let connection =
+            ServerConnection::connect(name, TracingTransport::mock(transport, name)).await?;
+        let mut servers = IndexMap::new();
+        servers.insert(name.to_string(), Arc::new(connection));
+        Ok(Self { servers })
+    }
+
     /// Get all available tools from all servers
     ///
-    /// Returns a list of (server_name, tool) pairs
+    /// Returns a list of (server_name, tool) pairs sorted by (server_name, tool name)
     pub fn list_all_tools(&self) -> Vec<(&str, &Tool)> {
-        self.servers
+        let mut tools: Vec<(&str, &Tool)> = self
+            .servers
             .iter()
             .flat_map(|(server_name, conn)| {
                 conn.tools()
                     .iter()
                     .map(move |tool| (server_name.as_str(), tool))
             })
-            .collect()
+            .collect();
+        sort_tools(&mut tools);
+        tools
+    }
+
+    /// Names of the connected servers, in configuration order
+    pub fn server_names(&self) -> Vec<&str> {
+        self.servers.keys().map(String::as_str).collect()
+    }
+
+    /// Check whether the named server's process is still running
+    ///
+    /// Returns false for unknown server names.
+    pub async fn is_server_alive(&self, server_name: &str) -> bool {
+        match self.servers.get(server_name) {
+            Some(connection) => connection.is_alive().await,
+            None => false,
+        }
     }
 
     /// Find which server provides a tool with the given name
-    fn find_server_for_tool(&self, tool_name: &str) -> Option<&ServerConnection> {
+    fn find_server_for_tool(&self, tool_name: &str) -> Option<&Arc<ServerConnection>> {
         for connection in self.servers.values() {
             if connection.tools().iter().any(|t| t.name == tool_name) {
                 return Some(connection);
@@ -254,24 +555,200 @@ impl McpClient {
             .find_server_for_tool(name)
             .ok_or_else(|| anyhow::anyhow!("Tool '{}' not found on any connected server", name))?;
 
+        Self::call_tool_on(connection, name, arguments).await
+    }
+
+    /// Get a rendered prompt by name
+    ///
+    /// `server::name` (as shown by `list_all_prompts`) selects the server;
+    /// a bare name uses the first server that lists a prompt with that name.
+    pub async fn get_prompt(
+        &self,
+        name: &str,
+        arguments: HashMap<String, String>,
+    ) -> Result<GetPromptResult> {
+        let (connection, prompt_name) = match name.split_once("::") {
+            Some((server, prompt_name)) => {
+                let connection = self
+                    .servers
+                    .get(server)
+                    .ok_or_else(|| anyhow::anyhow!("MCP server '{}' is not connected", server))?;
+                if !connection.prompts().iter().any(|p| p.name == prompt_name) {
+                    anyhow::bail!("Prompt '{}' not found on server '{}'", prompt_name, server);
+                }
+                (connection, prompt_name)
+            }
+            None => {
+                let connection = self
+                    .servers
+                    .values()
+                    .find(|conn| conn.prompts().iter().any(|p| p.name == name))
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("Prompt '{}' not found on any connected server", name)
+                    })?;
+                (connection, name)
+            }
+        };
+
+        connection.get_prompt(prompt_name, arguments).await
+    }
+
+    /// Refresh the tool list of every server that supports tools
+    ///
+    /// Servers may add or remove tools after the initial connection; the next
+    /// `list_all_tools` reflects the refreshed lists. Clones of this client
+    /// taken earlier keep the tool lists they had.
+    pub async fn refresh_all_tools(&mut self) -> Result<()> {
+        for connection in self.servers.values_mut() {
+            if connection.capabilities().tools.is_some() {
+                let connection = Arc::make_mut(connection);
+                let name = connection.name.clone();
+                connection
+                    .refresh_tools()
+                    .await
+                    .with_context(|| format!("Failed to refresh tools for server '{}'", name))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Refresh the tool lists of servers that sent `notifications/tools/list_changed`
+    ///
+    /// Returns the names of the servers whose tool list was updated. A failed refresh
+    /// is logged and the server keeps its previous tool list.
+    pub async fn handle_notifications(&mut self) -> Vec<String> {
+        let mut updated = Vec::new();
+        for (name, connection) in self.servers.iter_mut() {
+            match Arc::make_mut(connection).handle_notifications().await {
+                Ok(true) => {
+                    if !is_tui_enabled() {
+                        println!("[MCP: Tool list updated for '{}']", name);
+                    }
+                    updated.push(name.clone());
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    if !is_tui_enabled() {
+                        eprintln!("[MCP: {}] Warning: {:#}", name, e);
+                    }
+                }
+            }
+        }
+        updated
+    }
+
+    /// Set the minimum level of log messages (`notifications/message`) a server sends
+    pub async fn set_log_level(&self, server: &str, level: LoggingLevel) -> Result<()> {
+        let connection = self
+            .servers
+            .get(server)
+            .ok_or_else(|| anyhow::anyhow!("MCP server '{}' is not connected", server))?;
+
+        connection.set_log_level(level).await
+    }
+
+    /// Get the capabilities a connected server reported during initialization
+    pub fn server_capabilities(&self, server_name: &str) -> Option<&ServerCapabilities> {
+        self.servers.get(server_name).map(|conn| conn.capabilities())
+    }
+
+    /// Check whether a server opted into `use_temp_staging` for file outputs
+    pub fn uses_temp_staging(&self, server_name: &str) -> bool {
+        self.servers
+            .get(server_name)
+            .is_some_and(|conn| conn.temp_staging)
+    }
+
+    /// Drain the log messages (`notifications/message`) held back while the chat TUI was active
+    pub fn take_log_messages(&self) -> Vec<String> {
+        self.servers
+            .values()
+            .flat_map(|connection| connection.take_log_messages())
+            .collect()
+    }
+
+    /// Get all prompt templates from all servers as (server_name, prompt) pairs
+    pub fn list_all_prompts(&self) -> Vec<(&str, &Prompt)> {
+        self.servers
+            .iter()
+            .flat_map(|(server_name, conn)| {
+                conn.prompts()
+                    .iter()
+                    .map(move |prompt| (server_name.as_str(), prompt))
+            })
+            .collect()
+    }
+
+    /// Call a tool on a specific server
+    ///
+    /// Used when several servers expose a tool with the same name.
+    pub async fn call_tool_on_server(
+        &self,
+        server: &str,
+        name: &str,
+        arguments: serde_json::Value,
+    ) -> Result<ToolResult> {
+        let connection = self
+            .servers
+            .get(server)
+            .ok_or_else(|| anyhow::anyhow!("MCP server '{}' is not connected", server))?;
+
+        Self::call_tool_on(connection, name, arguments).await
+    }
+
+    /// Execute a tool on the given connection and convert the result
+    async fn call_tool_on(
+        connection: &ServerConnection,
+        name: &str,
+        arguments: serde_json::Value,
+    ) -> Result<ToolResult> {
+        if !connection.is_alive().await {
+            anyhow::bail!(
+                "MCP server '{}' is not running or not responding",
+                connection.name
+            );
+        }
         if !is_tui_enabled() {
             println!("[MCP: Calling tool '{}' on server '{}']", name, connection.name);
         }
 
-        // Call the tool
-        let result = connection
-            .call_tool(name, arguments)
-            .await
-            .with_context(|| format!("Failed to execute tool '{}'", name))?;
+        // Call the tool, retrying failures when the server opts in
+        let started = Instant::now();
+        let mut attempt = 0;
+        let result = loop {
+            let result = connection
+                .call_tool(name, arguments.clone())
+                .await
+                .with_context(|| format!("Failed to execute tool '{}'", name));
+            let failed = match &result {
+                Ok(result) => result.is_error == Some(true),
+                Err(_) => true,
+            };
+            if !failed || attempt >= connection.tool_retries {
+                break result?;
+            }
+            attempt += 1;
+            if !is_tui_enabled() {
+                println!(
+                    "[MCP: Retrying tool '{}' on server '{}' ({}/{})]",
+                    name, connection.name, attempt, connection.tool_retries
+                );
+            }
+            tokio::time::sleep(TOOL_RETRY_BACKOFF).await;
+        };
 
         // Convert to ToolResult
         let mut tool_result = ToolResult::from(result);
         tool_result.name = name.to_string();
+        tool_result.latency_ms = started.elapsed().as_millis() as u64;
 
         // Log result
         if tool_result.success {
             if !is_tui_enabled() {
-                println!("[MCP: Tool '{}' completed successfully]", name);
+                println!(
+                    "[MCP: Tool '{}' completed in {}ms]",
+                    name, tool_result.latency_ms
+                );
             }
         } else {
             if !is_tui_enabled() {
@@ -284,6 +761,15 @@ impl McpClient {
 
 }
 
+/// Sort (server_name, tool) pairs so prompts built from them are reproducible
+pub(crate) fn sort_tools(tools: &mut [(&str, &Tool)]) {
+    tools.sort_by(|(a_server, a_tool), (b_server, b_tool)| {
+        a_server
+            .cmp(b_server)
+            .then_with(|| a_tool.name.cmp(&b_tool.name))
+    });
+}
+
 fn is_tui_enabled() -> bool {
     env::var("RKLLM_TUI").ok().as_deref() == Some("1")
 }
@@ -291,6 +777,318 @@ fn is_tui_enabled() -> bool {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use serde_json::json;
+
+    fn initialize_result() -> serde_json::Value {
+        json!({
+            "protocolVersion": PROTOCOL_VERSION,
+            "capabilities": { "tools": {} },
+            "serverInfo": { "name": "mock", "version": "1.0" }
+        })
+    }
+
+    fn tools_list_result(names: &[&str]) -> serde_json::Value {
+        let tools: Vec<_> = names
+            .iter()
+            .map(|name| json!({ "name": name, "inputSchema": { "type": "object" } }))
+            .collect();
+        json!({ "tools": tools })
+    }
+
+    async fn connect_mock(names: &[&str]) -> (ServerConnection, MockTransport) {
+        let mock = MockTransport::new();
+        mock.expect_request("initialize", MockTransport::ok(initialize_result()))
+            .expect_request("tools/list", MockTransport::ok(tools_list_result(names)));
+        let connection =
+            ServerConnection::connect("mock", TracingTransport::mock(mock.clone(), "mock"))
+                .await
+                .unwrap();
+        (connection, mock)
+    }
+
+    #[tokio::test]
+    async fn test_connect_performs_handshake() {
+        let (connection, mock) = connect_mock(&["read_file"]).await;
+
+        mock.assert_all_consumed();
+        assert_eq!(mock.notifications(), vec!["notifications/initialized"]);
+        let names: Vec<&str> = connection.tools().iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, vec!["read_file"]);
+    }
+
+    #[tokio::test]
+    async fn test_connect_fails_without_initialize_result() {
+        let mock = MockTransport::new();
+        mock.expect_request(
+            "initialize",
+            Ok(JsonRpcResponse {
+                jsonrpc: "2.0".to_string(),
+                result: None,
+                error: None,
+                id: RequestId::Number(0),
+            }),
+        );
+
+        let result =
+            ServerConnection::connect("mock", TracingTransport::mock(mock.clone(), "mock")).await;
+
+        assert!(result.is_err());
+        assert!(mock.notifications().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_list_resources_skips_servers_without_capability() {
+        let (connection, mock) = connect_mock(&["read_file"]).await;
+
+        let resources = connection.list_resources().await.unwrap();
+
+        mock.assert_all_consumed();
+        assert!(resources.is_empty());
+        assert_eq!(connection.capabilities().supported(), vec!["tools"]);
+    }
+
+    #[tokio::test]
+    async fn test_missing_tools_lists_absent_required_tools() {
+        let (connection, _mock) = connect_mock(&["read_file"]).await;
+
+        let required = vec!["read_file".to_string(), "write_file".to_string()];
+        assert_eq!(connection.missing_tools(&required), vec!["write_file"]);
+        assert!(connection.missing_tools(&required[..1]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_refresh_tools_replaces_tool_list() {
+        let (mut connection, mock) = connect_mock(&["read_file"]).await;
+        mock.expect_request(
+            "tools/list",
+            MockTransport::ok(tools_list_result(&["search", "write_file"])),
+        );
+
+        connection.refresh_tools().await.unwrap();
+
+        mock.assert_all_consumed();
+        let names: Vec<&str> = connection.tools().iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, vec!["search", "write_file"]);
+    }
+
+    #[tokio::test]
+    async fn test_list_changed_notification_refreshes_tools() {
+        let (connection, mock) = connect_mock(&["read_file"]).await;
+        let mut servers = IndexMap::new();
+        servers.insert("mock".to_string(), Arc::new(connection));
+        let mut client = McpClient { servers };
+
+        // Without a notification nothing is requested
+        assert!(client.handle_notifications().await.is_empty());
+
+        mock.push_server_notification(TOOLS_LIST_CHANGED);
+        mock.expect_request(
+            "tools/list",
+            MockTransport::ok(tools_list_result(&["read_file", "search"])),
+        );
+        assert_eq!(client.handle_notifications().await, vec!["mock"]);
+
+        mock.assert_all_consumed();
+        let names: Vec<&str> = client.list_all_tools().iter().map(|(_, t)| t.name.as_str()).collect();
+        assert_eq!(names, vec!["read_file", "search"]);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_all_tools_lists_every_server() {
+        let (connection, mock) = connect_mock(&["read_file"]).await;
+        let mut servers = IndexMap::new();
+        servers.insert("mock".to_string(), Arc::new(connection));
+        let mut client = McpClient { servers };
+        mock.expect_request("tools/list", MockTransport::ok(tools_list_result(&["search"])));
+
+        client.refresh_all_tools().await.unwrap();
+
+        mock.assert_all_consumed();
+        assert_eq!(client.list_all_tools()[0].1.name, "search");
+    }
+
+    #[tokio::test]
+    async fn test_client_debug_shows_servers_and_tool_count() {
+        let (connection, _mock) = connect_mock(&["read_file", "write_file"]).await;
+        let mut servers = IndexMap::new();
+        servers.insert("mock".to_string(), Arc::new(connection));
+        let client = McpClient { servers };
+
+        let debug = format!("{:?}", client);
+        assert!(debug.contains(r#"name: "mock""#));
+        assert!(debug.contains("tool_count: 2"));
+    }
+
+    #[tokio::test]
+    async fn test_client_call_tool_routes_to_server() {
+        let (connection, mock) = connect_mock(&["echo"]).await;
+        mock.expect_request(
+            "tools/call",
+            MockTransport::ok(json!({ "content": [{ "type": "text", "text": "hi" }] })),
+        );
+        let mut servers = IndexMap::new();
+        servers.insert("mock".to_string(), Arc::new(connection));
+        let client = McpClient { servers };
+
+        let result = client.call_tool("echo", json!({})).await.unwrap();
+
+        mock.assert_all_consumed();
+        assert!(result.success);
+        assert_eq!(result.output.trim(), "hi");
+        assert!(client.call_tool("missing", json!({})).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cloned_client_calls_tools_from_background_task() {
+        let (connection, mock) = connect_mock(&["echo"]).await;
+        mock.expect_request(
+            "tools/call",
+            MockTransport::ok(json!({ "content": [{ "type": "text", "text": "hi" }] })),
+        );
+        let mut servers = IndexMap::new();
+        servers.insert("mock".to_string(), Arc::new(connection));
+        let mut client = McpClient { servers };
+
+        let background = client.clone();
+        let result = tokio::spawn(async move { background.call_tool("echo", json!({})).await })
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(result.output.trim(), "hi");
+
+        // A refresh replaces the client's own tool list; an earlier clone keeps its list
+        let snapshot = client.clone();
+        mock.expect_request("tools/list", MockTransport::ok(tools_list_result(&["search"])));
+        client.refresh_all_tools().await.unwrap();
+
+        mock.assert_all_consumed();
+        assert_eq!(client.list_all_tools()[0].1.name, "search");
+        assert_eq!(snapshot.list_all_tools()[0].1.name, "echo");
+    }
+
+    #[tokio::test]
+    async fn test_call_tool_retries_failed_calls() {
+        let (mut connection, mock) = connect_mock(&["echo"]).await;
+        connection.tool_retries = 1;
+        mock.expect_request(
+            "tools/call",
+            MockTransport::ok(json!({ "content": [], "isError": true })),
+        )
+        .expect_request(
+            "tools/call",
+            MockTransport::ok(json!({ "content": [{ "type": "text", "text": "hi" }] })),
+        );
+
+        let result = McpClient::call_tool_on(&connection, "echo", json!({}))
+            .await
+            .unwrap();
+
+        mock.assert_all_consumed();
+        assert!(result.success);
+        assert_eq!(result.output.trim(), "hi");
+    }
+
+    #[tokio::test]
+    async fn test_client_get_prompt_from_listing_server() {
+        let mock = MockTransport::new();
+        mock.expect_request(
+            "initialize",
+            MockTransport::ok(json!({
+                "protocolVersion": PROTOCOL_VERSION,
+                "capabilities": { "prompts": {} },
+                "serverInfo": { "name": "mock", "version": "1.0" }
+            })),
+        )
+        .expect_request(
+            "prompts/list",
+            MockTransport::ok(json!({ "prompts": [{ "name": "review" }] })),
+        )
+        .expect_request(
+            "prompts/get",
+            MockTransport::ok(json!({
+                "messages": [{ "role": "user", "content": { "type": "text", "text": "Review a.rs" } }]
+            })),
+        );
+        let connection =
+            ServerConnection::connect("mock", TracingTransport::mock(mock.clone(), "mock"))
+                .await
+                .unwrap();
+        let mut servers = IndexMap::new();
+        servers.insert("mock".to_string(), Arc::new(connection));
+        let client = McpClient { servers };
+
+        let arguments = HashMap::from([("file".to_string(), "a.rs".to_string())]);
+        let result = client.get_prompt("review", arguments).await.unwrap();
+        assert_eq!(result.to_text(), "Review a.rs");
+
+        mock.expect_request(
+            "prompts/get",
+            MockTransport::ok(json!({
+                "messages": [{ "role": "user", "content": { "type": "text", "text": "Review b.rs" } }]
+            })),
+        );
+        let result = client.get_prompt("mock::review", HashMap::new()).await.unwrap();
+        assert_eq!(result.to_text(), "Review b.rs");
+        let params = mock.requests().last().unwrap().1.clone().unwrap();
+        assert_eq!(params["name"], "review");
+
+        mock.assert_all_consumed();
+        assert_eq!(client.list_all_prompts().len(), 1);
+        assert!(client.get_prompt("missing", HashMap::new()).await.is_err());
+        assert!(client.get_prompt("other::review", HashMap::new()).await.is_err());
+        assert!(client.get_prompt("mock::missing", HashMap::new()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connect_survives_prompts_list_failure() {
+        let mock = MockTransport::new();
+        mock.expect_request(
+            "initialize",
+            MockTransport::ok(json!({
+                "protocolVersion": PROTOCOL_VERSION,
+                "capabilities": { "prompts": {} },
+                "serverInfo": { "name": "mock", "version": "1.0" }
+            })),
+        )
+        .expect_request("prompts/list", Err(anyhow::anyhow!("Method not found")));
+        let connection =
+            ServerConnection::connect("mock", TracingTransport::mock(mock.clone(), "mock"))
+                .await
+                .unwrap();
+
+        mock.assert_all_consumed();
+        assert!(connection.prompts().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_set_log_level_requires_logging_capability() {
+        let mock = MockTransport::new();
+        mock.expect_request(
+            "initialize",
+            MockTransport::ok(json!({
+                "protocolVersion": PROTOCOL_VERSION,
+                "capabilities": { "logging": {} },
+                "serverInfo": { "name": "mock", "version": "1.0" }
+            })),
+        )
+        .expect_request("logging/setLevel", MockTransport::ok(json!({})));
+        let connection =
+            ServerConnection::connect("mock", TracingTransport::mock(mock.clone(), "mock"))
+                .await
+                .unwrap();
+        let (without_logging, _) = connect_mock(&[]).await;
+        let mut servers = IndexMap::new();
+        servers.insert("mock".to_string(), Arc::new(connection));
+        servers.insert("tools".to_string(), Arc::new(without_logging));
+        let client = McpClient { servers };
+
+        client.set_log_level("mock", LoggingLevel::Warning).await.unwrap();
+        mock.assert_all_consumed();
+
+        let err = client.set_log_level("tools", LoggingLevel::Warning).await.unwrap_err();
+        assert!(err.to_string().contains("does not support logging"));
+        assert!(client.set_log_level("missing", LoggingLevel::Info).await.is_err());
+    }
 
     #[test]
     fn test_initialize_params_default() {
@@ -332,4 +1130,33 @@ mod tests {
         assert!(!tool_result.success);
         assert!(tool_result.output.contains("Error message"));
     }
+
+    #[test]
+    fn test_sort_tools_by_server_then_name() {
+        let make_tool = |name: &str| Tool {
+            name: name.to_string(),
+            description: None,
+            input_schema: ToolInputSchema {
+                schema_type: "object".to_string(),
+                properties: None,
+                required: None,
+                additional_properties: None,
+            },
+        };
+        let write = make_tool("write_file");
+        let read = make_tool("read_file");
+        let query = make_tool("query");
+
+        let mut tools = vec![("fs", &write), ("db", &query), ("fs", &read)];
+        sort_tools(&mut tools);
+
+        let order: Vec<(&str, &str)> = tools
+            .iter()
+            .map(|(server, tool)| (*server, tool.name.as_str()))
+            .collect();
+        assert_eq!(
+            order,
+            vec![("db", "query"), ("fs", "read_file"), ("fs", "write_file")]
+        );
+    }
 }
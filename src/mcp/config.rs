@@ -1,8 +1,9 @@
 // MCP configuration file handling
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 /// MCP configuration
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -11,16 +12,231 @@ pub struct McpConfig {
 }
 
 /// Individual MCP server configuration
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct ServerConfig {
     pub name: String,
     #[serde(default = "default_transport")]
     pub transport: Transport,
+    /// Command to spawn (stdio transport)
+    #[serde(default)]
     pub command: String,
     #[serde(default)]
     pub args: Vec<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub env: Option<std::collections::HashMap<String, String>>,
+    /// `.env` file merged into `env` on load (relative to the config file; `env` wins)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env_file: Option<String>,
+    /// Append server stderr to this file instead of the process stderr
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stderr_log_path: Option<PathBuf>,
+    /// Which server stderr lines to log
+    #[serde(default)]
+    pub stderr_log_level: StderrLogLevel,
+    /// Endpoint that receives JSON-RPC POSTs (http transport)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    /// Sent as `Authorization: Bearer` (http transport)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub api_key: Option<String>,
+    /// Keep the entry but do not connect to this server
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disabled: Option<bool>,
+    /// Retry tool calls that fail on this server (without reconnecting)
+    #[serde(default)]
+    pub retry_on_error: bool,
+    /// Maximum number of retries per tool call when `retry_on_error` is set
+    #[serde(default = "default_max_retries")]
+    pub max_retries: usize,
+    /// Tools this server is expected to provide (a warning is printed if any are missing)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub required_tools: Option<Vec<String>>,
+    /// Send a `$/ping` notification this often to keep the server alive (stdio transport)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub heartbeat_interval_secs: Option<u64>,
+    /// Send large file outputs as `{"path", "source_path"}` pointing at a staged temp file
+    #[serde(default)]
+    pub use_temp_staging: bool,
+}
+
+impl ServerConfig {
+    /// Check whether the server is marked `disabled = true`
+    pub fn is_disabled(&self) -> bool {
+        self.disabled == Some(true)
+    }
+
+    /// Merge the variables from `env_file` into `env`
+    ///
+    /// Keys already in `env` keep their value. A missing file only prints a warning.
+    fn load_env_file(&mut self, base_dir: &Path) -> Result<()> {
+        let Some(env_file) = &self.env_file else {
+            return Ok(());
+        };
+        let expanded = PathBuf::from(shellexpand::tilde(env_file).as_ref());
+        let env_path = if expanded.is_absolute() {
+            expanded
+        } else {
+            base_dir.join(expanded)
+        };
+        if !env_path.exists() {
+            eprintln!(
+                "[MCP: env_file '{}' for server '{}' not found]",
+                env_path.display(),
+                self.name
+            );
+            return Ok(());
+        }
+
+        let content = std::fs::read_to_string(&env_path).with_context(|| {
+            format!("Failed to read env_file for server '{}': {}", self.name, env_path.display())
+        })?;
+        let env = self.env.get_or_insert_with(Default::default);
+        for (key, value) in parse_env_file(&content) {
+            env.entry(key).or_insert(value);
+        }
+        Ok(())
+    }
+
+    /// Heartbeat interval, if `heartbeat_interval_secs` is set to a non-zero value
+    pub fn heartbeat_interval(&self) -> Option<std::time::Duration> {
+        self.heartbeat_interval_secs
+            .filter(|secs| *secs > 0)
+            .map(std::time::Duration::from_secs)
+    }
+}
+
+/// Placeholder printed instead of credentials in `Debug` output
+const REDACTED: &str = "[REDACTED]";
+
+/// Check whether an environment variable name looks like it holds a credential
+fn is_secret_env_key(key: &str) -> bool {
+    let upper = key.to_uppercase();
+    ["KEY", "TOKEN", "SECRET", "PASSWORD"]
+        .iter()
+        .any(|marker| upper.contains(marker))
+}
+
+// Manual impl so that crash output and debug logs never contain API keys or tokens
+impl std::fmt::Debug for ServerConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let env = self.env.as_ref().map(|env| {
+            env.iter()
+                .map(|(key, value)| {
+                    let value = if is_secret_env_key(key) { REDACTED } else { value.as_str() };
+                    (key.as_str(), value)
+                })
+                .collect::<std::collections::BTreeMap<_, _>>()
+        });
+        f.debug_struct("ServerConfig")
+            .field("name", &self.name)
+            .field("transport", &self.transport)
+            .field("command", &self.command)
+            .field("args", &self.args)
+            .field("env", &env)
+            .field("env_file", &self.env_file)
+            .field("stderr_log_path", &self.stderr_log_path)
+            .field("stderr_log_level", &self.stderr_log_level)
+            .field("url", &self.url)
+            .field("api_key", &self.api_key.as_ref().map(|_| REDACTED))
+            .field("disabled", &self.disabled)
+            .field("retry_on_error", &self.retry_on_error)
+            .field("max_retries", &self.max_retries)
+            .field("required_tools", &self.required_tools)
+            .field("heartbeat_interval_secs", &self.heartbeat_interval_secs)
+            .field("use_temp_staging", &self.use_temp_staging)
+            .finish()
+    }
+}
+
+/// Filter for MCP server stderr lines
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum StderrLogLevel {
+    /// Log every line
+    #[default]
+    All,
+    /// Only log lines mentioning "error" or "warn"
+    ErrorsOnly,
+}
+
+impl StderrLogLevel {
+    /// Check whether a stderr line passes this filter
+    pub fn allows(self, line: &str) -> bool {
+        match self {
+            StderrLogLevel::All => true,
+            StderrLogLevel::ErrorsOnly => {
+                let lower = line.to_lowercase();
+                lower.contains("error") || lower.contains("warn")
+            }
+        }
+    }
+}
+
+/// MCP configuration in the JSON format used by Claude Desktop / Cline
+///
+/// ```json
+/// { "mcpServers": { "filesystem": { "command": "npx", "args": ["..."] } } }
+/// ```
+///
+/// HTTP servers are given by `url` (and optionally `"transport": "http"` and `api_key`), as in TOML.
+#[derive(Debug, Clone, Deserialize)]
+pub struct McpConfigJson {
+    #[serde(rename = "mcpServers", default)]
+    pub mcp_servers: IndexMap<String, McpServerJson>,
+}
+
+/// Individual server entry in `mcpServers`
+#[derive(Debug, Clone, Deserialize)]
+pub struct McpServerJson {
+    /// Defaults to `http` when `url` is set, otherwise `stdio`
+    #[serde(default)]
+    pub transport: Option<Transport>,
+    #[serde(default)]
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default)]
+    pub api_key: Option<String>,
+    #[serde(default)]
+    pub env: Option<std::collections::HashMap<String, String>>,
+    #[serde(default)]
+    pub disabled: Option<bool>,
+}
+
+impl From<McpConfigJson> for McpConfig {
+    fn from(json: McpConfigJson) -> Self {
+        let servers = json
+            .mcp_servers
+            .into_iter()
+            .map(|(name, server)| ServerConfig {
+                name,
+                transport: server.transport.unwrap_or_else(|| {
+                    if server.url.is_some() {
+                        Transport::Http
+                    } else {
+                        default_transport()
+                    }
+                }),
+                command: server.command,
+                args: server.args,
+                env: server.env,
+                env_file: None,
+                stderr_log_path: None,
+                stderr_log_level: StderrLogLevel::default(),
+                url: server.url,
+                api_key: server.api_key,
+                disabled: server.disabled,
+                retry_on_error: false,
+                max_retries: default_max_retries(),
+                required_tools: None,
+                heartbeat_interval_secs: None,
+                use_temp_staging: false,
+            })
+            .collect();
+        Self { servers }
+    }
 }
 
 /// Transport type
@@ -28,28 +244,70 @@ pub struct ServerConfig {
 #[serde(rename_all = "lowercase")]
 pub enum Transport {
     Stdio,
+    /// JSON-RPC over HTTP POST (requires `url`)
+    Http,
     #[allow(dead_code)]
     Sse,
 }
 
+/// Parse `KEY=value` lines of a `.env` file
+///
+/// Blank lines, `#` comments and an `export ` prefix are skipped; matching quotes around the value are removed.
+fn parse_env_file(content: &str) -> Vec<(String, String)> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let line = line.strip_prefix("export ").unwrap_or(line);
+            let (key, value) = line.split_once('=')?;
+            let key = key.trim();
+            if key.is_empty() {
+                return None;
+            }
+            let value = value.trim();
+            let value = ['"', '\'']
+                .iter()
+                .find_map(|quote| value.strip_prefix(*quote)?.strip_suffix(*quote))
+                .unwrap_or(value);
+            Some((key.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
 fn default_transport() -> Transport {
     Transport::Stdio
 }
 
+fn default_max_retries() -> usize {
+    3
+}
+
 impl McpConfig {
-    /// Load MCP configuration from a TOML file
+    /// Load MCP configuration from a file
+    ///
+    /// `.json` files are parsed in the Claude Desktop `mcpServers` format;
+    /// anything else (`.toml` or no extension) is parsed as TOML.
     pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
         let content = std::fs::read_to_string(path)?;
-        let config: McpConfig = toml::from_str(&content)?;
-        Ok(config)
-    }
+        let is_json = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.eq_ignore_ascii_case("json"))
+            .unwrap_or(false);
 
-    /// Create a default configuration
-    #[allow(dead_code)]
-    pub fn default() -> Self {
-        Self {
-            servers: Vec::new(),
+        let mut config: McpConfig = if is_json {
+            let config: McpConfigJson = serde_json::from_str(&content)?;
+            config.into()
+        } else {
+            toml::from_str(&content)?
+        };
+        let base_dir = path.parent().unwrap_or(Path::new("."));
+        for server in &mut config.servers {
+            server.load_env_file(base_dir)?;
         }
+        Ok(config)
     }
 
     /// Check if configuration is empty
@@ -58,6 +316,121 @@ impl McpConfig {
     }
 }
 
+/// A well-known MCP server that `export-mcp-config` looks for on `PATH`
+struct KnownServer {
+    name: &'static str,
+    description: &'static str,
+    /// Ways to launch the server, in order of preference: (executable, args).
+    /// `{dir}` in an argument is replaced with the working directory.
+    launchers: &'static [(&'static str, &'static [&'static str])],
+}
+
+const KNOWN_SERVERS: &[KnownServer] = &[
+    KnownServer {
+        name: "filesystem",
+        description: "Read and write files under the given directories",
+        launchers: &[
+            ("mcp-server-filesystem", &["{dir}"]),
+            ("npx", &["-y", "@modelcontextprotocol/server-filesystem", "{dir}"]),
+        ],
+    },
+    KnownServer {
+        name: "git",
+        description: "Inspect and commit to the git repository",
+        launchers: &[
+            ("mcp-server-git", &["--repository", "{dir}"]),
+            ("uvx", &["mcp-server-git", "--repository", "{dir}"]),
+        ],
+    },
+    KnownServer {
+        name: "fetch",
+        description: "Fetch web pages as markdown",
+        launchers: &[
+            ("mcp-server-fetch", &[]),
+            ("uvx", &["mcp-server-fetch"]),
+        ],
+    },
+    KnownServer {
+        name: "time",
+        description: "Current time and timezone conversion",
+        launchers: &[
+            ("mcp-server-time", &[]),
+            ("uvx", &["mcp-server-time"]),
+        ],
+    },
+    KnownServer {
+        name: "memory",
+        description: "Knowledge-graph memory that persists across sessions",
+        launchers: &[
+            ("mcp-server-memory", &[]),
+            ("npx", &["-y", "@modelcontextprotocol/server-memory"]),
+        ],
+    },
+];
+
+/// Quote a string as a TOML basic string
+fn toml_string(value: &str) -> String {
+    toml::Value::String(value.to_string()).to_string()
+}
+
+/// Generate a starter `mcp_config.toml` for the servers that can be launched on this machine
+///
+/// `find` resolves an executable name to its path (normally a `PATH` lookup);
+/// each known server uses the first launcher it finds. `dir` is the directory
+/// given to the filesystem and git servers.
+pub fn starter_config_toml(dir: &Path, find: impl Fn(&str) -> Option<PathBuf>) -> String {
+    let dir = dir.to_string_lossy();
+    let mut out = String::from(
+        "# MCP configuration file for RKLLM CLI\n\
+         # Generated by `rkllm-cli export-mcp-config` from the servers found on PATH.\n\
+         # Review the entries, then pass this file with --mcp-config.\n",
+    );
+    let mut found = 0;
+    for server in KNOWN_SERVERS {
+        let Some((command, args, path)) = server
+            .launchers
+            .iter()
+            .find_map(|(command, args)| find(command).map(|path| (command, args, path)))
+        else {
+            continue;
+        };
+        found += 1;
+        let args: Vec<String> = args
+            .iter()
+            .map(|arg| toml_string(&arg.replace("{dir}", &dir)))
+            .collect();
+        out.push_str(&format!(
+            "\n# {} (found: {})\n[[servers]]\nname = {}\ncommand = {}\nargs = [{}]\n",
+            server.description,
+            path.display(),
+            toml_string(server.name),
+            toml_string(command),
+            args.join(", ")
+        ));
+    }
+    if found == 0 {
+        out.push_str(
+            "\n# No known MCP servers or launchers (npx, uvx) were found on PATH.\n\
+             # See mcp_config.toml.sample for the format.\n",
+        );
+    }
+    out
+}
+
+/// Generate a starter config by looking up executables on `PATH`
+pub fn starter_config_toml_from_path(dir: &Path) -> String {
+    starter_config_toml(dir, |name| which::which(name).ok())
+}
+
+impl Default for McpConfig {
+    /// Create a default configuration
+    fn default() -> Self {
+        Self {
+            servers: Vec::new(),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -91,9 +464,265 @@ args = []
         assert_eq!(config.servers[1].name, "weather");
     }
 
+    #[test]
+    fn test_load_env_file_merges_under_env() {
+        let dir = tempfile::TempDir::new().unwrap();
+        std::fs::write(
+            dir.path().join("server.env"),
+            "# credentials\nexport API_KEY=\"from-file\"\nREGION=eu\n\n",
+        )
+        .unwrap();
+        let config_path = dir.path().join("mcp.toml");
+        std::fs::write(
+            &config_path,
+            r#"
+[[servers]]
+name = "api"
+command = "api-server"
+env_file = "server.env"
+env = { REGION = "us" }
+
+[[servers]]
+name = "missing"
+command = "other-server"
+env_file = "absent.env"
+"#,
+        )
+        .unwrap();
+
+        let config = McpConfig::load(&config_path).unwrap();
+        let env = config.servers[0].env.as_ref().unwrap();
+        assert_eq!(env["API_KEY"], "from-file");
+        assert_eq!(env["REGION"], "us");
+        assert!(config.servers[1].env.is_none());
+    }
+
+    #[test]
+    fn test_debug_redacts_credentials() {
+        let config: McpConfig = toml::from_str(
+            r#"
+[[servers]]
+name = "github"
+command = "github-server"
+api_key = "sk-live"
+env = { GITHUB_TOKEN = "ghp_secret", db_password = "hunter2", LOG_LEVEL = "debug" }
+"#,
+        )
+        .unwrap();
+
+        let debug = format!("{:?}", config);
+        assert!(!debug.contains("sk-live"));
+        assert!(!debug.contains("ghp_secret"));
+        assert!(!debug.contains("hunter2"));
+        assert!(debug.contains(r#""GITHUB_TOKEN": "[REDACTED]""#));
+        assert!(debug.contains(r#""LOG_LEVEL": "debug""#));
+        assert!(debug.contains("github-server"));
+    }
+
     #[test]
     fn test_default_config() {
         let config = McpConfig::default();
         assert!(config.is_empty());
     }
+
+    #[test]
+    fn test_load_stderr_log_options() {
+        let config_toml = r#"
+[[servers]]
+name = "chatty"
+command = "chatty-server"
+stderr_log_path = "/tmp/chatty.log"
+stderr_log_level = "errors_only"
+
+[[servers]]
+name = "quiet"
+command = "quiet-server"
+"#;
+
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(config_toml.as_bytes()).unwrap();
+        file.flush().unwrap();
+
+        let config = McpConfig::load(file.path()).unwrap();
+        assert_eq!(
+            config.servers[0].stderr_log_path,
+            Some(PathBuf::from("/tmp/chatty.log"))
+        );
+        assert_eq!(config.servers[0].stderr_log_level, StderrLogLevel::ErrorsOnly);
+        assert_eq!(config.servers[1].stderr_log_path, None);
+        assert_eq!(config.servers[1].stderr_log_level, StderrLogLevel::All);
+    }
+
+    #[test]
+    fn test_load_disabled_server() {
+        let config: McpConfig = toml::from_str(
+            r#"
+[[servers]]
+name = "off"
+command = "off-server"
+disabled = true
+
+[[servers]]
+name = "on"
+command = "on-server"
+"#,
+        )
+        .unwrap();
+        assert!(config.servers[0].is_disabled());
+        assert!(!config.servers[1].is_disabled());
+
+        let json: McpConfigJson = serde_json::from_str(
+            r#"{"mcpServers":{"off":{"command":"off-server","disabled":true}}}"#,
+        )
+        .unwrap();
+        assert!(McpConfig::from(json).servers[0].is_disabled());
+    }
+
+    #[test]
+    fn test_load_retry_options() {
+        let config: McpConfig = toml::from_str(
+            r#"
+[[servers]]
+name = "flaky"
+command = "flaky-server"
+retry_on_error = true
+max_retries = 5
+
+[[servers]]
+name = "stable"
+command = "stable-server"
+"#,
+        )
+        .unwrap();
+        assert!(config.servers[0].retry_on_error);
+        assert_eq!(config.servers[0].max_retries, 5);
+        assert!(!config.servers[1].retry_on_error);
+        assert_eq!(config.servers[1].max_retries, 3);
+        assert!(config.servers[1].required_tools.is_none());
+    }
+
+    #[test]
+    fn test_load_required_tools() {
+        let config: McpConfig = toml::from_str(
+            r#"
+[[servers]]
+name = "fs"
+command = "fs-server"
+required_tools = ["read_file", "write_file"]
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.servers[0].required_tools.as_deref(),
+            Some(&["read_file".to_string(), "write_file".to_string()][..])
+        );
+    }
+
+    #[test]
+    fn test_load_http_server() {
+        let config: McpConfig = toml::from_str(
+            r#"
+[[servers]]
+name = "remote"
+transport = "http"
+url = "http://localhost:8080/rpc"
+api_key = "secret"
+"#,
+        )
+        .unwrap();
+
+        let server = &config.servers[0];
+        assert_eq!(server.transport, Transport::Http);
+        assert_eq!(server.url.as_deref(), Some("http://localhost:8080/rpc"));
+        assert_eq!(server.api_key.as_deref(), Some("secret"));
+        assert!(server.command.is_empty());
+    }
+
+    #[test]
+    fn test_stderr_log_level_filter() {
+        assert!(StderrLogLevel::All.allows("listening on stdio"));
+        assert!(!StderrLogLevel::ErrorsOnly.allows("listening on stdio"));
+        assert!(StderrLogLevel::ErrorsOnly.allows("ERROR: file not found"));
+        assert!(StderrLogLevel::ErrorsOnly.allows("Warning: deprecated option"));
+    }
+
+    #[test]
+    fn test_load_json_config() {
+        let config_json = r#"{
+  "mcpServers": {
+    "filesystem": {
+      "command": "npx",
+      "args": ["-y", "@modelcontextprotocol/server-filesystem", "/tmp"]
+    },
+    "github": {
+      "command": "npx",
+      "args": ["-y", "@modelcontextprotocol/server-github"],
+      "env": { "GITHUB_TOKEN": "token" }
+    }
+  }
+}"#;
+
+        let mut file = tempfile::Builder::new().suffix(".json").tempfile().unwrap();
+        file.write_all(config_json.as_bytes()).unwrap();
+        file.flush().unwrap();
+
+        let config = McpConfig::load(file.path()).unwrap();
+        assert_eq!(config.servers.len(), 2);
+        assert_eq!(config.servers[0].name, "filesystem");
+        assert_eq!(config.servers[0].transport, Transport::Stdio);
+        assert_eq!(config.servers[0].args.len(), 3);
+        assert_eq!(config.servers[1].name, "github");
+        assert_eq!(
+            config.servers[1].env.as_ref().unwrap().get("GITHUB_TOKEN").map(String::as_str),
+            Some("token")
+        );
+    }
+
+    #[test]
+    fn test_load_json_config_with_http_server() {
+        let config_json = r#"{
+  "mcpServers": {
+    "remote": { "url": "http://localhost:8080/rpc", "api_key": "secret" },
+    "explicit": { "transport": "http", "url": "http://localhost:9090/rpc" }
+  }
+}"#;
+
+        let mut file = tempfile::Builder::new().suffix(".json").tempfile().unwrap();
+        file.write_all(config_json.as_bytes()).unwrap();
+        file.flush().unwrap();
+
+        let config = McpConfig::load(file.path()).unwrap();
+        assert_eq!(config.servers[0].transport, Transport::Http);
+        assert_eq!(config.servers[0].url.as_deref(), Some("http://localhost:8080/rpc"));
+        assert_eq!(config.servers[0].api_key.as_deref(), Some("secret"));
+        assert!(config.servers[0].command.is_empty());
+        assert_eq!(config.servers[1].transport, Transport::Http);
+        assert_eq!(config.servers[1].url.as_deref(), Some("http://localhost:9090/rpc"));
+    }
+
+    #[test]
+    fn test_starter_config_uses_first_available_launcher() {
+        let found = |name: &str| {
+            ["npx", "mcp-server-git", "uvx"]
+                .contains(&name)
+                .then(|| PathBuf::from(format!("/usr/bin/{}", name)))
+        };
+        let toml_text = starter_config_toml(Path::new("/work/repo"), found);
+        assert!(toml_text.contains("# Read and write files under the given directories (found: /usr/bin/npx)"));
+
+        let config: McpConfig = toml::from_str(&toml_text).unwrap();
+        let names: Vec<&str> = config.servers.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, ["filesystem", "git", "fetch", "time", "memory"]);
+        assert_eq!(config.servers[0].command, "npx");
+        assert_eq!(
+            config.servers[0].args,
+            ["-y", "@modelcontextprotocol/server-filesystem", "/work/repo"]
+        );
+        assert_eq!(config.servers[1].command, "mcp-server-git");
+        assert_eq!(config.servers[1].args, ["--repository", "/work/repo"]);
+        assert_eq!(config.servers[2].command, "uvx");
+
+        let empty = starter_config_toml(Path::new("/work"), |_| None);
+        assert!(empty.contains("No known MCP servers"));
+    }
 }
@@ -0,0 +1,208 @@
+// HTTP transport for MCP
+// Each JSON-RPC message is sent as a POST to the server endpoint (e.g. http://host:port/rpc)
+// and the response is the JSON body.
+
+use anyhow::{Context, Result};
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::Duration;
+
+use crate::mcp::types::{JsonRpcNotification, JsonRpcRequest, JsonRpcResponse, RequestId};
+
+/// Default timeout for requests (30 seconds, same as the stdio transport)
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// HTTP POST transport for MCP server communication
+pub struct HttpTransport {
+    client: reqwest::Client,
+    url: String,
+    /// Sent as `Authorization: Bearer <auth>` when set
+    auth: Option<String>,
+    next_id: AtomicI64,
+}
+
+impl HttpTransport {
+    /// Create a transport for the given endpoint URL
+    pub fn new(url: impl Into<String>, api_key: Option<String>) -> Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(DEFAULT_REQUEST_TIMEOUT)
+            .build()
+            .context("Failed to create HTTP client")?;
+
+        Ok(Self {
+            client,
+            url: url.into(),
+            auth: api_key,
+            next_id: AtomicI64::new(1),
+        })
+    }
+
+    /// Build a POST request to the endpoint, with the bearer token if configured
+    fn post(&self) -> reqwest::RequestBuilder {
+        let builder = self.client.post(&self.url);
+        match &self.auth {
+            Some(token) => builder.bearer_auth(token),
+            None => builder,
+        }
+    }
+
+    /// Send a JSON-RPC request and wait for response
+    pub async fn request(
+        &self,
+        method: impl Into<String>,
+        params: Option<serde_json::Value>,
+    ) -> Result<JsonRpcResponse> {
+        let method = method.into();
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let request = JsonRpcRequest::new(method.clone(), params, id);
+
+        let response = self
+            .post()
+            .json(&request)
+            .send()
+            .await
+            .with_context(|| format!("Failed to send '{}' to MCP server {}", method, self.url))?
+            .error_for_status()
+            .with_context(|| format!("MCP server {} rejected '{}'", self.url, method))?;
+
+        let response: JsonRpcResponse = response
+            .json()
+            .await
+            .with_context(|| format!("Failed to parse response to '{}'", method))?;
+
+        // Check for JSON-RPC error
+        if let Some(error) = &response.error {
+            anyhow::bail!(
+                "JSON-RPC error (code {}): {}",
+                error.code,
+                error.message
+            );
+        }
+
+        // Errors may carry a null id (e.g. parse errors), so the id is checked only on success
+        if response.id != RequestId::Number(id) {
+            anyhow::bail!(
+                "MCP server {} answered '{}' (id {}) with mismatched id {:?}",
+                self.url,
+                method,
+                id,
+                response.id
+            );
+        }
+
+        Ok(response)
+    }
+
+    /// Send a JSON-RPC notification (the response body is ignored)
+    pub async fn notify(
+        &self,
+        method: impl Into<String>,
+        params: Option<serde_json::Value>,
+    ) -> Result<()> {
+        let notification = JsonRpcNotification::new(method.into(), params);
+
+        self.post()
+            .json(&notification)
+            .send()
+            .await
+            .with_context(|| format!("Failed to send notification to MCP server {}", self.url))?;
+
+        Ok(())
+    }
+
+    /// HTTP servers have no local process; they are treated as alive
+    pub async fn is_alive(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serve a single HTTP request with `body`, returning the raw request text
+    async fn serve_once(listener: TcpListener, body: &'static str) -> String {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0u8; 4096];
+        loop {
+            let n = socket.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+            let text = String::from_utf8_lossy(&request);
+            if let Some(header_end) = text.find("\r\n\r\n") {
+                let content_length = text[..header_end]
+                    .lines()
+                    .find_map(|line| {
+                        let (name, value) = line.split_once(':')?;
+                        name.eq_ignore_ascii_case("content-length")
+                            .then(|| value.trim().parse::<usize>().ok())
+                            .flatten()
+                    })
+                    .unwrap_or(0);
+                if request.len() >= header_end + 4 + content_length {
+                    break;
+                }
+            }
+        }
+        let reply = format!(
+            "HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        socket.write_all(reply.as_bytes()).await.unwrap();
+        String::from_utf8_lossy(&request).to_string()
+    }
+
+    #[tokio::test]
+    async fn test_request_posts_json_with_bearer_token() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/rpc", listener.local_addr().unwrap());
+        let server = tokio::spawn(serve_once(
+            listener,
+            r#"{"jsonrpc":"2.0","id":1,"result":{"tools":[]}}"#,
+        ));
+
+        let transport = HttpTransport::new(url, Some("secret".to_string())).unwrap();
+        let response = transport.request("tools/list", None).await.unwrap();
+
+        let request = server.await.unwrap();
+        assert!(request.starts_with("POST /rpc "));
+        assert!(request.to_lowercase().contains("authorization: bearer secret"));
+        assert!(request.contains(r#""method":"tools/list""#));
+        assert_eq!(response.result.unwrap()["tools"], serde_json::json!([]));
+    }
+
+    #[tokio::test]
+    async fn test_request_reports_json_rpc_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/rpc", listener.local_addr().unwrap());
+        let server = tokio::spawn(serve_once(
+            listener,
+            r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32601,"message":"Method not found"}}"#,
+        ));
+
+        let transport = HttpTransport::new(url, None).unwrap();
+        let err = transport.request("missing", None).await.unwrap_err();
+
+        let request = server.await.unwrap();
+        assert!(!request.to_lowercase().contains("authorization:"));
+        assert!(err.to_string().contains("Method not found"));
+    }
+
+    #[tokio::test]
+    async fn test_request_rejects_mismatched_response_id() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/rpc", listener.local_addr().unwrap());
+        let server = tokio::spawn(serve_once(
+            listener,
+            r#"{"jsonrpc":"2.0","id":42,"result":{}}"#,
+        ));
+
+        let transport = HttpTransport::new(url, None).unwrap();
+        let err = transport.request("tools/list", None).await.unwrap_err();
+
+        server.await.unwrap();
+        assert!(err.to_string().contains("mismatched id"), "{}", err);
+    }
+}
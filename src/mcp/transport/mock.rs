@@ -0,0 +1,121 @@
+// In-memory transport for testing MCP client logic without spawning a server
+// (unit tests and the integration tests under tests/)
+
+use anyhow::Result;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use crate::mcp::types::{JsonRpcResponse, RequestId};
+
+/// Pre-programmed (method, response) pairs, consumed in order
+type ExpectedRequests = VecDeque<(String, Result<JsonRpcResponse>)>;
+
+/// (method, params) of each request received
+type ReceivedRequests = Vec<(String, Option<serde_json::Value>)>;
+
+/// Transport that answers requests from a queue of pre-programmed responses
+///
+/// Clones share the same queue, so a test can keep a handle for
+/// `assert_all_consumed` after moving the transport into a connection.
+#[derive(Clone, Default)]
+pub struct MockTransport {
+    expected: Arc<Mutex<ExpectedRequests>>,
+    notifications: Arc<Mutex<Vec<String>>>,
+    requests: Arc<Mutex<ReceivedRequests>>,
+    server_notifications: Arc<Mutex<Vec<String>>>,
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue the response for the next request, which must use `method`
+    pub fn expect_request(&self, method: &str, response: Result<JsonRpcResponse>) -> &Self {
+        self.expected
+            .lock()
+            .unwrap()
+            .push_back((method.to_string(), response));
+        self
+    }
+
+    /// Build a successful response carrying `result`
+    pub fn ok(result: serde_json::Value) -> Result<JsonRpcResponse> {
+        Ok(JsonRpcResponse {
+            jsonrpc: "2.0".to_string(),
+            result: Some(result),
+            error: None,
+            id: RequestId::Number(0),
+        })
+    }
+
+    /// Panic if any queued response was not requested
+    pub fn assert_all_consumed(&self) {
+        let expected = self.expected.lock().unwrap();
+        let remaining: Vec<&str> = expected.iter().map(|(method, _)| method.as_str()).collect();
+        assert!(
+            remaining.is_empty(),
+            "unconsumed mock requests: {:?}",
+            remaining
+        );
+    }
+
+    /// (method, params) of the requests received so far, in order
+    pub fn requests(&self) -> ReceivedRequests {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Methods of the notifications sent so far, in order
+    pub fn notifications(&self) -> Vec<String> {
+        self.notifications.lock().unwrap().clone()
+    }
+
+    /// Simulate a notification pushed by the server
+    pub fn push_server_notification(&self, method: &str) {
+        self.server_notifications
+            .lock()
+            .unwrap()
+            .push(method.to_string());
+    }
+
+    /// Drain the simulated server notifications
+    pub fn take_server_notifications(&self) -> Vec<String> {
+        std::mem::take(&mut *self.server_notifications.lock().unwrap())
+    }
+
+    /// Return the next queued response (panics on an unexpected method)
+    pub async fn request(
+        &self,
+        method: impl Into<String>,
+        params: Option<serde_json::Value>,
+    ) -> Result<JsonRpcResponse> {
+        let method = method.into();
+        self.requests.lock().unwrap().push((method.clone(), params));
+        let (expected_method, response) = self
+            .expected
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or_else(|| panic!("unexpected mock request '{}'", method));
+        assert_eq!(
+            method, expected_method,
+            "mock requests arrived out of order"
+        );
+        response
+    }
+
+    /// Record a notification
+    pub async fn notify(
+        &self,
+        method: impl Into<String>,
+        _params: Option<serde_json::Value>,
+    ) -> Result<()> {
+        self.notifications.lock().unwrap().push(method.into());
+        Ok(())
+    }
+
+    /// A mock server never exits
+    pub async fn is_alive(&self) -> bool {
+        true
+    }
+}
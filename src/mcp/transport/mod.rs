@@ -1,5 +1,13 @@
 // MCP transport implementations
 
+#[cfg(any(test, feature = "test-util"))]
+pub mod mock;
+pub mod http;
 pub mod stdio;
+pub mod tracing;
 
-pub use stdio::StdioTransport;
+pub use http::HttpTransport;
+#[cfg(any(test, feature = "test-util"))]
+pub use mock::MockTransport;
+pub use stdio::{StderrLogOptions, StdioTransport};
+pub use tracing::TracingTransport;
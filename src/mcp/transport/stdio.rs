@@ -3,19 +3,46 @@
 // https://modelcontextprotocol.io/docs/concepts/transports
 
 use anyhow::{Context, Result};
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::env;
+use std::fs::OpenOptions;
 use std::io::{BufRead, BufReader, Write};
-use std::process::{Child, ChildStderr, ChildStdin, ChildStdout, Command, Stdio};
-use std::sync::Arc;
+use std::path::PathBuf;
+use std::process::{Child, ChildStderr, ChildStdin, ChildStdout, Command, ExitStatus, Stdio};
+use std::sync::{mpsc, Arc};
 use std::time::Duration;
-use tokio::sync::Mutex;
+use tokio::sync::{watch, Mutex, Notify};
+use tokio::task::JoinHandle;
 
-use crate::mcp::types::{JsonRpcNotification, JsonRpcRequest, JsonRpcResponse, RequestId};
+use crate::mcp::config::StderrLogLevel;
+use crate::mcp::types::{
+    JsonRpcNotification, JsonRpcRequest, JsonRpcResponse, LoggingLevel, LoggingMessageParams,
+    RequestId, TOOLS_LIST_CHANGED,
+};
 
 /// Default timeout for requests (30 seconds)
 const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
 
+/// How long to wait for the exit status after the server closes stdout
+const EXIT_STATUS_WAIT: Duration = Duration::from_millis(500);
+
+/// Consecutive failed heartbeats after which the server is treated as not responding
+const HEARTBEAT_MAX_FAILURES: u32 = 3;
+
+/// Log lines held back for the TUI; beyond this the oldest are dropped
+const MAX_QUEUED_LOG_MESSAGES: usize = 200;
+
+/// Log lines waiting to be shown between TUI turns (bounded by `MAX_QUEUED_LOG_MESSAGES`)
+type LogQueue = Arc<std::sync::Mutex<VecDeque<String>>>;
+
+/// Where and what to log from the server's stderr
+#[derive(Debug, Clone, Default)]
+pub struct StderrLogOptions {
+    /// Append lines to this file instead of the process stderr
+    pub path: Option<PathBuf>,
+    pub level: StderrLogLevel,
+}
+
 /// stdio transport for MCP server communication
 ///
 /// This transport implements the MCP stdio transport specification:
@@ -26,28 +53,66 @@ const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
 pub struct StdioTransport {
     child: Arc<Mutex<Child>>,
     stdin: Arc<Mutex<ChildStdin>>,
-    stdout: Arc<Mutex<BufReader<ChildStdout>>>,
+    /// Requests awaiting a reply, keyed by request ID (`Some` once the reader has stashed it)
+    pending: Arc<Mutex<HashMap<RequestId, Option<JsonRpcResponse>>>>,
+    /// Signalled whenever the reader stashes a response or stops
+    response_notify: Arc<Notify>,
+    /// Set when the reader stops (EOF or read error)
+    reader_error: Arc<Mutex<Option<String>>>,
+    /// Methods of server notifications the client must act on (e.g. tools/list_changed)
+    server_notifications: std::sync::Mutex<mpsc::Receiver<String>>,
+    /// Formatted `notifications/message` lines held back while the chat TUI owns the terminal
+    log_messages: LogQueue,
     next_id: Arc<Mutex<i64>>,
     server_name: String,
     request_timeout: Duration,
+    /// Cleared when heartbeats fail `HEARTBEAT_MAX_FAILURES` times in a row
+    alive: Arc<watch::Sender<bool>>,
+    /// Background `$/ping` task, if a heartbeat was started
+    heartbeat: Option<JoinHandle<()>>,
 }
 
 impl StdioTransport {
     /// Create a new stdio transport by spawning an MCP server process
+    ///
+    /// The command doubles as the server name in logs and errors.
     pub async fn new(
         command: &str,
         args: &[String],
         env: Option<&HashMap<String, String>>,
     ) -> Result<Self> {
-        Self::with_timeout(command, args, env, DEFAULT_REQUEST_TIMEOUT).await
+        Self::with_timeout(
+            command,
+            command,
+            args,
+            env,
+            DEFAULT_REQUEST_TIMEOUT,
+            StderrLogOptions::default(),
+        )
+        .await
+    }
+
+    /// Create a new stdio transport whose stderr is logged according to `stderr_log`
+    ///
+    /// `name` is the configured server name used in logs and errors (e.g. when the server exits).
+    pub async fn with_stderr_log(
+        name: &str,
+        command: &str,
+        args: &[String],
+        env: Option<&HashMap<String, String>>,
+        stderr_log: StderrLogOptions,
+    ) -> Result<Self> {
+        Self::with_timeout(name, command, args, env, DEFAULT_REQUEST_TIMEOUT, stderr_log).await
     }
 
     /// Create a new stdio transport with custom timeout
     pub async fn with_timeout(
+        name: &str,
         command: &str,
         args: &[String],
         env: Option<&HashMap<String, String>>,
         timeout: Duration,
+        stderr_log: StderrLogOptions,
     ) -> Result<Self> {
         let mut cmd = Command::new(command);
         cmd.args(args)
@@ -80,21 +145,108 @@ impl StdioTransport {
             .context("Failed to capture stderr of MCP server")?;
 
         // Spawn task to handle stderr logging
-        Self::spawn_stderr_logger(stderr, command.to_string());
+        Self::spawn_stderr_logger(stderr, name.to_string(), stderr_log);
+
+        let child = Arc::new(Mutex::new(child));
+        let pending = Arc::new(Mutex::new(HashMap::new()));
+        let response_notify = Arc::new(Notify::new());
+        let reader_error = Arc::new(Mutex::new(None));
+        let (notification_tx, notification_rx) = mpsc::channel();
+        let log_messages = LogQueue::default();
+        Self::spawn_stdout_reader(
+            stdout,
+            name.to_string(),
+            Arc::clone(&child),
+            Arc::clone(&pending),
+            Arc::clone(&response_notify),
+            Arc::clone(&reader_error),
+            NotificationSinks {
+                actions: notification_tx,
+                log: Arc::clone(&log_messages),
+            },
+        );
 
         Ok(Self {
-            child: Arc::new(Mutex::new(child)),
+            child,
             stdin: Arc::new(Mutex::new(stdin)),
-            stdout: Arc::new(Mutex::new(BufReader::new(stdout))),
+            pending,
+            response_notify,
+            reader_error,
+            server_notifications: std::sync::Mutex::new(notification_rx),
+            log_messages,
             next_id: Arc::new(Mutex::new(1)),
-            server_name: command.to_string(),
+            server_name: name.to_string(),
             request_timeout: timeout,
+            alive: Arc::new(watch::channel(true).0),
+            heartbeat: None,
         })
     }
 
+    /// Send a `$/ping` notification every `interval` to keep the server from idling out
+    ///
+    /// After `HEARTBEAT_MAX_FAILURES` consecutive failed pings the server is marked as not
+    /// alive (see `alive_watch`) and the heartbeat stops.
+    pub fn start_heartbeat(&mut self, interval: Duration) {
+        if let Some(previous) = self.heartbeat.take() {
+            previous.abort();
+        }
+        let stdin = Arc::clone(&self.stdin);
+        let alive = Arc::clone(&self.alive);
+        let server_name = self.server_name.clone();
+        self.heartbeat = Some(tokio::spawn(async move {
+            let ping = JsonRpcNotification::new("$/ping".to_string(), None);
+            let mut ticker = tokio::time::interval(interval);
+            // The first tick completes immediately; the server was just contacted
+            ticker.tick().await;
+            let mut failures = 0;
+            loop {
+                ticker.tick().await;
+                if write_notification(&stdin, &ping).await.is_ok() {
+                    failures = 0;
+                    continue;
+                }
+                failures += 1;
+                if failures >= HEARTBEAT_MAX_FAILURES {
+                    if !is_tui_enabled() {
+                        eprintln!(
+                            "[MCP: {}] Server is not responding ({} heartbeats failed)",
+                            server_name, failures
+                        );
+                    }
+                    alive.send_replace(false);
+                    break;
+                }
+            }
+        }));
+    }
+
+    /// Subscribe to the heartbeat status (changes to false when the server stops responding)
+    pub fn alive_watch(&self) -> watch::Receiver<bool> {
+        self.alive.subscribe()
+    }
+
     /// Spawn a task to read and log stderr from the server
-    fn spawn_stderr_logger(stderr: ChildStderr, server_name: String) {
+    ///
+    /// Lines go to `options.path` (append mode) when set, otherwise to the process stderr.
+    fn spawn_stderr_logger(stderr: ChildStderr, server_name: String, options: StderrLogOptions) {
         let tui_enabled = is_tui_enabled();
+        let mut log_file = options.path.as_ref().and_then(|path| {
+            match OpenOptions::new().create(true).append(true).open(path) {
+                Ok(file) => Some(file),
+                Err(e) => {
+                    if !tui_enabled {
+                        eprintln!(
+                            "[MCP Server: {}] Failed to open stderr log {}: {}",
+                            server_name,
+                            path.display(),
+                            e
+                        );
+                    }
+                    None
+                }
+            }
+        });
+
         tokio::spawn(async move {
             let mut reader = BufReader::new(stderr);
             let mut line = String::new();
@@ -104,12 +256,15 @@ impl StdioTransport {
                 match reader.read_line(&mut line) {
                     Ok(0) => break, // EOF
                     Ok(_) => {
-                        // Only print non-empty lines
+                        // Only log non-empty lines that pass the level filter
                         let trimmed = line.trim();
-                        if !trimmed.is_empty() {
-                            if !tui_enabled {
-                                eprintln!("[MCP Server: {}]: {}", server_name, trimmed);
-                            }
+                        if trimmed.is_empty() || !options.level.allows(trimmed) {
+                            continue;
+                        }
+                        if let Some(file) = log_file.as_mut() {
+                            let _ = writeln!(file, "[MCP Server: {}]: {}", server_name, trimmed);
+                        } else if !tui_enabled {
+                            eprintln!("[MCP Server: {}]: {}", server_name, trimmed);
                         }
                     }
                     Err(e) => {
@@ -123,6 +278,94 @@ impl StdioTransport {
         });
     }
 
+    /// Spawn a thread that continuously reads stdout and stashes responses by ID
+    ///
+    /// Responses may arrive in any order; each waiter picks its own from `pending`.
+    /// Replies to IDs that are no longer pending (timed out or never sent) are dropped.
+    /// Notifications are handled as soon as they are read; those the client must act on
+    /// and log messages held back for the TUI are forwarded through `notifications`.
+    /// On EOF the server's exit status is included in the error seen by waiters.
+    fn spawn_stdout_reader(
+        stdout: ChildStdout,
+        server_name: String,
+        child: Arc<Mutex<Child>>,
+        pending: Arc<Mutex<HashMap<RequestId, Option<JsonRpcResponse>>>>,
+        response_notify: Arc<Notify>,
+        reader_error: Arc<Mutex<Option<String>>>,
+        notifications: NotificationSinks,
+    ) {
+        std::thread::spawn(move || {
+            let mut reader = BufReader::new(stdout);
+            let mut line = String::new();
+
+            let reason = loop {
+                line.clear();
+                match reader.read_line(&mut line) {
+                    Ok(0) => break describe_exit(&server_name, wait_for_exit(&child)),
+                    Ok(_) => {}
+                    Err(e) => break format!("Failed to read from MCP server stdout: {}", e),
+                }
+
+                let trimmed = line.trim();
+                if trimmed.is_empty() {
+                    continue;
+                }
+
+                // Parse JSON-RPC message
+                let value: serde_json::Value = match serde_json::from_str(trimmed) {
+                    Ok(value) => value,
+                    Err(e) => {
+                        if !is_tui_enabled() {
+                            eprintln!(
+                                "[MCP: {}] Warning: Failed to parse JSON-RPC message ({}): {}",
+                                server_name, e, trimmed
+                            );
+                        }
+                        continue;
+                    }
+                };
+
+                // Check if this is a response (has 'id' field) or notification (no 'id')
+                if value.get("id").is_some() {
+                    match serde_json::from_value::<JsonRpcResponse>(value) {
+                        Ok(response) => {
+                            let mut pending = pending.blocking_lock();
+                            match pending.get_mut(&response.id) {
+                                Some(slot) => {
+                                    *slot = Some(response);
+                                    response_notify.notify_waiters();
+                                }
+                                None => {
+                                    if !is_tui_enabled() {
+                                        eprintln!(
+                                            "[MCP: {}] Warning: Dropped response to unknown or expired request {:?}",
+                                            server_name, response.id
+                                        );
+                                    }
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            if !is_tui_enabled() {
+                                eprintln!(
+                                    "[MCP: {}] Warning: Failed to parse JSON-RPC response: {}",
+                                    server_name, e
+                                );
+                            }
+                        }
+                    }
+                } else {
+                    // This is a server-initiated notification - handle it
+                    super::tracing::trace_incoming(&server_name, &value);
+                    handle_notification(&server_name, &value, &notifications);
+                }
+            };
+
+            *reader_error.blocking_lock() = Some(reason);
+            response_notify.notify_waiters();
+        });
+    }
+
     /// Generate next request ID
     async fn next_id(&self) -> i64 {
         let mut id = self.next_id.lock().await;
@@ -152,17 +395,24 @@ impl StdioTransport {
             "JSON-RPC message contains embedded newline"
         );
 
-        // Send request
-        {
-            let mut stdin = self.stdin.lock().await;
-            writeln!(stdin, "{}", request_json)
-                .context("Failed to write request to MCP server stdin")?;
-            stdin.flush().context("Failed to flush MCP server stdin")?;
+        // Register the ID before sending so the reader keeps the reply
+        let request_id = RequestId::Number(id);
+        self.pending.lock().await.insert(request_id.clone(), None);
+
+        // Send request, then wait for response with timeout
+        let response = async {
+            {
+                let mut stdin = self.stdin.lock().await;
+                writeln!(stdin, "{}", request_json)
+                    .context("Failed to write request to MCP server stdin")?;
+                stdin.flush().context("Failed to flush MCP server stdin")?;
+            }
+            anyhow::Ok(tokio::time::timeout(self.request_timeout, self.read_response(id)).await)
         }
-
-        // Wait for response with timeout
-        let response = tokio::time::timeout(self.request_timeout, self.read_response(id))
-            .await
+        .await;
+        // A reply arriving after a timeout or failure must not be kept forever
+        self.pending.lock().await.remove(&request_id);
+        let response = response?
             .with_context(|| {
                 format!(
                     "Timeout waiting for response to '{}' ({}s)",
@@ -190,110 +440,55 @@ impl StdioTransport {
         params: Option<serde_json::Value>,
     ) -> Result<()> {
         let notification = JsonRpcNotification::new(method.into(), params);
-
-        // Serialize to single-line JSON
-        let notification_json = serde_json::to_string(&notification)
-            .context("Failed to serialize JSON-RPC notification")?;
-
-        debug_assert!(
-            !notification_json.contains('\n'),
-            "JSON-RPC notification contains embedded newline"
-        );
-
-        let mut stdin = self.stdin.lock().await;
-        writeln!(stdin, "{}", notification_json)
-            .context("Failed to write notification to MCP server stdin")?;
-        stdin
-            .flush()
-            .context("Failed to flush MCP server stdin")?;
-
-        Ok(())
+        write_notification(&self.stdin, &notification).await
     }
 
-    /// Read responses from stdout until we get the one with matching ID
+    /// Wait until the background reader has stashed the response with matching ID
     async fn read_response(&self, expected_id: i64) -> Result<JsonRpcResponse> {
         let expected_id = RequestId::Number(expected_id);
 
         loop {
-            let line = {
-                let mut stdout = self.stdout.lock().await;
-                let mut line = String::new();
-
-                stdout
-                    .read_line(&mut line)
-                    .context("Failed to read from MCP server stdout")?;
-
-                if line.is_empty() {
-                    anyhow::bail!("MCP server closed stdout unexpectedly");
-                }
-
-                line
-            };
-
-            // Parse JSON-RPC message
-            let value: serde_json::Value = serde_json::from_str(&line)
-                .with_context(|| format!("Failed to parse JSON-RPC message: {}", line.trim()))?;
-
-            // Check if this is a response (has 'id' field) or notification (no 'id')
-            if value.get("id").is_some() {
-                // This is a response - parse it
-                let response: JsonRpcResponse = serde_json::from_value(value)
-                    .context("Failed to parse JSON-RPC response")?;
-
-                // Check if this is the response we're waiting for
-                if response.id == expected_id {
-                    return Ok(response);
-                } else {
-                    // Received response for different request - this shouldn't happen
-                    // in our usage pattern, but we'll ignore it
-                    if !is_tui_enabled() {
-                        eprintln!(
-                            "[MCP: {}] Warning: Received response for unexpected request ID: {:?}",
-                            self.server_name, response.id
-                        );
-                    }
-                }
-            } else {
-                // This is a server-initiated notification - handle it
-                self.handle_notification(&value).await;
+            // Register for wake-up before checking, so a response stashed in between is not missed
+            let notified = self.response_notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            if let Some(response) = self
+                .pending
+                .lock()
+                .await
+                .get_mut(&expected_id)
+                .and_then(Option::take)
+            {
+                return Ok(response);
+            }
+            if let Some(reason) = self.reader_error.lock().await.as_ref() {
+                anyhow::bail!("[{}] {}", self.server_name, reason);
             }
+
+            notified.await;
         }
     }
 
-    /// Handle server-initiated notifications
-    async fn handle_notification(&self, value: &serde_json::Value) {
-        // Extract method name
-        let method = value
-            .get("method")
-            .and_then(|m| m.as_str())
-            .unwrap_or("unknown");
+    /// Drain the server notifications received since the last call
+    ///
+    /// Only notifications that require client action (such as `notifications/tools/list_changed`)
+    /// are returned, by method name and in arrival order.
+    pub fn take_server_notifications(&self) -> Vec<String> {
+        match self.server_notifications.lock() {
+            Ok(receiver) => receiver.try_iter().collect(),
+            Err(_) => Vec::new(),
+        }
+    }
 
-        match method {
-            "notifications/progress" => {
-                // Progress notification - could display progress
-                if let Some(params) = value.get("params") {
-                    if !is_tui_enabled() {
-                        eprintln!("[MCP: {}] Progress: {:?}", self.server_name, params);
-                    }
-                }
-            }
-            "notifications/message" => {
-                // Message notification - server wants to show something to user
-                if let Some(params) = value.get("params") {
-                    if !is_tui_enabled() {
-                        eprintln!("[MCP: {}] Message: {:?}", self.server_name, params);
-                    }
-                }
-            }
-            _ => {
-                // Unknown notification - log it
-                if !is_tui_enabled() {
-                    eprintln!(
-                        "[MCP: {}] Unknown notification '{}': {:?}",
-                        self.server_name, method, value
-                    );
-                }
-            }
+    /// Drain the log messages received while the chat TUI was active, formatted for display
+    ///
+    /// Outside the TUI, log messages are printed to stderr as they arrive and never queued.
+    /// At most `MAX_QUEUED_LOG_MESSAGES` of the newest messages are kept.
+    pub fn take_log_messages(&self) -> Vec<String> {
+        match self.log_messages.lock() {
+            Ok(mut queue) => queue.drain(..).collect(),
+            Err(_) => Vec::new(),
         }
     }
 
@@ -316,20 +511,200 @@ impl StdioTransport {
         Ok(())
     }
 
-    /// Check if the server process is still running (tests only)
-    #[cfg(test)]
+    /// Check if the server process is still running (and answering heartbeats, if enabled)
     pub async fn is_alive(&self) -> bool {
+        if !*self.alive.borrow() {
+            return false;
+        }
         let mut child = self.child.lock().await;
         child.try_wait().ok().flatten().is_none()
     }
 }
 
+/// Write a notification to the server's stdin as a single line of JSON
+async fn write_notification(
+    stdin: &Mutex<ChildStdin>,
+    notification: &JsonRpcNotification,
+) -> Result<()> {
+    // Serialize to single-line JSON
+    let notification_json = serde_json::to_string(notification)
+        .context("Failed to serialize JSON-RPC notification")?;
+
+    debug_assert!(
+        !notification_json.contains('\n'),
+        "JSON-RPC notification contains embedded newline"
+    );
+
+    let mut stdin = stdin.lock().await;
+    writeln!(stdin, "{}", notification_json)
+        .context("Failed to write notification to MCP server stdin")?;
+    stdin
+        .flush()
+        .context("Failed to flush MCP server stdin")?;
+
+    Ok(())
+}
+
+/// Poll the server process for its exit status for up to `EXIT_STATUS_WAIT`
+///
+/// Closing stdout usually means the process is exiting, but the status may not be
+/// available immediately. Returns None if the process is still running.
+fn wait_for_exit(child: &Mutex<Child>) -> Option<ExitStatus> {
+    let deadline = std::time::Instant::now() + EXIT_STATUS_WAIT;
+    loop {
+        if let Ok(Some(status)) = child.blocking_lock().try_wait() {
+            return Some(status);
+        }
+        if std::time::Instant::now() >= deadline {
+            return None;
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// Build an actionable message for a server that closed stdout
+fn describe_exit(server_name: &str, status: Option<ExitStatus>) -> String {
+    let Some(status) = status else {
+        return format!(
+            "MCP server '{}' closed stdout unexpectedly (process still running). Check server logs.",
+            server_name
+        );
+    };
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return format!(
+                "MCP server '{}' was killed by signal {}{}. Check server logs.",
+                server_name,
+                signal,
+                signal_name(signal).map(|name| format!(" ({})", name)).unwrap_or_default()
+            );
+        }
+    }
+
+    match status.code() {
+        Some(code) => format!(
+            "MCP server '{}' exited with status {}. Check server logs.",
+            server_name, code
+        ),
+        None => format!("MCP server '{}' exited ({}). Check server logs.", server_name, status),
+    }
+}
+
+/// Names of the signals that commonly terminate a server
+#[cfg(unix)]
+fn signal_name(signal: i32) -> Option<&'static str> {
+    match signal {
+        libc::SIGINT => Some("SIGINT"),
+        libc::SIGABRT => Some("SIGABRT"),
+        libc::SIGKILL => Some("SIGKILL"),
+        libc::SIGSEGV => Some("SIGSEGV"),
+        libc::SIGTERM => Some("SIGTERM"),
+        _ => None,
+    }
+}
+
+/// Where the stdout reader forwards notifications instead of printing them
+struct NotificationSinks {
+    /// Methods the client must act on (e.g. tools/list_changed)
+    actions: mpsc::Sender<String>,
+    /// Log lines to show once the TUI is between turns (printing now would garble the input line)
+    log: LogQueue,
+}
+
+/// Queue a log line for the TUI, dropping the oldest once the queue is full
+fn push_log_message(queue: &LogQueue, line: String) {
+    let Ok(mut queue) = queue.lock() else {
+        return;
+    };
+    if queue.len() >= MAX_QUEUED_LOG_MESSAGES {
+        queue.pop_front();
+    }
+    queue.push_back(line);
+}
+
+/// Handle server-initiated notifications
+///
+/// Notifications the client must act on are sent to `forward` instead of being logged,
+/// as are log messages while the chat TUI is active.
+fn handle_notification(server_name: &str, value: &serde_json::Value, forward: &NotificationSinks) {
+    // Extract method name
+    let method = value
+        .get("method")
+        .and_then(|m| m.as_str())
+        .unwrap_or("unknown");
+
+    match method {
+        "notifications/progress" => {
+            // Progress notification - could display progress
+            if let Some(params) = value.get("params") {
+                if !is_tui_enabled() {
+                    eprintln!("[MCP: {}] Progress: {:?}", server_name, params);
+                }
+            }
+        }
+        TOOLS_LIST_CHANGED => {
+            // The receiver is gone only when the transport was dropped
+            let _ = forward.actions.send(method.to_string());
+        }
+        "notifications/message" => {
+            // Log message - the server already filters by the level set with logging/setLevel
+            let Some(params) = value.get("params") else {
+                return;
+            };
+            let line = match serde_json::from_value::<LoggingMessageParams>(params.clone()) {
+                Ok(message) => format_log_message(server_name, &message),
+                Err(_) => format!("[MCP: {}] Message: {:?}", server_name, params),
+            };
+            if is_tui_enabled() {
+                push_log_message(&forward.log, line);
+            } else {
+                eprintln!("{}", line);
+            }
+        }
+        _ => {
+            // Unknown notification - log it
+            if !is_tui_enabled() {
+                eprintln!(
+                    "[MCP: {}] Unknown notification '{}': {:?}",
+                    server_name, method, value
+                );
+            }
+        }
+    }
+}
+
+/// Render a `notifications/message` log entry, e.g. `[MCP: fs] ERROR: disk full`
+///
+/// Warnings and above are upper-cased so they stand out among routine messages.
+fn format_log_message(server_name: &str, message: &LoggingMessageParams) -> String {
+    let source = match &message.logger {
+        Some(logger) => format!("{}/{}", server_name, logger),
+        None => server_name.to_string(),
+    };
+    let level = if message.level >= LoggingLevel::Warning {
+        message.level.as_str().to_uppercase()
+    } else {
+        message.level.as_str().to_string()
+    };
+    let data = match &message.data {
+        serde_json::Value::String(text) => text.clone(),
+        other => other.to_string(),
+    };
+    format!("[MCP: {}] {}: {}", source, level, data)
+}
+
 fn is_tui_enabled() -> bool {
     env::var("RKLLM_TUI").ok().as_deref() == Some("1")
 }
 
 impl Drop for StdioTransport {
     fn drop(&mut self) {
+        if let Some(heartbeat) = self.heartbeat.take() {
+            heartbeat.abort();
+        }
         // Try to kill the child process when dropped
         if let Ok(mut child) = self.child.try_lock() {
             let _ = child.kill();
@@ -373,4 +748,163 @@ mod tests {
             drop(transport);
         }
     }
+
+    #[tokio::test]
+    async fn test_out_of_order_responses_are_matched_by_id() {
+        if cfg!(unix) {
+            // Reads two requests, then answers them in reverse order
+            let script = r#"read a; read b; echo '{"jsonrpc":"2.0","id":2,"result":{"n":2}}'; echo '{"jsonrpc":"2.0","id":1,"result":{"n":1}}'; sleep 1"#;
+            let transport = StdioTransport::new("sh", &["-c".to_string(), script.to_string()], None)
+                .await
+                .unwrap();
+
+            let (first, second) = tokio::join!(
+                transport.request("first", None),
+                transport.request("second", None)
+            );
+            let first = first.unwrap();
+            let second = second.unwrap();
+            assert_eq!(first.id, RequestId::Number(1));
+            assert_eq!(first.result.unwrap()["n"], 1);
+            assert_eq!(second.id, RequestId::Number(2));
+            assert_eq!(second.result.unwrap()["n"], 2);
+        }
+    }
+
+    // The stderr logger blocks a worker while the server is running
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_timed_out_and_unknown_replies_are_not_kept() {
+        if !cfg!(unix) {
+            return;
+        }
+        // Answers the request only after it has timed out, then replies to an ID never sent
+        let script = r#"read a; sleep 0.5; echo '{"jsonrpc":"2.0","id":1,"result":{}}'; echo '{"jsonrpc":"2.0","id":7,"result":{}}'; sleep 1"#;
+        let transport = StdioTransport::with_timeout(
+            "slow",
+            "sh",
+            &["-c".to_string(), script.to_string()],
+            None,
+            Duration::from_millis(100),
+            StderrLogOptions::default(),
+        )
+        .await
+        .unwrap();
+
+        assert!(transport.request("slow", None).await.is_err());
+        assert!(transport.pending.lock().await.is_empty());
+
+        tokio::time::sleep(Duration::from_millis(800)).await;
+        assert!(transport.pending.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_heartbeat_marks_exited_server_not_alive() {
+        if !cfg!(unix) {
+            return;
+        }
+        let mut transport = StdioTransport::new("true", &[], None).await.unwrap();
+        let mut alive = transport.alive_watch();
+        assert!(*alive.borrow());
+
+        transport.start_heartbeat(Duration::from_millis(10));
+        tokio::time::timeout(Duration::from_secs(5), alive.wait_for(|alive| !alive))
+            .await
+            .expect("heartbeat did not detect the exited server")
+            .unwrap();
+        assert!(!transport.is_alive().await);
+    }
+
+    // The stderr logger blocks a worker while `cat` is running
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_heartbeat_keeps_running_server_alive() {
+        if !cfg!(unix) {
+            return;
+        }
+        let mut transport = StdioTransport::new("cat", &[], None).await.unwrap();
+        transport.start_heartbeat(Duration::from_millis(10));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(transport.is_alive().await);
+    }
+
+    #[test]
+    fn test_log_queue_drops_oldest_when_full() {
+        let queue = LogQueue::default();
+        for i in 0..MAX_QUEUED_LOG_MESSAGES + 2 {
+            push_log_message(&queue, format!("line {}", i));
+        }
+        let queue = queue.lock().unwrap();
+        assert_eq!(queue.len(), MAX_QUEUED_LOG_MESSAGES);
+        assert_eq!(queue.front().map(String::as_str), Some("line 2"));
+        assert_eq!(
+            queue.back().cloned(),
+            Some(format!("line {}", MAX_QUEUED_LOG_MESSAGES + 1))
+        );
+    }
+
+    #[test]
+    fn test_format_log_message() {
+        let message = LoggingMessageParams {
+            level: LoggingLevel::Error,
+            logger: Some("db".to_string()),
+            data: serde_json::json!("disk full"),
+        };
+        assert_eq!(format_log_message("fs", &message), "[MCP: fs/db] ERROR: disk full");
+
+        let message = LoggingMessageParams {
+            level: LoggingLevel::Info,
+            logger: None,
+            data: serde_json::json!({ "files": 3 }),
+        };
+        assert_eq!(format_log_message("fs", &message), r#"[MCP: fs] info: {"files":3}"#);
+    }
+
+    #[tokio::test]
+    async fn test_tools_list_changed_is_forwarded() {
+        if cfg!(unix) {
+            let script = r#"read a; echo '{"jsonrpc":"2.0","method":"notifications/tools/list_changed"}'; echo '{"jsonrpc":"2.0","id":1,"result":{}}'; sleep 1"#;
+            let transport = StdioTransport::new("sh", &["-c".to_string(), script.to_string()], None)
+                .await
+                .unwrap();
+
+            // The notification is read before the response, so it is queued by now
+            transport.request("ping", None).await.unwrap();
+            assert_eq!(transport.take_server_notifications(), vec![TOOLS_LIST_CHANGED]);
+            assert!(transport.take_server_notifications().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_server_exit_status_in_error() {
+        if cfg!(unix) {
+            let script = "read a; exit 3";
+            let transport = StdioTransport::with_stderr_log(
+                "fs",
+                "sh",
+                &["-c".to_string(), script.to_string()],
+                None,
+                StderrLogOptions::default(),
+            )
+            .await
+            .unwrap();
+
+            let err = transport.request("ping", None).await.unwrap_err();
+            assert!(
+                err.to_string().contains("MCP server 'fs' exited with status 3"),
+                "{}",
+                err
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_describe_exit_reports_signal() {
+        use std::os::unix::process::ExitStatusExt;
+
+        let killed = describe_exit("fs", Some(ExitStatus::from_raw(libc::SIGKILL)));
+        assert_eq!(killed, "MCP server 'fs' was killed by signal 9 (SIGKILL). Check server logs.");
+        let exited = describe_exit("fs", Some(ExitStatus::from_raw(1 << 8)));
+        assert_eq!(exited, "MCP server 'fs' exited with status 1. Check server logs.");
+        assert!(describe_exit("fs", None).contains("still running"));
+    }
 }
@@ -0,0 +1,181 @@
+// JSON-RPC message tracing for MCP debugging
+
+use anyhow::Result;
+use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(any(test, feature = "test-util"))]
+use super::mock::MockTransport;
+use super::http::HttpTransport;
+use super::stdio::StdioTransport;
+use crate::mcp::types::JsonRpcResponse;
+
+/// Set by `--verbose`; `RKLLM_MCP_TRACE=1` enables tracing as well
+static TRACE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Enable JSON-RPC tracing for every MCP server connection
+pub fn enable_trace() {
+    TRACE_ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Whether JSON-RPC messages should be logged to stderr
+pub fn is_trace_enabled() -> bool {
+    TRACE_ENABLED.load(Ordering::SeqCst) || env::var("RKLLM_MCP_TRACE").ok().as_deref() == Some("1")
+}
+
+/// Log a message sent to the server
+pub fn trace_outgoing(server_name: &str, message: &serde_json::Value) {
+    if is_trace_enabled() {
+        eprintln!("[MCP→] {}: {}", server_name, message);
+    }
+}
+
+/// Log a message received from the server
+pub fn trace_incoming(server_name: &str, message: &serde_json::Value) {
+    if is_trace_enabled() {
+        eprintln!("[MCP←] {}: {}", server_name, message);
+    }
+}
+
+/// Transport decorator that logs every JSON-RPC exchange before delegating to the
+/// underlying stdio or HTTP transport
+///
+/// Logging only happens while tracing is enabled, so connections always go through
+/// this wrapper. Server-initiated notifications are read by the stdio reader thread
+/// and traced there.
+pub struct TracingTransport {
+    inner: Inner,
+    server_name: String,
+}
+
+/// Transport being traced; tests swap in `MockTransport` so no process is spawned
+enum Inner {
+    Stdio(StdioTransport),
+    Http(HttpTransport),
+    #[cfg(any(test, feature = "test-util"))]
+    Mock(MockTransport),
+}
+
+impl TracingTransport {
+    /// Wrap a transport, labelling its messages with `server_name`
+    pub fn new(inner: StdioTransport, server_name: impl Into<String>) -> Self {
+        Self {
+            inner: Inner::Stdio(inner),
+            server_name: server_name.into(),
+        }
+    }
+
+    /// Wrap an HTTP transport, labelling its messages with `server_name`
+    pub fn http(inner: HttpTransport, server_name: impl Into<String>) -> Self {
+        Self {
+            inner: Inner::Http(inner),
+            server_name: server_name.into(),
+        }
+    }
+
+    /// Wrap a mock transport for tests
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn mock(inner: MockTransport, server_name: impl Into<String>) -> Self {
+        Self {
+            inner: Inner::Mock(inner),
+            server_name: server_name.into(),
+        }
+    }
+
+    /// Send a JSON-RPC request and wait for response
+    pub async fn request(
+        &self,
+        method: impl Into<String>,
+        params: Option<serde_json::Value>,
+    ) -> Result<JsonRpcResponse> {
+        let method = method.into();
+        // The request ID is assigned by the inner transport; the response line shows it
+        trace_outgoing(
+            &self.server_name,
+            &serde_json::json!({ "method": method, "params": params }),
+        );
+
+        let result = match &self.inner {
+            Inner::Stdio(inner) => inner.request(method, params).await,
+            Inner::Http(inner) => inner.request(method, params).await,
+            #[cfg(any(test, feature = "test-util"))]
+            Inner::Mock(inner) => inner.request(method, params).await,
+        };
+        if is_trace_enabled() {
+            match &result {
+                Ok(response) => trace_incoming(
+                    &self.server_name,
+                    &serde_json::to_value(response).unwrap_or_default(),
+                ),
+                Err(e) => eprintln!("[MCP←] {}: error: {:#}", self.server_name, e),
+            }
+        }
+        result
+    }
+
+    /// Send a JSON-RPC notification (no response expected)
+    pub async fn notify(
+        &self,
+        method: impl Into<String>,
+        params: Option<serde_json::Value>,
+    ) -> Result<()> {
+        let method = method.into();
+        trace_outgoing(
+            &self.server_name,
+            &serde_json::json!({ "method": method, "params": params }),
+        );
+        match &self.inner {
+            Inner::Stdio(inner) => inner.notify(method, params).await,
+            Inner::Http(inner) => inner.notify(method, params).await,
+            #[cfg(any(test, feature = "test-util"))]
+            Inner::Mock(inner) => inner.notify(method, params).await,
+        }
+    }
+
+    /// Drain the server notifications that require client action
+    ///
+    /// HTTP servers cannot push notifications, so they never report any.
+    pub fn take_server_notifications(&self) -> Vec<String> {
+        match &self.inner {
+            Inner::Stdio(inner) => inner.take_server_notifications(),
+            Inner::Http(_) => Vec::new(),
+            #[cfg(any(test, feature = "test-util"))]
+            Inner::Mock(inner) => inner.take_server_notifications(),
+        }
+    }
+
+    /// Drain the server log messages held back while the chat TUI was active
+    pub fn take_log_messages(&self) -> Vec<String> {
+        match &self.inner {
+            Inner::Stdio(inner) => inner.take_log_messages(),
+            Inner::Http(_) => Vec::new(),
+            #[cfg(any(test, feature = "test-util"))]
+            Inner::Mock(_) => Vec::new(),
+        }
+    }
+
+    /// Start the `$/ping` heartbeat (stdio only; HTTP and mock transports have no process)
+    pub fn start_heartbeat(&mut self, interval: std::time::Duration) {
+        if let Inner::Stdio(inner) = &mut self.inner {
+            inner.start_heartbeat(interval);
+        }
+    }
+
+    /// Heartbeat status of a stdio server (None for transports without a heartbeat)
+    pub fn alive_watch(&self) -> Option<tokio::sync::watch::Receiver<bool>> {
+        match &self.inner {
+            Inner::Stdio(inner) => Some(inner.alive_watch()),
+            _ => None,
+        }
+    }
+
+    /// Check if the server process is still running
+    pub async fn is_alive(&self) -> bool {
+        match &self.inner {
+            Inner::Stdio(inner) => inner.is_alive().await,
+            Inner::Http(inner) => inner.is_alive().await,
+            #[cfg(any(test, feature = "test-util"))]
+            Inner::Mock(inner) => inner.is_alive().await,
+        }
+    }
+}
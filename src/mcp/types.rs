@@ -4,16 +4,21 @@
 
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
 
 /// Protocol version (latest specification)
 pub const PROTOCOL_VERSION: &str = "2025-06-18";
 
+/// Server notification sent when the server's tool list changes
+pub const TOOLS_LIST_CHANGED: &str = "notifications/tools/list_changed";
+
 // ============================================================================
 // JSON-RPC 2.0 Base Types
 // ============================================================================
 
 /// JSON-RPC 2.0 Request ID (can be string, number, or null)
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum RequestId {
     String(String),
@@ -205,6 +210,21 @@ pub struct ServerCapabilities {
     pub tools: Option<ToolsCapability>,
 }
 
+impl ServerCapabilities {
+    /// Names of the advertised capabilities, e.g. `["tools", "resources"]`
+    pub fn supported(&self) -> Vec<&'static str> {
+        [
+            ("tools", self.tools.is_some()),
+            ("prompts", self.prompts.is_some()),
+            ("resources", self.resources.is_some()),
+            ("logging", self.logging.is_some()),
+        ]
+        .into_iter()
+        .filter_map(|(name, present)| present.then_some(name))
+        .collect()
+    }
+}
+
 /// Tools capability
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ToolsCapability {
@@ -320,6 +340,129 @@ pub struct ResourceContents {
     pub blob: Option<String>,
 }
 
+// ============================================================================
+// MCP Resource Types
+// ============================================================================
+
+/// MCP Resource definition (an item of `resources/list`)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Resource {
+    /// Unique identifier of the resource (e.g. `file:///project/src/main.rs`)
+    pub uri: String,
+    /// Human-readable name
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(rename = "mimeType")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mime_type: Option<String>,
+}
+
+/// List resources request parameters
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ListResourcesParams {
+    /// Opaque pagination cursor returned as `nextCursor` by the previous page
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<String>,
+}
+
+/// List resources response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListResourcesResult {
+    pub resources: Vec<Resource>,
+    #[serde(rename = "nextCursor")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+/// Read resource request parameters
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReadResourceParams {
+    pub uri: String,
+}
+
+/// Read resource response (text or base64 `blob` contents for each URI)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReadResourceResult {
+    pub contents: Vec<ResourceContents>,
+}
+
+// ============================================================================
+// MCP Prompt Types
+// ============================================================================
+
+/// MCP Prompt template definition
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Prompt {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arguments: Option<Vec<PromptArgument>>,
+}
+
+/// Argument accepted by a prompt template
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PromptArgument {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub required: Option<bool>,
+}
+
+/// List prompts response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListPromptsResult {
+    pub prompts: Vec<Prompt>,
+    #[serde(rename = "nextCursor")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+/// Get prompt request parameters
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetPromptParams {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub arguments: Option<HashMap<String, String>>,
+}
+
+/// Get prompt response
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GetPromptResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    pub messages: Vec<PromptMessage>,
+}
+
+impl GetPromptResult {
+    /// Concatenate the text of all messages, separated by blank lines
+    ///
+    /// Non-text content is replaced by a short placeholder, as in `ToolResult`.
+    pub fn to_text(&self) -> String {
+        self.messages
+            .iter()
+            .map(|message| match &message.content {
+                Content::Text { text } => text.clone(),
+                Content::Image { mime_type, .. } => format!("[Image: {}]", mime_type),
+                Content::Resource { resource } => resource
+                    .text
+                    .clone()
+                    .unwrap_or_else(|| format!("[Resource: {}]", resource.uri)),
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    }
+}
+
+/// Message rendered from a prompt template
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PromptMessage {
+    pub role: String,
+    pub content: Content,
+}
+
 // ============================================================================
 // High-Level Types for Application Use
 // ============================================================================
@@ -329,6 +472,8 @@ pub struct ResourceContents {
 pub struct ToolCall {
     pub name: String,
     pub arguments: serde_json::Value,
+    /// Server explicitly targeted with `server::tool` syntax (None = first server providing the tool)
+    pub server: Option<String>,
 }
 
 /// Tool execution result (simplified for application use)
@@ -337,6 +482,8 @@ pub struct ToolResult {
     pub name: String,
     pub success: bool,
     pub output: String,
+    /// Time taken by the tool call in milliseconds (0 for built-in tools)
+    pub latency_ms: u64,
 }
 
 impl From<CallToolResult> for ToolResult {
@@ -363,10 +510,92 @@ impl From<CallToolResult> for ToolResult {
             name: String::new(), // Will be set by caller
             success,
             output,
+            latency_ms: 0, // Will be set by caller
+        }
+    }
+}
+
+// ============================================================================
+// Logging
+// ============================================================================
+
+/// Severity of a server log message (syslog levels, least severe first)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LoggingLevel {
+    Debug,
+    Info,
+    Notice,
+    Warning,
+    Error,
+    Critical,
+    Alert,
+    Emergency,
+}
+
+impl LoggingLevel {
+    pub const ALL: [LoggingLevel; 8] = [
+        LoggingLevel::Debug,
+        LoggingLevel::Info,
+        LoggingLevel::Notice,
+        LoggingLevel::Warning,
+        LoggingLevel::Error,
+        LoggingLevel::Critical,
+        LoggingLevel::Alert,
+        LoggingLevel::Emergency,
+    ];
+
+    /// Name used on the wire, e.g. `"warning"`
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LoggingLevel::Debug => "debug",
+            LoggingLevel::Info => "info",
+            LoggingLevel::Notice => "notice",
+            LoggingLevel::Warning => "warning",
+            LoggingLevel::Error => "error",
+            LoggingLevel::Critical => "critical",
+            LoggingLevel::Alert => "alert",
+            LoggingLevel::Emergency => "emergency",
         }
     }
 }
 
+impl fmt::Display for LoggingLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for LoggingLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        LoggingLevel::ALL
+            .into_iter()
+            .find(|level| level.as_str().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                let names: Vec<&str> = LoggingLevel::ALL.iter().map(|l| l.as_str()).collect();
+                format!("unknown log level '{}' (expected one of: {})", s, names.join(", "))
+            })
+    }
+}
+
+/// `logging/setLevel` request parameters
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetLevelParams {
+    pub level: LoggingLevel,
+}
+
+/// `notifications/message` parameters
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoggingMessageParams {
+    pub level: LoggingLevel,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logger: Option<String>,
+    pub data: serde_json::Value,
+}
+
 // ============================================================================
 // Cancellation Support
 // ============================================================================
@@ -408,6 +637,24 @@ pub enum ProgressToken {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_logging_level_parsing_and_order() {
+        assert_eq!("Warning".parse::<LoggingLevel>(), Ok(LoggingLevel::Warning));
+        assert!("verbose".parse::<LoggingLevel>().unwrap_err().contains("debug, info"));
+        assert!(LoggingLevel::Debug < LoggingLevel::Emergency);
+
+        let params = SetLevelParams { level: LoggingLevel::Critical };
+        assert_eq!(serde_json::to_value(&params).unwrap(), serde_json::json!({ "level": "critical" }));
+
+        let message: LoggingMessageParams = serde_json::from_value(serde_json::json!({
+            "level": "error",
+            "data": { "error": "disk full" }
+        }))
+        .unwrap();
+        assert_eq!(message.level, LoggingLevel::Error);
+        assert!(message.logger.is_none());
+    }
+
     #[test]
     fn test_request_serialization() {
         let req = JsonRpcRequest::new("test_method", None, 1);
@@ -438,6 +685,39 @@ mod tests {
         assert_eq!(params.client_info.name, "rkllm-cli");
     }
 
+    #[test]
+    fn test_get_prompt_result_to_text() {
+        let result: GetPromptResult = serde_json::from_str(
+            r#"{"messages":[
+                {"role":"user","content":{"type":"text","text":"Review this code"}},
+                {"role":"user","content":{"type":"resource","resource":{"uri":"file:///a.rs","text":"fn main() {}"}}}
+            ]}"#,
+        )
+        .unwrap();
+        assert!(result.description.is_none());
+        assert_eq!(result.to_text(), "Review this code\n\nfn main() {}");
+    }
+
+    #[test]
+    fn test_resource_results_deserialize() {
+        let list: ListResourcesResult = serde_json::from_str(
+            r#"{"resources":[{"uri":"file:///a.rs","name":"a.rs","mimeType":"text/x-rust"}],"nextCursor":"2"}"#,
+        )
+        .unwrap();
+        assert_eq!(list.resources[0].uri, "file:///a.rs");
+        assert_eq!(list.resources[0].mime_type.as_deref(), Some("text/x-rust"));
+        assert_eq!(list.next_cursor.as_deref(), Some("2"));
+
+        let read: ReadResourceResult = serde_json::from_str(
+            r#"{"contents":[{"uri":"file:///a.rs","text":"fn main() {}"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(read.contents[0].text.as_deref(), Some("fn main() {}"));
+
+        let params = serde_json::to_value(ListResourcesParams::default()).unwrap();
+        assert_eq!(params, serde_json::json!({}));
+    }
+
     #[test]
     fn test_error_codes() {
         assert_eq!(JsonRpcError::PARSE_ERROR, -32700);
@@ -1,9 +1,11 @@
 use crate::file_ops::FileContent;
 use crate::mcp::types::ToolResult;
+use std::collections::HashSet;
 
 /// システム向けの基本方針
 const SYSTEM_INSTRUCTIONS: &str = r#"
-You are a helpful coding assistant running on a local CLI. Always reply in Japanese.
+You are a helpful coding assistant running on a local CLI.
+Current date/time: {current_datetime}. Working directory: {execution_dir}.
 The <files> section is read-only context. Do NOT echo it back. Only create or modify files the user explicitly asked for.
 When the user asks for translation/summarization/rewriting, transform the content accordingly. Do NOT copy the input verbatim unless explicitly instructed.
 If <files> is provided, you MUST base your answer on it and MUST NOT ignore it.
@@ -16,8 +18,12 @@ Never call a tool more than once for the same request; when tool results are ava
 For local file access, use tool calls in this format:
 <tool_call name="read_file">{"path":"path/to/file.txt"}</tool_call>
 <tool_call name="write_file">{"path":"path/to/file.txt","content":"..."}</tool_call>
+Calling read_file with a directory path returns a tree listing (optional "max_depth", default 3).
 "#;
 
+/// 応答言語が指定されていない場合の既定値（既定のシステムプロンプト使用時のみ）
+pub const DEFAULT_RESPONSE_LANGUAGE: &str = "Japanese";
+
 /// ファイル操作の指示（システムプロンプトの補足）
 const FILE_OPERATION_INSTRUCTIONS: &str = r#"
 ## File Operation Instructions
@@ -40,9 +46,74 @@ fn main() {
 </file>
 
 You can create multiple files in a single response.
+To make a small edit to a large existing file, you may output a unified diff instead of the whole file:
+<patch path="path/to/file.ext">
+--- a/path/to/file.ext
++++ b/path/to/file.ext
+@@ -1,1 +1,1 @@
+-old line
++new line
+</patch>
 Preferred format is <file path="..."> ... </file>. Bracket format [CREATE_FILE: ...] ... [END_FILE] is allowed for compatibility only.
 "#;
 
+/// 入力ファイルがあり出力先が指定されていない場合の指示（入力ファイルへの書き戻しを防ぐ）
+const NO_OUTPUT_TARGETS_INSTRUCTION: &str =
+    "No output targets are specified. If creating new files, choose a different name from the input files.";
+
+/// 実行環境の情報（シェルスクリプトやパス表記をモデルに合わせさせるため）
+#[derive(Debug, Clone)]
+pub struct EnvironmentInfo {
+    pub os: String,
+    pub arch: String,
+    pub shell: Option<String>,
+    pub cwd: String,
+}
+
+/// `build_chat_prompt` に渡すプロンプトの構成要素
+///
+/// 指定しない項目は `Default`（ファイル書き込み有効、実行ディレクトリ "."、その他は空）のまま使う。
+#[derive(Debug, Clone, Copy)]
+pub struct PromptParts<'a> {
+    pub user_input: &'a str,
+    pub files: &'a [FileContent],
+    pub errors: &'a [(String, String)],
+    pub tool_info: Option<&'a str>,
+    pub output_targets: &'a [String],
+    pub has_file_op_intent: bool,
+    pub file_writes_enabled: bool,
+    pub tool_results: &'a [ToolResult],
+    pub execution_dir: &'a str,
+    pub prior_turns: &'a [(String, String)],
+    pub system_prompt: Option<&'a str>,
+    pub environment: Option<&'a EnvironmentInfo>,
+    pub last_error: Option<&'a str>,
+    pub response_language: Option<&'a str>,
+    pub extra_context: &'a [String],
+}
+
+impl Default for PromptParts<'_> {
+    fn default() -> Self {
+        Self {
+            user_input: "",
+            files: &[],
+            errors: &[],
+            tool_info: None,
+            output_targets: &[],
+            has_file_op_intent: false,
+            file_writes_enabled: true,
+            tool_results: &[],
+            execution_dir: ".",
+            prior_turns: &[],
+            system_prompt: None,
+            environment: None,
+            last_error: None,
+            response_language: None,
+            extra_context: &[],
+        }
+    }
+}
+
 /// tool-only モード時の指示
 const TOOL_ONLY_INSTRUCTIONS: &str = r#"
 ## Tool-only Mode
@@ -55,32 +126,24 @@ Do not emit <file>...</file> or [CREATE_FILE: ...] blocks. Provide the result di
 /// ファイル内容を含むプロンプトを構築する（テスト用）
 pub fn build_prompt(user_input: &str, files: &[FileContent], errors: &[(String, String)]) -> String {
     use crate::intent::has_file_operation_intent;
-    build_chat_prompt(
+    build_chat_prompt(&PromptParts {
         user_input,
         files,
         errors,
-        None,
-        &[],
-        has_file_operation_intent(user_input),
-        true,
-        &[],
-    )
+        has_file_op_intent: has_file_operation_intent(user_input) > 0.0,
+        ..Default::default()
+    })
 }
 
 #[cfg(test)]
 /// シンプルなプロンプトを構築（テスト用、ファイルなし）
 pub fn build_simple_prompt(user_input: &str) -> String {
     use crate::intent::has_file_operation_intent;
-    build_chat_prompt(
+    build_chat_prompt(&PromptParts {
         user_input,
-        &[],
-        &[],
-        None,
-        &[],
-        has_file_operation_intent(user_input),
-        true,
-        &[],
-    )
+        has_file_op_intent: has_file_operation_intent(user_input) > 0.0,
+        ..Default::default()
+    })
 }
 
 /// 役割分離版のチャットプロンプトを構築
@@ -89,27 +152,62 @@ pub fn build_simple_prompt(user_input: &str) -> String {
 /// - tools: MCPツール情報（任意）
 /// - context: <files> ブロック（参照専用）
 /// - user_input: ユーザー入力
-pub fn build_chat_prompt(
-    user_input: &str,
-    files: &[FileContent],
-    errors: &[(String, String)],
-    tool_info: Option<&str>,
-    output_targets: &[String],
-    has_file_op_intent: bool,
-    file_writes_enabled: bool,
-    tool_results: &[ToolResult],
-) -> String {
+///
+/// system には現在日時と実行ディレクトリ（`execution_dir`）を埋め込む。
+/// `prior_turns`（(ユーザー発言, アシスタント応答) の古い順）が空でなければ、
+/// <user_input> の直前に <conversation_history> として埋め込む。
+/// `system_prompt` が指定された場合は既定の基本方針（SYSTEM_INSTRUCTIONS）を置き換える。
+/// `environment` が指定された場合は <user_input> の直前に <environment> を埋め込む。
+/// `last_error`（前のターンの失敗内容）が指定された場合は <user_input> の直前に
+/// <last_error> として埋め込み、再試行時にモデルが同じ失敗を避けられるようにする。
+/// `response_language` が指定された場合は system に "Please respond in <language>." を加える
+/// （未指定なら既定のシステムプロンプトでのみ `DEFAULT_RESPONSE_LANGUAGE` を使う）。
+/// `extra_context`（`--include-context` で渡されたテキスト）は <files> 内に
+/// <context_snippet> として埋め込む。
+/// 入力ファイルがあり `output_targets` が空なら、入力ファイルと別の名前で書くよう指示を加える。
+pub fn build_chat_prompt(parts: &PromptParts) -> String {
+    let PromptParts {
+        user_input,
+        files,
+        errors,
+        tool_info,
+        output_targets,
+        has_file_op_intent,
+        file_writes_enabled,
+        tool_results,
+        execution_dir,
+        prior_turns,
+        system_prompt,
+        environment,
+        last_error,
+        response_language,
+        extra_context,
+    } = *parts;
     let mut prompt = String::new();
 
     // system
+    let current_datetime = chrono::Local::now().format("%Y-%m-%d %H:%M %Z").to_string();
+    let system = system_prompt
+        .unwrap_or(SYSTEM_INSTRUCTIONS)
+        .replace("{current_datetime}", &current_datetime)
+        .replace("{execution_dir}", execution_dir);
     prompt.push_str("<system>\n");
-    prompt.push_str(SYSTEM_INSTRUCTIONS);
+    prompt.push_str(&system);
     prompt.push_str("\n");
+    let language =
+        response_language.or(system_prompt.is_none().then_some(DEFAULT_RESPONSE_LANGUAGE));
+    if let Some(language) = language {
+        prompt.push_str(&format!("Please respond in {}.\n", language));
+    }
     if !file_writes_enabled {
         prompt.push_str(TOOL_ONLY_INSTRUCTIONS);
         prompt.push_str("\n");
     } else if has_file_op_intent {
         prompt.push_str(FILE_OPERATION_INSTRUCTIONS);
+        if !files.is_empty() && output_targets.is_empty() {
+            prompt.push_str(NO_OUTPUT_TARGETS_INSTRUCTION);
+            prompt.push('\n');
+        }
         prompt.push_str("\n");
     }
     prompt.push_str("</system>\n\n");
@@ -123,8 +221,22 @@ pub fn build_chat_prompt(
         }
     }
 
-    // context files
-    if !files.is_empty() || !errors.is_empty() {
+    // context files（read_file の結果に同じ内容があるファイルは重複させない）
+    let tool_read_paths = read_file_result_paths(tool_results);
+    let files: Vec<&FileContent> = files
+        .iter()
+        .filter(|file| {
+            let duplicated = tool_read_paths.contains(&file.original_path);
+            if duplicated && std::env::var("RKLLM_DEBUG_PROMPT").is_ok() {
+                eprintln!(
+                    "[File {} already present in tool_results, omitted from context]",
+                    file.original_path
+                );
+            }
+            !duplicated
+        })
+        .collect();
+    if !files.is_empty() || !errors.is_empty() || !extra_context.is_empty() {
         prompt.push_str("<files>\n");
 
         for file in files {
@@ -134,6 +246,10 @@ pub fn build_chat_prompt(
             ));
         }
 
+        for snippet in extra_context {
+            prompt.push_str(&format!("<context_snippet>\n{}\n</context_snippet>\n\n", snippet));
+        }
+
         for (path, error) in errors {
             prompt.push_str(&format!(
                 "<file_error path=\"{}\">\n{}\n</file_error>\n\n",
@@ -164,6 +280,37 @@ pub fn build_chat_prompt(
         prompt.push_str("</tool_results>\n\n");
     }
 
+    // conversation history
+    if !prior_turns.is_empty() {
+        prompt.push_str("<conversation_history>\n");
+        for (user, assistant) in prior_turns {
+            prompt.push_str(&format!(
+                "<turn role=\"user\">{}</turn><turn role=\"assistant\">{}</turn>\n",
+                user, assistant
+            ));
+        }
+        prompt.push_str("</conversation_history>\n\n");
+    }
+
+    // environment
+    if let Some(env) = environment {
+        prompt.push_str("<environment>\n");
+        prompt.push_str(&format!("os: {}\n", env.os));
+        prompt.push_str(&format!("arch: {}\n", env.arch));
+        if let Some(shell) = &env.shell {
+            prompt.push_str(&format!("shell: {}\n", shell));
+        }
+        prompt.push_str(&format!("cwd: {}\n", env.cwd));
+        prompt.push_str("</environment>\n\n");
+    }
+
+    // last error
+    if let Some(error) = last_error.filter(|e| !e.trim().is_empty()) {
+        prompt.push_str("<last_error>\n");
+        prompt.push_str(error.trim());
+        prompt.push_str("\n</last_error>\n\n");
+    }
+
     // user input
     prompt.push_str("<user_input>\n");
     prompt.push_str(user_input);
@@ -172,6 +319,19 @@ pub fn build_chat_prompt(
     prompt
 }
 
+/// 成功した `read_file` ツール結果に含まれる `path` を集める
+fn read_file_result_paths(tool_results: &[ToolResult]) -> HashSet<String> {
+    tool_results
+        .iter()
+        .filter(|result| result.name == "read_file" && result.success)
+        .filter_map(|result| {
+            let value: serde_json::Value = serde_json::from_str(&result.output).ok()?;
+            value.get("content")?;
+            value.get("path")?.as_str().map(str::to_string)
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -180,35 +340,35 @@ mod tests {
     #[test]
     fn test_has_file_operation_intent_japanese() {
         // ファイル操作の意図がある
-        assert!(has_file_operation_intent("test.txtを作成して"));
-        assert!(has_file_operation_intent("ファイルを書いて"));
-        assert!(has_file_operation_intent("ファイルを作成"));
-        assert!(has_file_operation_intent("コードを生成してください"));
-        assert!(has_file_operation_intent("結果を保存して"));
+        assert!(has_file_operation_intent("test.txtを作成して") > 0.0);
+        assert!(has_file_operation_intent("ファイルを書いて") > 0.0);
+        assert!(has_file_operation_intent("ファイルを作成") > 0.0);
+        assert!(has_file_operation_intent("コードを生成してください") > 0.0);
+        assert!(has_file_operation_intent("結果を保存して") > 0.0);
 
         // ファイル操作の意図がない
-        assert!(!has_file_operation_intent("こんにちは"));
-        assert!(!has_file_operation_intent("日本の首都は？"));
-        assert!(!has_file_operation_intent("これは何ですか？"));
-        assert!(!has_file_operation_intent("ファイルを要約して"));
-        assert!(!has_file_operation_intent("ファイルを読んで"));
-        assert!(!has_file_operation_intent("このファイルは何？"));
+        assert_eq!(has_file_operation_intent("こんにちは"), 0.0);
+        assert_eq!(has_file_operation_intent("日本の首都は？"), 0.0);
+        assert_eq!(has_file_operation_intent("これは何ですか？"), 0.0);
+        assert_eq!(has_file_operation_intent("ファイルを要約して"), 0.0);
+        assert_eq!(has_file_operation_intent("ファイルを読んで"), 0.0);
+        assert_eq!(has_file_operation_intent("このファイルは何？"), 0.0);
     }
 
     #[test]
     fn test_has_file_operation_intent_english() {
         // ファイル操作の意図がある
-        assert!(has_file_operation_intent("create a file"));
-        assert!(has_file_operation_intent("write to example.txt"));
-        assert!(has_file_operation_intent("generate code"));
-        assert!(has_file_operation_intent("save the output"));
-        assert!(has_file_operation_intent("create file test.txt"));
+        assert!(has_file_operation_intent("create a file") > 0.0);
+        assert!(has_file_operation_intent("write to example.txt") > 0.0);
+        assert!(has_file_operation_intent("generate code") > 0.0);
+        assert!(has_file_operation_intent("save the output") > 0.0);
+        assert!(has_file_operation_intent("create file test.txt") > 0.0);
 
         // ファイル操作の意図がない
-        assert!(!has_file_operation_intent("hello"));
-        assert!(!has_file_operation_intent("what is this?"));
-        assert!(!has_file_operation_intent("summarize the file"));
-        assert!(!has_file_operation_intent("read the file"));
+        assert_eq!(has_file_operation_intent("hello"), 0.0);
+        assert_eq!(has_file_operation_intent("what is this?"), 0.0);
+        assert_eq!(has_file_operation_intent("summarize the file"), 0.0);
+        assert_eq!(has_file_operation_intent("read the file"), 0.0);
     }
 
     #[test]
@@ -286,35 +446,298 @@ mod tests {
             content: "Hello".to_string(),
             original_path: "a.txt".to_string(),
         }];
-        let prompt = build_chat_prompt(
-            "翻訳して b.txt に保存",
-            &files,
-            &[],
-            None,
-            &["b.txt".to_string()],
-            true,
-            true,
-            &[],
-        );
+        let prompt = build_chat_prompt(&PromptParts {
+            user_input: "翻訳して b.txt に保存",
+            files: &files,
+            output_targets: &["b.txt".to_string()],
+            has_file_op_intent: true,
+            ..Default::default()
+        });
         assert!(prompt.contains("<output_targets>"));
         assert!(prompt.contains("<target>b.txt</target>"));
     }
 
+    #[test]
+    fn test_no_output_targets_instruction_only_with_input_files() {
+        let files = vec![FileContent {
+            content: "Hello".to_string(),
+            original_path: "a.txt".to_string(),
+        }];
+        let build = |files: &[FileContent], targets: &[String]| {
+            build_chat_prompt(&PromptParts {
+                user_input: "翻訳してファイルに保存して",
+                files,
+                output_targets: targets,
+                has_file_op_intent: true,
+                ..Default::default()
+            })
+        };
+
+        assert!(build(&files, &[]).contains(NO_OUTPUT_TARGETS_INSTRUCTION));
+        assert!(!build(&files, &["b.txt".to_string()]).contains(NO_OUTPUT_TARGETS_INSTRUCTION));
+        assert!(!build(&[], &[]).contains(NO_OUTPUT_TARGETS_INSTRUCTION));
+    }
+
+    #[test]
+    fn test_system_includes_datetime_and_execution_dir() {
+        let prompt = build_chat_prompt(&PromptParts {
+            user_input: "hello",
+            execution_dir: "/work/project",
+            ..Default::default()
+        });
+        assert!(prompt.contains("Working directory: /work/project."));
+        assert!(!prompt.contains("{current_datetime}"));
+        assert!(!prompt.contains("{execution_dir}"));
+    }
+
     #[test]
     fn test_tool_only_instructions_and_no_output_targets() {
-        let prompt = build_chat_prompt(
-            "test.txtを作成して",
-            &[],
-            &[],
-            None,
-            &["test.txt".to_string()],
-            true,
-            false,
-            &[],
-        );
+        let prompt = build_chat_prompt(&PromptParts {
+            user_input: "test.txtを作成して",
+            output_targets: &["test.txt".to_string()],
+            has_file_op_intent: true,
+            file_writes_enabled: false,
+            ..Default::default()
+        });
 
         assert!(prompt.contains("Tool-only Mode"));
         assert!(!prompt.contains("<output_targets>"));
         assert!(!prompt.contains("File Operation Instructions"));
     }
+
+    #[test]
+    fn test_conversation_history_before_user_input() {
+        let turns = vec![
+            ("a.txt を要約して".to_string(), "要約しました。".to_string()),
+            ("もっと短く".to_string(), "短くしました。".to_string()),
+        ];
+        let prompt = build_chat_prompt(&PromptParts {
+            user_input: "ありがとう",
+            prior_turns: &turns,
+            ..Default::default()
+        });
+
+        let history_pos = prompt.find("<conversation_history>").unwrap();
+        let input_pos = prompt.find("<user_input>").unwrap();
+        assert!(history_pos < input_pos);
+        assert!(prompt.contains(
+            "<turn role=\"user\">もっと短く</turn><turn role=\"assistant\">短くしました。</turn>"
+        ));
+
+        let prompt = build_simple_prompt("ありがとう");
+        assert!(!prompt.contains("<conversation_history>"));
+    }
+
+    #[test]
+    fn test_environment_block_before_user_input() {
+        let environment = EnvironmentInfo {
+            os: "linux".to_string(),
+            arch: "aarch64".to_string(),
+            shell: Some("/bin/zsh".to_string()),
+            cwd: "/home/user/project".to_string(),
+        };
+        let prompt = build_chat_prompt(&PromptParts {
+            user_input: "ビルドスクリプトを書いて",
+            environment: Some(&environment),
+            ..Default::default()
+        });
+
+        let env_pos = prompt.find("<environment>").unwrap();
+        let input_pos = prompt.find("<user_input>").unwrap();
+        assert!(env_pos < input_pos);
+        assert!(prompt.contains("os: linux\narch: aarch64\nshell: /bin/zsh\ncwd: /home/user/project\n"));
+
+        let prompt = build_simple_prompt("hello");
+        assert!(!prompt.contains("<environment>"));
+    }
+
+    #[test]
+    fn test_last_error_block_before_user_input() {
+        let prompt = build_chat_prompt(&PromptParts {
+            user_input: "もう一度お願い",
+            last_error: Some("Inference timed out; the response was cut off."),
+            ..Default::default()
+        });
+
+        let error_pos = prompt.find("<last_error>\nInference timed out").unwrap();
+        let input_pos = prompt.find("<user_input>").unwrap();
+        assert!(error_pos < input_pos);
+
+        let prompt = build_simple_prompt("hello");
+        assert!(!prompt.contains("<last_error>"));
+    }
+
+    #[test]
+    fn test_files_already_read_by_tool_are_omitted() {
+        let files = vec![
+            FileContent {
+                content: "fn main() {}".to_string(),
+                original_path: "src/main.rs".to_string(),
+            },
+            FileContent {
+                content: "# Title".to_string(),
+                original_path: "README.md".to_string(),
+            },
+        ];
+        let tool_results = vec![ToolResult {
+            name: "read_file".to_string(),
+            success: true,
+            output: serde_json::json!({"path": "src/main.rs", "content": "fn main() {}"}).to_string(),
+            latency_ms: 0,
+        }];
+        let prompt = build_chat_prompt(&PromptParts {
+            user_input: "main.rs を説明して",
+            files: &files,
+            tool_results: &tool_results,
+            ..Default::default()
+        });
+
+        assert!(!prompt.contains("<file path=\"src/main.rs\">"));
+        assert!(prompt.contains("<file path=\"README.md\">"));
+        assert_eq!(prompt.matches("fn main() {}").count(), 1);
+    }
+
+    #[test]
+    fn test_system_prompt_override_replaces_default() {
+        let prompt = build_chat_prompt(&PromptParts {
+            user_input: "契約書を要約して",
+            system_prompt: Some("You are a legal assistant. Today is {current_datetime}."),
+            ..Default::default()
+        });
+        assert!(prompt.contains("You are a legal assistant."));
+        assert!(!prompt.contains("{current_datetime}"));
+        assert!(!prompt.contains("helpful coding assistant"));
+        assert!(!prompt.contains("Please respond in"));
+    }
+
+    #[test]
+    fn test_response_language_instruction() {
+        let prompt = build_simple_prompt("Hello");
+        assert!(prompt.contains("Please respond in Japanese.\n"));
+
+        let prompt = build_chat_prompt(&PromptParts {
+            user_input: "Hello",
+            response_language: Some("English"),
+            ..Default::default()
+        });
+        assert!(prompt.contains("Please respond in English.\n"));
+        assert!(!prompt.contains("Japanese"));
+    }
+
+    #[test]
+    fn test_extra_context_snippets_in_files_block() {
+        let snippets = vec!["error[E0308]: mismatched types".to_string()];
+        let prompt = build_chat_prompt(&PromptParts {
+            user_input: "Fix the build",
+            extra_context: &snippets,
+            ..Default::default()
+        });
+        let files_start = prompt.find("<files>").unwrap();
+        let snippet_pos = prompt
+            .find("<context_snippet>\nerror[E0308]: mismatched types\n</context_snippet>")
+            .unwrap();
+        let files_end = prompt.find("</files>").unwrap();
+        assert!(files_start < snippet_pos && snippet_pos < files_end);
+    }
+
+    mod snapshots {
+        use super::*;
+
+        /// スナップショット用のプロンプト（実行ごとに変わる現在日時を固定の文字列に置き換える）
+        fn snapshot_prompt(
+            user_input: &str,
+            files: &[FileContent],
+            tool_info: Option<&str>,
+            output_targets: &[String],
+            file_writes_enabled: bool,
+            tool_results: &[ToolResult],
+        ) -> String {
+            let prompt = build_chat_prompt(&PromptParts {
+                user_input,
+                files,
+                tool_info,
+                output_targets,
+                has_file_op_intent: true,
+                file_writes_enabled,
+                tool_results,
+                execution_dir: "/work",
+                ..Default::default()
+            });
+            let start = prompt.find("Current date/time: ").unwrap() + "Current date/time: ".len();
+            let end = start + prompt[start..].find(". Working directory").unwrap();
+            format!("{}<datetime>{}", &prompt[..start], &prompt[end..])
+        }
+
+        fn input_file() -> Vec<FileContent> {
+            vec![FileContent {
+                content: "fn main() {\n    println!(\"hello\");\n}".to_string(),
+                original_path: "src/main.rs".to_string(),
+            }]
+        }
+
+        #[test]
+        fn snapshot_no_files() {
+            insta::assert_snapshot!(snapshot_prompt("hello.txt を作成して", &[], None, &[], true, &[]));
+        }
+
+        #[test]
+        fn snapshot_single_file() {
+            insta::assert_snapshot!(snapshot_prompt("コメントを追加して保存して", &input_file(), None, &[], true, &[]));
+        }
+
+        #[test]
+        fn snapshot_file_and_tools() {
+            let tool_info = "## Available Tools\n\n### search\nSearch the web\n";
+            insta::assert_snapshot!(snapshot_prompt(
+                "コメントを追加して保存して",
+                &input_file(),
+                Some(tool_info),
+                &[],
+                true,
+                &[]
+            ));
+        }
+
+        #[test]
+        fn snapshot_file_and_tool_results() {
+            let tool_results = vec![ToolResult {
+                name: "search".to_string(),
+                success: true,
+                output: "println! prints to stdout".to_string(),
+                latency_ms: 0,
+            }];
+            insta::assert_snapshot!(snapshot_prompt(
+                "コメントを追加して保存して",
+                &input_file(),
+                None,
+                &[],
+                true,
+                &tool_results
+            ));
+        }
+
+        #[test]
+        fn snapshot_tool_only_mode() {
+            insta::assert_snapshot!(snapshot_prompt(
+                "コメントを追加して保存して",
+                &input_file(),
+                None,
+                &["src/main_commented.rs".to_string()],
+                false,
+                &[]
+            ));
+        }
+
+        #[test]
+        fn snapshot_output_targets() {
+            insta::assert_snapshot!(snapshot_prompt(
+                "コメントを追加して保存して",
+                &input_file(),
+                None,
+                &["src/main_commented.rs".to_string()],
+                true,
+                &[]
+            ));
+        }
+    }
 }
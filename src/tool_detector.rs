@@ -1,32 +1,146 @@
 // Tool call detection from LLM output
 
+use once_cell::sync::Lazy;
 use regex::Regex;
+use serde::Deserialize;
 use serde_json::Value;
 
 use crate::mcp::types::ToolCall;
 
+// A comma directly before a closing brace or bracket
+static TRAILING_COMMA_PATTERN: Lazy<Regex> = Lazy::new(|| Regex::new(r",\s*([}\]])").unwrap());
+
+// One <argument name="...">value</argument> inside an XML-style call body
+static XML_ARG_PATTERN: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"<argument\s+name="([^"]+)"\s*>([^<]*)</argument>"#).unwrap()
+});
+
+/// Strip trailing commas (`{"key": "value",}`), which many models emit but JSON rejects
+pub fn sanitize_json(s: &str) -> String {
+    TRAILING_COMMA_PATTERN.replace_all(s, "$1").into_owned()
+}
+
+/// Parse JSON, retrying with trailing commas stripped if the text is not valid as-is
+///
+/// Valid JSON is parsed untouched so commas inside string values are never rewritten.
+fn parse_json_lenient(s: &str) -> Option<Value> {
+    serde_json::from_str::<Value>(s)
+        .or_else(|_| serde_json::from_str::<Value>(&sanitize_json(s)))
+        .ok()
+}
+
+/// User-defined tool call delimiters (`[tools] custom_patterns` in config.toml)
+///
+/// `open` may contain a `{name}` placeholder for the tool name, e.g.
+/// `{ open = "<<<TOOL: {name}>>>", close = "<<<END_TOOL>>>", body_format = "json" }`.
+/// Without the placeholder the body must be `{"name": ..., "arguments": {...}}`.
+#[derive(Debug, Clone, Deserialize, PartialEq)]
+pub struct CustomPattern {
+    pub open: String,
+    pub close: String,
+    #[serde(default)]
+    pub body_format: BodyFormat,
+}
+
+/// How the text between the custom delimiters is parsed
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BodyFormat {
+    #[default]
+    Json,
+}
+
+impl CustomPattern {
+    /// Build the regex matching one call; `name` is the tool name (if any), `body` the payload
+    fn to_regex(&self) -> Result<Regex, regex::Error> {
+        let open = match self.open.split_once("{name}") {
+            Some((before, after)) => format!(
+                r"{}(?P<name>[\w.:-]+){}",
+                regex::escape(before),
+                regex::escape(after)
+            ),
+            None => regex::escape(&self.open),
+        };
+        Regex::new(&format!(
+            r"(?s){}\s*(?P<body>.*?)\s*{}",
+            open,
+            regex::escape(&self.close)
+        ))
+    }
+}
+
+/// Builds a tool call from one match of a registered pattern (`None` skips the match)
+pub type ToolCallParser = Box<dyn Fn(&regex::Captures) -> Option<ToolCall> + Send + Sync>;
+
 /// Tool call detector that extracts tool calls from LLM output
 pub struct ToolCallDetector {
-    json_pattern: Regex,
-    xml_pattern: Regex,
-    xml_arg_pattern: Regex,
+    /// Registered (name, pattern, parser) triples, tried in registration order
+    patterns: Vec<(String, Regex, ToolCallParser)>,
+    openai_pattern: Regex,
+    mistral_pattern: Regex,
+    custom_patterns: Vec<(Regex, BodyFormat)>,
 }
 
 impl ToolCallDetector {
     /// Create a new tool call detector
-    pub fn new() -> Self {
-        Self {
-            // JSON style: [TOOL_CALL] {...} [END_TOOL_CALL]
-            json_pattern: Regex::new(
-                r"(?s)\[TOOL_CALL\]\s*(\{.*?\})\s*\[END_TOOL_CALL\]"
+    ///
+    /// `custom_patterns` are tried in addition to the built-in formats; invalid
+    /// ones are reported and skipped.
+    pub fn new(custom_patterns: &[CustomPattern]) -> Self {
+        let custom_patterns = custom_patterns
+            .iter()
+            .filter_map(|pattern| match pattern.to_regex() {
+                Ok(regex) => Some((regex, pattern.body_format)),
+                Err(e) => {
+                    eprintln!("[Tools] Ignoring custom pattern '{}': {}", pattern.open, e);
+                    None
+                }
+            })
+            .collect();
+
+        let mut detector = Self {
+            patterns: Vec::new(),
+            // OpenAI style: {"function_call": {"name": "...", "arguments": "..."}}
+            openai_pattern: Regex::new(
+                r#"\{\s*"function_call"\s*:"#
             ).unwrap(),
-            // XML style: <tool_call name="...">...</tool_call>
-            xml_pattern: Regex::new(
-                r#"<tool_call\s+name="([^"]+)"\s*>([\s\S]*?)</tool_call>"#
+            // Mistral style: [TOOL_CALLS] [{"name": "...", "arguments": {...}}, ...]
+            mistral_pattern: Regex::new(
+                r"\[TOOL_CALLS\]\s*\["
             ).unwrap(),
-            xml_arg_pattern: Regex::new(
-                r#"<argument\s+name="([^"]+)"\s*>([^<]*)</argument>"#
+            custom_patterns,
+        };
+
+        // JSON style: [TOOL_CALL] {...} [END_TOOL_CALL]
+        detector.register_pattern(
+            "json",
+            Regex::new(r"(?s)\[TOOL_CALL\]\s*(\{.*?\})\s*\[END_TOOL_CALL\]").unwrap(),
+            Box::new(parse_json_call),
+        );
+        // XML style: <tool_call name="...">...</tool_call>
+        // (tolerates single quotes, spaces around '=' and whitespace inside the closing tag)
+        detector.register_pattern(
+            "xml",
+            Regex::new(
+                r#"<tool_call\s+name\s*=\s*["']([^"']+)["']\s*>([\s\S]*?)<\s*/\s*tool_call\s*>"#
             ).unwrap(),
+            Box::new(parse_xml_call),
+        );
+
+        detector
+    }
+
+    /// Register a pattern whose matches are turned into tool calls by `parser`
+    ///
+    /// Patterns are tried in registration order; registering an existing `name`
+    /// replaces that pattern in place.
+    pub fn register_pattern(&mut self, name: &str, pattern: Regex, parser: ToolCallParser) {
+        match self.patterns.iter_mut().find(|(existing, _, _)| existing == name) {
+            Some(entry) => {
+                entry.1 = pattern;
+                entry.2 = parser;
+            }
+            None => self.patterns.push((name.to_string(), pattern, parser)),
         }
     }
 
@@ -34,95 +148,231 @@ impl ToolCallDetector {
     pub fn detect(&self, text: &str) -> Vec<ToolCall> {
         let mut calls = Vec::new();
 
-        // Detect JSON style
-        calls.extend(self.detect_json_style(text));
+        // Detect registered styles (JSON and XML by default)
+        for (_, pattern, parser) in &self.patterns {
+            calls.extend(pattern.captures_iter(text).filter_map(|cap| parser(&cap)));
+        }
+
+        // Detect OpenAI function-call style
+        calls.extend(self.detect_openai_style(text));
+
+        // Detect Mistral/Mixtral style
+        calls.extend(self.detect_mistral_style(text));
 
-        // Detect XML style
-        calls.extend(self.detect_xml_style(text));
+        // Detect user-defined styles
+        calls.extend(self.detect_custom_style(text));
 
         calls
     }
 
-    /// Detect JSON-style tool calls
-    fn detect_json_style(&self, text: &str) -> Vec<ToolCall> {
+    /// Detect OpenAI function-call style tool calls
+    ///
+    /// `arguments` is usually a JSON-encoded string, so it is parsed a second time.
+    fn detect_openai_style(&self, text: &str) -> Vec<ToolCall> {
         let mut calls = Vec::new();
 
-        for cap in self.json_pattern.captures_iter(text) {
-            if let Ok(value) = serde_json::from_str::<Value>(&cap[1]) {
-                if let Some(obj) = value.as_object() {
-                    if let (Some(name), Some(args)) = (
-                        obj.get("name").and_then(|v| v.as_str()),
-                        obj.get("arguments"),
-                    ) {
-                        calls.push(ToolCall {
-                            name: name.to_string(),
-                            arguments: args.clone(),
-                        });
-                    }
-                }
-            }
+        for m in self.openai_pattern.find_iter(text) {
+            // Parse only the first complete JSON value starting at the match
+            let mut stream = serde_json::Deserializer::from_str(&text[m.start()..]).into_iter::<Value>();
+            let Some(Ok(value)) = stream.next() else {
+                continue;
+            };
+            let Some(function_call) = value.get("function_call").and_then(|v| v.as_object()) else {
+                continue;
+            };
+            let Some(name) = function_call
+                .get("name")
+                .and_then(|v| v.as_str())
+                .filter(|name| is_valid_name(name))
+            else {
+                continue;
+            };
+
+            let arguments = match function_call.get("arguments") {
+                Some(Value::String(encoded)) => serde_json::from_str::<Value>(encoded)
+                    .unwrap_or_else(|_| Value::String(encoded.clone())),
+                Some(other) => other.clone(),
+                None => Value::Object(serde_json::Map::new()),
+            };
+
+            calls.push(ToolCall {
+                name: name.to_string(),
+                arguments,
+                server: None,
+            });
         }
 
         calls
     }
 
-    /// Detect XML-style tool calls
-    fn detect_xml_style(&self, text: &str) -> Vec<ToolCall> {
+    /// Detect Mistral/Mixtral-style tool calls
+    ///
+    /// The array is parsed with serde_json from the opening bracket, so brackets
+    /// inside argument values do not cut it short.
+    fn detect_mistral_style(&self, text: &str) -> Vec<ToolCall> {
         let mut calls = Vec::new();
 
-        for cap in self.xml_pattern.captures_iter(text) {
-            let name = cap[1].to_string();
-            let args_str = cap[2].trim();
-
-            if args_str.starts_with('{') {
-                if let Ok(value) = serde_json::from_str::<Value>(args_str) {
-                    if let Some(obj) = value.as_object() {
-                        if let Some(arguments) = obj.get("arguments").and_then(|v| v.as_object()) {
-                            calls.push(ToolCall {
-                                name,
-                                arguments: Value::Object(arguments.clone()),
-                            });
-                            continue;
-                        }
+        for m in self.mistral_pattern.find_iter(text) {
+            let array_start = m.end() - 1;
+            let mut stream = serde_json::Deserializer::from_str(&text[array_start..]).into_iter::<Value>();
+            let Some(Ok(Value::Array(items))) = stream.next() else {
+                continue;
+            };
+
+            for item in items {
+                let Some(name) = item
+                    .get("name")
+                    .and_then(|v| v.as_str())
+                    .filter(|name| is_valid_name(name))
+                else {
+                    continue;
+                };
+                let arguments = match item.get("arguments") {
+                    Some(Value::String(encoded)) => serde_json::from_str::<Value>(encoded)
+                        .unwrap_or_else(|_| Value::String(encoded.clone())),
+                    Some(other) => other.clone(),
+                    None => Value::Object(serde_json::Map::new()),
+                };
 
-                        calls.push(ToolCall {
-                            name,
-                            arguments: value,
-                        });
-                        continue;
-                    }
-                }
+                calls.push(ToolCall {
+                    name: name.to_string(),
+                    arguments,
+                    server: None,
+                });
             }
+        }
 
-            // Simple XML argument parsing
-            let mut args = serde_json::Map::new();
+        calls
+    }
 
-            for arg_cap in self.xml_arg_pattern.captures_iter(args_str) {
-                let arg_name = arg_cap[1].to_string();
-                let arg_value = arg_cap[2].to_string();
+    /// Detect tool calls using the custom patterns from config
+    fn detect_custom_style(&self, text: &str) -> Vec<ToolCall> {
+        let mut calls = Vec::new();
 
-                // Try to parse as JSON value, otherwise use as string
-                let value = match serde_json::from_str::<Value>(&arg_value) {
-                    Ok(v) => v,
-                    Err(_) => Value::String(arg_value),
+        for (pattern, body_format) in &self.custom_patterns {
+            for cap in pattern.captures_iter(text) {
+                let value = match body_format {
+                    BodyFormat::Json => match serde_json::from_str::<Value>(&cap["body"]) {
+                        Ok(value) => value,
+                        Err(_) => continue,
+                    },
                 };
 
-                args.insert(arg_name, value);
-            }
+                let (raw_name, arguments) = match cap.name("name") {
+                    // Name comes from the opening delimiter; the body is the arguments
+                    // (or an object wrapping them in "arguments")
+                    Some(name) => {
+                        let arguments = value.get("arguments").cloned().unwrap_or(value);
+                        (name.as_str().to_string(), arguments)
+                    }
+                    None => {
+                        let Some(name) = value.get("name").and_then(|v| v.as_str()) else {
+                            continue;
+                        };
+                        let arguments = value
+                            .get("arguments")
+                            .cloned()
+                            .unwrap_or_else(|| Value::Object(serde_json::Map::new()));
+                        (name.to_string(), arguments)
+                    }
+                };
+                let Some((server, name)) = split_tool_name(&raw_name) else {
+                    continue;
+                };
 
-            calls.push(ToolCall {
-                name,
-                arguments: Value::Object(args),
-            });
+                calls.push(ToolCall {
+                    name,
+                    arguments,
+                    server,
+                });
+            }
         }
 
         calls
     }
 }
 
+/// Whether a tool or server name can be called (not blank, no NUL bytes)
+fn is_valid_name(name: &str) -> bool {
+    !name.trim().is_empty() && !name.contains('\0')
+}
+
+/// Split `server_name::tool_name` into its parts; None if either part is not a valid name
+fn split_tool_name(raw: &str) -> Option<(Option<String>, String)> {
+    let (server, name) = match raw.split_once("::") {
+        Some((server, tool)) => (Some(server), tool),
+        None => (None, raw),
+    };
+    if !is_valid_name(name) || server.is_some_and(|server| !is_valid_name(server)) {
+        return None;
+    }
+    Some((server.map(str::to_string), name.to_string()))
+}
+
+/// Parse a JSON-style call: the body is `{"name": ..., "arguments": {...}}`
+fn parse_json_call(cap: &regex::Captures) -> Option<ToolCall> {
+    let value = parse_json_lenient(&cap[1])?;
+    let obj = value.as_object()?;
+    let name = obj
+        .get("name")
+        .and_then(|v| v.as_str())
+        .filter(|name| is_valid_name(name))?;
+    let arguments = obj.get("arguments")?;
+
+    Some(ToolCall {
+        name: name.to_string(),
+        arguments: arguments.clone(),
+        server: None,
+    })
+}
+
+/// Parse an XML-style call; the body is JSON or `<argument name="...">` elements
+fn parse_xml_call(cap: &regex::Captures) -> Option<ToolCall> {
+    // "server_name::tool_name" targets a specific MCP server
+    let (server, name) = split_tool_name(&cap[1])?;
+    let args_str = cap[2].trim();
+
+    // Body is usually a raw JSON object (see build_tool_sample_block)
+    if let Some(value) = parse_json_lenient(args_str)
+        && let Some(obj) = value.as_object()
+    {
+        let arguments = match obj.get("arguments").and_then(|v| v.as_object()) {
+            Some(arguments) => Value::Object(arguments.clone()),
+            None => value,
+        };
+        return Some(ToolCall {
+            name,
+            arguments,
+            server,
+        });
+    }
+
+    // Fall back to simple XML argument parsing
+    let mut args = serde_json::Map::new();
+
+    for arg_cap in XML_ARG_PATTERN.captures_iter(args_str) {
+        let arg_name = arg_cap[1].to_string();
+        let arg_value = arg_cap[2].to_string();
+
+        // Try to parse as JSON value, otherwise use as string
+        let value = match serde_json::from_str::<Value>(&arg_value) {
+            Ok(v) => v,
+            Err(_) => Value::String(arg_value),
+        };
+
+        args.insert(arg_name, value);
+    }
+
+    Some(ToolCall {
+        name,
+        arguments: Value::Object(args),
+        server,
+    })
+}
+
 impl Default for ToolCallDetector {
     fn default() -> Self {
-        Self::new()
+        Self::new(&[])
     }
 }
 
@@ -130,9 +380,56 @@ impl Default for ToolCallDetector {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_detect_custom_pattern_with_name_placeholder() {
+        let patterns = vec![CustomPattern {
+            open: "<<<TOOL: {name}>>>".to_string(),
+            close: "<<<END_TOOL>>>".to_string(),
+            body_format: BodyFormat::Json,
+        }];
+        let detector = ToolCallDetector::new(&patterns);
+
+        let text = "Reading it.\n<<<TOOL: read_file>>>\n{\"path\": \"a.txt\"}\n<<<END_TOOL>>>\nDone.";
+
+        let calls = detector.detect(text);
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].name, "read_file");
+        assert_eq!(calls[0].server, None);
+        assert_eq!(
+            calls[0].arguments.get("path").and_then(|v| v.as_str()),
+            Some("a.txt")
+        );
+
+        let text = "<<<TOOL: fs::read_file>>>\n{\"path\": \"a.txt\"}\n<<<END_TOOL>>>";
+        let calls = detector.detect(text);
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].name, "read_file");
+        assert_eq!(calls[0].server.as_deref(), Some("fs"));
+    }
+
+    #[test]
+    fn test_detect_custom_pattern_without_name_placeholder() {
+        let patterns: Vec<CustomPattern> = toml::from_str::<toml::Value>(
+            "patterns = [{ open = \"<call>\", close = \"</call>\" }]",
+        )
+        .unwrap()["patterns"]
+            .clone()
+            .try_into()
+            .unwrap();
+        let detector = ToolCallDetector::new(&patterns);
+
+        let text = r#"<call>{"name": "search", "arguments": {"q": "rust"}}</call>"#;
+
+        let calls = detector.detect(text);
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].name, "search");
+        assert_eq!(calls[0].arguments.get("q").and_then(|v| v.as_str()), Some("rust"));
+        assert!(ToolCallDetector::default().detect(text).is_empty());
+    }
+
     #[test]
     fn test_detect_json_style() {
-        let detector = ToolCallDetector::new();
+        let detector = ToolCallDetector::default();
 
         let text = r#"
 Let me check the weather for you.
@@ -160,7 +457,7 @@ I'll get that information for you.
 
     #[test]
     fn test_detect_xml_style() {
-        let detector = ToolCallDetector::new();
+        let detector = ToolCallDetector::default();
 
         let text = r#"
 I'll search for that file.
@@ -188,7 +485,7 @@ Here are the results.
 
     #[test]
     fn test_detect_xml_style_json_body() {
-        let detector = ToolCallDetector::new();
+        let detector = ToolCallDetector::default();
 
         let text = r#"
 <tool_call name="read_file">
@@ -205,9 +502,55 @@ Here are the results.
         );
     }
 
+    #[test]
+    fn test_sanitize_json_strips_trailing_commas() {
+        assert_eq!(sanitize_json(r#"{"key": "value",}"#), r#"{"key": "value"}"#);
+        assert_eq!(sanitize_json("[1, 2, 3 , ]"), "[1, 2, 3 ]");
+        assert_eq!(
+            sanitize_json("{\"a\": {\"b\": [1,],\n},\n}"),
+            "{\"a\": {\"b\": [1]}}"
+        );
+        assert_eq!(sanitize_json(r#"{"a": 1, "b": 2}"#), r#"{"a": 1, "b": 2}"#);
+    }
+
+    #[test]
+    fn test_detect_tolerates_trailing_commas() {
+        let detector = ToolCallDetector::default();
+
+        let text = r#"<tool_call name="write_file">
+{
+  "path": "out.txt",
+  "options": {"append": true, "modes": ["a", "b",],},
+}
+</tool_call>
+[TOOL_CALL] {"name": "read_file", "arguments": {"path": "a.txt",},} [END_TOOL_CALL]"#;
+
+        let calls = detector.detect(text);
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].name, "read_file");
+        assert_eq!(calls[0].arguments["path"], "a.txt");
+        assert_eq!(calls[1].name, "write_file");
+        assert_eq!(calls[1].arguments["options"]["modes"], serde_json::json!(["a", "b"]));
+    }
+
+    #[test]
+    fn test_detect_xml_style_inline_json_body() {
+        let detector = ToolCallDetector::default();
+
+        let text = r#"<tool_call name="read_file">{"path": "/tmp/x.txt"}</tool_call>"#;
+
+        let calls = detector.detect(text);
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].name, "read_file");
+        assert_eq!(
+            calls[0].arguments.get("path").and_then(|v| v.as_str()),
+            Some("/tmp/x.txt")
+        );
+    }
+
     #[test]
     fn test_detect_multiple_calls() {
-        let detector = ToolCallDetector::new();
+        let detector = ToolCallDetector::default();
 
         let text = r#"
 First, I'll check the weather:
@@ -229,13 +572,194 @@ Then I'll save it to a file:
         assert_eq!(calls[1].name, "write_file");
     }
 
+    #[test]
+    fn test_detect_openai_style() {
+        let detector = ToolCallDetector::default();
+
+        let text = r#"
+I'll read it.
+{"function_call": {"name": "read_file", "arguments": "{\"path\": \"foo.txt\"}"}}
+Done.
+"#;
+
+        let calls = detector.detect(text);
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].name, "read_file");
+        assert_eq!(
+            calls[0].arguments.get("path").and_then(|v| v.as_str()),
+            Some("foo.txt")
+        );
+    }
+
+    #[test]
+    fn test_detect_openai_style_object_arguments() {
+        let detector = ToolCallDetector::default();
+
+        let text = r#"{"function_call": {"name": "get_weather", "arguments": {"location": "Tokyo"}}}"#;
+
+        let calls = detector.detect(text);
+        assert_eq!(calls.len(), 1);
+        assert_eq!(
+            calls[0].arguments.get("location").and_then(|v| v.as_str()),
+            Some("Tokyo")
+        );
+    }
+
+    #[test]
+    fn test_detect_xml_style_server_qualified_name() {
+        let detector = ToolCallDetector::default();
+
+        let calls = detector.detect(r#"<tool_call name="docs::read_file">{"path": "a.md"}</tool_call>"#);
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].server.as_deref(), Some("docs"));
+        assert_eq!(calls[0].name, "read_file");
+        assert_eq!(calls[0].arguments["path"], "a.md");
+
+        let calls = detector.detect(r#"<tool_call name="read_file">{"path": "a.md"}</tool_call>"#);
+        assert_eq!(calls[0].server, None);
+    }
+
+    #[test]
+    fn test_detect_drops_empty_and_nul_names() {
+        let detector = ToolCallDetector::default();
+
+        for text in [
+            r#"[TOOL_CALL] {"name":"","arguments":{}} [END_TOOL_CALL]"#,
+            r#"<tool_call name="srv::">{}</tool_call>"#,
+            r#"<tool_call name="::read_file">{}</tool_call>"#,
+            "<tool_call name=\"read\0file\">{}</tool_call>",
+        ] {
+            assert!(detector.detect(text).is_empty(), "detected a call in {:?}", text);
+        }
+    }
+
+    #[test]
+    fn test_detect_mistral_style_single() {
+        let detector = ToolCallDetector::default();
+
+        let text = r#"[TOOL_CALLS] [{"name": "read_file", "arguments": {"path": "notes[1].md"}}]"#;
+
+        let calls = detector.detect(text);
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].name, "read_file");
+        assert_eq!(
+            calls[0].arguments.get("path").and_then(|v| v.as_str()),
+            Some("notes[1].md")
+        );
+    }
+
+    #[test]
+    fn test_detect_mistral_style_multiple() {
+        let detector = ToolCallDetector::default();
+
+        let text = r#"
+[TOOL_CALLS] [
+  {"name": "get_weather", "arguments": {"location": "Tokyo"}},
+  {"name": "write_file", "arguments": {"path": "weather.txt", "content": "sunny"}}
+]
+"#;
+
+        let calls = detector.detect(text);
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].name, "get_weather");
+        assert_eq!(calls[0].arguments["location"], "Tokyo");
+        assert_eq!(calls[1].name, "write_file");
+        assert_eq!(calls[1].arguments["content"], "sunny");
+    }
+
+    #[test]
+    fn test_register_pattern_adds_detection() {
+        let mut detector = ToolCallDetector::default();
+        detector.register_pattern(
+            "call",
+            Regex::new(r"CALL (\w+)\((\w*)\)").unwrap(),
+            Box::new(|cap| {
+                Some(ToolCall {
+                    name: cap[1].to_string(),
+                    arguments: serde_json::json!({ "path": &cap[2] }),
+                    server: None,
+                })
+            }),
+        );
+
+        let calls = detector.detect(
+            "CALL read_file(main) and <tool_call name=\"list_files\">{}</tool_call>",
+        );
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[0].name, "list_files");
+        assert_eq!(calls[1].name, "read_file");
+        assert_eq!(calls[1].arguments["path"], "main");
+    }
+
     #[test]
     fn test_no_tool_calls() {
-        let detector = ToolCallDetector::new();
+        let detector = ToolCallDetector::default();
 
         let text = "This is just regular text without any tool calls.";
 
         let calls = detector.detect(text);
         assert_eq!(calls.len(), 0);
     }
+
+    #[test]
+    fn test_xml_no_args_call() {
+        let detector = ToolCallDetector::default();
+        let calls = detector.detect("<tool_call name=\"ping\">\n</tool_call>");
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].name, "ping");
+        assert_eq!(calls[0].arguments, serde_json::json!({}));
+    }
+
+    #[test]
+    fn test_xml_nested_json_and_escaped_quotes() {
+        let detector = ToolCallDetector::default();
+        let text = r#"<tool_call name="write-file2">
+{"path": "a.json", "content": "say \"hi\"", "options": {"mode": {"append": true}}}
+</tool_call  >"#;
+        let calls = detector.detect(text);
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].name, "write-file2");
+        assert_eq!(calls[0].arguments["content"], "say \"hi\"");
+        assert_eq!(calls[0].arguments["options"]["mode"]["append"], true);
+    }
+
+    #[test]
+    fn test_xml_tolerates_loose_tag_syntax() {
+        let detector = ToolCallDetector::default();
+        let calls = detector.detect("<tool_call name = 'list_dir_2' >{\"path\": \"/tmp\"}< /tool_call>   ");
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].name, "list_dir_2");
+        assert_eq!(calls[0].arguments["path"], "/tmp");
+    }
+
+    mod prop {
+        use super::*;
+        use proptest::prelude::*;
+
+        proptest! {
+            #[test]
+            fn detect_never_panics(text in "\\PC*") {
+                let _ = ToolCallDetector::default().detect(&text);
+            }
+
+            #[test]
+            fn xml_call_roundtrips(
+                name in "[a-z][a-z0-9_-]{0,15}",
+                args in proptest::collection::btree_map("[a-z_]{1,8}", "[^<]{0,20}", 0..4),
+                leading in "[ \t\n]{0,3}",
+                trailing in "[ \t\n]{0,3}",
+            ) {
+                let arguments = serde_json::to_value(&args).unwrap();
+                let text = format!(
+                    "<tool_call name=\"{}\">{}{}{}</tool_call>",
+                    name, leading, arguments, trailing
+                );
+
+                let calls = ToolCallDetector::default().detect(&text);
+                prop_assert_eq!(calls.len(), 1);
+                prop_assert_eq!(&calls[0].name, &name);
+                prop_assert_eq!(&calls[0].arguments, &arguments);
+            }
+        }
+    }
 }
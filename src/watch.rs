@@ -0,0 +1,129 @@
+//! `--watch` / `/watch` 用のファイル監視
+//!
+//! glob に一致するファイルの変更を検知し、チャットループが直前の質問を再実行できるようにする。
+
+use anyhow::{anyhow, Context, Result};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// glob に一致するファイルの変更を監視する
+pub struct FileWatch {
+    pattern: String,
+    // 監視を続けるために保持する（drop すると監視が止まる）
+    _watcher: RecommendedWatcher,
+    // 最後に変更されたファイル（取り出すまで保持する）
+    changed: Arc<Mutex<Option<PathBuf>>>,
+}
+
+impl FileWatch {
+    /// 監視を開始する
+    ///
+    /// エディタは一時ファイルからのリネームで保存することが多いため、ファイルそのものではなく
+    /// 一致したファイルの親ディレクトリを監視し、イベントのパスを glob で絞り込む。
+    ///
+    /// # 引数
+    /// * `pattern` - 監視するファイルの glob（相対パスは `base` からの相対）
+    /// * `base` - 相対パスの基準ディレクトリ
+    ///
+    /// # エラー
+    /// glob が不正な場合、一致するファイルがない場合、監視を開始できない場合
+    pub fn start(pattern: &str, base: &Path) -> Result<Self> {
+        let absolute = if Path::new(pattern).is_absolute() {
+            pattern.to_string()
+        } else {
+            let base = glob::Pattern::escape(&base.to_string_lossy());
+            format!("{}/{}", base.trim_end_matches('/'), pattern)
+        };
+        let matcher = glob::Pattern::new(&absolute)
+            .with_context(|| format!("Invalid watch pattern: {}", pattern))?;
+
+        let dirs: BTreeSet<PathBuf> = glob::glob(&absolute)
+            .with_context(|| format!("Invalid watch pattern: {}", pattern))?
+            .filter_map(|entry| entry.ok())
+            .filter(|path| path.is_file())
+            .filter_map(|path| path.parent().map(Path::to_path_buf))
+            .collect();
+        if dirs.is_empty() {
+            return Err(anyhow!("No files match watch pattern: {}", pattern));
+        }
+
+        let changed = Arc::new(Mutex::new(None));
+        let changed_ref = Arc::clone(&changed);
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+            let Ok(event) = event else {
+                return;
+            };
+            if !matches!(event.kind, EventKind::Modify(_) | EventKind::Create(_)) {
+                return;
+            }
+            if let Some(path) = event.paths.into_iter().find(|p| matcher.matches_path(p))
+                && let Ok(mut changed) = changed_ref.lock()
+            {
+                *changed = Some(path);
+            }
+        })
+        .context("Failed to create file watcher")?;
+        for dir in &dirs {
+            watcher
+                .watch(dir, RecursiveMode::NonRecursive)
+                .with_context(|| format!("Failed to watch directory: {}", dir.display()))?;
+        }
+
+        Ok(Self {
+            pattern: pattern.to_string(),
+            _watcher: watcher,
+            changed,
+        })
+    }
+
+    /// 監視している glob
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    /// 前回の呼び出し以降に変更されたファイルを取り出す（なければ None）
+    pub fn take_change(&self) -> Option<PathBuf> {
+        self.changed.lock().ok().and_then(|mut changed| changed.take())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+    use tempfile::TempDir;
+
+    #[test]
+    fn start_rejects_pattern_without_matches() {
+        let dir = TempDir::new().unwrap();
+        let err = FileWatch::start("*.rs", dir.path()).err().unwrap();
+        assert!(err.to_string().contains("No files match"));
+    }
+
+    #[test]
+    fn take_change_reports_modified_file() {
+        let dir = TempDir::new().unwrap();
+        let watched = dir.path().join("main.rs");
+        std::fs::write(&watched, "fn main() {}").unwrap();
+        std::fs::write(dir.path().join("notes.txt"), "").unwrap();
+
+        let watch = FileWatch::start("*.rs", dir.path()).unwrap();
+        assert_eq!(watch.pattern(), "*.rs");
+        assert!(watch.take_change().is_none());
+
+        std::fs::write(dir.path().join("notes.txt"), "ignored").unwrap();
+        std::fs::write(&watched, "fn main() { println!(); }").unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let changed = loop {
+            if let Some(path) = watch.take_change() {
+                break path;
+            }
+            assert!(Instant::now() < deadline, "no change event received");
+            std::thread::sleep(Duration::from_millis(20));
+        };
+        assert_eq!(changed.file_name().unwrap(), "main.rs");
+    }
+}
@@ -0,0 +1,89 @@
+// End-to-end test of `ChatSession::run_once` with the mock RKLLM backend
+// (`RKLLM_MOCK_RESPONSE`) and an in-memory MCP server, so it runs without
+// Rockchip hardware or external MCP servers.
+
+use rkllm_cli::ChatSessionBuilder;
+use rkllm_cli::mcp::McpClient;
+use rkllm_cli::mcp::transport::MockTransport;
+use rkllm_cli::mcp::types::PROTOCOL_VERSION;
+use serde_json::json;
+
+// A bare `read_file` is the built-in local reader; the server prefix sends it to MCP
+const MOCK_RESPONSE: &str =
+    "<tool_call name=\"mock-fs::read_file\">{\"path\": \"notes.txt\"}</tool_call>";
+
+/// A mock server exposing only `read_file`, which answers one call with `contents`
+fn read_file_server(contents: &str) -> MockTransport {
+    let mock = MockTransport::new();
+    mock.expect_request(
+        "initialize",
+        MockTransport::ok(json!({
+            "protocolVersion": PROTOCOL_VERSION,
+            "capabilities": { "tools": {} },
+            "serverInfo": { "name": "mock-fs", "version": "1.0" }
+        })),
+    )
+    .expect_request(
+        "tools/list",
+        MockTransport::ok(json!({
+            "tools": [{
+                "name": "read_file",
+                "description": "Read a file",
+                "inputSchema": {
+                    "type": "object",
+                    "properties": { "path": { "type": "string" } },
+                    "required": ["path"]
+                }
+            }]
+        })),
+    )
+    .expect_request(
+        "tools/call",
+        MockTransport::ok(json!({ "content": [{ "type": "text", "text": contents }] })),
+    );
+    mock
+}
+
+#[tokio::test]
+async fn run_once_calls_mcp_tool_and_feeds_result_back() {
+    // SAFETY: this is the only test in this binary, so no other thread reads the environment
+    unsafe {
+        std::env::set_var("RKLLM_MOCK_RESPONSE", MOCK_RESPONSE);
+        std::env::set_var("RKLLM_NO_BANNER", "1");
+    }
+    let workdir = tempfile::TempDir::new().unwrap();
+    let server = read_file_server("mock file contents: 42");
+    let client = McpClient::connect_mock("mock-fs", server.clone()).await.unwrap();
+
+    let session = ChatSessionBuilder::new("mock.rkllm")
+        .mcp_client(client)
+        .confirm_writes(false)
+        .execution_dir(Some(workdir.path().to_path_buf()))
+        .build()
+        .await
+        .unwrap();
+
+    let response = session
+        .run_once("What do my notes say?", |_| {})
+        .await
+        .unwrap();
+
+    // The follow-up inference reruns the mock, which answers with the same tool call
+    assert!(response.contains("read_file"));
+
+    server.assert_all_consumed();
+    let calls: Vec<_> = server
+        .requests()
+        .into_iter()
+        .filter(|(method, _)| method == "tools/call")
+        .collect();
+    assert_eq!(calls.len(), 1);
+    let params = calls[0].1.as_ref().unwrap();
+    assert_eq!(params["name"], "read_file");
+    assert_eq!(params["arguments"], json!({ "path": "notes.txt" }));
+
+    let followup = session.last_prompt().unwrap();
+    assert!(followup.contains("<tool_results>"));
+    assert!(followup.contains("<tool_result name=\"read_file\" success=\"true\">"));
+    assert!(followup.contains("mock file contents: 42"));
+}